@@ -0,0 +1,92 @@
+//! Helpers the peer and the relay have to agree on, a peer and relay computing the Noise
+//! prologue differently can't connect to each other.
+
+use libp2p::{Multiaddr, identity, multiaddr::Protocol};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Hashes a string to a [u8; 32] key using SHA-256.
+pub fn string_to_32_bytes(s: &str) -> [u8; 32] {
+    let hash = Sha256::digest(s.as_bytes());
+    let mut arr = [0u8; 32];
+    arr.copy_from_slice(&hash[..]);
+    arr
+}
+
+pub fn generate_ed25519() -> identity::Keypair {
+    identity::Keypair::generate_ed25519()
+}
+
+/// Deterministic keypair for the seed, only meant for testing
+pub fn generate_ed25519_from_seed(secret_key_seed: u8) -> identity::Keypair {
+    let mut bytes = [0u8; 32];
+    bytes[0] = secret_key_seed;
+
+    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+}
+
+/// Transports a node listens and dials over
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transports {
+    #[default]
+    Both,
+    /// Only QUIC, for networks where TCP is undesirable
+    Quic,
+    /// Only TCP, for networks blocking UDP
+    Tcp,
+}
+
+impl Transports {
+    pub fn quic(self) -> bool {
+        self != Transports::Tcp
+    }
+
+    pub fn tcp(self) -> bool {
+        self != Transports::Quic
+    }
+
+    /// Whether the address runs over one of the transports, addresses of neither are assumed to
+    /// be handled elsewhere
+    pub fn supports(self, address: &Multiaddr) -> bool {
+        if address.iter().any(|protocol| protocol == Protocol::QuicV1) {
+            self.quic()
+        } else if address
+            .iter()
+            .any(|protocol| matches!(protocol, Protocol::Tcp(_)))
+        {
+            self.tcp()
+        } else {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_hashes_to_its_sha256() {
+        let key = string_to_32_bytes("secret");
+
+        let hex = key
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        assert_eq!(
+            hex,
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+    }
+
+    #[test]
+    fn seeded_keypair_has_a_fixed_peer_id() {
+        let keypair = generate_ed25519_from_seed(1);
+
+        assert_eq!(
+            keypair.public().to_peer_id().to_string(),
+            "12D3KooWPjceQrSwdWXPyLLeABRXmuqt69Rg3sBYbU1Nft9HyQ6X"
+        );
+    }
+}
@@ -1,18 +1,30 @@
 use libp2p::{
-    autonat, dcutr, gossipsub, identify,
-    kad::{self, store::MemoryStore},
-    ping, relay,
-    swarm::NetworkBehaviour,
+    autonat, dcutr, gossipsub, identify, kad, mdns, ping, relay, request_response,
+    swarm::{NetworkBehaviour, behaviour::toggle::Toggle},
+    upnp,
 };
+use libp2p_kad_store::Store;
+
+use crate::{control::BytesCodec, document_fetch::DocumentCodec};
 
 #[derive(NetworkBehaviour)]
 pub struct Behaviour {
     pub relay_client: relay::client::Behaviour,
+    /// Relay server role, only enabled for publicly reachable "super peers"
+    pub relay_server: Toggle<relay::Behaviour>,
     pub identify: identify::Behaviour,
     pub dcutr: dcutr::Behaviour,
-    pub kademlia: kad::Behaviour<MemoryStore>,
+    pub kademlia: kad::Behaviour<Store>,
     pub ping: ping::Behaviour,
     pub gossipsub: gossipsub::Behaviour,
     pub autonat: autonat::v2::client::Behaviour,
+    /// Port mapping on the local router, only enabled when configured
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+    /// Discovery of peers on the local network, only enabled when configured
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     pub automerge: libp2p_automerge::Behaviour,
+    /// Opaque request-response exchanges for the application
+    pub control: request_response::Behaviour<BytesCodec>,
+    /// Fetches of a peer's full copy of a document
+    pub document_fetch: request_response::Behaviour<DocumentCodec>,
 }
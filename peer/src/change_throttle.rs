@@ -0,0 +1,86 @@
+use std::{collections::HashMap, time::Duration};
+
+use automerge::ChangeHash;
+use tokio::time::Instant;
+
+/// Window changes are coalesced over unless configured otherwise
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Coalesces document changes so each document is published at most once per interval
+pub struct ChangeThrottle {
+    interval: Duration,
+    /// Documents with unpublished changes and when their window closes
+    pending: HashMap<String, Instant>,
+    /// Heads covered by the last successful publish of each document
+    published_heads: HashMap<String, Vec<ChangeHash>>,
+}
+
+impl ChangeThrottle {
+    pub fn new(interval: Duration) -> Self {
+        ChangeThrottle {
+            interval,
+            pending: HashMap::new(),
+            published_heads: HashMap::new(),
+        }
+    }
+
+    /// Records a change, opening a window for the document unless one is already open
+    pub fn mark_changed(&mut self, document_id: &str, now: Instant) {
+        if !self.pending.contains_key(document_id) {
+            self.pending
+                .insert(document_id.to_string(), now + self.interval);
+        }
+    }
+
+    /// Whether the document has changes waiting for their window to close
+    pub fn is_pending(&self, document_id: &str) -> bool {
+        self.pending.contains_key(document_id)
+    }
+
+    /// When the earliest open window closes
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().min().copied()
+    }
+
+    /// Takes the documents whose window has closed
+    pub fn take_due(&mut self, now: Instant) -> Vec<String> {
+        self.pending
+            .extract_if(|_, deadline| *deadline <= now)
+            .map(|(document_id, _)| document_id)
+            .collect()
+    }
+
+    /// Heads the next publish of the document should send changes after
+    pub fn published_heads(&self, document_id: &str) -> &[ChangeHash] {
+        self.published_heads
+            .get(document_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn set_published_heads(&mut self, document_id: &str, heads: Vec<ChangeHash>) {
+        self.published_heads.insert(document_id.to_string(), heads);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_within_the_window_are_published_once() {
+        let mut throttle = ChangeThrottle::new(Duration::from_secs(1));
+        let start = Instant::now();
+        for edit in 0..10 {
+            throttle.mark_changed("doc", start + Duration::from_millis(edit * 50));
+        }
+
+        assert!(
+            throttle
+                .take_due(start + Duration::from_millis(999))
+                .is_empty()
+        );
+        assert_eq!(throttle.take_due(start + Duration::from_secs(1)), ["doc"]);
+        assert!(throttle.take_due(start + Duration::from_secs(2)).is_empty());
+    }
+}
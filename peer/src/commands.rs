@@ -0,0 +1,122 @@
+/// Stdin commands and their usage, listed by `help` and used to suggest a command when the input
+/// matches none
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("help", "help"),
+    ("exit", "exit"),
+    ("db put", "db put <key> <value>"),
+    ("db get", "db get <key>"),
+    ("promote db", "promote db [--now]"),
+    ("demote db", "demote db"),
+    ("get providers", "get providers <key>"),
+    ("dial", "dial <peer_id>"),
+    ("dial_id", "dial_id <peer_id>"),
+    ("put-batch", "put-batch <doc> <json>"),
+    ("exists", "exists <doc>"),
+    ("json", "json <doc>"),
+    ("share", "share"),
+    ("status", "status"),
+    ("peer-docs", "peer-docs <peer_id>"),
+    ("snapshot", "snapshot [path]"),
+    ("restore", "restore <path>"),
+    (
+        "export-node",
+        "export-node <path> [--passphrase <passphrase>]",
+    ),
+    (
+        "import-node",
+        "import-node <path> [--passphrase <passphrase>] [--force]",
+    ),
+    ("request", "request <peer_id> <data>"),
+    ("fetch", "fetch <peer_id> <doc>"),
+    ("converged", "converged <peer_id> <doc>"),
+    ("sub", "sub <topic>"),
+    ("unsub", "unsub <topic>"),
+    ("pub", "pub <topic> <msg>"),
+    ("mesh", "mesh <topic>"),
+    ("connectivity", "connectivity <peer_id>"),
+    ("change-sizes", "change-sizes <doc>"),
+    ("heads", "heads <doc>"),
+    ("doc-peers", "doc-peers <doc>"),
+    ("put_record", "put_record <key> <value>"),
+    ("get_record", "get_record <key>"),
+    ("label", "label <doc> [labels...]"),
+    ("docs", "docs [--label <label>]"),
+    ("delete", "delete <doc>"),
+    ("compact", "compact <doc>"),
+    ("doc-subscribe", "doc-subscribe <doc>"),
+    ("doc-unsubscribe", "doc-unsubscribe <doc>"),
+    ("gc-docs", "gc-docs [--dry-run]"),
+    ("reservation-limits", "reservation-limits"),
+    ("reservations", "reservations"),
+    ("renew-reservation", "renew-reservation <relay_peer_id>"),
+    ("peer-info", "peer-info <peer_id>"),
+    (
+        "rekey",
+        "rekey <pre-shared key> [--id <key id>] [--grace <secs>] [--reconnect]",
+    ),
+    ("psk-fingerprint", "psk-fingerprint"),
+    ("stats", "stats"),
+    ("metrics", "metrics [--json]"),
+    ("connections", "connections"),
+    ("disconnect", "disconnect <peer_id>"),
+];
+
+/// Usage of the command closest to the input, if any is close enough to be a typo. Commands are
+/// compared against as many leading words of the input as they have themselves.
+pub fn suggest(input: &str) -> Option<&'static str> {
+    let words = input.split_whitespace().collect::<Vec<_>>();
+    COMMANDS
+        .iter()
+        .filter_map(|(name, usage)| {
+            let name_words = name.split(' ').count();
+            let typed = words[..name_words.min(words.len())].join(" ");
+            let distance = levenshtein(&typed, name);
+            (distance <= (name.len() / 3).max(1)).then_some((distance, *usage))
+        })
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, usage)| usage)
+}
+
+/// Usage of every command, one per line
+pub fn help() -> String {
+    COMMANDS
+        .iter()
+        .map(|(_, usage)| *usage)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_misses_suggest_the_closest_command() {
+        assert_eq!(suggest("conections"), Some("connections"));
+        assert_eq!(suggest("db gte key"), Some("db get <key>"));
+        assert_eq!(suggest("dial_di 12D3KooW"), Some("dial_id <peer_id>"));
+        assert_eq!(suggest("frobnicate"), None);
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let help = help();
+        for (_, usage) in COMMANDS {
+            assert!(help.lines().any(|line| line == *usage));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::net::ToSocketAddrs;
+
+use libp2p::{Multiaddr, PeerId, multiaddr::Protocol};
+
+use crate::local_config::{AppConfig, check_dial_address};
+
+/// Loads the config and everything it points at without starting the swarm, printing a line
+/// per check. Returns whether every check passed.
+pub fn run(path: Option<String>) -> bool {
+    let location = path
+        .clone()
+        .unwrap_or_else(AppConfig::default_config_location);
+    let mut report = Report::default();
+
+    let config = match AppConfig::load(path) {
+        Ok(config) => {
+            report.pass(format!("parsed {location}"));
+            config
+        }
+        Err(err) => {
+            report.fail(format!("parsing {location}: {err}"));
+            return report.finish();
+        }
+    };
+
+    match config.validate() {
+        Ok(()) => report.pass("settings are valid"),
+        Err(err) => report.fail(err.to_string()),
+    }
+
+    match config.identity.load_pre_shared_key() {
+        Ok(_) => report.pass("pre-shared key loaded"),
+        Err(err) => report.fail(err.to_string()),
+    }
+
+    let relays = config.relay_candidates();
+    for relay in &relays {
+        report.address(
+            &format!("relay {}", relay.peer_id),
+            relay.peer_id,
+            &relay.address,
+        );
+    }
+    for peer in &config.bootstrap {
+        report.address(
+            &format!("bootstrap peer {}", peer.peer_id),
+            peer.peer_id,
+            &peer.address,
+        );
+    }
+
+    let key_file = &config.identity.key_file_path;
+    if key_file.exists() {
+        match config.load_keypair() {
+            Ok(keypair) => report.pass(format!(
+                "{:?} key loaded from {}, peer id {}",
+                config.identity.key_type,
+                key_file.display(),
+                keypair.public().to_peer_id()
+            )),
+            Err(err) => report.fail(format!("loading key {}: {err}", key_file.display())),
+        }
+    } else {
+        report.pass(format!(
+            "no key at {}, a new {:?} identity is generated on startup",
+            key_file.display(),
+            config.identity.key_type
+        ));
+    }
+
+    report.finish()
+}
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&self, message: impl AsRef<str>) {
+        println!("ok    {}", message.as_ref());
+    }
+
+    fn fail(&mut self, message: impl AsRef<str>) {
+        self.failures += 1;
+        println!("FAIL  {}", message.as_ref());
+    }
+
+    /// Checks the address can be dialed and resolves its DNS name, if it has one
+    fn address(&mut self, name: &str, peer_id: PeerId, address: &Multiaddr) {
+        if let Err(err) = check_dial_address(address, peer_id) {
+            self.fail(format!("{name} at {address}: {err}"));
+            return;
+        }
+
+        let mut host = None;
+        let mut port = 0;
+        for protocol in address.iter() {
+            match protocol {
+                Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                    host = Some(name.to_string())
+                }
+                Protocol::Tcp(number) | Protocol::Udp(number) => port = number,
+                _ => {}
+            }
+        }
+
+        let Some(host) = host else {
+            self.pass(format!("{name} at {address}"));
+            return;
+        };
+        match (host.as_str(), port).to_socket_addrs() {
+            Ok(resolved) => {
+                let resolved = resolved
+                    .map(|addr| addr.ip().to_string())
+                    .collect::<Vec<_>>();
+                if resolved.is_empty() {
+                    self.fail(format!("{name} at {address}: {host} has no addresses"));
+                } else {
+                    self.pass(format!(
+                        "{name} at {address}, {host} resolves to {}",
+                        resolved.join(", ")
+                    ));
+                }
+            }
+            Err(err) => self.fail(format!("{name} at {address}: resolving {host}: {err}")),
+        }
+    }
+
+    fn finish(self) -> bool {
+        if self.failures == 0 {
+            println!("config is ready to use");
+        } else {
+            println!("{} check(s) failed", self.failures);
+        }
+        self.failures == 0
+    }
+}
@@ -0,0 +1,168 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{
+    PeerId, StreamProtocol,
+    request_response::{self, ProtocolSupport},
+};
+
+/// Application level request-response protocol carrying opaque bytes
+pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/p2p/control/0.0.1");
+
+/// Largest request or response accepted from a remote
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Produces the response for a request received from a peer
+pub type RequestHandler = Box<dyn FnMut(PeerId, Vec<u8>) -> Vec<u8> + Send>;
+
+/// Answers every request with its own payload, used until the application installs a handler
+pub fn echo_handler() -> RequestHandler {
+    Box::new(|_peer, request| request)
+}
+
+pub fn behaviour() -> request_response::Behaviour<BytesCodec> {
+    request_response::Behaviour::new(
+        [(PROTOCOL_NAME, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Frames requests and responses as a big endian u32 length followed by the payload
+#[derive(Debug, Clone, Default)]
+pub struct BytesCodec;
+
+#[async_trait]
+impl request_response::Codec for BytesCodec {
+    type Protocol = StreamProtocol;
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_frame(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_frame(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, &response).await
+    }
+}
+
+async fn read_frame<T>(io: &mut T) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut length = [0u8; 4];
+    io.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {length} bytes exceeds the maximum size"),
+        ));
+    }
+
+    let mut bytes = vec![0u8; length];
+    io.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+async fn write_frame<T>(io: &mut T, bytes: &[u8]) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds the maximum size", bytes.len()),
+        ));
+    }
+
+    io.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    io.write_all(bytes).await?;
+    io.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+    use request_response::Codec;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn request_gets_the_handler_response() {
+        let mut codec = BytesCodec;
+        let mut handler: RequestHandler = Box::new(|_peer, request| {
+            let mut response = b"re: ".to_vec();
+            response.extend(request);
+            response
+        });
+
+        let mut wire = Cursor::new(Vec::new());
+        codec
+            .write_request(&PROTOCOL_NAME, &mut wire, b"ping".to_vec())
+            .await
+            .unwrap();
+        wire.set_position(0);
+        let request = codec.read_request(&PROTOCOL_NAME, &mut wire).await.unwrap();
+
+        let mut wire = Cursor::new(Vec::new());
+        codec
+            .write_response(
+                &PROTOCOL_NAME,
+                &mut wire,
+                handler(PeerId::random(), request),
+            )
+            .await
+            .unwrap();
+        wire.set_position(0);
+        let response = codec
+            .read_response(&PROTOCOL_NAME, &mut wire)
+            .await
+            .unwrap();
+
+        assert_eq!(response, b"re: ping");
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused() {
+        let mut wire = Cursor::new(((MAX_MESSAGE_SIZE + 1) as u32).to_be_bytes().to_vec());
+
+        let error = BytesCodec
+            .read_request(&PROTOCOL_NAME, &mut wire)
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,16 +1,23 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
-use libp2p::{Multiaddr, swarm::SwarmEvent};
+use libp2p::Multiaddr;
 use tokio::{
     select,
-    sync::{broadcast, mpsc},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot,
+    },
+    time::Interval,
 };
-use tracing::info;
+use tracing::{debug, info, warn};
 
-use crate::{
-    behaviour::{Behaviour, BehaviourEvent},
-    swarm_dispatch::SwarmCommand,
-};
+use crate::swarm_dispatch::{Liveness, NodeEvent, SwarmCommand};
 
 pub enum DatabaseCommand {
     RequestUpgradeToProvider(Multiaddr),
@@ -18,35 +25,53 @@ pub enum DatabaseCommand {
 
 pub enum DatabaseEvent {
     RequestUpgradeToProvider,
+    /// Sent every heartbeat interval while the swarm answers, a node that stops sending them
+    /// has stalled
+    Heartbeat(Liveness),
 }
 
 pub struct DatabaseManager {
     event_tx: mpsc::Sender<DatabaseEvent>,
     command_rx: mpsc::Receiver<DatabaseCommand>,
     swarm_command_tx: mpsc::Sender<SwarmCommand>,
-    swarm_event_rx: broadcast::Receiver<Arc<SwarmEvent<BehaviourEvent>>>,
+    node_event_rx: broadcast::Receiver<NodeEvent>,
+    /// Node events we missed because we fell behind the broadcast channel
+    dropped_events: Arc<AtomicU64>,
+    heartbeat_interval: Option<Duration>,
 }
 
 impl DatabaseManager {
     pub fn new(
         event_tx: mpsc::Sender<DatabaseEvent>,
         command_rx: mpsc::Receiver<DatabaseCommand>,
-        swarm_event_rx: broadcast::Receiver<Arc<SwarmEvent<BehaviourEvent>>>,
+        node_event_rx: broadcast::Receiver<NodeEvent>,
         swarm_command_tx: mpsc::Sender<SwarmCommand>,
+        dropped_events: Arc<AtomicU64>,
     ) -> Self {
         DatabaseManager {
             event_tx,
             command_rx,
             swarm_command_tx,
-            swarm_event_rx,
+            node_event_rx,
+            dropped_events,
+            heartbeat_interval: None,
         }
     }
 
+    /// Sends a [`DatabaseEvent::Heartbeat`] every interval
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
     pub async fn run(mut self) {
         info!("DatabaseManager started");
+        let mut heartbeat = self.heartbeat_interval.map(tokio::time::interval);
 
         loop {
             select! {
+                _ = tick(&mut heartbeat) => self.send_heartbeat(),
+
                 command = self.command_rx.recv() => {
                     if let Some(command) = command {
                         self.handle_command(command);
@@ -56,15 +81,53 @@ impl DatabaseManager {
                     }
                 }
 
-                event = self.swarm_event_rx.recv() => {
-                    if let Ok(event) = event {
-                        self.handle_swarm_event(event);
+                event = self.node_event_rx.recv() => {
+                    match event {
+                        Ok(event) => self.handle_node_event(event),
+                        Err(RecvError::Lagged(count)) => {
+                            warn!("DatabaseManager fell behind, missed {} node events", count);
+                            self.dropped_events.fetch_add(count, Ordering::Relaxed);
+                        }
+                        Err(RecvError::Closed) => {
+                            info!("Node event channel closed, shutting down DatabaseManager");
+                            break;
+                        }
                     }
                 }
             }
         }
     }
 
+    /// Asks the swarm what it's doing and forwards the answer as a heartbeat from a separate
+    /// task, so neither a busy swarm nor a slow event reader holds up our own loop. A heartbeat
+    /// that can't be queued is skipped.
+    fn send_heartbeat(&self) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if let Err(err) = self
+            .swarm_command_tx
+            .try_send(SwarmCommand::Liveness(reply_tx))
+        {
+            warn!(
+                "Skipping heartbeat, the swarm is not taking commands: {}",
+                err
+            );
+            return;
+        }
+
+        let event_tx = self.event_tx.clone();
+        tokio::spawn(async move {
+            let Ok(liveness) = reply_rx.await else {
+                return;
+            };
+            if event_tx
+                .try_send(DatabaseEvent::Heartbeat(liveness))
+                .is_err()
+            {
+                debug!("Dropped a heartbeat, nobody is reading database events");
+            }
+        });
+    }
+
     pub fn handle_command(&mut self, command: DatabaseCommand) {
         match command {
             DatabaseCommand::RequestUpgradeToProvider(addr) => {
@@ -73,5 +136,81 @@ impl DatabaseManager {
         }
     }
 
-    pub fn handle_swarm_event(&mut self, event: Arc<SwarmEvent<BehaviourEvent>>) {}
+    pub fn handle_node_event(&mut self, event: NodeEvent) {
+        match event {
+            NodeEvent::DirectConnectionEstablished(peer_id) => {
+                // a direct connection is faster and unlimited, unlike the relayed one the
+                // documents were synced over so far
+                debug!("Direct connection to {}, syncing documents", peer_id);
+                if let Err(err) = self
+                    .swarm_command_tx
+                    .try_send(SwarmCommand::SyncDocuments(peer_id))
+                {
+                    warn!(
+                        "Failed to request a document sync with {}: {}",
+                        peer_id, err
+                    );
+                }
+            }
+            NodeEvent::GossipMessage {
+                topic,
+                source,
+                data,
+            } => {
+                info!(
+                    "Message on {} from {}: {}",
+                    topic,
+                    source.map_or_else(|| "unknown".to_string(), |source| source.to_string()),
+                    String::from_utf8_lossy(&data)
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Waits for the next tick, forever without an interval
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lagging_behind_counts_the_missed_events() {
+        let (event_tx, _event_rx) = mpsc::channel(1);
+        let (_command_tx, command_rx) = mpsc::channel(1);
+        let (swarm_command_tx, _swarm_command_rx) = mpsc::channel(1);
+        let (node_event_tx, node_event_rx) = broadcast::channel(2);
+        let dropped_events = Arc::new(AtomicU64::new(0));
+        let database_manager = DatabaseManager::new(
+            event_tx,
+            command_rx,
+            node_event_rx,
+            swarm_command_tx,
+            dropped_events.clone(),
+        );
+
+        for _ in 0..5 {
+            node_event_tx
+                .send(NodeEvent::PeerDisconnected {
+                    peer: PeerId::random(),
+                })
+                .unwrap();
+        }
+        // runs until it read what's left after the missed events and finds the channel closed
+        drop(node_event_tx);
+        database_manager.run().await;
+
+        assert_eq!(dropped_events.load(Ordering::Relaxed), 3);
+    }
 }
@@ -0,0 +1,210 @@
+use std::{error::Error, fmt, io};
+
+use libp2p::{TransportError, swarm::DialError};
+
+/// Coarse cause of a failed dial, short enough to scan for in the logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialFailure {
+    Dns,
+    ConnectionRefused,
+    Timeout,
+    /// The secure channel couldn't be set up, e.g. because of a mismatching pre-shared key
+    Handshake,
+    NoAddresses,
+    /// A behaviour refused the connection, e.g. a connection limit
+    Denied,
+    /// The remote answered with another peer id than the one dialed
+    WrongPeerId,
+    /// The address uses protocols none of our transports support
+    UnsupportedAddress,
+    /// The dial was aborted or skipped locally
+    Aborted,
+    Other,
+}
+
+impl DialFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DialFailure::Dns => "dns failure",
+            DialFailure::ConnectionRefused => "connection refused",
+            DialFailure::Timeout => "timeout",
+            DialFailure::Handshake => "handshake failure",
+            DialFailure::NoAddresses => "no addresses",
+            DialFailure::Denied => "denied",
+            DialFailure::WrongPeerId => "wrong peer id",
+            DialFailure::UnsupportedAddress => "unsupported address",
+            DialFailure::Aborted => "aborted",
+            DialFailure::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for DialFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Classifies a dial error. When several addresses failed, the first failure more specific than
+/// [`DialFailure::Other`] is reported.
+pub fn classify(error: &DialError) -> DialFailure {
+    match error {
+        DialError::NoAddresses => DialFailure::NoAddresses,
+        DialError::Denied { .. } => DialFailure::Denied,
+        DialError::WrongPeerId { .. } | DialError::LocalPeerId { .. } => DialFailure::WrongPeerId,
+        DialError::Aborted | DialError::DialPeerConditionFalse(_) => DialFailure::Aborted,
+        DialError::Transport(errors) => errors
+            .iter()
+            .map(|(_, error)| classify_transport(error))
+            .find(|failure| *failure != DialFailure::Other)
+            .unwrap_or(DialFailure::Other),
+    }
+}
+
+fn classify_transport(error: &TransportError<io::Error>) -> DialFailure {
+    match error {
+        TransportError::MultiaddrNotSupported(_) => DialFailure::UnsupportedAddress,
+        TransportError::Other(error) => {
+            // the transport stack nests its errors, the interesting one is usually at the bottom
+            let mut source: Option<&(dyn Error + 'static)> = Some(error);
+            while let Some(error) = source {
+                let failure = classify_error(error);
+                if failure != DialFailure::Other {
+                    return failure;
+                }
+                source = error.source();
+            }
+            DialFailure::Other
+        }
+    }
+}
+
+fn classify_error(error: &(dyn Error + 'static)) -> DialFailure {
+    if let Some(error) = error.downcast_ref::<io::Error>() {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => return DialFailure::ConnectionRefused,
+            io::ErrorKind::TimedOut => return DialFailure::Timeout,
+            _ => {}
+        }
+    }
+
+    let message = error.to_string().to_lowercase();
+    let contains_any = |needles: &[&str]| needles.iter().any(|needle| message.contains(needle));
+    if contains_any(&["noise", "handshake", "decrypt"]) {
+        DialFailure::Handshake
+    } else if contains_any(&["dns", "resolve", "no record"]) {
+        DialFailure::Dns
+    } else if contains_any(&["refused"]) {
+        DialFailure::ConnectionRefused
+    } else if contains_any(&["timed out", "timeout"]) {
+        DialFailure::Timeout
+    } else {
+        DialFailure::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        Multiaddr, PeerId,
+        core::{ConnectedPoint, Endpoint, transport::PortUse},
+        swarm::{ConnectionDenied, dial_opts::PeerCondition},
+    };
+
+    use super::*;
+
+    fn transport(errors: Vec<TransportError<io::Error>>) -> DialError {
+        DialError::Transport(
+            errors
+                .into_iter()
+                .map(|error| (Multiaddr::empty(), error))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn dial_errors_are_classified() {
+        let endpoint = ConnectedPoint::Dialer {
+            address: Multiaddr::empty(),
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        };
+        let cases = [
+            (DialError::NoAddresses, DialFailure::NoAddresses),
+            (
+                DialError::Denied {
+                    cause: ConnectionDenied::new(io::Error::other("too many connections")),
+                },
+                DialFailure::Denied,
+            ),
+            (
+                DialError::WrongPeerId {
+                    obtained: PeerId::random(),
+                    endpoint: endpoint.clone(),
+                },
+                DialFailure::WrongPeerId,
+            ),
+            (
+                DialError::LocalPeerId { endpoint },
+                DialFailure::WrongPeerId,
+            ),
+            (DialError::Aborted, DialFailure::Aborted),
+            (
+                DialError::DialPeerConditionFalse(PeerCondition::Disconnected),
+                DialFailure::Aborted,
+            ),
+            (
+                transport(vec![TransportError::MultiaddrNotSupported(
+                    Multiaddr::empty(),
+                )]),
+                DialFailure::UnsupportedAddress,
+            ),
+            (
+                transport(vec![TransportError::Other(
+                    io::ErrorKind::ConnectionRefused.into(),
+                )]),
+                DialFailure::ConnectionRefused,
+            ),
+            (
+                transport(vec![TransportError::Other(io::ErrorKind::TimedOut.into())]),
+                DialFailure::Timeout,
+            ),
+            (
+                transport(vec![TransportError::Other(io::Error::other(
+                    "noise handshake failed: decrypt error",
+                ))]),
+                DialFailure::Handshake,
+            ),
+            (
+                transport(vec![TransportError::Other(io::Error::other(
+                    "failed to resolve /dns4/example.invalid",
+                ))]),
+                DialFailure::Dns,
+            ),
+            // nested errors are classified by the innermost known cause
+            (
+                transport(vec![TransportError::Other(io::Error::other(
+                    io::Error::from(io::ErrorKind::TimedOut),
+                ))]),
+                DialFailure::Timeout,
+            ),
+            // the first specific failure of several addresses wins
+            (
+                transport(vec![
+                    TransportError::Other(io::Error::other("boom")),
+                    TransportError::Other(io::ErrorKind::ConnectionRefused.into()),
+                    TransportError::Other(io::ErrorKind::TimedOut.into()),
+                ]),
+                DialFailure::ConnectionRefused,
+            ),
+            (
+                transport(vec![TransportError::Other(io::Error::other("boom"))]),
+                DialFailure::Other,
+            ),
+        ];
+
+        for (error, expected) in cases {
+            assert_eq!(classify(&error), expected, "{error}");
+        }
+    }
+}
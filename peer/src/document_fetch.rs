@@ -0,0 +1,105 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    StreamProtocol,
+    request_response::{self, ProtocolSupport},
+};
+use libp2p_automerge::{Message, SyncErrorReason};
+
+/// Request-response protocol fetching a peer's full copy of a document, framed like the
+/// automerge protocol's `RequestDocument` and `Document` messages
+pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/p2p/document-fetch/0.0.1");
+
+pub fn behaviour() -> request_response::Behaviour<DocumentCodec> {
+    request_response::Behaviour::new(
+        [(PROTOCOL_NAME, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Requests carry the document id, responses the saved document or `None` if the peer doesn't
+/// have it
+#[derive(Debug, Clone, Default)]
+pub struct DocumentCodec;
+
+#[async_trait]
+impl request_response::Codec for DocumentCodec {
+    type Protocol = StreamProtocol;
+    type Request = String;
+    type Response = Option<Vec<u8>>;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<String>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        match libp2p_automerge::read_message(io).await? {
+            Message::RequestDocument { document_id } => Ok(document_id),
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Option<Vec<u8>>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        match libp2p_automerge::read_message(io).await? {
+            Message::Document { document, .. } => Ok(Some(document)),
+            Message::SyncError {
+                reason: SyncErrorReason::DOCUMENT_NOT_FOUND,
+                ..
+            } => Ok(None),
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        document_id: String,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        libp2p_automerge::write_message(io, &Message::RequestDocument { document_id }).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        document: Option<Vec<u8>>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        // the request-response protocol pairs the response with its request, so the id isn't
+        // needed to tell documents apart
+        let message = match document {
+            Some(document) => Message::Document {
+                document_id: String::new(),
+                document,
+                auth: None,
+            },
+            None => Message::SyncError {
+                document_id: String::new(),
+                reason: SyncErrorReason::DOCUMENT_NOT_FOUND,
+                details: String::new(),
+            },
+        };
+        libp2p_automerge::write_message(io, &message).await
+    }
+}
+
+fn unexpected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unexpected message in a document fetch",
+    )
+}
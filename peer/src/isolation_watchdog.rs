@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Tracks how long the node has been without any connection and when to attempt recovery
+pub struct IsolationWatchdog {
+    timeout: Duration,
+    /// When the last connection closed, `None` while connected
+    isolated_since: Option<Instant>,
+    /// When the next recovery is due
+    recover_at: Option<Instant>,
+    /// Recoveries attempted since the node became isolated
+    cycles: u32,
+}
+
+impl IsolationWatchdog {
+    pub fn new(timeout: Duration) -> Self {
+        IsolationWatchdog {
+            timeout,
+            isolated_since: None,
+            recover_at: None,
+            cycles: 0,
+        }
+    }
+
+    /// Starts the timeout unless the node is already isolated
+    pub fn isolated(&mut self, now: Instant) {
+        if self.isolated_since.is_none() {
+            self.isolated_since = Some(now);
+            self.recover_at = Some(now + self.timeout);
+            self.cycles = 0;
+        }
+    }
+
+    pub fn connected(&mut self) {
+        self.isolated_since = None;
+        self.recover_at = None;
+        self.cycles = 0;
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.recover_at
+    }
+
+    /// Counts a recovery attempt and schedules the next one, returning the attempt's number
+    pub fn start_recovery(&mut self, now: Instant) -> u32 {
+        self.cycles += 1;
+        self.recover_at = Some(now + self.timeout);
+        self.cycles
+    }
+
+    /// How long the node has been isolated
+    pub fn isolated_for(&self, now: Instant) -> Duration {
+        self.isolated_since
+            .map(|since| now.duration_since(since))
+            .unwrap_or_default()
+    }
+}
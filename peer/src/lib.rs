@@ -0,0 +1,346 @@
+use std::{error::Error, time::Duration};
+
+use common::string_to_32_bytes;
+use libp2p::{
+    Swarm, Transport, autonat,
+    core::{transport::OptionalTransport, upgrade},
+    dcutr, gossipsub, identify, identity,
+    kad::{self, store::MemoryStore},
+    mdns,
+    metrics::Registry,
+    noise, ping, quic, relay, tcp, upnp, yamux,
+};
+use libp2p_kad_store::{DiskStore, Store};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::{
+    behaviour::Behaviour,
+    local_config::AppConfig,
+    rekeyable_noise::RekeyableNoise,
+    swarm_dispatch::{SwarmCommand, SwarmManager},
+};
+
+pub mod behaviour;
+pub mod change_throttle;
+pub mod commands;
+pub mod config_check;
+pub mod control;
+pub mod database_manager;
+pub mod dial_error;
+pub mod document_fetch;
+pub mod isolation_watchdog;
+pub mod local_config;
+pub mod metrics;
+pub mod node_bundle;
+pub mod provider_warmup;
+pub mod reachability;
+pub mod rekeyable_noise;
+pub mod rpc;
+pub mod self_check;
+pub mod swarm_dispatch;
+
+/// The gossipsub config with the configured mesh, and with an allow list, one that holds
+/// messages back until the swarm manager accepted them. Messages are then identified by their
+/// source and content, so a peer republishing the same data doesn't get it validated and
+/// forwarded again.
+fn gossipsub_config(config: &AppConfig) -> gossipsub::Config {
+    let mesh = &config.gossipsub;
+    let mut builder = gossipsub::ConfigBuilder::default();
+    builder
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .mesh_n(mesh.mesh_n)
+        .mesh_n_low(mesh.mesh_n_low)
+        .mesh_n_high(mesh.mesh_n_high)
+        // gossipsub requires outbound peers to fill at most half the mesh
+        .mesh_outbound_min(2.min(mesh.mesh_n_low).min(mesh.mesh_n / 2))
+        .heartbeat_interval(mesh.heartbeat_interval())
+        .duplicate_cache_time(mesh.duplicate_cache_ttl());
+    if !config.gossip_allowed_peers.is_empty() {
+        builder
+            .validate_messages()
+            .message_id_fn(|message: &gossipsub::Message| {
+                let mut hasher = Sha256::new();
+                if let Some(source) = &message.source {
+                    hasher.update(source.to_bytes());
+                }
+                hasher.update(message.topic.as_str());
+                hasher.update(&message.data);
+                gossipsub::MessageId::from(hasher.finalize().to_vec())
+            });
+    }
+    builder.build().expect("valid gossipsub config")
+}
+
+/// The QUIC transport config, tuned apart from the swarm wide idle connection timeout.
+/// Handshakes give up after the dial timeout like TCP connection attempts.
+fn quic_config(config: &AppConfig, keypair: &identity::Keypair) -> quic::Config {
+    let mut quic_config = quic::Config::new(keypair);
+    quic_config.handshake_timeout = config.dial_timeout();
+    quic_config.max_idle_timeout = config.quic.max_idle_timeout_ms;
+    quic_config.keep_alive_interval = config.quic.keep_alive_interval();
+    quic_config
+}
+
+/// The automerge config of the configured documents, kept in the database directory
+pub(crate) fn automerge_config(config: &AppConfig) -> libp2p_automerge::Config {
+    libp2p_automerge::Config {
+        documents_whitelist: Some(config.documents.clone()),
+        max_simultaneous_syncs: config.max_concurrent_syncs,
+        data_dir: config.db_path.clone(),
+        peer_activity_window: config.document_peer_window(),
+        max_handlers_per_peer: config.max_handlers_per_peer,
+        persistence: config.document_persistence(),
+        sync_scheduling: config.document_sync_scheduling(),
+        sync_timeout: config.sync_timeout(),
+        max_queued_messages: config.max_queued_sync_messages,
+        queue_overflow: config.document_queue_overflow(),
+        inbound_sync_limit: config.document_sync_rate_limit(),
+        compact_after_changes: config.auto_compact_after_changes,
+    }
+}
+
+/// Builds the swarm of a peer from its config, without listening on anything yet. The returned
+/// noise upgrade is shared with the TCP transport, so the pre-shared key can be changed later
+/// through [`SwarmManager::with_rekeyable_noise`].
+pub fn build_swarm(
+    config: &AppConfig,
+    keypair: identity::Keypair,
+    pre_shared_key: &str,
+    registry: &mut Registry,
+) -> Result<(Swarm<Behaviour>, RekeyableNoise), Box<dyn Error>> {
+    let local_peer_id = keypair.public().to_peer_id();
+    let mut kad_config = kad::Config::new(kad::PROTOCOL_NAME);
+    kad_config
+        .set_provider_record_ttl(Some(config.kademlia.provider_ttl()))
+        .set_provider_publication_interval(Some(config.kademlia.provider_announce_interval()));
+    let record_store = match &config.kademlia.record_store_dir {
+        Some(path) => Store::Disk(DiskStore::open(local_peer_id, path)?),
+        None => Store::Memory(MemoryStore::new(local_peer_id)),
+    };
+    let mut kademlia = kad::Behaviour::with_config(local_peer_id, record_store, kad_config);
+    // a node that relays for others is publicly reachable and can serve the DHT as well
+    let relay_server_enabled = config.relay_server.enabled;
+    kademlia.set_mode(Some(if relay_server_enabled {
+        kad::Mode::Server
+    } else {
+        kad::Mode::Client
+    }));
+    for relay in config.relay_candidates() {
+        kademlia.add_address(&relay.peer_id, relay.address);
+    }
+    for peer in &config.bootstrap {
+        kademlia.add_address(&peer.peer_id, peer.address.clone());
+    }
+
+    let mdns = if config.enable_mdns {
+        Some(mdns::tokio::Behaviour::new(
+            mdns::Config::default(),
+            local_peer_id,
+        )?)
+    } else {
+        None
+    };
+
+    let rekeyable_noise = RekeyableNoise::new(
+        &keypair,
+        string_to_32_bytes(pre_shared_key).to_vec(),
+        config.identity.pre_shared_key_id.as_deref(),
+    )?;
+
+    let dial_timeout = config.dial_timeout();
+    let transport_noise = rekeyable_noise.clone();
+    // a disabled transport refuses every address, so nothing is listened on or dialed over it
+    let transports = config.transports;
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_other_transport(|keypair| {
+            if !transports.quic() {
+                return OptionalTransport::none();
+            }
+            OptionalTransport::some(quic::tokio::Transport::new(quic_config(config, keypair)))
+        })?
+        .with_other_transport(|_keypair| {
+            if !transports.tcp() {
+                return OptionalTransport::none();
+            }
+            OptionalTransport::some(
+                tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+                    .upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(transport_noise)
+                    .multiplex(yamux::Config::default())
+                    .outbound_timeout(dial_timeout),
+            )
+        })?
+        .with_dns()?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_bandwidth_metrics(registry)
+        .with_behaviour(|keypair, relay_behaviour| Behaviour {
+            relay_client: relay_behaviour,
+            relay_server: relay_server_enabled
+                .then(|| relay::Behaviour::new(keypair.public().to_peer_id(), Default::default()))
+                .into(),
+            ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(30))),
+            identify: identify::Behaviour::new(
+                identify::Config::new("ipfs/1.0.0".to_owned(), keypair.public())
+                    .with_interval(config.identify.interval())
+                    .with_hide_listen_addrs(false)
+                    .with_push_listen_addr_updates(true),
+            ),
+            autonat: autonat::v2::client::Behaviour::new(
+                OsRng,
+                autonat::v2::client::Config::default(),
+            ),
+            dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
+            upnp: config
+                .enable_upnp
+                .then(upnp::tokio::Behaviour::default)
+                .into(),
+            mdns: mdns.into(),
+            gossipsub: gossipsub::Behaviour::new(
+                gossipsub::MessageAuthenticity::Signed(keypair.clone()),
+                gossipsub_config(config),
+            )
+            .unwrap(),
+            kademlia,
+            automerge: libp2p_automerge::Behaviour::new(automerge_config(config)),
+            control: control::behaviour(),
+            document_fetch: document_fetch::behaviour(),
+        })?
+        .with_swarm_config(|swarm_config| {
+            swarm_config.with_idle_connection_timeout(Duration::from_secs(60))
+        })
+        .build();
+
+    Ok((swarm, rekeyable_noise))
+}
+
+/// Wraps a swarm built by [`build_swarm`] in a [`SwarmManager`] set up as the config asks
+pub fn swarm_manager(
+    config: &AppConfig,
+    swarm: Swarm<Behaviour>,
+    rekeyable_noise: RekeyableNoise,
+    registry: Registry,
+    command_rx: mpsc::Receiver<SwarmCommand>,
+) -> SwarmManager {
+    let relays = config
+        .relay_candidates()
+        .into_iter()
+        .map(|relay| (relay.peer_id, relay.address))
+        .collect::<Vec<_>>();
+    let bootstrap_peers = config
+        .bootstrap
+        .iter()
+        .map(|peer| (peer.peer_id, peer.address.clone()))
+        .collect::<Vec<_>>();
+
+    let mut swarm_manager = SwarmManager::new(
+        swarm,
+        command_rx,
+        relays,
+        config.identify.expiry(),
+        config.relay_dial_attempts,
+    )
+    .with_event_channel_capacity(config.event_channel_capacity)
+    .with_change_publish_interval(config.change_publish_interval())
+    .with_synced_documents(config.documents.clone())
+    .with_bootstrap_peers(bootstrap_peers)
+    .with_rekeyable_noise(rekeyable_noise)
+    .with_metrics_registry(registry)
+    .with_record_put_policy(config.kademlia.put_quorum(), config.kademlia.put_attempts)
+    .with_dial_retries(config.dial_retries)
+    .with_control_handler(Box::new(|peer, request| {
+        info!("Control request from {}: {} bytes", peer, request.len());
+        request
+    }));
+    if let Some(timeout) = config.isolation_timeout() {
+        swarm_manager = swarm_manager.with_isolation_watchdog(timeout);
+    }
+    if !config.gossip_allowed_peers.is_empty() {
+        swarm_manager = swarm_manager.with_gossip_allow_list(config.gossip_allowed_peers.clone());
+    }
+    if let Some(timeout) = config.idle_peer_timeout() {
+        swarm_manager = swarm_manager.with_idle_peer_reaper(timeout);
+    }
+    swarm_manager
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use libp2p::{Multiaddr, swarm::SwarmEvent};
+
+    use super::*;
+    use crate::local_config::{QuicConfig, RelayServerConfig};
+
+    #[test]
+    fn quic_timeouts_come_from_the_config() {
+        let config = AppConfig {
+            dial_timeout_secs: 3,
+            quic: QuicConfig {
+                max_idle_timeout_ms: 120_000,
+                keep_alive_interval_secs: 20,
+            },
+            ..Default::default()
+        };
+
+        let quic_config = quic_config(&config, &identity::Keypair::generate_ed25519());
+
+        assert_eq!(quic_config.max_idle_timeout, 120_000);
+        assert_eq!(quic_config.keep_alive_interval, Duration::from_secs(20));
+        assert_eq!(quic_config.handshake_timeout, Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn relay_server_role_follows_the_config() {
+        for enabled in [false, true] {
+            let config = AppConfig {
+                relay_server: RelayServerConfig { enabled },
+                enable_mdns: false,
+                ..Default::default()
+            };
+            let (swarm, _) = build_swarm(
+                &config,
+                identity::Keypair::generate_ed25519(),
+                "secret",
+                &mut Registry::default(),
+            )
+            .unwrap();
+
+            // the relay client is always there, the server role only when asked for
+            assert_eq!(swarm.behaviour().relay_server.is_enabled(), enabled);
+        }
+    }
+
+    #[tokio::test]
+    async fn dial_to_an_unroutable_address_gives_up_within_the_dial_timeout() {
+        let config = AppConfig {
+            dial_timeout_secs: 1,
+            enable_mdns: false,
+            ..Default::default()
+        };
+        let (mut swarm, _) = build_swarm(
+            &config,
+            identity::Keypair::generate_ed25519(),
+            "secret",
+            &mut Registry::default(),
+        )
+        .unwrap();
+
+        // packets to this address are dropped rather than refused, only the timeout ends the dial
+        swarm
+            .dial("/ip4/10.255.255.1/tcp/4001".parse::<Multiaddr>().unwrap())
+            .unwrap();
+        let failed = tokio::time::timeout(config.dial_timeout() + Duration::from_secs(2), async {
+            loop {
+                if let SwarmEvent::OutgoingConnectionError { .. } = swarm.select_next_some().await {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(failed.is_ok());
+    }
+}
@@ -1,17 +1,36 @@
-use std::path::PathBuf;
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
-use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey, spki::der::pem::LineEnding};
+use ed25519_dalek::pkcs8::{
+    DecodePrivateKey, EncodePrivateKey,
+    spki::der::pem::{self, LineEnding},
+};
 use libp2p::{
     Multiaddr, PeerId,
     identity::{self},
+    kad,
+    multiaddr::Protocol,
 };
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 
+pub use common::Transports;
+
 const CONFIG_DIR_NAME: &str = "chippy";
 const CONFIG_FILE_NAME: &str = "Config.toml";
 const KEY_FILE_NAME: &str = "key.pem";
+/// PEM label of SEC1 encoded elliptic curve keys, used for the secp256k1 and ECDSA key types
+const EC_PRIVATE_KEY_LABEL: &str = "EC PRIVATE KEY";
+/// DER encoded object identifiers of the curves, as found in a SEC1 key's parameters
+const SECP256K1_OID: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x0a];
+const P256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// The swarm builder abandons every connection attempt after 10 seconds, whatever the transport
+/// timeouts are
+const MAX_DIAL_TIMEOUT_SECS: u64 = 10;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RelayConfig {
@@ -28,10 +47,62 @@ impl Default for RelayConfig {
     }
 }
 
+/// A well-known node seeding the Kademlia routing table
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BootstrapPeer {
+    pub peer_id: PeerId,
+    pub address: Multiaddr,
+}
+
+/// Settings for running a relay server alongside the peer application
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RelayServerConfig {
+    /// Accept reservations and relay circuits for other peers. Only useful on publicly reachable
+    /// nodes.
+    pub enabled: bool,
+}
+
+/// Kind of key the node's identity uses, which also determines the key file format
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    /// PKCS#8 PEM, as written by most ed25519 tooling
+    #[default]
+    Ed25519,
+    /// SEC1 `EC PRIVATE KEY` PEM, as written by `openssl ecparam -name secp256k1 -genkey -noout`
+    Secp256k1,
+    /// ECDSA over P-256, as a SEC1 `EC PRIVATE KEY` PEM
+    Ecdsa,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct IdentityConfig {
     pub key_file_path: PathBuf,
+    #[serde(default)]
+    pub key_type: KeyType,
+    /// Inline pre-shared key, leave empty when using `pre_shared_key_file`
+    #[serde(default)]
     pub pre_shared_key: String,
+    /// File holding the pre-shared key, e.g. a mounted secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_shared_key_file: Option<PathBuf>,
+    /// Public label of the pre-shared key, e.g. the date it was issued. Peers sharing the key
+    /// must use the same id, and a new key needs a new id. It's sent in cleartext while
+    /// connecting, so it must not be derived from the key. Without an id, peers on the previous
+    /// key can't connect during a rotation's grace period.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_shared_key_id: Option<String>,
+    /// Refuse to load a key file that is readable by group or others instead of restricting it
+    /// to the owner
+    #[serde(default)]
+    pub strict_key_permissions: bool,
+    /// Seconds the previous pre-shared key is still accepted after rotating it with `rekey`
+    #[serde(default = "default_pre_shared_key_grace_secs")]
+    pub pre_shared_key_grace_secs: u64,
+}
+
+fn default_pre_shared_key_grace_secs() -> u64 {
+    300
 }
 
 impl Default for IdentityConfig {
@@ -41,24 +112,480 @@ impl Default for IdentityConfig {
                 .unwrap()
                 .join(CONFIG_DIR_NAME)
                 .join(KEY_FILE_NAME),
+            key_type: KeyType::default(),
             pre_shared_key: "".to_string(),
+            pre_shared_key_file: None,
+            pre_shared_key_id: None,
+            strict_key_permissions: false,
+            pre_shared_key_grace_secs: default_pre_shared_key_grace_secs(),
+        }
+    }
+}
+
+impl IdentityConfig {
+    /// The pre-shared key from whichever source is configured, without the file's trailing
+    /// newline
+    pub fn load_pre_shared_key(&self) -> Result<String> {
+        let Some(path) = &self.pre_shared_key_file else {
+            return Ok(self.pre_shared_key.clone());
+        };
+
+        let key = std::fs::read_to_string(path).map_err(|err| {
+            anyhow::anyhow!(
+                "Failed reading pre-shared key file {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        let key = key.trim_end_matches(['\n', '\r']);
+        if key.is_empty() {
+            anyhow::bail!("Pre-shared key file {} is empty", path.display());
+        }
+        Ok(key.to_string())
+    }
+}
+
+/// Whether a pre-shared key id fits in the `/noise/psk/<id>` protocol name
+pub fn valid_key_id(key_id: &str) -> bool {
+    !key_id.is_empty() && !key_id.contains(|c: char| c == '/' || c.is_whitespace())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdentifyConfig {
+    /// Seconds between periodic identify requests to each connected peer
+    pub interval_secs: u64,
+    /// Number of intervals without a refresh after which cached identify info is considered stale
+    pub expiry_intervals: u32,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5 * 60,
+            expiry_intervals: 3,
+        }
+    }
+}
+
+impl IdentifyConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn expiry(&self) -> Duration {
+        self.interval() * self.expiry_intervals
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct KademliaConfig {
+    /// Seconds a provider record stays valid on other nodes before it expires
+    pub provider_ttl_secs: u64,
+    /// Seconds between re-announcements of the keys this node provides
+    pub provider_announce_interval_secs: u64,
+    /// Number of peers that must store a record for a put to succeed
+    #[serde(default = "default_put_quorum")]
+    pub put_quorum: usize,
+    /// Attempts at storing a record before a put is reported as failed
+    #[serde(default = "default_put_attempts")]
+    pub put_attempts: u32,
+    /// Directory to keep DHT records in so they survive restarts, held in memory when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_store_dir: Option<PathBuf>,
+}
+
+fn default_put_quorum() -> usize {
+    1
+}
+
+fn default_put_attempts() -> u32 {
+    3
+}
+
+impl Default for KademliaConfig {
+    fn default() -> Self {
+        Self {
+            provider_ttl_secs: 24 * 60 * 60,
+            provider_announce_interval_secs: 12 * 60 * 60,
+            put_quorum: default_put_quorum(),
+            put_attempts: default_put_attempts(),
+            record_store_dir: None,
+        }
+    }
+}
+
+impl KademliaConfig {
+    pub fn provider_ttl(&self) -> Duration {
+        Duration::from_secs(self.provider_ttl_secs)
+    }
+
+    pub fn provider_announce_interval(&self) -> Duration {
+        Duration::from_secs(self.provider_announce_interval_secs)
+    }
+
+    pub fn put_quorum(&self) -> kad::Quorum {
+        match NonZeroUsize::new(self.put_quorum) {
+            Some(quorum) if quorum.get() > 1 => kad::Quorum::N(quorum),
+            _ => kad::Quorum::One,
+        }
+    }
+}
+
+/// How far the database document must be synced before we announce as a provider of it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderReadiness {
+    /// The document exists locally
+    Present,
+    /// The document exists locally and its heads match those of an existing provider
+    Converged,
+}
+
+/// Order in which document syncs waiting for a free slot are started
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncScheduling {
+    /// In the order they were requested
+    #[default]
+    Fifo,
+    /// Taking turns between documents
+    RoundRobin,
+}
+
+/// What happens to document changes while too many sync messages wait to be sent
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOverflow {
+    /// Changes are held back until the queue drained, then sent together
+    #[default]
+    Backpressure,
+    /// The oldest sync message is dropped and its sync started over later
+    DropOldest,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DatabaseConfig {
+    /// Automerge document backing the database
+    pub document: String,
+    /// Announce as database provider on startup, once the document is ready
+    pub provide_on_startup: bool,
+    pub provider_readiness: ProviderReadiness,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            document: "test".to_string(),
+            provide_on_startup: false,
+            provider_readiness: ProviderReadiness::Converged,
         }
     }
 }
 
+/// QUIC transport tuning, independent of the swarm wide idle connection timeout
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct QuicConfig {
+    /// Milliseconds without any traffic after which a QUIC connection is dropped
+    pub max_idle_timeout_ms: u32,
+    /// Seconds between keep-alive packets sent on idle QUIC connections
+    pub keep_alive_interval_secs: u64,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_timeout_ms: 10 * 1000,
+            keep_alive_interval_secs: 5,
+        }
+    }
+}
+
+impl QuicConfig {
+    pub fn keep_alive_interval(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_interval_secs)
+    }
+}
+
+/// Gossipsub mesh tuning, the defaults are those of libp2p
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GossipsubConfig {
+    /// Peers kept in the mesh of each topic
+    pub mesh_n: usize,
+    /// Fewer mesh peers than this makes the heartbeat graft more
+    pub mesh_n_low: usize,
+    /// More mesh peers than this makes the heartbeat prune down to `mesh_n`
+    pub mesh_n_high: usize,
+    /// Milliseconds between heartbeats maintaining the meshes
+    pub heartbeat_interval_ms: u64,
+    /// Seconds a seen message id is remembered, so the message isn't processed again
+    pub duplicate_cache_ttl_secs: u64,
+}
+
+impl Default for GossipsubConfig {
+    fn default() -> Self {
+        Self {
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            heartbeat_interval_ms: 1000,
+            duplicate_cache_ttl_secs: 60,
+        }
+    }
+}
+
+impl GossipsubConfig {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+
+    pub fn duplicate_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.duplicate_cache_ttl_secs)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AppConfig {
-    pub relay: RelayConfig,
+    /// Relays to reserve a circuit on, every reachable one hosts a reservation. The first to
+    /// respond becomes the primary, used for relayed dials.
+    #[serde(default)]
+    pub relays: Vec<RelayConfig>,
+    /// Single relay table of older configs, used as if it were listed in `relays`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<RelayConfig>,
+    /// Relays of older configs, used as if they were listed in `relays`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backup_relays: Vec<RelayConfig>,
     pub identity: IdentityConfig,
     pub db_path: PathBuf,
+    #[serde(default)]
+    pub identify: IdentifyConfig,
+    /// Seconds after which an outgoing connection attempt is abandoned, at most 10 as the swarm
+    /// builder caps every connection attempt at that
+    #[serde(default = "default_dial_timeout_secs")]
+    pub dial_timeout_secs: u64,
+    #[serde(default)]
+    pub relay_server: RelayServerConfig,
+    /// Ask the local router to forward a port through UPnP, giving us a direct address when the
+    /// router supports it
+    #[serde(default)]
+    pub enable_upnp: bool,
+    /// Find peers on the local network through mDNS and connect to them directly, without a
+    /// relay hop
+    #[serde(default = "default_enable_mdns")]
+    pub enable_mdns: bool,
+    #[serde(default)]
+    pub kademlia: KademliaConfig,
+    /// Nodes added to the routing table next to the relays, kademlia bootstraps once one of them
+    /// is reachable
+    #[serde(default)]
+    pub bootstrap: Vec<BootstrapPeer>,
+    #[serde(default)]
+    pub quic: QuicConfig,
+    #[serde(default)]
+    pub gossipsub: GossipsubConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    /// Attempts at reaching the relay on startup before giving up, 0 retries forever
+    #[serde(default = "default_relay_dial_attempts")]
+    pub relay_dial_attempts: u32,
+    /// Redials of a peer that couldn't be reached through a dial command, 0 gives up right away
+    #[serde(default = "default_dial_retries")]
+    pub dial_retries: u32,
+    /// Node and swarm events buffered per subscriber, e.g. the database manager, before the
+    /// slowest one starts missing events
+    #[serde(default = "default_event_channel_capacity")]
+    pub event_channel_capacity: usize,
+    /// Window over which local document changes are coalesced before publishing to gossipsub
+    #[serde(default = "default_change_publish_interval_ms")]
+    pub change_publish_interval_ms: u64,
+    /// How long a peer is listed as working on a document after its last message about it
+    #[serde(default = "default_document_peer_window_secs")]
+    pub document_peer_window_secs: u64,
+    /// Seconds without changes or peer interest after which unpinned documents can be collected
+    #[serde(default = "default_document_gc_ttl_secs")]
+    pub document_gc_ttl_secs: u64,
+    /// Append document changes to a log instead of rewriting whole documents, compacting the log
+    /// into a snapshot after this many changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_log_compact_after: Option<usize>,
+    /// Compact a document once it gained this many changes since it was last compacted, only on
+    /// request when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_compact_after_changes: Option<usize>,
+    /// Documents created at startup if missing and sent to every peer we connect to. Documents
+    /// not listed here are not accepted from peers.
+    #[serde(default = "default_documents")]
+    pub documents: Vec<String>,
+    /// Peers whose gossipsub messages are accepted and forwarded, messages from anyone else are
+    /// rejected. Empty accepts every signed message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gossip_allowed_peers: Vec<PeerId>,
+    /// Seconds without any connection after which the relays are redialed, 0 disables it
+    #[serde(default = "default_isolation_timeout_secs")]
+    pub isolation_timeout_secs: u64,
+    /// Seconds between heartbeats reporting the connected peers and running syncs, 0 disables
+    /// them
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// Seconds without document syncs or gossip after which a peer is disconnected, 0 keeps idle
+    /// peers connected. Relays are never disconnected for being idle.
+    #[serde(default)]
+    pub idle_peer_timeout_secs: u64,
+    /// Document syncs running at the same time, further ones wait for a free slot
+    #[serde(default = "default_max_concurrent_syncs")]
+    pub max_concurrent_syncs: usize,
+    #[serde(default)]
+    pub sync_scheduling: SyncScheduling,
+    /// Seconds a document sync may run without converging before it's started over
+    #[serde(default = "default_sync_timeout_secs")]
+    pub sync_timeout_secs: u64,
+    /// Connections per peer that sync documents, further connections to the same peer don't
+    #[serde(default = "default_max_handlers_per_peer")]
+    pub max_handlers_per_peer: usize,
+    /// Document sync messages waiting to be sent before `sync_queue_overflow` applies
+    #[serde(default = "default_max_queued_sync_messages")]
+    pub max_queued_sync_messages: usize,
+    #[serde(default)]
+    pub sync_queue_overflow: QueueOverflow,
+    /// Automerge messages per second a peer may send before further ones are dropped, full
+    /// documents, requests and changes published over gossipsub included, 0 doesn't limit them
+    #[serde(default = "default_max_sync_messages_per_sec")]
+    pub max_sync_messages_per_sec: u32,
+    /// Automerge messages a peer may send at once above that rate
+    #[serde(default = "default_sync_message_burst")]
+    pub sync_message_burst: u32,
+    /// Unix socket accepting JSON-RPC requests, stdin commands are only read when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_socket: Option<PathBuf>,
+    /// Addresses the node listens on, port 0 picking a free port. A fixed port is needed when
+    /// the router forwards one to us.
+    #[serde(default = "default_listen_addresses")]
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Listen addresses of the other transport are skipped
+    #[serde(default)]
+    pub transports: Transports,
+}
+
+fn default_listen_addresses() -> Vec<Multiaddr> {
+    vec![
+        "/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap(),
+        "/ip4/0.0.0.0/tcp/0".parse().unwrap(),
+    ]
+}
+
+fn default_documents() -> Vec<String> {
+    vec!["test".to_string(), "codereview".to_string()]
+}
+
+fn default_max_concurrent_syncs() -> usize {
+    2
+}
+
+fn default_sync_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_handlers_per_peer() -> usize {
+    2
+}
+
+fn default_max_queued_sync_messages() -> usize {
+    1000
+}
+
+fn default_max_sync_messages_per_sec() -> u32 {
+    100
+}
+
+fn default_sync_message_burst() -> u32 {
+    200
+}
+
+fn default_isolation_timeout_secs() -> u64 {
+    120
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    30
+}
+
+fn default_relay_dial_attempts() -> u32 {
+    10
+}
+
+fn default_dial_retries() -> u32 {
+    3
+}
+
+fn default_event_channel_capacity() -> usize {
+    32
+}
+
+fn default_change_publish_interval_ms() -> u64 {
+    crate::change_throttle::DEFAULT_INTERVAL.as_millis() as u64
+}
+
+fn default_document_peer_window_secs() -> u64 {
+    300
+}
+
+fn default_document_gc_ttl_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_enable_mdns() -> bool {
+    true
+}
+
+fn default_dial_timeout_secs() -> u64 {
+    MAX_DIAL_TIMEOUT_SECS
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
             identity: IdentityConfig::default(),
-            relay: RelayConfig::default(),
+            relays: vec![RelayConfig::default()],
+            relay: None,
+            backup_relays: Vec::new(),
+            bootstrap: Vec::new(),
             db_path: dirs::data_dir().unwrap().join(CONFIG_DIR_NAME).join("data"),
+            identify: IdentifyConfig::default(),
+            dial_timeout_secs: default_dial_timeout_secs(),
+            relay_server: RelayServerConfig::default(),
+            enable_upnp: false,
+            enable_mdns: default_enable_mdns(),
+            kademlia: KademliaConfig::default(),
+            quic: QuicConfig::default(),
+            gossipsub: GossipsubConfig::default(),
+            database: DatabaseConfig::default(),
+            relay_dial_attempts: default_relay_dial_attempts(),
+            dial_retries: default_dial_retries(),
+            event_channel_capacity: default_event_channel_capacity(),
+            change_publish_interval_ms: default_change_publish_interval_ms(),
+            document_peer_window_secs: default_document_peer_window_secs(),
+            document_gc_ttl_secs: default_document_gc_ttl_secs(),
+            change_log_compact_after: None,
+            auto_compact_after_changes: None,
+            documents: default_documents(),
+            gossip_allowed_peers: Vec::new(),
+            isolation_timeout_secs: default_isolation_timeout_secs(),
+            heartbeat_interval_secs: default_heartbeat_interval_secs(),
+            idle_peer_timeout_secs: 0,
+            max_concurrent_syncs: default_max_concurrent_syncs(),
+            sync_scheduling: SyncScheduling::default(),
+            sync_timeout_secs: default_sync_timeout_secs(),
+            max_handlers_per_peer: default_max_handlers_per_peer(),
+            max_queued_sync_messages: default_max_queued_sync_messages(),
+            sync_queue_overflow: QueueOverflow::default(),
+            max_sync_messages_per_sec: default_max_sync_messages_per_sec(),
+            sync_message_burst: default_sync_message_burst(),
+            rpc_socket: None,
+            listen_addresses: default_listen_addresses(),
+            transports: Transports::default(),
         }
     }
 }
@@ -75,6 +602,83 @@ impl AppConfig {
             .to_string()
     }
 
+    pub fn dial_timeout(&self) -> Duration {
+        Duration::from_secs(self.dial_timeout_secs)
+    }
+
+    /// Every configured relay, `relays` first, without duplicate peer ids
+    pub fn relay_candidates(&self) -> Vec<RelayConfig> {
+        let mut relays: Vec<RelayConfig> = Vec::new();
+        for relay in self
+            .relays
+            .iter()
+            .chain(&self.relay)
+            .chain(&self.backup_relays)
+        {
+            if !relays.iter().any(|known| known.peer_id == relay.peer_id) {
+                relays.push(relay.clone());
+            }
+        }
+        relays
+    }
+
+    pub fn sync_timeout(&self) -> Duration {
+        Duration::from_secs(self.sync_timeout_secs)
+    }
+
+    pub fn document_peer_window(&self) -> Duration {
+        Duration::from_secs(self.document_peer_window_secs)
+    }
+
+    pub fn document_gc_ttl(&self) -> Duration {
+        Duration::from_secs(self.document_gc_ttl_secs)
+    }
+
+    pub fn document_persistence(&self) -> libp2p_automerge::Persistence {
+        match self.change_log_compact_after {
+            Some(compact_after) => libp2p_automerge::Persistence::ChangeLog { compact_after },
+            None => libp2p_automerge::Persistence::Snapshot,
+        }
+    }
+
+    pub fn document_sync_scheduling(&self) -> libp2p_automerge::SyncScheduling {
+        match self.sync_scheduling {
+            SyncScheduling::Fifo => libp2p_automerge::SyncScheduling::Fifo,
+            SyncScheduling::RoundRobin => libp2p_automerge::SyncScheduling::RoundRobin,
+        }
+    }
+
+    pub fn document_queue_overflow(&self) -> libp2p_automerge::QueueOverflow {
+        match self.sync_queue_overflow {
+            QueueOverflow::Backpressure => libp2p_automerge::QueueOverflow::Backpressure,
+            QueueOverflow::DropOldest => libp2p_automerge::QueueOverflow::DropOldest,
+        }
+    }
+
+    pub fn document_sync_rate_limit(&self) -> Option<libp2p_automerge::SyncRateLimit> {
+        (self.max_sync_messages_per_sec != 0).then(|| libp2p_automerge::SyncRateLimit {
+            messages_per_sec: self.max_sync_messages_per_sec,
+            burst: self.sync_message_burst,
+        })
+    }
+
+    pub fn isolation_timeout(&self) -> Option<Duration> {
+        (self.isolation_timeout_secs != 0).then(|| Duration::from_secs(self.isolation_timeout_secs))
+    }
+
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        (self.heartbeat_interval_secs != 0)
+            .then(|| Duration::from_secs(self.heartbeat_interval_secs))
+    }
+
+    pub fn idle_peer_timeout(&self) -> Option<Duration> {
+        (self.idle_peer_timeout_secs != 0).then(|| Duration::from_secs(self.idle_peer_timeout_secs))
+    }
+
+    pub fn change_publish_interval(&self) -> Duration {
+        Duration::from_millis(self.change_publish_interval_ms)
+    }
+
     pub fn load(path: Option<String>) -> Result<Self> {
         if let Some(p) = path {
             return Self::load_from_file(&p);
@@ -90,7 +694,7 @@ impl AppConfig {
         Ok(config)
     }
 
-    fn save_to_file(&self, path: &str) -> Result<()> {
+    pub fn save_to_file(&self, path: &str) -> Result<()> {
         let config_data = toml::to_string(self)?;
         std::fs::write(path, config_data)?;
         Ok(())
@@ -102,23 +706,250 @@ impl AppConfig {
     }
 
     pub fn validate(&self) -> Result<()> {
-        if self.identity.pre_shared_key.is_empty() {
-            anyhow::bail!(
+        match (
+            self.identity.pre_shared_key.is_empty(),
+            self.identity.pre_shared_key_file.is_some(),
+        ) {
+            (true, false) => anyhow::bail!(
                 "Failed loading config at {}: Pre-shared key cannot be empty",
                 Self::default_config_location()
+            ),
+            (false, true) => anyhow::bail!(
+                "Failed loading config at {}: Set either pre_shared_key or pre_shared_key_file, not both",
+                Self::default_config_location()
+            ),
+            _ => {}
+        }
+
+        if let Some(key_id) = &self.identity.pre_shared_key_id
+            && !valid_key_id(key_id)
+        {
+            anyhow::bail!(
+                "Failed loading config at {}: Pre-shared key id {:?} must be non-empty and can't contain '/' or whitespace",
+                Self::default_config_location(),
+                key_id
+            );
+        }
+
+        let relays = self.relay_candidates();
+        if relays.is_empty() {
+            anyhow::bail!(
+                "Failed loading config at {}: At least one relay must be configured",
+                Self::default_config_location()
+            );
+        }
+
+        if relays.iter().any(|relay| relay.address.iter().count() == 0) {
+            anyhow::bail!(
+                "Failed loading config at {}: Relay addresses cannot be empty",
+                Self::default_config_location()
             );
         }
 
-        if self.relay.address.iter().count() == 0 {
+        if self
+            .bootstrap
+            .iter()
+            .any(|peer| peer.address.iter().count() == 0)
+        {
             anyhow::bail!(
-                "Failed loading config at {}: Relay address cannot be empty",
+                "Failed loading config at {}: Bootstrap peer addresses cannot be empty",
                 Self::default_config_location()
             );
         }
 
-        if self.relay.peer_id.to_string().is_empty() {
+        if self.listen_addresses.is_empty() {
             anyhow::bail!(
-                "Failed loading config at {}: Relay peer ID cannot be empty",
+                "Failed loading config at {}: At least one listen address must be configured",
+                Self::default_config_location()
+            );
+        }
+
+        if !self
+            .listen_addresses
+            .iter()
+            .any(|address| self.transports.supports(address))
+        {
+            anyhow::bail!(
+                "Failed loading config at {}: At least one listen address must use an enabled transport ({:?})",
+                Self::default_config_location(),
+                self.transports
+            );
+        }
+
+        // the swarm has no transport to dial these over, the node would load and never reach them
+        for relay in &relays {
+            if !self.transports.supports(&relay.address) {
+                anyhow::bail!(
+                    "Failed loading config at {}: Relay address {} needs a transport that isn't enabled ({:?})",
+                    Self::default_config_location(),
+                    relay.address,
+                    self.transports
+                );
+            }
+        }
+        for peer in &self.bootstrap {
+            if !self.transports.supports(&peer.address) {
+                anyhow::bail!(
+                    "Failed loading config at {}: Bootstrap address {} needs a transport that isn't enabled ({:?})",
+                    Self::default_config_location(),
+                    peer.address,
+                    self.transports
+                );
+            }
+        }
+
+        for address in &self.listen_addresses {
+            if let Err(err) = check_listen_address(address) {
+                anyhow::bail!(
+                    "Failed loading config at {}: Listen address {}: {}",
+                    Self::default_config_location(),
+                    address,
+                    err
+                );
+            }
+        }
+
+        if self.identify.interval_secs == 0 || self.identify.expiry_intervals == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Identify interval and expiry must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        let gossipsub = &self.gossipsub;
+        if gossipsub.mesh_n_low == 0
+            || gossipsub.mesh_n_low > gossipsub.mesh_n
+            || gossipsub.mesh_n > gossipsub.mesh_n_high
+        {
+            anyhow::bail!(
+                "Failed loading config at {}: Gossipsub mesh bounds must satisfy 0 < mesh_n_low <= mesh_n <= mesh_n_high",
+                Self::default_config_location()
+            );
+        }
+        if gossipsub.heartbeat_interval_ms == 0 || gossipsub.duplicate_cache_ttl_secs == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Gossipsub heartbeat interval and duplicate cache TTL must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.dial_timeout_secs == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Dial timeout must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.dial_timeout_secs > MAX_DIAL_TIMEOUT_SECS {
+            anyhow::bail!(
+                "Failed loading config at {}: Dial timeout can't exceed {} seconds, the swarm abandons connection attempts after that",
+                Self::default_config_location(),
+                MAX_DIAL_TIMEOUT_SECS
+            );
+        }
+
+        if self.change_publish_interval_ms == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Change publish interval must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self
+            .documents
+            .iter()
+            .any(|document_id| document_id.is_empty())
+        {
+            anyhow::bail!(
+                "Failed loading config at {}: Document ids cannot be empty",
+                Self::default_config_location()
+            );
+        }
+
+        if self.change_log_compact_after == Some(0) {
+            anyhow::bail!(
+                "Failed loading config at {}: Change log compaction threshold must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.auto_compact_after_changes == Some(0) {
+            anyhow::bail!(
+                "Failed loading config at {}: Auto compaction threshold must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.max_concurrent_syncs == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Max concurrent syncs must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.sync_timeout_secs == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Sync timeout must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.max_handlers_per_peer == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Max handlers per peer must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.max_queued_sync_messages == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Max queued sync messages must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.max_sync_messages_per_sec != 0 && self.sync_message_burst == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Sync message burst must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.event_channel_capacity == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Event channel capacity must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        // re-announcing after the records expired would leave windows where nobody can find us
+        if self.kademlia.provider_announce_interval_secs == 0
+            || self.kademlia.provider_announce_interval_secs >= self.kademlia.provider_ttl_secs
+        {
+            anyhow::bail!(
+                "Failed loading config at {}: Provider announce interval must be shorter than the provider TTL",
+                Self::default_config_location()
+            );
+        }
+
+        if self.database.document.is_empty() {
+            anyhow::bail!(
+                "Failed loading config at {}: Database document cannot be empty",
+                Self::default_config_location()
+            );
+        }
+
+        if self.kademlia.put_quorum == 0 || self.kademlia.put_attempts == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Kademlia put quorum and attempts must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        // without a keep-alive inside the idle window, idle QUIC connections are always dropped
+        if self.quic.keep_alive_interval().as_millis() >= self.quic.max_idle_timeout_ms as u128 {
+            anyhow::bail!(
+                "Failed loading config at {}: QUIC keep-alive interval must be shorter than the idle timeout",
                 Self::default_config_location()
             );
         }
@@ -133,9 +964,59 @@ impl AppConfig {
                 .unwrap(),
         )?;
 
-        let keypair = ed25519_dalek::SigningKey::generate(&mut OsRng);
-        let pem = keypair.to_pkcs8_pem(LineEnding::LF).unwrap();
-        std::fs::write(&self.identity.key_file_path, pem).expect("Unable to write key file");
+        let pem = match self.identity.key_type {
+            KeyType::Ed25519 => {
+                let keypair = ed25519_dalek::SigningKey::generate(&mut OsRng);
+                keypair.to_pkcs8_pem(LineEnding::LF).unwrap().to_string()
+            }
+            KeyType::Secp256k1 => {
+                let keypair = identity::secp256k1::Keypair::generate();
+                encode_ec_private_key(&keypair.secret().to_bytes(), SECP256K1_OID)?
+            }
+            KeyType::Ecdsa => {
+                let keypair = identity::ecdsa::Keypair::generate();
+                encode_ec_private_key(&keypair.secret().to_bytes(), P256_OID)?
+            }
+        };
+        write_key_file(&self.identity.key_file_path, pem.as_bytes())
+            .expect("Unable to write key file");
+        Ok(())
+    }
+
+    /// Restricts the private key to the owner when other users can read it, or fails in strict
+    /// mode
+    #[cfg(unix)]
+    fn check_key_permissions(&self) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(&self.identity.key_file_path)?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            if self.identity.strict_key_permissions {
+                anyhow::bail!(
+                    "Key file {} has permissions {:o}, expected 600",
+                    self.identity.key_file_path.display(),
+                    mode & 0o777
+                );
+            }
+
+            tracing::warn!(
+                "Key file {} has permissions {:o} and is readable by other users, changing them to 600",
+                self.identity.key_file_path.display(),
+                mode & 0o777
+            );
+            std::fs::set_permissions(
+                &self.identity.key_file_path,
+                std::fs::Permissions::from_mode(0o600),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_key_permissions(&self) -> Result<()> {
         Ok(())
     }
 
@@ -151,9 +1032,373 @@ impl AppConfig {
             return self.load_keypair();
         }
 
+        self.check_key_permissions()?;
         let pem = std::fs::read_to_string(&self.identity.key_file_path)?;
-        let key = ed25519_dalek::SigningKey::from_pkcs8_pem(&pem)?;
-        let key_bytes = key.as_bytes();
-        Ok(identity::Keypair::ed25519_from_bytes(*key_bytes)?)
+        match self.identity.key_type {
+            KeyType::Ed25519 => {
+                let key = ed25519_dalek::SigningKey::from_pkcs8_pem(&pem)?;
+                let key_bytes = key.as_bytes();
+                Ok(identity::Keypair::ed25519_from_bytes(*key_bytes)?)
+            }
+            KeyType::Secp256k1 => {
+                let mut secret = decode_ec_private_key(&pem, SECP256K1_OID)?;
+                let secret = identity::secp256k1::SecretKey::try_from_bytes(&mut secret)?;
+                Ok(identity::secp256k1::Keypair::from(secret).into())
+            }
+            KeyType::Ecdsa => {
+                let secret = decode_ec_private_key(&pem, P256_OID)?;
+                let secret = identity::ecdsa::SecretKey::try_from_bytes(secret)?;
+                Ok(identity::ecdsa::Keypair::from(secret).into())
+            }
+        }
+    }
+}
+
+/// Checks that an address can be dialed at all: a host, then TCP or QUIC over UDP, or a
+/// `/dnsaddr` name, and no `/p2p` component naming a different peer than the one configured.
+/// Only run by `--check-config`, the swarm dials whatever its transports support.
+pub fn check_dial_address(address: &Multiaddr, peer_id: PeerId) -> Result<(), String> {
+    let check_peer_id = |protocol: Protocol<'_>| match protocol {
+        Protocol::P2p(address_peer_id) if address_peer_id != peer_id => {
+            Err(format!("names peer {address_peer_id}"))
+        }
+        _ => Ok(()),
+    };
+    let mut protocols = address.iter();
+    match protocols.next() {
+        // resolves to the full addresses through DNS TXT records
+        Some(Protocol::Dnsaddr(_)) => return protocols.try_for_each(check_peer_id),
+        Some(
+            Protocol::Ip4(_)
+            | Protocol::Ip6(_)
+            | Protocol::Dns(_)
+            | Protocol::Dns4(_)
+            | Protocol::Dns6(_),
+        ) => {}
+        _ => return Err("must start with an IP address or DNS name".to_string()),
+    }
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Tcp(_)), after_tcp) => after_tcp.map_or(Ok(()), check_peer_id)?,
+        (Some(Protocol::Udp(_)), Some(Protocol::QuicV1)) => {}
+        _ => return Err("must continue with /tcp/<port> or /udp/<port>/quic-v1".to_string()),
+    }
+    protocols.try_for_each(check_peer_id)
+}
+
+/// Checks that we can listen on an address: an IP address, then TCP or QUIC over UDP and
+/// nothing else
+pub fn check_listen_address(address: &Multiaddr) -> Result<(), String> {
+    let mut protocols = address.iter();
+    match protocols.next() {
+        Some(Protocol::Ip4(_) | Protocol::Ip6(_)) => {}
+        _ => return Err("must start with an IP address".to_string()),
+    }
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Tcp(_)), None) | (Some(Protocol::Udp(_)), Some(Protocol::QuicV1)) => {}
+        _ => return Err("must continue with /tcp/<port> or /udp/<port>/quic-v1".to_string()),
+    }
+    match protocols.next() {
+        None => Ok(()),
+        Some(protocol) => Err(format!("unexpected {protocol} after the transport")),
+    }
+}
+
+/// PEM of a SEC1 `ECPrivateKey` holding only the version, the secret and the curve
+fn encode_ec_private_key(secret: &[u8], curve_oid: &[u8]) -> Result<String> {
+    let mut parameters = vec![0xa0, curve_oid.len() as u8];
+    parameters.extend_from_slice(curve_oid);
+    let mut body = vec![0x02, 0x01, 0x01, 0x04, secret.len() as u8];
+    body.extend_from_slice(secret);
+    body.extend_from_slice(&parameters);
+    let mut der = vec![0x30, body.len() as u8];
+    der.extend_from_slice(&body);
+
+    pem::encode_string(EC_PRIVATE_KEY_LABEL, LineEnding::LF, &der)
+        .map_err(|err| anyhow::anyhow!("Failed encoding key: {err}"))
+}
+
+/// Secret of a SEC1 `ECPrivateKey` PEM, checking the key is on the expected curve when the
+/// key names its curve
+fn decode_ec_private_key(pem: &str, curve_oid: &[u8]) -> Result<Vec<u8>> {
+    let (label, der) =
+        pem::decode_vec(pem.as_bytes()).map_err(|err| anyhow::anyhow!("Invalid PEM: {err}"))?;
+    if label != EC_PRIVATE_KEY_LABEL {
+        anyhow::bail!("Expected an {EC_PRIVATE_KEY_LABEL} key file, found {label}");
+    }
+
+    // SEQUENCE { INTEGER 1, OCTET STRING secret, [0] curve OPTIONAL, [1] public key OPTIONAL },
+    // keys are far below 128 bytes so every length fits in a single byte
+    let invalid = || anyhow::anyhow!("Invalid SEC1 private key");
+    let body = match der.as_slice() {
+        [0x30, length, body @ ..] if *length as usize == body.len() => body,
+        _ => return Err(invalid()),
+    };
+    let (secret, rest) = match body {
+        [0x02, 0x01, 0x01, 0x04, length, rest @ ..] if rest.len() >= *length as usize => {
+            rest.split_at(*length as usize)
+        }
+        _ => return Err(invalid()),
+    };
+    if let [0xa0, length, rest @ ..] = rest
+        && let Some(curve) = rest.get(..*length as usize)
+        && curve != curve_oid
+    {
+        anyhow::bail!("The key file holds a key on a different curve than its key type");
+    }
+    Ok(secret.to_vec())
+}
+
+/// Writes the key file readable and writable by the owner only. The mode only applies to new
+/// files, so an existing file is restricted before the key is written to it.
+#[cfg(unix)]
+pub(crate) fn write_key_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::{
+        io::Write,
+        os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    };
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_key_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config whose key file lives in an empty directory of its own
+    fn key_config(name: &str, key_type: KeyType) -> AppConfig {
+        let dir = std::env::temp_dir().join(format!("peer-key-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        AppConfig {
+            identity: IdentityConfig {
+                key_file_path: dir.join(KEY_FILE_NAME),
+                key_type,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Removes the directory `key_config` put the key file in
+    fn remove_key_dir(config: &AppConfig) {
+        std::fs::remove_dir_all(config.identity.key_file_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn generated_key_loads_back_for_every_key_type() {
+        for (name, key_type) in [
+            ("ed25519", KeyType::Ed25519),
+            ("secp256k1", KeyType::Secp256k1),
+            ("ecdsa", KeyType::Ecdsa),
+        ] {
+            let config = key_config(name, key_type);
+
+            let generated = config.load_keypair().unwrap();
+            let loaded = config.load_keypair().unwrap();
+
+            assert_eq!(generated.public(), loaded.public(), "{name} key changed");
+            remove_key_dir(&config);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_is_restricted_to_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let config = key_config("permissions", KeyType::Ed25519);
+        let mode = |config: &AppConfig| {
+            std::fs::metadata(&config.identity.key_file_path)
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        config.load_keypair().unwrap();
+        assert_eq!(mode(&config), 0o600);
+
+        for open in [0o640, 0o604] {
+            std::fs::set_permissions(
+                &config.identity.key_file_path,
+                std::fs::Permissions::from_mode(open),
+            )
+            .unwrap();
+            config.load_keypair().unwrap();
+            assert_eq!(mode(&config), 0o600, "{open:o} wasn't restricted");
+        }
+
+        remove_key_dir(&config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn strict_mode_refuses_readable_key_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut config = key_config("strict-permissions", KeyType::Ed25519);
+        config.identity.strict_key_permissions = true;
+        config.load_keypair().unwrap();
+
+        for open in [0o640, 0o604] {
+            std::fs::set_permissions(
+                &config.identity.key_file_path,
+                std::fs::Permissions::from_mode(open),
+            )
+            .unwrap();
+            assert!(config.load_keypair().is_err(), "{open:o} was accepted");
+        }
+
+        remove_key_dir(&config);
+    }
+
+    #[test]
+    fn relay_and_bootstrap_addresses_need_an_enabled_transport() {
+        let quic_only = |relay: &str, bootstrap: &[&str]| AppConfig {
+            relays: vec![RelayConfig {
+                address: relay.parse().unwrap(),
+                peer_id: PeerId::random(),
+            }],
+            bootstrap: bootstrap
+                .iter()
+                .map(|address| BootstrapPeer {
+                    peer_id: PeerId::random(),
+                    address: address.parse().unwrap(),
+                })
+                .collect(),
+            transports: Transports::Quic,
+            listen_addresses: vec!["/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap()],
+            identity: IdentityConfig {
+                pre_shared_key: "secret".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let error = quic_only("/ip4/10.0.0.1/tcp/4001", &[])
+            .validate()
+            .unwrap_err();
+        assert!(error.to_string().contains("Relay address"), "{error}");
+        let error = quic_only(
+            "/ip4/10.0.0.1/udp/4001/quic-v1",
+            &["/ip4/10.0.0.2/tcp/4001"],
+        )
+        .validate()
+        .unwrap_err();
+        assert!(error.to_string().contains("Bootstrap address"), "{error}");
+    }
+
+    #[test]
+    fn partial_tables_fall_back_to_defaults() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            db_path = "db"
+
+            [identity]
+            key_file_path = "key"
+            pre_shared_key = "secret"
+
+            [kademlia]
+            put_quorum = 2
+
+            [quic]
+            keep_alive_interval_secs = 10
+            "#,
+        )
+        .unwrap();
+
+        let defaults = AppConfig::default();
+        assert_eq!(config.kademlia.put_quorum, 2);
+        assert_eq!(
+            config.kademlia.provider_ttl_secs,
+            defaults.kademlia.provider_ttl_secs
+        );
+        assert_eq!(config.kademlia.put_attempts, defaults.kademlia.put_attempts);
+        assert_eq!(config.quic.keep_alive_interval_secs, 10);
+        assert_eq!(
+            config.quic.max_idle_timeout_ms,
+            defaults.quic.max_idle_timeout_ms
+        );
+    }
+
+    #[test]
+    fn pre_shared_key_file_gives_the_same_prologue_as_the_inline_key() {
+        let dir = std::env::temp_dir().join(format!("peer-psk-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("psk");
+        std::fs::write(&path, "secret\n").unwrap();
+        let from_file = IdentityConfig {
+            pre_shared_key_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let inline = IdentityConfig {
+            pre_shared_key: "secret".to_string(),
+            ..Default::default()
+        };
+
+        let key = from_file.load_pre_shared_key().unwrap();
+        assert_eq!(
+            common::string_to_32_bytes(&key),
+            common::string_to_32_bytes(&inline.load_pre_shared_key().unwrap())
+        );
+        std::fs::write(&path, "\n").unwrap();
+        assert!(from_file.load_pre_shared_key().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pre_shared_key_ids_must_fit_a_protocol_name() {
+        let with_key_id = |key_id: &str| AppConfig {
+            relays: vec![RelayConfig {
+                address: "/ip4/10.0.0.1/udp/4001/quic-v1".parse().unwrap(),
+                peer_id: PeerId::random(),
+            }],
+            identity: IdentityConfig {
+                pre_shared_key: "secret".to_string(),
+                pre_shared_key_id: Some(key_id.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(with_key_id("2025-01").validate().is_ok());
+        for key_id in ["", "a/b", "a b"] {
+            assert!(with_key_id(key_id).validate().is_err(), "{key_id:?}");
+        }
+    }
+
+    #[test]
+    fn dial_check_accepts_dnsaddr_and_websocket_addresses() {
+        let peer_id = PeerId::random();
+        let check = |address: String| check_dial_address(&address.parse().unwrap(), peer_id);
+
+        assert!(check(format!("/dnsaddr/bootstrap.example.com/p2p/{peer_id}")).is_ok());
+        assert!(check(format!("/dns4/relay.example.com/tcp/443/wss/p2p/{peer_id}")).is_ok());
+        assert!(check(format!("/ip4/10.0.0.1/tcp/80/ws/p2p/{peer_id}")).is_ok());
+        assert!(check(format!("/ip4/10.0.0.1/tcp/80/p2p/{}", PeerId::random())).is_err());
+        assert!(check(format!("/dnsaddr/example.com/p2p/{}", PeerId::random())).is_err());
+        assert!(check("/ip4/10.0.0.1/udp/80".to_string()).is_err());
+    }
+
+    #[test]
+    fn sec1_keys_round_trip_and_check_their_curve() {
+        for (curve_oid, other_oid) in [(SECP256K1_OID, P256_OID), (P256_OID, SECP256K1_OID)] {
+            let secret = [7u8; 32];
+            let pem = encode_ec_private_key(&secret, curve_oid).unwrap();
+
+            assert_eq!(decode_ec_private_key(&pem, curve_oid).unwrap(), secret);
+            assert!(decode_ec_private_key(&pem, other_oid).is_err());
+        }
     }
 }
@@ -1,20 +1,34 @@
-use std::{error::Error, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    error::Error,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
 use automerge::transaction::Transactable;
 use clap::Parser;
+use common::string_to_32_bytes;
 use futures::stream::StreamExt;
 use libp2p::{
-    PeerId, autonat,
+    PeerId,
     core::multiaddr::Multiaddr,
-    dcutr, gossipsub, identify,
-    identity::{self, ed25519},
-    kad::{self, QueryResult, store::MemoryStore},
+    gossipsub,
+    kad::{self, QueryResult},
+    metrics::Registry,
     multiaddr::Protocol,
-    noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    swarm::NetworkBehaviour,
+};
+use peer::{
+    build_swarm, commands, config_check,
+    database_manager::{self, DatabaseManager},
+    local_config::{self, AppConfig},
+    metrics, node_bundle, rpc,
+    swarm_dispatch::{self, Convergence, NodeEvent, PathOutcome},
 };
-use rand::rngs::OsRng;
 use sha2::{Digest, Sha256};
 use tokio::{
     io::{self, AsyncBufReadExt},
@@ -23,32 +37,109 @@ use tokio::{
 use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
-use crate::{
-    behaviour::{Behaviour, BehaviourEvent},
-    database_manager::DatabaseManager,
-    local_config::AppConfig,
-    swarm_dispatch::SwarmManager,
-};
-
-pub mod behaviour;
-pub mod database_manager;
-pub mod local_config;
-pub mod swarm_dispatch;
-
 #[derive(Debug, Parser)]
 #[command(name = "libp2p DCUtR client")]
 struct Opts {
     /// Config file path
     #[arg(long)]
     config: Option<String>,
+
+    /// Print node events, such as received gossipsub messages, as they happen
+    #[arg(long)]
+    emit_events: bool,
+
+    /// Exit with an error on an unknown command, for scripts feeding commands through stdin
+    #[arg(long)]
+    strict_commands: bool,
+
+    /// Check the config, its addresses and key without starting the node, exiting with 1 if
+    /// anything is wrong
+    #[arg(long)]
+    check_config: bool,
+
+    /// Address to listen on instead of the configured ones, repeat for several addresses
+    #[arg(long = "listen")]
+    listen_addresses: Vec<Multiaddr>,
 }
 
-/// Hashes a string to a [u8; 32] key using SHA-256.
-fn string_to_32_bytes(s: &str) -> [u8; 32] {
-    let hash = Sha256::digest(s.as_bytes());
-    let mut arr = [0u8; 32];
-    arr.copy_from_slice(&hash[..]);
-    arr
+/// Hex SHA-256 of the Noise prologue derived from the pre-shared key, so SHA-256 applied twice to
+/// the key. The prologue, SHA-256 of the key, is all a peer needs to pass the handshake check,
+/// so printing it would leak as much as printing the key.
+fn prologue_fingerprint(pre_shared_key: &str) -> String {
+    Sha256::digest(string_to_32_bytes(pre_shared_key))
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn print_node_event(event: &NodeEvent) {
+    match event {
+        NodeEvent::GossipMessage {
+            topic,
+            source,
+            data,
+        } => {
+            let source = source.map_or("anonymous".to_string(), |peer_id| peer_id.to_string());
+            println!("[{}] {}: {}", topic, source, String::from_utf8_lossy(data));
+        }
+        NodeEvent::PeerSubscribed { peer, topic } => println!("{} joined {}", peer, topic),
+        NodeEvent::PeerUnsubscribed { peer, topic } => println!("{} left {}", peer, topic),
+        NodeEvent::Isolated {
+            duration,
+            recoveries,
+        } => println!(
+            "isolated for {:?}, {} recoveries failed",
+            duration, recoveries
+        ),
+        NodeEvent::DirectConnectionEstablished(peer) => println!("direct connection to {}", peer),
+        NodeEvent::RelayedSyncFallback(peer) => {
+            println!(
+                "hole punching to {} failed, syncing through the relay",
+                peer
+            )
+        }
+        NodeEvent::PeerConnected { peer, relayed } => {
+            println!(
+                "connected to {}{}",
+                peer,
+                if *relayed { " through a relay" } else { "" }
+            )
+        }
+        NodeEvent::PeerDisconnected { peer } => println!("disconnected from {}", peer),
+        NodeEvent::DocumentSynced { peer, document_id } => {
+            println!("synced {} with {}", document_id, peer)
+        }
+        NodeEvent::DocumentDeleted { peer, document_id } => {
+            println!("{} deleted {}", peer, document_id)
+        }
+        NodeEvent::ProvidersFound { key, providers } => println!(
+            "providers of {}: {}",
+            String::from_utf8_lossy(key.as_ref()),
+            providers
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        NodeEvent::RelayReservationActive { relay, renewal } => println!(
+            "reservation {} by relay {}",
+            if *renewal { "renewed" } else { "accepted" },
+            relay
+        ),
+        NodeEvent::DialFailed {
+            target,
+            attempts,
+            failure,
+        } => println!("could not reach {target} after {attempts} attempts: {failure}"),
+    }
+}
+
+fn describe_path(outcome: &PathOutcome) -> String {
+    match outcome {
+        PathOutcome::Reachable(latency) => format!("reachable in {}ms", latency.as_millis()),
+        PathOutcome::Failed(reason) => format!("failed, {}", reason),
+        PathOutcome::Skipped(reason) => format!("skipped, {}", reason),
+    }
 }
 
 fn get_config_or_default(
@@ -75,88 +166,47 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .try_init();
 
     let opts: Opts = Opts::parse();
+    if opts.check_config {
+        let passed = config_check::run(opts.config.clone());
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    let peer_config = get_config_or_default(opts.config).unwrap_or_else(|e| {
+    let config_path = opts
+        .config
+        .clone()
+        .unwrap_or_else(AppConfig::default_config_location);
+    let mut peer_config = get_config_or_default(opts.config.clone()).unwrap_or_else(|e| {
         println!("{}", e);
         std::process::exit(1);
     });
+    if !opts.listen_addresses.is_empty() {
+        peer_config.listen_addresses = opts.listen_addresses.clone();
+        peer_config.validate().unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(1);
+        });
+    }
 
     let keypair = peer_config.load_keypair().expect("Failed to load keypair");
-    let mut kademlia = libp2p::kad::Behaviour::new(
-        keypair.public().to_peer_id(),
-        MemoryStore::new(keypair.public().to_peer_id()),
-    );
-    kademlia.set_mode(Some(kad::Mode::Client));
-    kademlia.add_address(
-        &peer_config.relay.peer_id,
-        peer_config.relay.address.clone(),
-    );
+    let relays = peer_config.relay_candidates();
+    // relayed dials go through the first relay
+    let (relay_peer_id, relay_address) = (relays[0].peer_id, relays[0].address.clone());
 
-    let noise_config_with_prologue =
-        |keypair: &identity::Keypair| -> Result<noise::Config, std::io::Error> {
-            let mut config = noise::Config::new(keypair).expect("Noise key generation failed");
-            config = config
-                .with_prologue(string_to_32_bytes(&peer_config.identity.pre_shared_key).to_vec());
-            Ok(config)
-        };
-
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default().nodelay(true),
-            noise_config_with_prologue,
-            yamux::Config::default,
-        )?
-        .with_quic()
-        .with_dns()?
-        .with_relay_client(noise::Config::new, yamux::Config::default)?
-        .with_behaviour(|keypair, relay_behaviour| Behaviour {
-            relay_client: relay_behaviour,
-            ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(30))),
-            identify: identify::Behaviour::new(
-                identify::Config::new("ipfs/1.0.0".to_owned(), keypair.public())
-                    .with_hide_listen_addrs(false)
-                    .with_push_listen_addr_updates(true),
-            ),
-            autonat: autonat::v2::client::Behaviour::new(
-                OsRng,
-                autonat::v2::client::Config::default(),
-            ),
-            dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
-            gossipsub: gossipsub::Behaviour::new(
-                gossipsub::MessageAuthenticity::Signed(keypair.clone()),
-                gossipsub::Config::default(),
-            )
-            .unwrap(),
-            kademlia,
-            automerge: libp2p_automerge::Behaviour::new(libp2p_automerge::Config {
-                documents_whitelist: Some(vec!["test".to_string(), "codereview".to_string()]),
-                max_simultaneous_syncs: 2,
-                data_dir: peer_config.db_path,
-            }),
-        })?
-        .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::from_secs(60)))
-        .build();
-
-    swarm
-        .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap())
-        .unwrap();
-    swarm
-        .listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
-        .unwrap();
+    let mut psk = peer_config.identity.load_pre_shared_key()?;
+    let mut psk_id = peer_config.identity.pre_shared_key_id.clone();
+    let mut registry = Registry::default();
+    let (mut swarm, rekeyable_noise) = build_swarm(&peer_config, keypair, &psk, &mut registry)?;
 
-    // Connect to the relay server. Not for the reservation or relayed connection, but to (a) learn
-    // our local public address and (b) enable a freshly started relay to learn its public address.
-    swarm
-        .dial(
-            peer_config
-                .relay
-                .address
-                .clone()
-                .with_p2p(peer_config.relay.peer_id)
-                .unwrap(),
-        )
-        .unwrap();
+    for address in &peer_config.listen_addresses {
+        if !peer_config.transports.supports(address) {
+            info!(
+                "Not listening on {}, {:?} transports are enabled",
+                address, peer_config.transports
+            );
+            continue;
+        }
+        swarm.listen_on(address.clone())?;
+    }
 
     let mut stdin = io::BufReader::new(io::stdin()).lines();
     let ctrl_c_signal = tokio::signal::ctrl_c();
@@ -164,41 +214,117 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let mut is_db_provider = false;
     let db_key = kad::RecordKey::new(&"db".as_bytes().to_vec());
-    // swarm
-    //     .behaviour_mut()
-    //     .kademlia
-    //     .start_providing(db_key.clone())
-    //     .expect("failed to start providing as kademlia relay");
-
-    let (swarm_event_tx, swarm_event_rx) =
-        tokio::sync::broadcast::channel::<Arc<SwarmEvent<BehaviourEvent>>>(32);
+
+    let dropped_events = Arc::new(AtomicU64::new(0));
     let (swarm_command_tx, swarm_command_rx) =
         tokio::sync::mpsc::channel::<swarm_dispatch::SwarmCommand>(32);
-    let (db_event_tx, db_event_rx) =
+    let (db_event_tx, mut db_event_rx) =
         tokio::sync::mpsc::channel::<database_manager::DatabaseEvent>(32);
     let (db_command_tx, db_command_rx) =
         tokio::sync::mpsc::channel::<database_manager::DatabaseCommand>(32);
 
-    let swarm_manager = SwarmManager::new(
+    let swarm_manager = peer::swarm_manager(
+        &peer_config,
         swarm,
-        swarm_event_tx,
+        rekeyable_noise,
+        registry,
         swarm_command_rx,
-        peer_config.relay.peer_id,
-        peer_config.relay.address.clone(),
     );
 
-    let database_manager = DatabaseManager::new(
+    let mut database_manager = DatabaseManager::new(
         db_event_tx,
         db_command_rx,
-        swarm_event_rx,
+        swarm_manager.subscribe_node_events(),
         swarm_command_tx.clone(),
+        dropped_events.clone(),
     );
+    if let Some(interval) = peer_config.heartbeat_interval() {
+        database_manager = database_manager.with_heartbeat(interval);
+    }
+
+    let print_heartbeats = opts.emit_events;
+    tokio::spawn(async move {
+        while let Some(event) = db_event_rx.recv().await {
+            if let database_manager::DatabaseEvent::Heartbeat(liveness) = event {
+                let line = format!(
+                    "heartbeat: {} peers connected, {} document syncs running",
+                    liveness.connected_peers.len(),
+                    liveness.active_syncs.len()
+                );
+                if print_heartbeats {
+                    println!("{}", line);
+                } else {
+                    tracing::debug!("{}", line);
+                }
+            }
+        }
+    });
+
+    if opts.emit_events {
+        let mut node_events = swarm_manager.subscribe_node_events();
+        tokio::spawn(async move {
+            loop {
+                match node_events.recv().await {
+                    Ok(event) => print_node_event(&event),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(count)) => {
+                        warn!("missed {} node events", count);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
 
     tokio::spawn(async move { swarm_manager.run().await });
 
+    // addresses only come up once the swarm runs, `status` shows them when they have
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    swarm_command_tx
+        .send(swarm_dispatch::SwarmCommand::Status(reply_tx))
+        .await
+        .unwrap();
+    if let Ok(status) = reply_rx.await {
+        print_status(&status);
+    }
+
+    // announcing before we have the database would point peers at an empty copy
+    if peer_config.database.provide_on_startup {
+        swarm_command_tx
+            .send(swarm_dispatch::SwarmCommand::ProvideWhenReady {
+                key: db_key.clone(),
+                document_id: peer_config.database.document.clone(),
+                readiness: peer_config.database.provider_readiness,
+            })
+            .await
+            .unwrap();
+        is_db_provider = true;
+    }
+    tokio::spawn(async move { database_manager.run().await });
+
+    // the socket replaces stdin, the node keeps running until asked to shut down over it
+    let (rpc_shutdown_tx, mut rpc_shutdown_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let read_stdin = peer_config.rpc_socket.is_none();
+    if let Some(path) = peer_config.rpc_socket.clone() {
+        let node = rpc::Node {
+            swarm_command_tx: swarm_command_tx.clone(),
+            shutdown_tx: rpc_shutdown_tx,
+            dropped_events: dropped_events.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(err) = rpc::serve(path, node).await {
+                warn!("JSON-RPC socket failed: {}", err);
+            }
+        });
+    }
+
+    let mut failure = None;
     loop {
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
+            Some(()) = rpc_shutdown_rx.recv() => {
+                info!("shutdown requested over JSON-RPC");
+                break;
+            },
+            Ok(Some(line)) = stdin.next_line(), if read_stdin => {
                 let line = line.trim();
                 if line == "exit" || line == "quit" || line == "q" {
                     info!("exiting...");
@@ -216,14 +342,32 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     let parts: Vec<&str> = line.splitn(3, ' ').collect();
                     if parts.len() == 3 {
                         let key = parts[2];
-                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::GetTestValue(key.to_string())).await.unwrap();
+                        let key = key.to_string();
+                        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::GetTestValue(key.clone(), reply_tx)).await.unwrap();
+                        tokio::spawn(async move {
+                            match reply_rx.await {
+                                Ok(Some(value)) => println!("{}", value),
+                                Ok(None) => info!("key {} not found", key),
+                                Err(_) => {}
+                            }
+                        });
                     } else {
                         warn!("usage: db get <key>");
                     }
-                } else if line == "promote db" {
+                } else if line == "promote db" || line == "promote db --now" {
                     if !is_db_provider {
-                        info!("promoting to db provider");
-                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::BeginProviderRole(db_key.clone())).await.unwrap();
+                        if line.ends_with("--now") {
+                            info!("promoting to db provider without warm-up");
+                            swarm_command_tx.send(swarm_dispatch::SwarmCommand::BeginProviderRole(db_key.clone())).await.unwrap();
+                        } else {
+                            info!("promoting to db provider once the database is ready");
+                            swarm_command_tx.send(swarm_dispatch::SwarmCommand::ProvideWhenReady {
+                                key: db_key.clone(),
+                                document_id: peer_config.database.document.clone(),
+                                readiness: peer_config.database.provider_readiness,
+                            }).await.unwrap();
+                        }
                         is_db_provider = true;
                     } else {
                         info!("already a db provider");
@@ -243,38 +387,730 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         let key_str = parts[2];
                         let key = kad::RecordKey::new(&key_str.as_bytes().to_vec());
                         info!("looking for providers of key: {}", key_str);
-                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::FindProviders(key)).await.unwrap();
+                        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::FindProviders(key, reply_tx)).await.unwrap();
+                        tokio::spawn(async move {
+                            let Ok(providers) = reply_rx.await else {
+                                return;
+                            };
+                            if providers.is_empty() {
+                                println!("no providers found");
+                            }
+                            for provider in providers {
+                                println!("{}", provider);
+                            }
+                        });
                     } else {
                         warn!("usage: get providers <key>");
                     }
-                } else if line.starts_with("dial") {
+                } else if line.starts_with("dial_id ") {
                     let parts: Vec<&str> = line.splitn(2, ' ').collect();
                     if parts.len() == 2 {
-                        let peer_id = parts[1];
-                                let addr = peer_config.relay.address
-                                    .clone()
-                                    .with(Protocol::P2p(peer_config.relay.peer_id))
-                                    .with(Protocol::P2pCircuit)
-                                    .with(Protocol::P2p(PeerId::from_str(peer_id).unwrap()));
-                                info!("dialing {}", addr);
-                                swarm_command_tx.send(swarm_dispatch::SwarmCommand::Dial(addr)).await.unwrap();
+                        let Ok(peer_id) = PeerId::from_str(parts[1]) else {
+                            warn!("invalid peer id: {}", parts[1]);
+                            continue;
+                        };
+                        info!("dialing peer id {}", peer_id);
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::DialPeerId(peer_id)).await.unwrap();
                     } else {
-                        warn!("usage: dial <multiaddr>");
+                        warn!("usage: dial_id <peer_id>");
                     }
-                } else if line.starts_with("dial_id") {
+                } else if line.starts_with("dial ") {
                     let parts: Vec<&str> = line.splitn(2, ' ').collect();
                     if parts.len() == 2 {
-                        let peer_id = parts[1];
-                        let peer_id = PeerId::from_str(peer_id).unwrap();
-                        info!("dialing peer id {}", peer_id);
-                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::DialPeerId(peer_id)).await.unwrap();
+                        let Ok(peer_id) = PeerId::from_str(parts[1]) else {
+                            warn!("invalid peer id: {}", parts[1]);
+                            continue;
+                        };
+                        // over a circuit of the first relay, dial_id uses the addresses known for the peer
+                        let addr = relay_address
+                            .clone()
+                            .with(Protocol::P2p(relay_peer_id))
+                            .with(Protocol::P2pCircuit)
+                            .with(Protocol::P2p(peer_id));
+                        info!("dialing {}", addr);
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::Dial(addr)).await.unwrap();
                     } else {
-                        warn!("usage: dial_id <peer_id>");
+                        warn!("usage: dial <peer_id>");
                     }
+                } else if line.starts_with("put-batch ") { // put-batch <doc> <json>
+                    let parts: Vec<&str> = line.splitn(3, ' ').collect();
+                    if parts.len() == 3 {
+                        let document_id = parts[1];
+                        match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(parts[2]) {
+                            Ok(values) => {
+                                swarm_command_tx.send(swarm_dispatch::SwarmCommand::PutBatch(document_id.to_string(), values)).await.unwrap();
+                            }
+                            Err(err) => {
+                                warn!("invalid json object: {}", err);
+                            }
+                        }
+                    } else {
+                        warn!("usage: put-batch <doc> <json>");
+                    }
+                } else if line.starts_with("exists ") { // exists <doc>
+                    let parts: Vec<&str> = line.splitn(2, ' ').collect();
+                    if parts.len() == 2 {
+                        let document_id = parts[1];
+                        info!("looking up document {} on the network", document_id);
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::DocumentExists(document_id.to_string())).await.unwrap();
+                    } else {
+                        warn!("usage: exists <doc>");
+                    }
+                } else if line.starts_with("json ") { // json <doc>
+                    let parts: Vec<&str> = line.splitn(2, ' ').collect();
+                    if parts.len() == 2 {
+                        let document_id = parts[1];
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::DocumentJson(document_id.to_string())).await.unwrap();
+                    } else {
+                        warn!("usage: json <doc>");
+                    }
+                } else if line == "share" {
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::ShareAddress(reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(addresses) = reply_rx.await else {
+                            return;
+                        };
+                        if addresses.circuit.is_empty() && addresses.direct.is_empty() {
+                            println!("no dialable addresses yet, waiting for a relay reservation");
+                        }
+                        // direct addresses skip the relay hop, so they're listed first
+                        for address in addresses.direct.iter().chain(addresses.circuit.iter()) {
+                            println!("{}", address);
+                        }
+                    });
+                } else if line.starts_with("peer-docs ") { // peer-docs <peer_id>
+                    let parts: Vec<&str> = line.splitn(2, ' ').collect();
+                    match PeerId::from_str(parts[1]) {
+                        Ok(peer_id) => {
+                            let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                            swarm_command_tx.send(swarm_dispatch::SwarmCommand::PeerDocuments(peer_id, reply_tx)).await.unwrap();
+                            tokio::spawn(async move {
+                                match reply_rx.await {
+                                    Ok(Ok(document_ids)) => info!("{} has documents: {:?}", peer_id, document_ids),
+                                    Ok(Err(err)) => warn!("could not list documents of {}: {}", peer_id, err),
+                                    Err(_) => {}
+                                }
+                            });
+                        }
+                        Err(err) => {
+                            warn!("invalid peer id: {}", err);
+                        }
+                    }
+                } else if line == "snapshot" || line.starts_with("snapshot ") { // snapshot [path]
+                    let path = line.strip_prefix("snapshot").map(str::trim).filter(|path| !path.is_empty()).map(PathBuf::from);
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::Snapshot(path, reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(Ok(path)) => println!("{}", path.display()),
+                            Ok(Err(err)) => warn!("failed to write snapshot: {}", err),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("restore ") { // restore <path>
+                    let path = PathBuf::from(line["restore ".len()..].trim());
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::RestoreSnapshot(path, reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(Ok(document_ids)) => info!("restored documents: {:?}", document_ids),
+                            Ok(Err(err)) => warn!("failed to restore snapshot: {}", err),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("export-node ") { // export-node <path> [--passphrase <passphrase>]
+                    let Some((path, passphrase, false)) = parse_bundle_args(&line["export-node ".len()..]) else {
+                        warn!("usage: export-node <path> [--passphrase <passphrase>]");
+                        continue;
+                    };
+                    // secrets kept in separate files travel inline, the bundle has to be enough on its own
+                    let mut config = peer_config.clone();
+                    config.identity.pre_shared_key = psk.clone();
+                    config.identity.pre_shared_key_file = None;
+                    config.identity.pre_shared_key_id = psk_id.clone();
+                    let config = match toml::to_string(&config) {
+                        Ok(config) => config,
+                        Err(err) => {
+                            warn!("failed to export node: {}", err);
+                            continue;
+                        }
+                    };
+                    let key_file_path = peer_config.identity.key_file_path.clone();
+                    let archive_path = peer_config.db_path.join("export-node.archive");
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::Snapshot(Some(archive_path), reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let archive_path = match reply_rx.await {
+                            Ok(Ok(archive_path)) => archive_path,
+                            Ok(Err(err)) => {
+                                warn!("failed to export documents: {}", err);
+                                return;
+                            }
+                            Err(_) => return,
+                        };
+                        let result = std::fs::read(&archive_path)
+                            .and_then(|documents| Ok((std::fs::read(&key_file_path)?, documents)))
+                            .map_err(anyhow::Error::from)
+                            .and_then(|(key_pem, documents)| {
+                                node_bundle::write(&path, &node_bundle::NodeBundle { key_pem, config, documents }, passphrase.as_deref())
+                            });
+                        let _ = std::fs::remove_file(&archive_path);
+                        match result {
+                            Ok(()) => info!("node exported to {}", path.display()),
+                            Err(err) => warn!("failed to export node: {:#}", err),
+                        }
+                    });
+                } else if line.starts_with("import-node ") { // import-node <path> [--passphrase <passphrase>] [--force]
+                    let Some((path, passphrase, force)) = parse_bundle_args(&line["import-node ".len()..]) else {
+                        warn!("usage: import-node <path> [--passphrase <passphrase>] [--force]");
+                        continue;
+                    };
+                    let bundle = match node_bundle::read(&path, passphrase.as_deref()) {
+                        Ok(bundle) => bundle,
+                        Err(err) => {
+                            warn!("failed to import node: {:#}", err);
+                            continue;
+                        }
+                    };
+                    let archive_path = peer_config.db_path.join("import-node.archive");
+                    let written = node_bundle::install(&bundle, &peer_config, &config_path, force)
+                        .and_then(|_| Ok(std::fs::write(&archive_path, &bundle.documents)?));
+                    if let Err(err) = written {
+                        warn!("failed to import node: {:#}", err);
+                        continue;
+                    }
+
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::RestoreSnapshot(archive_path.clone(), reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(Ok(document_ids)) => info!("imported node with documents {:?}, restart to run with its identity and config", document_ids),
+                            Ok(Err(err)) => warn!("failed to import documents: {}", err),
+                            Err(_) => {}
+                        }
+                        let _ = std::fs::remove_file(&archive_path);
+                    });
+                } else if line.starts_with("request ") { // request <peer_id> <data>
+                    let parts: Vec<&str> = line.splitn(3, ' ').collect();
+                    if parts.len() < 3 {
+                        warn!("usage: request <peer_id> <data>");
+                        continue;
+                    }
+                    match PeerId::from_str(parts[1]) {
+                        Ok(peer) => {
+                            let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                            swarm_command_tx.send(swarm_dispatch::SwarmCommand::SendRequest {
+                                peer,
+                                data: parts[2].as_bytes().to_vec(),
+                                reply: reply_tx,
+                            }).await.unwrap();
+                            tokio::spawn(async move {
+                                match reply_rx.await {
+                                    Ok(Ok(response)) => info!("{} responded: {}", peer, String::from_utf8_lossy(&response)),
+                                    Ok(Err(err)) => warn!("request to {} failed: {}", peer, err),
+                                    Err(_) => {}
+                                }
+                            });
+                        }
+                        Err(err) => {
+                            warn!("invalid peer id: {}", err);
+                        }
+                    }
+                } else if line.starts_with("fetch ") { // fetch <peer_id> <doc>
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    let [_, peer, document_id] = parts[..] else {
+                        warn!("usage: fetch <peer_id> <doc>");
+                        continue;
+                    };
+                    let Ok(peer) = PeerId::from_str(peer) else {
+                        warn!("invalid peer id: {}", peer);
+                        continue;
+                    };
+                    let document_id = document_id.to_string();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::FetchDocument {
+                        peer,
+                        document_id: document_id.clone(),
+                        reply: reply_tx,
+                    }).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(Ok(Some(document))) => println!("{}: {} bytes from {}", document_id, document.len(), peer),
+                            Ok(Ok(None)) => println!("{} doesn't have {}", peer, document_id),
+                            Ok(Err(err)) => warn!("fetching {} from {} failed: {}", document_id, peer, err),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("converged ") { // converged <peer_id> <doc>
+                    let parts: Vec<&str> = line.splitn(3, ' ').collect();
+                    if parts.len() < 3 {
+                        warn!("usage: converged <peer_id> <doc>");
+                        continue;
+                    }
+                    match PeerId::from_str(parts[1]) {
+                        Ok(peer_id) => {
+                            let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                            swarm_command_tx.send(swarm_dispatch::SwarmCommand::Converged(peer_id, parts[2].to_string(), reply_tx)).await.unwrap();
+                            tokio::spawn(async move {
+                                match reply_rx.await {
+                                    Ok(Ok(Convergence::Converged)) => println!("yes"),
+                                    Ok(Ok(Convergence::Divergent { local_only, remote_only })) => {
+                                        println!("no");
+                                        for head in local_only {
+                                            println!("  local only:  {}", head);
+                                        }
+                                        for head in remote_only {
+                                            println!("  remote only: {}", head);
+                                        }
+                                    }
+                                    Ok(Ok(Convergence::Missing { locally, remotely })) => {
+                                        println!("no, document missing (locally: {}, remotely: {})", locally, remotely);
+                                    }
+                                    Ok(Err(err)) => warn!("could not compare with {}: {}", peer_id, err),
+                                    Err(_) => {}
+                                }
+                            });
+                        }
+                        Err(err) => {
+                            warn!("invalid peer id: {}", err);
+                        }
+                    }
+                } else if line.starts_with("sub ") { // sub <topic>
+                    let topic = line["sub ".len()..].trim().to_string();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::Subscribe(topic)).await.unwrap();
+                } else if line.starts_with("unsub ") { // unsub <topic>
+                    let topic = line["unsub ".len()..].trim().to_string();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::Unsubscribe(topic)).await.unwrap();
+                } else if line.starts_with("pub ") { // pub <topic> <msg>
+                    let parts: Vec<&str> = line.splitn(3, ' ').collect();
+                    if parts.len() == 3 {
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::Publish {
+                            topic: parts[1].to_string(),
+                            data: parts[2].as_bytes().to_vec(),
+                        }).await.unwrap();
+                    } else {
+                        warn!("usage: pub <topic> <msg>");
+                    }
+                } else if line.starts_with("mesh ") { // mesh <topic>
+                    let topic = line["mesh ".len()..].trim().to_string();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::MeshPeers(gossipsub::IdentTopic::new(topic.clone()), reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(peers) = reply_rx.await else {
+                            return;
+                        };
+                        println!("{}: {} mesh peers, {} subscribed peers", topic, peers.mesh.len(), peers.subscribed.len());
+                        for peer_id in &peers.subscribed {
+                            let role = if peers.mesh.contains(peer_id) { "mesh" } else { "subscribed" };
+                            println!("  {} ({})", peer_id, role);
+                        }
+                    });
+                } else if line.starts_with("connectivity ") { // connectivity <peer_id>
+                    match PeerId::from_str(line["connectivity ".len()..].trim()) {
+                        Ok(peer_id) => {
+                            info!("testing connectivity to {}, this can take a while", peer_id);
+                            let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                            swarm_command_tx.send(swarm_dispatch::SwarmCommand::ConnectivityReport(peer_id, reply_tx)).await.unwrap();
+                            tokio::spawn(async move {
+                                let Ok(report) = reply_rx.await else {
+                                    return;
+                                };
+                                println!("connectivity to {}:", peer_id);
+                                println!("  direct:   {}", describe_path(&report.direct));
+                                println!("  relayed:  {}", describe_path(&report.relayed));
+                                println!("  upgraded: {}", describe_path(&report.upgraded));
+                            });
+                        }
+                        Err(err) => {
+                            warn!("invalid peer id: {}", err);
+                        }
+                    }
+                } else if line.starts_with("change-sizes ") { // change-sizes <doc>
+                    let document_id = line["change-sizes ".len()..].trim().to_string();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::ChangeSizes(document_id.clone(), reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(Some(sizes)) => print_change_sizes(&document_id, &sizes),
+                            Ok(None) => info!("Document '{}' not found", document_id),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("heads ") { // heads <doc>
+                    let document_id = line["heads ".len()..].trim().to_string();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::DocumentHeads(document_id.clone(), reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(Some(heads)) => {
+                                for head in heads {
+                                    println!("{}", head);
+                                }
+                            }
+                            Ok(None) => info!("Document '{}' not found", document_id),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("doc-peers ") { // doc-peers <doc>
+                    let document_id = line["doc-peers ".len()..].trim().to_string();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::DocumentPeers(document_id.clone(), reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(peers) = reply_rx.await else {
+                            return;
+                        };
+                        if peers.is_empty() {
+                            println!("nobody is working on {}", document_id);
+                        }
+                        for peer in peers {
+                            println!("{}", peer);
+                        }
+                    });
+                } else if line.starts_with("put_record ") { // put_record <key> <value>
+                    let parts: Vec<&str> = line.splitn(3, ' ').collect();
+                    if parts.len() == 3 {
+                        let key = kad::RecordKey::new(&parts[1]);
+                        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::PutRecord {
+                            key,
+                            value: parts[2].as_bytes().to_vec(),
+                            reply: reply_tx,
+                        }).await.unwrap();
+                        tokio::spawn(async move {
+                            match reply_rx.await {
+                                Ok(Ok(())) => info!("record stored"),
+                                Ok(Err(err)) => warn!("failed to store record: {}", err),
+                                Err(_) => {}
+                            }
+                        });
+                    } else {
+                        warn!("usage: put_record <key> <value>");
+                    }
+                } else if line.starts_with("get_record ") { // get_record <key>
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    if parts.len() == 2 {
+                        let key = kad::RecordKey::new(&parts[1]);
+                        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::GetRecord(key, reply_tx)).await.unwrap();
+                        tokio::spawn(async move {
+                            match reply_rx.await {
+                                Ok(Ok(Some(value))) => println!("{}", String::from_utf8_lossy(&value)),
+                                Ok(Ok(None)) => info!("record not found"),
+                                Ok(Err(err)) => warn!("failed to get record: {}", err),
+                                Err(_) => {}
+                            }
+                        });
+                    } else {
+                        warn!("usage: get_record <key>");
+                    }
+                } else if line.starts_with("label ") { // label <doc> [labels...]
+                    let mut parts = line.split_whitespace().skip(1);
+                    let Some(document_id) = parts.next() else {
+                        warn!("usage: label <doc> [labels...]");
+                        continue;
+                    };
+                    let document_id = document_id.to_string();
+                    let labels = parts.map(str::to_string).collect::<Vec<_>>();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::SetLabels(document_id.clone(), labels, reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(true) => info!("labels of {} updated", document_id),
+                            Ok(false) => warn!("no document {}", document_id),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("delete ") { // delete <doc>
+                    let Some(document_id) = line.split_whitespace().nth(1) else {
+                        warn!("usage: delete <doc>");
+                        continue;
+                    };
+                    let document_id = document_id.to_string();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::DeleteDocument(document_id.clone(), reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(true) => info!("deleted document {}", document_id),
+                            Ok(false) => warn!("no document {}", document_id),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("compact ") { // compact <doc>
+                    let Some(document_id) = line.split_whitespace().nth(1) else {
+                        warn!("usage: compact <doc>");
+                        continue;
+                    };
+                    let document_id = document_id.to_string();
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::CompactDocument { document_id: document_id.clone(), reply: reply_tx }).await.unwrap();
+                    tokio::spawn(async move {
+                        match reply_rx.await {
+                            Ok(true) => info!("compacting document {}", document_id),
+                            Ok(false) => warn!("no document {}", document_id),
+                            Err(_) => {}
+                        }
+                    });
+                } else if line.starts_with("doc-subscribe ") { // doc-subscribe <doc>
+                    let Some(document_id) = line.split_whitespace().nth(1) else {
+                        warn!("usage: doc-subscribe <doc>");
+                        continue;
+                    };
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::SubscribeDocument(document_id.to_string())).await.unwrap();
+                } else if line.starts_with("doc-unsubscribe ") { // doc-unsubscribe <doc>
+                    let Some(document_id) = line.split_whitespace().nth(1) else {
+                        warn!("usage: doc-unsubscribe <doc>");
+                        continue;
+                    };
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::UnsubscribeDocument(document_id.to_string())).await.unwrap();
+                } else if line == "docs" || line.starts_with("docs ") { // docs [--label <label>]
+                    let label = match line["docs".len()..].split_whitespace().collect::<Vec<_>>()[..] {
+                        [] => None,
+                        ["--label", label] => Some(label.to_string()),
+                        _ => {
+                            warn!("usage: docs [--label <label>]");
+                            continue;
+                        }
+                    };
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::ListDocuments(label, reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(documents) = reply_rx.await else {
+                            return;
+                        };
+                        if documents.is_empty() {
+                            println!("no documents");
+                        }
+                        for (document_id, labels) in documents {
+                            if labels.is_empty() {
+                                println!("{}", document_id);
+                            } else {
+                                println!("{} [{}]", document_id, labels.join(", "));
+                            }
+                        }
+                    });
+                } else if line == "gc-docs" || line.starts_with("gc-docs ") { // gc-docs [--dry-run]
+                    let dry_run = match line["gc-docs".len()..].trim() {
+                        "" => false,
+                        "--dry-run" => true,
+                        _ => {
+                            warn!("usage: gc-docs [--dry-run]");
+                            continue;
+                        }
+                    };
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::CollectGarbage {
+                        ttl: peer_config.document_gc_ttl(),
+                        dry_run,
+                        reply: reply_tx,
+                    }).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(collected) = reply_rx.await else {
+                            return;
+                        };
+                        let verb = if dry_run { "would collect" } else { "collected" };
+                        if collected.is_empty() {
+                            println!("nothing to collect");
+                        }
+                        for document_id in collected {
+                            println!("{} {}", verb, document_id);
+                        }
+                    });
+                } else if line == "reservation-limits" {
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::ReservationLimits(reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(limits) = reply_rx.await else {
+                            return;
+                        };
+                        if limits.is_empty() {
+                            println!("no active reservations");
+                        }
+                        for (relay_peer_id, limits) in limits {
+                            let duration = limits.duration.map_or("unlimited".to_string(), |duration| format!("{}s", duration.as_secs()));
+                            let data = limits.data_in_bytes.map_or("unlimited".to_string(), |bytes| format!("{} bytes", bytes));
+                            println!(
+                                "{}: circuits last {}, carry {}, accepted {}s ago",
+                                relay_peer_id,
+                                duration,
+                                data,
+                                limits.accepted_at.elapsed().as_secs()
+                            );
+                        }
+                    });
+                } else if line == "reservations" {
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::ListReservations(reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(reservations) = reply_rx.await else {
+                            return;
+                        };
+                        for (relay_peer_id, remaining) in reservations {
+                            match remaining {
+                                Some(remaining) => println!("{}: expires in about {}s", relay_peer_id, remaining.as_secs()),
+                                None => println!("{}: no reservation", relay_peer_id),
+                            }
+                        }
+                    });
+                } else if line.starts_with("renew-reservation") { // renew-reservation <relay_peer_id>
+                    let Some(Ok(relay_peer_id)) = line.split_whitespace().nth(1).map(PeerId::from_str) else {
+                        warn!("usage: renew-reservation <relay_peer_id>");
+                        continue;
+                    };
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::RenewReservation(relay_peer_id, reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        if let Ok(Err(err)) = reply_rx.await {
+                            warn!("Failed to renew the reservation: {}", err);
+                        }
+                    });
+                } else if line.starts_with("peer-info") { // peer-info <peer_id>
+                    let Some(Ok(peer_id)) = line.split_whitespace().nth(1).map(PeerId::from_str) else {
+                        warn!("usage: peer-info <peer_id>");
+                        continue;
+                    };
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::GetPeerInfo(peer_id, reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(info) = reply_rx.await else {
+                            return;
+                        };
+                        let Some(info) = info else {
+                            println!("{} hasn't identified itself", peer_id);
+                            return;
+                        };
+                        println!(
+                            "{}: {} ({}), received {}s ago{}",
+                            peer_id,
+                            info.agent_version,
+                            info.protocol_version,
+                            info.age.as_secs(),
+                            if info.stale { ", stale" } else { "" }
+                        );
+                        println!("  observes us on {}", info.observed_addr);
+                        for address in &info.listen_addrs {
+                            println!("  listens on {}", address);
+                        }
+                        for protocol in &info.protocols {
+                            println!("  supports {}", protocol);
+                        }
+                    });
+                } else if line.starts_with("rekey ") { // rekey <pre-shared key> [--id <key id>] [--grace <secs>] [--reconnect]
+                    let mut words = line.split_whitespace().skip(1);
+                    let key = words.next().unwrap_or_default().to_string();
+                    let mut grace = (!key.is_empty()).then(|| Duration::from_secs(peer_config.identity.pre_shared_key_grace_secs));
+                    let mut key_id = None;
+                    let mut reconnect = false;
+                    while let Some(word) = words.next() {
+                        match word {
+                            "--reconnect" => reconnect = true,
+                            "--id" => match words.next().filter(|id| local_config::valid_key_id(id)) {
+                                Some(id) => key_id = Some(id.to_string()),
+                                None => grace = None,
+                            },
+                            "--grace" => grace = words.next().and_then(|secs| secs.parse().ok()).map(Duration::from_secs),
+                            _ => grace = None,
+                        }
+                    }
+                    if let Some(grace) = grace {
+                        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                        swarm_command_tx.send(swarm_dispatch::SwarmCommand::RotatePreSharedKey {
+                            key: key.clone(),
+                            key_id: key_id.clone(),
+                            grace,
+                            reply: reply_tx,
+                        }).await.unwrap();
+                        match reply_rx.await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => {
+                                warn!("failed to change pre-shared key: {}", err);
+                                continue;
+                            }
+                            Err(_) => continue,
+                        }
+                        psk = key;
+                        info!("pre-shared key changed, new connections use fingerprint {}", prologue_fingerprint(&psk));
+                        if !grace.is_zero() {
+                            if psk_id.is_some() {
+                                info!("peers on the previous key can still connect for {}s", grace.as_secs());
+                            } else {
+                                warn!("the previous key had no id, peers still on it can't connect anymore");
+                            }
+                        }
+                        psk_id = key_id;
+                        warn!("update the pre-shared key and its id in the config too, restarts load them from there");
+                        if reconnect {
+                            let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                            swarm_command_tx.send(swarm_dispatch::SwarmCommand::Reconnect(reply_tx)).await.unwrap();
+                            tokio::spawn(async move {
+                                if let Ok(count) = reply_rx.await {
+                                    info!("reconnecting {} peers with the new pre-shared key", count);
+                                }
+                            });
+                        }
+                    } else {
+                        warn!("usage: rekey <pre-shared key> [--id <key id>] [--grace <secs>] [--reconnect]");
+                    }
+                } else if line == "status" {
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::Status(reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        if let Ok(status) = reply_rx.await {
+                            print_status(&status);
+                        }
+                    });
+                } else if line == "psk-fingerprint" {
+                    println!("{}", prologue_fingerprint(&psk));
+                } else if line == "stats" {
+                    println!("dropped events: {}", dropped_events.load(Ordering::Relaxed));
+                } else if line == "metrics" || line == "metrics --json" {
+                    let json = line.ends_with("--json");
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::Metrics(reply_tx)).await.unwrap();
+                    let dropped_events = dropped_events.clone();
+                    tokio::spawn(async move {
+                        let Ok(mut snapshot) = reply_rx.await else {
+                            return;
+                        };
+                        snapshot.dropped_events = dropped_events.load(Ordering::Relaxed);
+                        if json {
+                            println!("{}", serde_json::to_string_pretty(&snapshot).unwrap());
+                        } else {
+                            print_metrics(&snapshot);
+                        }
+                    });
                 } else if line.starts_with("connections") {
-                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::ListConnections).await.unwrap();
-                } else {
-                    warn!("unknown command: {}", line);
+                    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                    swarm_command_tx.send(swarm_dispatch::SwarmCommand::ListConnections(reply_tx)).await.unwrap();
+                    tokio::spawn(async move {
+                        let Ok(peers) = reply_rx.await else {
+                            return;
+                        };
+                        if peers.is_empty() {
+                            println!("no active connections");
+                        }
+                        for peer in peers {
+                            println!("{}", peer);
+                        }
+                    });
+                } else if line.starts_with("disconnect ") { // disconnect <peer_id>
+                    let parts: Vec<&str> = line.splitn(2, ' ').collect();
+                    match PeerId::from_str(parts[1].trim()) {
+                        Ok(peer_id) => swarm_command_tx.send(swarm_dispatch::SwarmCommand::Disconnect(peer_id)).await.unwrap(),
+                        Err(err) => warn!("invalid peer id: {}", err),
+                    }
+                } else if line == "help" {
+                    println!("{}", commands::help());
+                } else if !line.is_empty() {
+                    match commands::suggest(line) {
+                        Some(usage) => warn!("unknown command: {}, did you mean: {}", line, usage),
+                        None => warn!("unknown command: {}, type help for the list of commands", line),
+                    }
+                    if opts.strict_commands {
+                        failure = Some(format!("unknown command: {line}"));
+                        break;
+                    }
                 }
             },
             _ = &mut ctrl_c_signal => {
@@ -285,16 +1121,124 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    Ok(())
+    // give the relays a chance to free our reservation slots instead of waiting for them to
+    // expire, and the swarm a chance to leave the network cleanly
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    if swarm_command_tx
+        .send(swarm_dispatch::SwarmCommand::Shutdown(reply_tx))
+        .await
+        .is_ok()
+    {
+        let _ = reply_rx.await;
+    }
+
+    match failure {
+        Some(failure) => Err(failure.into()),
+        None => Ok(()),
+    }
 }
 
-fn generate_ed25519() -> identity::Keypair {
-    identity::Keypair::generate_ed25519()
+/// Path, passphrase and `--force` flag of the node bundle commands
+fn parse_bundle_args(args: &str) -> Option<(PathBuf, Option<String>, bool)> {
+    let mut words = args.split_whitespace();
+    let path = PathBuf::from(words.next()?);
+    let mut passphrase = None;
+    let mut force = false;
+    while let Some(word) = words.next() {
+        match word {
+            "--passphrase" => passphrase = Some(words.next()?.to_string()),
+            "--force" => force = true,
+            _ => return None,
+        }
+    }
+    Some((path, passphrase, force))
 }
 
-fn generate_ed25519_from_seed(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
+fn print_metrics(snapshot: &metrics::MetricsSnapshot) {
+    let rows = [
+        ("connected peers", snapshot.connected_peers as u64),
+        ("open connections", snapshot.open_connections as u64),
+        ("connections established", snapshot.connections_established),
+        ("connections closed", snapshot.connections_closed),
+        ("dial failures", snapshot.dial_failures),
+        ("gossip messages", snapshot.gossip_messages),
+        ("gossip bytes", snapshot.gossip_bytes),
+        ("bytes received", snapshot.bytes_received),
+        ("bytes sent", snapshot.bytes_sent),
+        ("syncs started", snapshot.syncs_started),
+        ("syncs finished", snapshot.syncs_finished),
+        ("sync errors", snapshot.sync_errors),
+        ("dropped events", snapshot.dropped_events),
+    ];
+    for (name, value) in rows {
+        println!("{:<24} {:>10}", name, value);
+    }
+    for (peer, rtt) in &snapshot.ping_rtts_ms {
+        println!("ping {} {:>6} ms", peer, rtt);
+    }
+}
 
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+fn print_status(status: &swarm_dispatch::NodeStatus) {
+    println!("peer id: {}", status.peer_id);
+    for address in &status.listen_addrs {
+        println!("listening on {}", address);
+    }
+    for address in &status.addresses.direct {
+        println!("external address {}", address);
+    }
+    for address in &status.addresses.circuit {
+        println!("relay circuit {}", address);
+    }
+    println!(
+        "{} connections to {} peers",
+        status.connections, status.connected_peers
+    );
+}
+
+/// Prints the number of changes and their total size, bucketed by powers of two
+fn print_change_sizes(document_id: &str, sizes: &[usize]) {
+    let total = sizes.iter().sum::<usize>();
+    println!(
+        "{}: {} changes, {} bytes total",
+        document_id,
+        sizes.len(),
+        total
+    );
+
+    let mut buckets = std::collections::BTreeMap::<u32, usize>::new();
+    for size in sizes {
+        *buckets.entry(size.max(&1).ilog2()).or_default() += 1;
+    }
+    let widest = buckets.values().copied().max().unwrap_or_default();
+    for (exponent, count) in buckets {
+        let bar = "#".repeat((count * 40).div_ceil(widest));
+        println!(
+            "  {:>8} - {:<8} bytes {:>6} {}",
+            1usize << exponent,
+            (1usize << (exponent + 1)) - 1,
+            count,
+            bar
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prologue_fingerprint_hashes_the_prologue() {
+        assert_eq!(
+            hex(&string_to_32_bytes("secret")),
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+        assert_eq!(
+            prologue_fingerprint("secret"),
+            "3881219d087dd9c634373fd33dfa33a2cb6bfc6c520b64b8bb60ef2ceb534ae7"
+        );
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
 }
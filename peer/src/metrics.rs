@@ -0,0 +1,208 @@
+use std::{collections::HashMap, time::Duration};
+
+use libp2p::{PeerId, gossipsub, metrics::Registry, ping, swarm::SwarmEvent};
+use prometheus_client::encoding::text::encode;
+use serde::Serialize;
+
+use crate::behaviour::BehaviourEvent;
+
+/// Counters of the node's activity since startup
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections_established: u64,
+    connections_closed: u64,
+    dial_failures: u64,
+    gossip_messages: u64,
+    gossip_bytes: u64,
+    syncs_started: u64,
+    syncs_finished: u64,
+    sync_errors: u64,
+    /// Last ping round trip to each connected peer
+    ping_rtts: HashMap<PeerId, Duration>,
+    /// Holds the bandwidth counters of the transport, see [`Metrics::with_registry`]
+    registry: Registry,
+}
+
+/// Point in time copy of the metrics, with the gauges read from the swarm
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub connected_peers: usize,
+    pub open_connections: u32,
+    pub connections_established: u64,
+    pub connections_closed: u64,
+    pub dial_failures: u64,
+    pub gossip_messages: u64,
+    pub gossip_bytes: u64,
+    /// Bytes received over every transport
+    pub bytes_received: u64,
+    /// Bytes sent over every transport
+    pub bytes_sent: u64,
+    pub syncs_started: u64,
+    pub syncs_finished: u64,
+    pub sync_errors: u64,
+    /// Round trips in milliseconds, by peer id
+    pub ping_rtts_ms: Vec<(String, u128)>,
+    /// Node events the database manager fell behind on, counted outside the swarm manager
+    pub dropped_events: u64,
+}
+
+impl Metrics {
+    /// Counters reading the bandwidth from the registry the swarm recorded it in with
+    /// `with_bandwidth_metrics`
+    pub fn with_registry(registry: Registry) -> Self {
+        Metrics {
+            registry,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, event: &SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::ConnectionEstablished { .. } => self.connections_established += 1,
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                ..
+            } => {
+                self.connections_closed += 1;
+                if *num_established == 0 {
+                    self.ping_rtts.remove(peer_id);
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { .. } => self.dial_failures += 1,
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                message,
+                ..
+            })) => {
+                self.gossip_messages += 1;
+                self.gossip_bytes += message.data.len() as u64;
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Ok(rtt),
+                ..
+            })) => {
+                self.ping_rtts.insert(*peer, *rtt);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(event)) => match event {
+                libp2p_automerge::Event::SyncStarted { .. } => self.syncs_started += 1,
+                libp2p_automerge::Event::SyncFinished { .. } => self.syncs_finished += 1,
+                libp2p_automerge::Event::SyncError { .. } => self.sync_errors += 1,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self, connected_peers: usize, open_connections: u32) -> MetricsSnapshot {
+        let mut ping_rtts_ms = self
+            .ping_rtts
+            .iter()
+            .map(|(peer, rtt)| (peer.to_string(), rtt.as_millis()))
+            .collect::<Vec<_>>();
+        ping_rtts_ms.sort();
+        let (bytes_received, bytes_sent) = bandwidth(&self.registry);
+
+        MetricsSnapshot {
+            connected_peers,
+            open_connections,
+            connections_established: self.connections_established,
+            connections_closed: self.connections_closed,
+            dial_failures: self.dial_failures,
+            gossip_messages: self.gossip_messages,
+            gossip_bytes: self.gossip_bytes,
+            bytes_received,
+            bytes_sent,
+            syncs_started: self.syncs_started,
+            syncs_finished: self.syncs_finished,
+            sync_errors: self.sync_errors,
+            ping_rtts_ms,
+            dropped_events: 0,
+        }
+    }
+}
+
+/// Bytes received and sent, summed over the transports `libp2p-metrics` counts them by. Its
+/// counters aren't public, so they're read from the encoded registry.
+fn bandwidth(registry: &Registry) -> (u64, u64) {
+    let mut encoded = String::new();
+    if let Err(err) = encode(&mut encoded, registry) {
+        tracing::debug!("Failed to encode the metrics registry: {err}");
+        return (0, 0);
+    }
+
+    let (mut received, mut sent) = (0, 0);
+    for line in encoded
+        .lines()
+        .filter(|line| line.starts_with("libp2p_bandwidth_bytes_total{"))
+    {
+        let Some(bytes) = line
+            .rsplit(' ')
+            .next()
+            .and_then(|value| value.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if line.contains("direction=\"Inbound\"") {
+            received += bytes;
+        } else if line.contains("direction=\"Outbound\"") {
+            sent += bytes;
+        }
+    }
+    (received, sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family},
+        registry::Unit,
+    };
+
+    use super::*;
+
+    /// Same labels as the bandwidth counters of `libp2p-metrics`
+    #[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+    struct Labels {
+        protocols: String,
+        direction: Direction,
+    }
+
+    #[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+    enum Direction {
+        Inbound,
+        Outbound,
+    }
+
+    #[test]
+    fn snapshot_sums_bandwidth_over_transports() {
+        let mut registry = Registry::default();
+        let family = Family::<Labels, Counter>::default();
+        registry
+            .sub_registry_with_prefix("libp2p")
+            .register_with_unit(
+                "bandwidth",
+                "Bandwidth usage by direction and transport protocols",
+                Unit::Bytes,
+                family.clone(),
+            );
+        for (protocols, received, sent) in [("/ip4/tcp", 100, 40), ("/ip4/udp/quic-v1", 20, 2)] {
+            let labels = |direction| Labels {
+                protocols: protocols.to_string(),
+                direction,
+            };
+            family
+                .get_or_create(&labels(Direction::Inbound))
+                .inc_by(received);
+            family
+                .get_or_create(&labels(Direction::Outbound))
+                .inc_by(sent);
+        }
+
+        let snapshot = Metrics::with_registry(registry).snapshot(1, 2);
+
+        assert_eq!(snapshot.bytes_received, 120);
+        assert_eq!(snapshot.bytes_sent, 42);
+    }
+}
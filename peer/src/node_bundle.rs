@@ -0,0 +1,240 @@
+//! Portable bundle of a node's identity, config and documents, for moving a node to another
+//! machine.
+//!
+//! The bundle starts with [`MAGIC`] and a flag byte, 1 if the rest is encrypted. An encrypted
+//! bundle continues with the salt and nonce, followed by the payload sealed with
+//! ChaCha20-Poly1305 under a key derived from the passphrase with PBKDF2. The payload holds the
+//! key file, the config and the document archive, each as a big endian u64 length followed by
+//! the bytes.
+
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow, bail};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, KeyInit},
+};
+use rand::{RngCore, rngs::OsRng};
+use sha2::Sha256;
+
+use crate::local_config::{AppConfig, write_key_file};
+
+const MAGIC: &[u8; 8] = b"P2PNODE1";
+const PBKDF2_ROUNDS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+pub struct NodeBundle {
+    /// Contents of the identity key file
+    pub key_pem: Vec<u8>,
+    /// The config as TOML, secrets included
+    pub config: String,
+    /// Every document, as written by the `snapshot` command
+    pub documents: Vec<u8>,
+}
+
+/// Writes the bundle readable by the owner only, it holds the node's private key
+pub fn write(path: &Path, bundle: &NodeBundle, passphrase: Option<&str>) -> Result<()> {
+    let mut payload = Vec::new();
+    for part in [
+        bundle.key_pem.as_slice(),
+        bundle.config.as_bytes(),
+        bundle.documents.as_slice(),
+    ] {
+        payload.extend_from_slice(&(part.len() as u64).to_be_bytes());
+        payload.extend_from_slice(part);
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    match passphrase {
+        Some(passphrase) => {
+            let mut salt = [0u8; SALT_LEN];
+            let mut nonce = [0u8; NONCE_LEN];
+            OsRng.fill_bytes(&mut salt);
+            OsRng.fill_bytes(&mut nonce);
+            let sealed = cipher(passphrase, &salt)
+                .encrypt(Nonce::from_slice(&nonce), payload.as_slice())
+                .map_err(|_| anyhow!("Failed to encrypt the bundle"))?;
+            bytes.push(1);
+            bytes.extend_from_slice(&salt);
+            bytes.extend_from_slice(&nonce);
+            bytes.extend_from_slice(&sealed);
+        }
+        None => {
+            bytes.push(0);
+            bytes.extend_from_slice(&payload);
+        }
+    }
+
+    write_key_file(path, &bytes).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+pub fn read(path: &Path, passphrase: Option<&str>) -> Result<NodeBundle> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        bail!("{} is not a node bundle", path.display());
+    };
+
+    let payload = match (rest.split_first(), passphrase) {
+        (Some((0, payload)), _) => payload.to_vec(),
+        (Some((1, _)), None) => bail!("The bundle is encrypted, a passphrase is required"),
+        (Some((1, sealed)), Some(passphrase)) => {
+            if sealed.len() < SALT_LEN + NONCE_LEN {
+                bail!("The bundle is truncated");
+            }
+            let (salt, sealed) = sealed.split_at(SALT_LEN);
+            let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+            cipher(passphrase, salt)
+                .decrypt(Nonce::from_slice(nonce), sealed)
+                .map_err(|_| anyhow!("Wrong passphrase or corrupted bundle"))?
+        }
+        _ => bail!("Unknown bundle format"),
+    };
+
+    let mut rest = payload.as_slice();
+    let mut parts = Vec::with_capacity(3);
+    for _ in 0..3 {
+        let (length, tail) = rest
+            .split_first_chunk::<8>()
+            .ok_or_else(|| anyhow!("The bundle is truncated"))?;
+        let length = usize::try_from(u64::from_be_bytes(*length))?;
+        if tail.len() < length {
+            bail!("The bundle is truncated");
+        }
+        let (part, tail) = tail.split_at(length);
+        parts.push(part.to_vec());
+        rest = tail;
+    }
+
+    let [key_pem, config, documents] = <[Vec<u8>; 3]>::try_from(parts).unwrap();
+    Ok(NodeBundle {
+        key_pem,
+        config: String::from_utf8(config).context("The bundled config is not valid UTF-8")?,
+        documents,
+    })
+}
+
+/// Replaces the node's identity and config with the bundled ones, returning the installed
+/// config. The bundle comes from another machine, so the local key file, database and record
+/// store paths are kept. Refuses to replace a different identity unless `force` is set.
+pub fn install(
+    bundle: &NodeBundle,
+    local: &AppConfig,
+    config_path: &str,
+    force: bool,
+) -> Result<AppConfig> {
+    let mut config =
+        toml::from_str::<AppConfig>(&bundle.config).context("The bundled config is invalid")?;
+    let key_file_path = &local.identity.key_file_path;
+    let same_identity = std::fs::read(key_file_path).is_ok_and(|key_pem| key_pem == bundle.key_pem);
+    if key_file_path.exists() && !same_identity && !force {
+        bail!(
+            "Refusing to overwrite the identity at {}, use --force to replace it",
+            key_file_path.display()
+        );
+    }
+
+    config.identity.key_file_path = key_file_path.clone();
+    config.db_path = local.db_path.clone();
+    config.kademlia.record_store_dir = local.kademlia.record_store_dir.clone();
+    config.validate()?;
+    if let Some(parent) = key_file_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_key_file(key_file_path, &bundle.key_pem)
+        .with_context(|| format!("Failed to write {}", key_file_path.display()))?;
+    config.save_to_file(config_path)?;
+    Ok(config)
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::transaction::Transactable;
+    use libp2p::PeerId;
+
+    use super::*;
+    use crate::local_config::{IdentityConfig, RelayConfig};
+
+    /// A node keeping its key, config and documents in the directory
+    fn node_config(dir: &Path) -> AppConfig {
+        AppConfig {
+            relays: vec![RelayConfig {
+                address: "/ip4/10.0.0.1/udp/4001/quic-v1".parse().unwrap(),
+                peer_id: PeerId::random(),
+            }],
+            identity: IdentityConfig {
+                key_file_path: dir.join("key.pem"),
+                pre_shared_key: "secret".to_string(),
+                ..Default::default()
+            },
+            db_path: dir.join("data"),
+            documents: vec!["doc".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exported_node_is_reproduced_on_a_fresh_data_dir() {
+        let dir = std::env::temp_dir().join(format!("peer-node-bundle-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (source_dir, target_dir) = (dir.join("source"), dir.join("target"));
+
+        let source = node_config(&source_dir);
+        let identity = source.load_keypair().unwrap();
+        let mut automerge = libp2p_automerge::Behaviour::new(crate::automerge_config(&source));
+        automerge.modify_document("doc", |doc| {
+            doc.put(automerge::ROOT, "key", 1).unwrap();
+        });
+        let archive_path = source_dir.join("documents.archive");
+        automerge.export_archive(&archive_path).unwrap();
+        let bundle_path = dir.join("node.bundle");
+        let bundle = NodeBundle {
+            key_pem: std::fs::read(&source.identity.key_file_path).unwrap(),
+            config: toml::to_string(&source).unwrap(),
+            documents: std::fs::read(&archive_path).unwrap(),
+        };
+        write(&bundle_path, &bundle, Some("passphrase")).unwrap();
+
+        assert!(read(&bundle_path, None).is_err());
+        assert!(read(&bundle_path, Some("wrong")).is_err());
+        let bundle = read(&bundle_path, Some("passphrase")).unwrap();
+        let target = node_config(&target_dir);
+        let config_path = target_dir.join("Config.toml");
+        let installed = install(&bundle, &target, config_path.to_str().unwrap(), false).unwrap();
+
+        assert_eq!(
+            installed.load_keypair().unwrap().public(),
+            identity.public()
+        );
+        assert_eq!(installed.db_path, target.db_path);
+        let saved = AppConfig::load(Some(config_path.to_str().unwrap().to_string())).unwrap();
+        assert_eq!(saved.relays[0].peer_id, source.relays[0].peer_id);
+        assert_eq!(
+            saved.identity.pre_shared_key,
+            source.identity.pre_shared_key
+        );
+        let archive_path = target_dir.join("documents.archive");
+        std::fs::write(&archive_path, &bundle.documents).unwrap();
+        let mut automerge = libp2p_automerge::Behaviour::new(crate::automerge_config(&installed));
+        automerge.import_archive(&archive_path).unwrap();
+        assert_eq!(
+            automerge.document_to_json("doc"),
+            Some(serde_json::json!({"key": 1}))
+        );
+
+        // a node with an identity of its own keeps it unless forced
+        let other = node_config(&dir.join("other"));
+        other.load_keypair().unwrap();
+        assert!(install(&bundle, &other, config_path.to_str().unwrap(), false).is_err());
+        assert!(install(&bundle, &other, config_path.to_str().unwrap(), true).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
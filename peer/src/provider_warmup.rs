@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+
+use libp2p::{PeerId, kad};
+
+use crate::local_config::ProviderReadiness;
+
+/// Holds back announcing as provider of a key until we have the document behind it, so peers
+/// never find us while we'd only serve an empty copy
+pub struct ProviderWarmup {
+    pub key: kad::RecordKey,
+    pub document_id: String,
+    readiness: ProviderReadiness,
+    provider_query: Option<kad::QueryId>,
+    lookup_finished: bool,
+    /// Other peers already providing the key
+    providers: HashSet<PeerId>,
+    /// Provider whose heads matched ours
+    converged_with: Option<PeerId>,
+}
+
+impl ProviderWarmup {
+    pub fn new(key: kad::RecordKey, document_id: String, readiness: ProviderReadiness) -> Self {
+        ProviderWarmup {
+            key,
+            document_id,
+            readiness,
+            provider_query: None,
+            lookup_finished: false,
+            providers: HashSet::new(),
+            converged_with: None,
+        }
+    }
+
+    /// Whether we may announce, given whether the document is present locally. Without any other
+    /// provider there is nobody to converge with, so the document being present is enough.
+    pub fn is_ready(&self, document_present: bool) -> bool {
+        if !document_present {
+            return false;
+        }
+
+        match self.readiness {
+            ProviderReadiness::Present => true,
+            ProviderReadiness::Converged => {
+                self.converged_with.is_some() || (self.lookup_finished && self.providers.is_empty())
+            }
+        }
+    }
+
+    /// Whether the existing providers still have to be looked up
+    pub fn needs_lookup(&self) -> bool {
+        self.provider_query.is_none()
+    }
+
+    pub fn start_lookup(&mut self, query_id: kad::QueryId) {
+        self.provider_query = Some(query_id);
+        self.lookup_finished = false;
+    }
+
+    /// Forgets the finished lookup, so the next attempt looks the providers up again
+    pub fn restart_lookup(&mut self) {
+        self.provider_query = None;
+        self.lookup_finished = false;
+        self.providers.clear();
+    }
+
+    pub fn is_lookup(&self, query_id: &kad::QueryId) -> bool {
+        self.provider_query.as_ref() == Some(query_id)
+    }
+
+    pub fn lookup_finished(&self) -> bool {
+        self.lookup_finished
+    }
+
+    pub fn record_providers(&mut self, providers: impl IntoIterator<Item = PeerId>) {
+        self.providers.extend(providers);
+    }
+
+    pub fn finish_lookup(&mut self) {
+        self.lookup_finished = true;
+    }
+
+    pub fn providers(&self) -> impl Iterator<Item = &PeerId> {
+        self.providers.iter()
+    }
+
+    pub fn is_provider(&self, peer: &PeerId) -> bool {
+        self.providers.contains(peer)
+    }
+
+    pub fn record_converged(&mut self, peer: PeerId) {
+        self.converged_with = Some(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warmup(readiness: ProviderReadiness) -> ProviderWarmup {
+        ProviderWarmup::new(kad::RecordKey::new(&"db"), "db".to_string(), readiness)
+    }
+
+    #[test]
+    fn announcement_waits_for_the_document() {
+        let present = warmup(ProviderReadiness::Present);
+
+        assert!(!present.is_ready(false));
+        assert!(present.is_ready(true));
+    }
+
+    #[test]
+    fn announcement_waits_for_convergence_with_an_existing_provider() {
+        let provider = PeerId::random();
+        let mut converged = warmup(ProviderReadiness::Converged);
+        converged.record_providers([provider]);
+        converged.finish_lookup();
+
+        assert!(!converged.is_ready(true));
+        converged.record_converged(provider);
+        assert!(converged.is_ready(true));
+        assert!(!converged.is_ready(false));
+    }
+
+    #[test]
+    fn first_provider_only_waits_for_the_lookup() {
+        let mut first = warmup(ProviderReadiness::Converged);
+
+        assert!(!first.is_ready(true));
+        first.finish_lookup();
+        assert!(first.is_ready(true));
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+
+use libp2p::{Multiaddr, multiaddr::Protocol};
+
+/// Failed AutoNAT tests, without any address confirmed, after which we consider ourselves
+/// unreachable. Behind a symmetric NAT every peer observes a different port, so the failures are
+/// spread over many candidate addresses instead of repeating one.
+const FAILURES_BEFORE_PRIVATE: u32 = 3;
+
+/// What AutoNAT found out about our addresses so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatStatus {
+    Unknown,
+    /// At least one address was dialed back by an AutoNAT server
+    Public,
+    /// Every tested address failed, peers can only reach us through the relay
+    Private,
+}
+
+/// Tracks AutoNAT v2 results, so only addresses a server dialed back are advertised
+pub struct Reachability {
+    confirmed: HashSet<Multiaddr>,
+    failures: u32,
+    status: NatStatus,
+}
+
+impl Reachability {
+    pub fn new() -> Self {
+        Reachability {
+            confirmed: HashSet::new(),
+            failures: 0,
+            status: NatStatus::Unknown,
+        }
+    }
+
+    pub fn status(&self) -> NatStatus {
+        self.status
+    }
+
+    pub fn is_confirmed(&self, address: &Multiaddr) -> bool {
+        self.confirmed.contains(address)
+    }
+
+    /// Records the outcome of a test, returning the new status if it changed
+    pub fn record(&mut self, address: &Multiaddr, success: bool) -> Option<NatStatus> {
+        if success {
+            self.confirmed.insert(address.clone());
+            self.failures = 0;
+        } else {
+            self.confirmed.remove(address);
+            self.failures += 1;
+        }
+
+        let status = if !self.confirmed.is_empty() {
+            NatStatus::Public
+        } else if self.failures >= FAILURES_BEFORE_PRIVATE {
+            NatStatus::Private
+        } else {
+            self.status
+        };
+        (status != self.status).then(|| {
+            self.status = status;
+            status
+        })
+    }
+}
+
+/// Whether the address goes through a relay, which the relay itself vouches for
+pub fn is_relayed(address: &Multiaddr) -> bool {
+    address
+        .iter()
+        .any(|protocol| protocol == Protocol::P2pCircuit)
+}
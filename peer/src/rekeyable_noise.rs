@@ -0,0 +1,292 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    core::upgrade::{InboundConnectionUpgrade, OutboundConnectionUpgrade, UpgradeInfo},
+    identity, noise,
+};
+
+/// Protocol name every node speaks, always with its current prologue
+const NOISE_PROTOCOL: &str = "/noise";
+
+/// Noise upgrade whose prologue can be swapped while the swarm runs. Every handshake uses the
+/// prologue current at its start, so a new pre-shared key applies to new connections while
+/// established ones keep theirs.
+///
+/// The current prologue is offered under `/noise` and, if the key has an id, under
+/// `/noise/psk/<id>`. Protocol names go over the wire in cleartext, so the id is a label
+/// configured next to the key and nothing derived from the key itself. A rotation can keep the
+/// previous prologue under its id for a grace period, so peers still on the old key can connect
+/// while the new one is rolled out. Dials try the ids first and fall back to `/noise`, so two
+/// peers agree on the key they share, whichever of them rotated. A key without an id is only
+/// offered under `/noise`, so rotating away from it leaves no grace period.
+///
+/// The prologue only keeps peers without the key out of TCP connections. QUIC authenticates
+/// through TLS and relayed connections use the plain noise config of the relay client, so
+/// neither checks the pre-shared key. Over TCP, a peer whose key matches no offered prologue
+/// either finds no common protocol or fails the handshake.
+#[derive(Clone)]
+pub struct RekeyableNoise {
+    keys: Arc<RwLock<Keys>>,
+    keypair: identity::Keypair,
+}
+
+struct Keys {
+    current: noise::Config,
+    /// Protocol name of the current prologue's key id
+    current_protocol: Option<String>,
+    /// Previous prologue, the protocol name of its key id and when it stops being accepted
+    previous: Option<(noise::Config, String, Instant)>,
+}
+
+impl RekeyableNoise {
+    pub fn new(
+        keypair: &identity::Keypair,
+        prologue: Vec<u8>,
+        key_id: Option<&str>,
+    ) -> Result<Self, noise::Error> {
+        Ok(RekeyableNoise {
+            keys: Arc::new(RwLock::new(Keys {
+                current_protocol: key_id.map(key_protocol),
+                current: noise::Config::new(keypair)?.with_prologue(prologue),
+                previous: None,
+            })),
+            keypair: keypair.clone(),
+        })
+    }
+
+    /// Uses the prologue for every handshake started from now on, while still accepting the
+    /// previous one for the grace period, if any and if the previous key had an id
+    pub fn rotate(
+        &self,
+        prologue: Vec<u8>,
+        key_id: Option<&str>,
+        grace: Duration,
+    ) -> Result<(), noise::Error> {
+        let config = noise::Config::new(&self.keypair)?.with_prologue(prologue);
+        let mut keys = self.keys.write().expect("noise config lock poisoned");
+        let previous = std::mem::replace(&mut keys.current, config);
+        let previous_protocol =
+            std::mem::replace(&mut keys.current_protocol, key_id.map(key_protocol));
+        keys.previous = previous_protocol
+            .filter(|_| !grace.is_zero())
+            .map(|protocol| (previous, protocol, Instant::now() + grace));
+        Ok(())
+    }
+
+    /// The config for a protocol name, `None` if the name isn't offered
+    fn config(&self, protocol: &str) -> Option<noise::Config> {
+        let keys = self.keys.read().expect("noise config lock poisoned");
+        if protocol == NOISE_PROTOCOL || keys.current_protocol.as_deref() == Some(protocol) {
+            return Some(keys.current.clone());
+        }
+        match &keys.previous {
+            Some((previous, previous_protocol, until))
+                if Instant::now() < *until && protocol == previous_protocol =>
+            {
+                Some(previous.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Protocol name of a key id
+fn key_protocol(key_id: &str) -> String {
+    format!("{NOISE_PROTOCOL}/psk/{key_id}")
+}
+
+/// Error of a handshake negotiated on a protocol name that stopped being offered before the
+/// upgrade started, i.e. the grace period of the previous prologue ended in between
+fn withdrawn_protocol(protocol: &str) -> noise::Error {
+    noise::Error::Io(io::Error::other(format!(
+        "noise protocol {protocol} is no longer offered"
+    )))
+}
+
+impl UpgradeInfo for RekeyableNoise {
+    type Info = String;
+    type InfoIter = Vec<String>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        let keys = self.keys.read().expect("noise config lock poisoned");
+        let mut protocols = keys.current_protocol.iter().cloned().collect::<Vec<_>>();
+        if let Some((_, previous_protocol, until)) = &keys.previous
+            && Instant::now() < *until
+        {
+            protocols.push(previous_protocol.clone());
+        }
+        protocols.push(NOISE_PROTOCOL.to_string());
+        protocols
+    }
+}
+
+impl<T> InboundConnectionUpgrade<T> for RekeyableNoise
+where
+    noise::Config: InboundConnectionUpgrade<T, Info = &'static str, Error = noise::Error>,
+{
+    type Output = <noise::Config as InboundConnectionUpgrade<T>>::Output;
+    type Error = noise::Error;
+    type Future = futures::future::Either<
+        <noise::Config as InboundConnectionUpgrade<T>>::Future,
+        futures::future::Ready<Result<Self::Output, noise::Error>>,
+    >;
+
+    fn upgrade_inbound(self, socket: T, info: Self::Info) -> Self::Future {
+        match self.config(&info) {
+            Some(config) => {
+                futures::future::Either::Left(config.upgrade_inbound(socket, NOISE_PROTOCOL))
+            }
+            None => futures::future::Either::Right(futures::future::ready(Err(
+                withdrawn_protocol(&info),
+            ))),
+        }
+    }
+}
+
+impl<T> OutboundConnectionUpgrade<T> for RekeyableNoise
+where
+    noise::Config: OutboundConnectionUpgrade<T, Info = &'static str, Error = noise::Error>,
+{
+    type Output = <noise::Config as OutboundConnectionUpgrade<T>>::Output;
+    type Error = noise::Error;
+    type Future = futures::future::Either<
+        <noise::Config as OutboundConnectionUpgrade<T>>::Future,
+        futures::future::Ready<Result<Self::Output, noise::Error>>,
+    >;
+
+    fn upgrade_outbound(self, socket: T, info: Self::Info) -> Self::Future {
+        match self.config(&info) {
+            Some(config) => {
+                futures::future::Either::Left(config.upgrade_outbound(socket, NOISE_PROTOCOL))
+            }
+            None => futures::future::Either::Right(futures::future::ready(Err(
+                withdrawn_protocol(&info),
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use libp2p::{
+        Multiaddr, Transport,
+        core::{
+            Endpoint,
+            transport::{DialOpts, ListenerId, MemoryTransport, PortUse, TransportEvent},
+            upgrade::Version,
+        },
+        multiaddr::Protocol,
+        yamux,
+    };
+
+    use super::*;
+
+    /// Keys and their ids
+    const OLD_KEY: (&[u8], &str) = (b"old key", "2025-01");
+    const NEW_KEY: (&[u8], &str) = (b"new key", "2025-02");
+    const GRACE: Duration = Duration::from_secs(60);
+
+    fn node((prologue, key_id): (&[u8], &str)) -> RekeyableNoise {
+        RekeyableNoise::new(
+            &identity::Keypair::generate_ed25519(),
+            prologue.to_vec(),
+            Some(key_id),
+        )
+        .unwrap()
+    }
+
+    fn rotated(grace: Duration) -> RekeyableNoise {
+        let node = node(OLD_KEY);
+        node.rotate(NEW_KEY.0.to_vec(), Some(NEW_KEY.1), grace)
+            .unwrap();
+        node
+    }
+
+    /// Whether the dialer gets a connection to the listener over an in-memory transport
+    fn connects(listener: &RekeyableNoise, dialer: &RekeyableNoise) -> bool {
+        let transport = |noise: &RekeyableNoise| {
+            MemoryTransport::default()
+                .upgrade(Version::V1)
+                .authenticate(noise.clone())
+                .multiplex(yamux::Config::default())
+                .boxed()
+        };
+        let address: Multiaddr = Protocol::Memory(rand::random::<u64>().saturating_add(1)).into();
+        let mut listening = transport(listener);
+        listening
+            .listen_on(ListenerId::next(), address.clone())
+            .unwrap();
+        let dial = transport(dialer)
+            .dial(
+                address,
+                DialOpts {
+                    role: Endpoint::Dialer,
+                    port_use: PortUse::New,
+                },
+            )
+            .unwrap();
+        let accept = async {
+            loop {
+                if let TransportEvent::Incoming { upgrade, .. } = listening.select_next_some().await
+                {
+                    return upgrade.await.is_ok();
+                }
+            }
+        };
+
+        let (accepted, dialed) = futures::executor::block_on(futures::future::join(accept, dial));
+        accepted && dialed.is_ok()
+    }
+
+    #[test]
+    fn old_and_new_keys_overlap_during_the_grace_period() {
+        let rotated_node = rotated(GRACE);
+        let peers = [
+            ("old key", node(OLD_KEY)),
+            ("new key", node(NEW_KEY)),
+            ("rotated", rotated(GRACE)),
+        ];
+
+        for (name, peer) in &peers {
+            assert!(connects(&rotated_node, peer), "{name} peer can't dial in");
+            assert!(connects(peer, &rotated_node), "{name} peer can't be dialed");
+        }
+        assert!(!connects(&rotated_node, &node((b"other key", "2025-03"))));
+    }
+
+    #[test]
+    fn old_key_is_refused_without_a_grace_period() {
+        let rotated_node = rotated(Duration::ZERO);
+        let old_peer = node(OLD_KEY);
+
+        assert!(!connects(&rotated_node, &old_peer));
+        assert!(!connects(&old_peer, &rotated_node));
+        assert!(connects(&rotated_node, &node(NEW_KEY)));
+    }
+
+    #[test]
+    fn protocol_names_only_carry_the_key_ids() {
+        assert_eq!(
+            rotated(GRACE).protocol_info(),
+            ["/noise/psk/2025-02", "/noise/psk/2025-01", "/noise"]
+        );
+
+        let without_id = RekeyableNoise::new(
+            &identity::Keypair::generate_ed25519(),
+            OLD_KEY.0.to_vec(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(without_id.protocol_info(), ["/noise"]);
+        // nothing to offer the previous key under, so there's no grace period
+        without_id
+            .rotate(NEW_KEY.0.to_vec(), Some(NEW_KEY.1), GRACE)
+            .unwrap();
+        assert_eq!(without_id.protocol_info(), ["/noise/psk/2025-02", "/noise"]);
+    }
+}
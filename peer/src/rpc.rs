@@ -0,0 +1,349 @@
+//! JSON-RPC 2.0 control socket, for driving the node from other processes instead of stdin.
+//!
+//! Every line a client writes to the Unix socket is one request, answered with one line holding
+//! the response. Methods map onto [`SwarmCommand`]s and take their arguments as named params:
+//!
+//! - `dial` with an `address` multiaddr or a `peer_id`, `disconnect` with a `peer_id`
+//! - `get_providers`, `get_record` with a `key`
+//! - `put_record` with a `key` and `value`
+//! - `publish` with a `topic` and `data`, `subscribe` and `unsubscribe` with a `topic`
+//! - `list_connections`, `metrics` and `shutdown` without params
+
+use std::{
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use libp2p::{Multiaddr, PeerId, kad};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, info, warn};
+
+use crate::swarm_dispatch::SwarmCommand;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The swarm failed to carry the request out
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// What the requests need from the node, shared by every client
+#[derive(Clone)]
+pub struct Node {
+    pub swarm_command_tx: mpsc::Sender<SwarmCommand>,
+    /// Receives the `shutdown` method, so the node leaves the network the same way it does on
+    /// Ctrl-C
+    pub shutdown_tx: mpsc::Sender<()>,
+    /// Node events the database manager fell behind on, reported with the metrics
+    pub dropped_events: Arc<AtomicU64>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        RpcError {
+            code: INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+
+    fn server(message: impl Into<String>) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message: message.into(),
+        }
+    }
+}
+
+/// Accepts clients on the socket until the process exits. A leftover socket file from an earlier
+/// run is replaced.
+pub async fn serve(path: PathBuf, node: Node) -> std::io::Result<()> {
+    let listener = bind(&path)?;
+    info!("Accepting JSON-RPC requests on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let node = node.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_client(stream, node).await {
+                debug!("JSON-RPC client failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Binds the socket so only our own user can connect, every client gets full control of the node
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Removes the socket file of an earlier run. Anything else at the path, or a socket another
+/// process still accepts on, is left alone and fails.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another process accepts requests on {}", path.display()),
+        ));
+    }
+    std::fs::remove_file(path)
+}
+
+async fn handle_client(stream: UnixStream, node: Node) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) if request.jsonrpc != "2.0" => response(
+                request.id,
+                Err(RpcError {
+                    code: INVALID_REQUEST,
+                    message: "only JSON-RPC 2.0 is supported".to_string(),
+                }),
+            ),
+            Ok(request) => {
+                let result = call(&request, &node).await;
+                response(request.id, result)
+            }
+            Err(err) => response(
+                Value::Null,
+                Err(RpcError {
+                    code: PARSE_ERROR,
+                    message: err.to_string(),
+                }),
+            ),
+        };
+
+        let mut bytes = serde_json::to_vec(&response).expect("responses serialize");
+        bytes.push(b'\n');
+        writer.write_all(&bytes).await?;
+    }
+    Ok(())
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(RpcError { code, message }) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+async fn call(request: &Request, node: &Node) -> Result<Value, RpcError> {
+    let swarm_command_tx = &node.swarm_command_tx;
+    let params = &request.params;
+    match request.method.as_str() {
+        "dial" => {
+            let command = if let Some(address) = optional_param(params, "address")? {
+                let address = Multiaddr::from_str(address)
+                    .map_err(|err| RpcError::invalid_params(format!("invalid address: {err}")))?;
+                SwarmCommand::Dial(address)
+            } else if let Some(peer_id) = optional_param(params, "peer_id")? {
+                let peer_id = PeerId::from_str(peer_id)
+                    .map_err(|err| RpcError::invalid_params(format!("invalid peer id: {err}")))?;
+                SwarmCommand::DialPeerId(peer_id)
+            } else {
+                return Err(RpcError::invalid_params("expected an address or a peer_id"));
+            };
+            send(swarm_command_tx, command).await?;
+            Ok(Value::Null)
+        }
+        "disconnect" => {
+            let peer_id = PeerId::from_str(param(params, "peer_id")?)
+                .map_err(|err| RpcError::invalid_params(format!("invalid peer id: {err}")))?;
+            send(swarm_command_tx, SwarmCommand::Disconnect(peer_id)).await?;
+            Ok(Value::Null)
+        }
+        "get_providers" => {
+            let key = kad::RecordKey::new(&param(params, "key")?);
+            let (reply_tx, reply_rx) = oneshot::channel();
+            send(swarm_command_tx, SwarmCommand::FindProviders(key, reply_tx)).await?;
+            let providers = receive(reply_rx).await?;
+            Ok(json!(
+                providers
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            ))
+        }
+        "put_record" => {
+            let key = kad::RecordKey::new(&param(params, "key")?);
+            let value = param(params, "value")?.as_bytes().to_vec();
+            let (reply_tx, reply_rx) = oneshot::channel();
+            send(
+                swarm_command_tx,
+                SwarmCommand::PutRecord {
+                    key,
+                    value,
+                    reply: reply_tx,
+                },
+            )
+            .await?;
+            receive(reply_rx).await?.map_err(RpcError::server)?;
+            Ok(Value::Null)
+        }
+        "get_record" => {
+            let key = kad::RecordKey::new(&param(params, "key")?);
+            let (reply_tx, reply_rx) = oneshot::channel();
+            send(swarm_command_tx, SwarmCommand::GetRecord(key, reply_tx)).await?;
+            let value = receive(reply_rx).await?.map_err(RpcError::server)?;
+            Ok(json!(
+                value.map(|value| String::from_utf8_lossy(&value).into_owned())
+            ))
+        }
+        "publish" => {
+            let topic = param(params, "topic")?.to_string();
+            let data = param(params, "data")?.as_bytes().to_vec();
+            send(swarm_command_tx, SwarmCommand::Publish { topic, data }).await?;
+            Ok(Value::Null)
+        }
+        "subscribe" => {
+            let topic = param(params, "topic")?.to_string();
+            send(swarm_command_tx, SwarmCommand::Subscribe(topic)).await?;
+            Ok(Value::Null)
+        }
+        "unsubscribe" => {
+            let topic = param(params, "topic")?.to_string();
+            send(swarm_command_tx, SwarmCommand::Unsubscribe(topic)).await?;
+            Ok(Value::Null)
+        }
+        "list_connections" => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            send(swarm_command_tx, SwarmCommand::ListConnections(reply_tx)).await?;
+            let peers = receive(reply_rx).await?;
+            Ok(json!(
+                peers.iter().map(ToString::to_string).collect::<Vec<_>>()
+            ))
+        }
+        "metrics" => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            send(swarm_command_tx, SwarmCommand::Metrics(reply_tx)).await?;
+            let mut snapshot = receive(reply_rx).await?;
+            snapshot.dropped_events = node.dropped_events.load(Ordering::Relaxed);
+            serde_json::to_value(snapshot).map_err(|err| RpcError::server(err.to_string()))
+        }
+        "shutdown" => {
+            if node.shutdown_tx.send(()).await.is_err() {
+                warn!("JSON-RPC shutdown requested while already shutting down");
+            }
+            Ok(Value::Null)
+        }
+        method => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("unknown method: {method}"),
+        }),
+    }
+}
+
+fn param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    optional_param(params, name)?
+        .ok_or_else(|| RpcError::invalid_params(format!("missing param: {name}")))
+}
+
+fn optional_param<'a>(params: &'a Value, name: &str) -> Result<Option<&'a str>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(RpcError::invalid_params(format!(
+            "param {name} must be a string"
+        ))),
+    }
+}
+
+async fn send(
+    swarm_command_tx: &mpsc::Sender<SwarmCommand>,
+    command: SwarmCommand,
+) -> Result<(), RpcError> {
+    swarm_command_tx
+        .send(command)
+        .await
+        .map_err(|_| RpcError::server("the swarm has stopped"))
+}
+
+async fn receive<T>(reply_rx: oneshot::Receiver<T>) -> Result<T, RpcError> {
+    reply_rx
+        .await
+        .map_err(|_| RpcError::server("the swarm dropped the request"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("peer-rpc-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("rpc.sock")
+    }
+
+    #[tokio::test]
+    async fn socket_is_only_accessible_by_its_owner() {
+        let path = socket_path("permissions");
+
+        let _listener = bind(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn stale_socket_is_replaced_but_other_files_are_kept() {
+        let path = socket_path("stale");
+        drop(bind(&path).unwrap());
+        let _listener = bind(&path).unwrap();
+        assert!(bind(&path).is_err(), "replaced a socket still in use");
+
+        let file = path.with_file_name("config.toml");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(bind(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+    }
+}
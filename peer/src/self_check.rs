@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use libp2p::{PeerId, swarm::DialError};
+use tokio::time::Instant;
+
+use crate::dial_error::{self, DialFailure};
+
+/// Follows the relay handshake after startup to explain what's misconfigured if it stalls
+pub struct SelfCheck {
+    deadline: Instant,
+    connected: bool,
+    identified: bool,
+    reserved: bool,
+    /// Peer id the relay address actually answered with
+    wrong_peer_id: Option<PeerId>,
+    last_dial_error: Option<(DialFailure, String)>,
+}
+
+impl SelfCheck {
+    pub fn new(timeout: Duration) -> Self {
+        SelfCheck {
+            deadline: Instant::now() + timeout,
+            connected: false,
+            identified: false,
+            reserved: false,
+            wrong_peer_id: None,
+            last_dial_error: None,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn record_connected(&mut self) {
+        self.connected = true;
+    }
+
+    pub fn record_identified(&mut self) {
+        self.identified = true;
+    }
+
+    pub fn record_reserved(&mut self) {
+        self.reserved = true;
+    }
+
+    pub fn record_dial_error(&mut self, error: &DialError) {
+        if let DialError::WrongPeerId { obtained, .. } = error {
+            self.wrong_peer_id = Some(*obtained);
+        }
+        self.last_dial_error = Some((dial_error::classify(error), error.to_string()));
+    }
+
+    pub fn passed(&self) -> bool {
+        self.connected && self.identified && self.reserved
+    }
+
+    /// The most likely cause for the first step that didn't complete
+    pub fn diagnosis(&self, relay_peer_id: &PeerId) -> String {
+        if !self.connected {
+            if let Some(obtained) = self.wrong_peer_id {
+                return format!(
+                    "connection not established: the relay address answered as {obtained} instead of {relay_peer_id}, check relay.peer_id in the config"
+                );
+            }
+
+            return match &self.last_dial_error {
+                Some((DialFailure::Handshake, error)) => format!(
+                    "connection not established: the secure handshake with the relay failed ({error}), check that identity.pre_shared_key matches the relay's"
+                ),
+                Some((failure, error)) => format!(
+                    "connection not established: relay {relay_peer_id} is unreachable, {failure} ({error}), check relay.address and that the relay is running"
+                ),
+                None => format!(
+                    "connection not established: relay {relay_peer_id} did not respond, check relay.address and that the relay is running"
+                ),
+            };
+        }
+
+        if !self.identified {
+            return "connected to the relay but identify never completed, check that the relay runs a compatible version".to_string();
+        }
+
+        "relay did not accept a reservation, it may be at its reservation limit or not acting as a relay server".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        Multiaddr,
+        core::{ConnectedPoint, Endpoint, transport::PortUse},
+    };
+
+    use super::*;
+
+    #[test]
+    fn relay_answering_as_another_peer_is_named() {
+        let relay = PeerId::random();
+        let obtained = PeerId::random();
+        let mut check = SelfCheck::new(Duration::from_secs(10));
+        check.record_dial_error(&DialError::WrongPeerId {
+            obtained,
+            endpoint: ConnectedPoint::Dialer {
+                address: Multiaddr::empty(),
+                role_override: Endpoint::Dialer,
+                port_use: PortUse::Reuse,
+            },
+        });
+        // a later failure doesn't hide that the address belongs to another peer
+        check.record_dial_error(&DialError::NoAddresses);
+
+        let diagnosis = check.diagnosis(&relay);
+        assert!(!check.passed());
+        assert!(diagnosis.contains(&format!("answered as {obtained} instead of {relay}")));
+        assert!(diagnosis.contains("relay.peer_id"));
+    }
+}
@@ -1,83 +1,993 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use automerge::{ReadDoc, transaction::Transactable};
+use automerge::{ChangeHash, ReadDoc, transaction::Transactable};
 use futures::StreamExt;
 use libp2p::{
-    Multiaddr, Swarm, autonat, identify,
-    kad::{self, QueryResult},
+    Multiaddr, PeerId, StreamProtocol, Swarm, autonat,
+    core::ConnectedPoint,
+    core::transport::ListenerId,
+    gossipsub, identify,
+    kad::{self, QueryResult, store::RecordStore},
+    mdns,
+    metrics::Registry,
     multiaddr::Protocol,
-    relay,
-    swarm::SwarmEvent,
+    relay, request_response,
+    swarm::{
+        ConnectionId, DialError, SwarmEvent,
+        dial_opts::{DialOpts, PeerCondition},
+    },
+    upnp,
 };
 use tokio::{
     select,
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, oneshot},
 };
 use tracing::{debug, info, warn};
 
-use crate::behaviour::{Behaviour, BehaviourEvent};
+use crate::{
+    behaviour::{Behaviour, BehaviourEvent},
+    change_throttle::{self, ChangeThrottle},
+    control::{self, RequestHandler},
+    dial_error::{self, DialFailure},
+    isolation_watchdog::IsolationWatchdog,
+    local_config::ProviderReadiness,
+    metrics::{Metrics, MetricsSnapshot},
+    provider_warmup::ProviderWarmup,
+    reachability::{self, NatStatus, Reachability},
+    rekeyable_noise::RekeyableNoise,
+    self_check::SelfCheck,
+};
+
+/// How often the manager runs its periodic housekeeping
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a document lookup waits for the DHT and peers to answer
+const DOCUMENT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a request to a single peer may stay unanswered
+const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Shortest time between two bootstraps and announcements triggered by connecting to a relay,
+/// so a relay that keeps reconnecting doesn't flood the DHT
+const RELAY_ANNOUNCE_MIN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Delay before the first relay re-dial, doubled on every further failure
+const RELAY_DIAL_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RELAY_DIAL_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long the relay gets to connect, identify and accept a reservation after startup
+const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often relays that didn't answer are probed again
+const RELAY_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+/// Lifetime of a relay reservation. Relays don't tell clients when a reservation expires, so
+/// this assumes the libp2p default.
+const RESERVATION_TTL: Duration = Duration::from_secs(60 * 60);
+/// Delay before retrying a record put that missed its quorum, doubled on every further failure
+const RECORD_PUT_INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+/// Delay before redialing a peer we failed to reach, doubled on every further failure
+const DIAL_RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const DIAL_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Longest a redial waits for the DHT lookup of its peer before going out anyway
+const DIAL_LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay before listening on a relay's circuit again after the listen or the reservation failed
+const CIRCUIT_LISTEN_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// How long shutdown waits for the relay connection to close after dropping the circuit listeners
+const RELAY_RELEASE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long shutdown keeps polling the swarm so the unsubscribes reach the peers, and then again
+/// for the connections to close
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+/// Events buffered per subscriber before the slowest one starts missing events, unless set with
+/// [`SwarmManager::with_event_channel_capacity`]
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+/// How long each path of a connectivity report may take
+const CONNECTIVITY_STAGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Recoveries from isolation after which the node warns that it is cut off
+const ISOLATION_WARNING_CYCLES: u32 = 3;
+/// Number of connected peers asked directly during a document lookup
+const DOCUMENT_LOOKUP_PEERS: usize = 3;
 
 pub enum SwarmCommand {
     Dial(Multiaddr),
     DialPeerId(libp2p::PeerId),
     BeginProviderRole(kad::RecordKey),
     StopProviderRole(kad::RecordKey),
-    FindProviders(kad::RecordKey),
-    ListConnections,
+    /// Announces as provider of the key once the document behind it is ready to be served
+    ProvideWhenReady {
+        key: kad::RecordKey,
+        document_id: String,
+        readiness: ProviderReadiness,
+    },
+    /// Looks the key's providers up in the DHT, replying with all found once the query is done
+    FindProviders(kad::RecordKey, oneshot::Sender<Vec<PeerId>>),
+    ListConnections(oneshot::Sender<Vec<PeerId>>),
+    /// Closes every connection to the peer
+    Disconnect(PeerId),
     PutTestValue(String, String),
-    GetTestValue(String),
+    /// Value of a key in the root of the test document, `None` if the key or document is missing
+    GetTestValue(String, oneshot::Sender<Option<String>>),
+    PutBatch(String, serde_json::Map<String, serde_json::Value>),
+    DocumentJson(String),
+    /// Checks whether any other peer has the document, via the DHT and by asking peers directly
+    DocumentExists(String),
+    /// Addresses other peers can use to dial us
+    ShareAddress(oneshot::Sender<SharedAddresses>),
+    /// Queues a sync of every local document with the peer
+    SyncDocuments(PeerId),
+    /// Asks a connected peer which documents it has
+    PeerDocuments(
+        PeerId,
+        oneshot::Sender<Result<Vec<String>, PeerRequestError>>,
+    ),
+    /// Writes every document into one archive, at the given path or a timestamped file in the
+    /// data directory, replying with the path written
+    Snapshot(Option<PathBuf>, oneshot::Sender<io::Result<PathBuf>>),
+    /// Restores all documents of an archive, replying with the restored document ids
+    RestoreSnapshot(PathBuf, oneshot::Sender<io::Result<Vec<String>>>),
+    /// Compares our heads of a document with a peer's copy, without syncing
+    Converged(
+        PeerId,
+        String,
+        oneshot::Sender<Result<Convergence, PeerRequestError>>,
+    ),
+    /// Stops listening on relay circuits and closes the relay connections, freeing our reservation
+    /// slots on the relays. Replies once the connections are closed or the release timed out.
+    ReleaseRelay(oneshot::Sender<()>),
+    /// Leaves every gossipsub topic, stops providing every kademlia key, releases the relays and
+    /// closes the remaining connections, then stops the swarm. Replies once it stopped.
+    Shutdown(oneshot::Sender<()>),
+    /// Byte sizes of every change of a document, `None` if the document doesn't exist
+    ChangeSizes(String, oneshot::Sender<Option<Vec<usize>>>),
+    /// Replaces the local labels of a document, replying `false` if we don't have it
+    SetLabels(String, Vec<String>, oneshot::Sender<bool>),
+    /// Deletes a document here and on connected peers, replying `false` if we don't have it
+    DeleteDocument(String, oneshot::Sender<bool>),
+    /// Compacts a document once none of its syncs is running, replying `false` if we don't have
+    /// it
+    CompactDocument {
+        document_id: String,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Syncs the document with peers, the first one limiting syncs to the subscribed documents
+    SubscribeDocument(String),
+    /// Stops syncing the document with peers, keeping our copy
+    UnsubscribeDocument(String),
+    /// Our documents with their labels, only those carrying the label if one is given
+    ListDocuments(Option<String>, oneshot::Sender<Vec<(String, Vec<String>)>>),
+    /// Current heads of a document as hex change hashes, `None` if the document doesn't exist
+    DocumentHeads(String, oneshot::Sender<Option<Vec<String>>>),
+    /// Connected peers that recently exchanged messages about a document
+    DocumentPeers(String, oneshot::Sender<Vec<PeerId>>),
+    /// Stores a record in the DHT, retrying until the configured quorum is met
+    PutRecord {
+        key: kad::RecordKey,
+        value: Vec<u8>,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
+    /// Looks a record up in the DHT, replying with the first value found or `None` if there is
+    /// no record under the key
+    GetRecord(
+        kad::RecordKey,
+        oneshot::Sender<Result<Option<Vec<u8>>, String>>,
+    ),
+    /// Evicts unpinned documents nobody touched within the TTL, replying with their ids. A dry
+    /// run only lists them.
+    CollectGarbage {
+        ttl: Duration,
+        dry_run: bool,
+        reply: oneshot::Sender<Vec<String>>,
+    },
+    /// Closes every connection and dials the peers again once they're gone, so all connections
+    /// run a fresh handshake, e.g. with a new pre-shared key. Replies with the number of peers.
+    Reconnect(oneshot::Sender<usize>),
+    /// Switches new connections to the pre-shared key, still accepting the previous key for the
+    /// grace period. Established connections keep the key they were made with.
+    RotatePreSharedKey {
+        key: String,
+        key_id: Option<String>,
+        grace: Duration,
+        reply: oneshot::Sender<Result<(), libp2p::noise::Error>>,
+    },
+    /// Tries reaching a peer directly, through the relay and by hole punching, in that order
+    ConnectivityReport(PeerId, oneshot::Sender<ConnectivityReport>),
+    /// Peers in our gossipsub mesh for a topic, and every peer subscribed to it
+    MeshPeers(gossipsub::IdentTopic, oneshot::Sender<TopicPeers>),
+    /// Current values of the node's metrics
+    Metrics(oneshot::Sender<MetricsSnapshot>),
+    /// Connected peers and running document syncs, answered right away to show the swarm is
+    /// still making progress
+    Liveness(oneshot::Sender<Liveness>),
+    /// Limits of the reservations relays granted us, per relay
+    ReservationLimits(oneshot::Sender<HashMap<PeerId, ReservationLimits>>),
+    /// Every configured relay with the time left on our reservation, `None` without one
+    ListReservations(oneshot::Sender<Vec<(PeerId, Option<Duration>)>>),
+    /// Listens on the relay's circuit again, requesting a fresh reservation before the current
+    /// one expires. Replies with an error if the relay isn't configured or connected, or
+    /// listening on its circuit failed.
+    RenewReservation(PeerId, oneshot::Sender<Result<(), String>>),
+    /// Who we are, where we can be reached and how connected we are
+    Status(oneshot::Sender<NodeStatus>),
+    /// What the peer last told us about itself through identify, `None` if it never did
+    GetPeerInfo(PeerId, oneshot::Sender<Option<PeerInfo>>),
+    /// Sends opaque bytes over the control protocol and replies with the peer's response
+    SendRequest {
+        peer: PeerId,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<Vec<u8>, PeerRequestError>>,
+    },
+    /// Fetches a connected peer's full copy of a document, replying `None` if it doesn't have
+    /// it. The document isn't merged into ours.
+    FetchDocument {
+        peer: PeerId,
+        document_id: String,
+        reply: oneshot::Sender<Result<Option<Vec<u8>>, PeerRequestError>>,
+    },
+    Subscribe(String),
+    Unsubscribe(String),
+    /// Publishes raw bytes on a gossipsub topic, received messages reach the swarm event
+    /// subscribers as gossipsub message events
+    Publish {
+        topic: String,
+        data: Vec<u8>,
+    },
+}
+
+/// Why a request to a specific peer failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerRequestError {
+    NotConnected,
+    /// The peer doesn't speak the protocol the request needs
+    Unsupported,
+    Timeout,
+    /// The request failed for another reason, e.g. a stream error
+    Failed,
+}
+
+impl std::fmt::Display for PeerRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerRequestError::NotConnected => write!(f, "peer is not connected"),
+            PeerRequestError::Unsupported => write!(f, "peer does not support the protocol"),
+            PeerRequestError::Timeout => write!(f, "peer did not answer in time"),
+            PeerRequestError::Failed => write!(f, "request failed"),
+        }
+    }
+}
+
+/// Outcome of comparing the heads of a document between us and a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Convergence {
+    Converged,
+    Divergent {
+        /// Heads only we have
+        local_only: Vec<ChangeHash>,
+        /// Heads only the peer has
+        remote_only: Vec<ChangeHash>,
+    },
+    /// At least one side doesn't have the document
+    Missing {
+        locally: bool,
+        remotely: bool,
+    },
+}
+
+/// Events of the node meant for the application, as opposed to raw swarm events
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    /// A gossipsub message arrived, `source` is `None` for anonymous messages
+    GossipMessage {
+        topic: gossipsub::TopicHash,
+        source: Option<PeerId>,
+        data: Vec<u8>,
+    },
+    PeerSubscribed {
+        peer: PeerId,
+        topic: gossipsub::TopicHash,
+    },
+    PeerUnsubscribed {
+        peer: PeerId,
+        topic: gossipsub::TopicHash,
+    },
+    /// The node has had no connection for a while and recovering from it keeps failing
+    Isolated { duration: Duration, recoveries: u32 },
+    /// Hole punching upgraded the relayed connection to the peer to a direct one
+    DirectConnectionEstablished(PeerId),
+    /// Hole punching to the peer failed, documents keep syncing over the slower relayed
+    /// connection
+    RelayedSyncFallback(PeerId),
+    /// First connection to the peer, `relayed` if it goes through a relay
+    PeerConnected { peer: PeerId, relayed: bool },
+    /// Last connection to the peer closed
+    PeerDisconnected { peer: PeerId },
+    /// The peer and we have the same version of the document
+    DocumentSynced { peer: PeerId, document_id: String },
+    /// The peer deleted the document and we removed our copy
+    DocumentDeleted { peer: PeerId, document_id: String },
+    /// A provider query found providers of the key, sent for every batch of results
+    ProvidersFound {
+        key: kad::RecordKey,
+        providers: Vec<PeerId>,
+    },
+    /// A relay accepted or renewed our reservation, peers can reach us through it
+    RelayReservationActive { relay: PeerId, renewal: bool },
+    /// Dialing failed on every attempt, including the retries
+    DialFailed {
+        target: DialTarget,
+        attempts: u32,
+        failure: DialFailure,
+    },
+}
+
+/// What a dial asked for through [`SwarmCommand::Dial`] or [`SwarmCommand::DialPeerId`] was
+/// aimed at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DialTarget {
+    Address(Multiaddr),
+    Peer(PeerId),
+}
+
+impl DialTarget {
+    /// The peer being dialed, for an address the one of its last `/p2p` component
+    pub fn peer_id(&self) -> Option<PeerId> {
+        match self {
+            DialTarget::Address(address) => {
+                address.iter().fold(None, |peer, protocol| match protocol {
+                    Protocol::P2p(peer_id) => Some(peer_id),
+                    _ => peer,
+                })
+            }
+            DialTarget::Peer(peer_id) => Some(*peer_id),
+        }
+    }
+
+    fn is_relayed(&self) -> bool {
+        matches!(self, DialTarget::Address(address)
+            if address.iter().any(|protocol| protocol == Protocol::P2pCircuit))
+    }
+}
+
+impl fmt::Display for DialTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialTarget::Address(address) => write!(f, "{address}"),
+            DialTarget::Peer(peer_id) => write!(f, "{peer_id}"),
+        }
+    }
+}
+
+/// Gossipsub peers of a topic. Messages are forwarded to mesh peers, subscribed peers outside
+/// the mesh only learn of them through gossip.
+#[derive(Debug, Clone)]
+pub struct TopicPeers {
+    pub mesh: Vec<PeerId>,
+    pub subscribed: Vec<PeerId>,
+}
+
+/// What the swarm is busy with right now
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    pub connected_peers: Vec<PeerId>,
+    /// Document syncs still exchanging messages, by peer and document
+    pub active_syncs: Vec<(PeerId, String)>,
+}
+
+/// Result of trying one way of reaching a peer
+#[derive(Debug, Clone)]
+pub enum PathOutcome {
+    /// Connected after the given time
+    Reachable(Duration),
+    Failed(String),
+    /// Not attempted, with the reason why
+    Skipped(String),
+}
+
+/// How a peer can be reached, found by trying each path in turn
+#[derive(Debug, Clone)]
+pub struct ConnectivityReport {
+    pub direct: PathOutcome,
+    pub relayed: PathOutcome,
+    /// Upgrading the relayed connection to a direct one through DCUtR
+    pub upgraded: PathOutcome,
+}
+
+/// Limits a relay attached to our reservation, `None` where the relay set no limit
+#[derive(Debug, Clone)]
+pub struct ReservationLimits {
+    /// How long each relayed circuit may stay open
+    pub duration: Option<Duration>,
+    /// How many bytes each relayed circuit may carry
+    pub data_in_bytes: Option<u64>,
+    /// When the reservation was last accepted or renewed
+    pub accepted_at: Instant,
+}
+
+/// Dialable addresses of the local peer, including the `/p2p/<local peer id>` suffix
+#[derive(Debug, Clone)]
+pub struct SharedAddresses {
+    /// One relay circuit address per active reservation
+    pub circuit: Vec<Multiaddr>,
+    /// Confirmed external addresses that can be dialed without a relay
+    pub direct: Vec<Multiaddr>,
+}
+
+/// Identify info last received from a peer
+pub struct CachedIdentify {
+    pub info: identify::Info,
+    pub received_at: Instant,
+    /// Set once the peer hasn't refreshed its info within the expiry window
+    pub stale: bool,
+}
+
+/// The local peer's identity and reachability, what a user needs to let others dial them
+#[derive(Debug, Clone)]
+pub struct NodeStatus {
+    pub peer_id: PeerId,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub addresses: SharedAddresses,
+    pub connected_peers: usize,
+    pub connections: u32,
+}
+
+/// What a peer advertised about itself through identify
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub agent_version: String,
+    pub protocol_version: String,
+    pub protocols: Vec<StreamProtocol>,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// The address the peer sees us on
+    pub observed_addr: Multiaddr,
+    /// How long ago the info arrived
+    pub age: Duration,
+    /// The peer hasn't refreshed its info within the expiry window
+    pub stale: bool,
+}
+
+impl From<&CachedIdentify> for PeerInfo {
+    fn from(cached: &CachedIdentify) -> Self {
+        PeerInfo {
+            agent_version: cached.info.agent_version.clone(),
+            protocol_version: cached.info.protocol_version.clone(),
+            protocols: cached.info.protocols.clone(),
+            listen_addrs: cached.info.listen_addrs.clone(),
+            observed_addr: cached.info.observed_addr.clone(),
+            age: cached.received_at.elapsed(),
+            stale: cached.stale,
+        }
+    }
+}
+
+/// A caller waiting for the providers a DHT query finds
+struct ProviderQuery {
+    providers: HashSet<PeerId>,
+    reply: oneshot::Sender<Vec<PeerId>>,
+}
+
+/// An in-flight check for whether a document exists on the network
+struct DocumentLookup {
+    document_id: String,
+    provider_query: kad::QueryId,
+    dht_finished: bool,
+    /// Peers asked directly that haven't answered yet
+    pending_peers: HashSet<PeerId>,
+    /// Peers reporting the document, either as DHT provider or directly
+    found: HashSet<PeerId>,
+    /// When the lookup is reported with the answers it got so far
+    deadline: tokio::time::Instant,
 }
 
 pub struct SwarmManager {
     swarm: Swarm<Behaviour>,
     event_tx: broadcast::Sender<Arc<SwarmEvent<BehaviourEvent>>>,
     command_rx: mpsc::Receiver<SwarmCommand>,
+    /// The primary relay, dialed with backoff and used for relayed dials. Every connected relay
+    /// hosts a reservation, so the node stays reachable while any of them is up.
     relay_peer_id: libp2p::PeerId,
     relay_address: Multiaddr,
+    /// Every configured relay, including the primary
+    relay_candidates: Vec<RelayCandidate>,
+    /// Set once a relay answered the startup probe and became the primary
+    relay_selected: bool,
+    last_relay_probe: Instant,
+    /// Startup check of the relay handshake, dropped once it passed or reported
+    self_check: Option<SelfCheck>,
     sent_identify: bool,
     received_identify: bool,
+    identify_cache: HashMap<libp2p::PeerId, CachedIdentify>,
+    identify_expiry: Duration,
+    document_lookups: Vec<DocumentLookup>,
+    provider_queries: HashMap<kad::QueryId, ProviderQuery>,
+    /// Relays that accepted a reservation for us
+    active_reservations: HashSet<PeerId>,
+    /// Limits of the reservations in `active_reservations`
+    reservation_limits: HashMap<PeerId, ReservationLimits>,
+    /// Connectivity reports in progress, with the connection each one is dialing
+    connectivity_probes: Vec<(Option<ConnectionId>, ConnectivityProbe)>,
+    /// Peers disconnected by a reconnect, dialed again once their last connection closed
+    reconnecting_peers: HashSet<PeerId>,
+    /// Provider announcement waiting for its document
+    provider_warmup: Option<ProviderWarmup>,
+    node_event_tx: broadcast::Sender<NodeEvent>,
+    /// Noise upgrade of the transport, whose pre-shared key can be rotated
+    noise: Option<RekeyableNoise>,
+    metrics: Metrics,
+    /// Redials relays once the node has been without connections for too long
+    isolation_watchdog: Option<IsolationWatchdog>,
+    /// Time without syncs or gossip after which a peer is disconnected, relays excepted
+    idle_peer_timeout: Option<Duration>,
+    /// When each connected peer connected or last sent us gossip
+    peer_activity: HashMap<PeerId, Instant>,
+    /// Sources whose gossipsub messages are accepted, `None` accepts every message. Requires
+    /// gossipsub to be configured with `validate_messages`.
+    gossip_allow_list: Option<HashSet<PeerId>>,
+    /// Peers known to be subscribed to each topic
+    topic_members: HashMap<gossipsub::TopicHash, HashSet<PeerId>>,
+    /// Documents exchanged with every newly connected peer
+    synced_documents: Vec<String>,
+    /// Well-known nodes seeding the routing table, dialed on startup
+    bootstrap_peers: Vec<(PeerId, Multiaddr)>,
+    /// Set once a bootstrap peer was reached and kademlia bootstrapped from it
+    bootstrapped: bool,
+    /// When connecting to a relay last bootstrapped kademlia and announced what we provide
+    last_relay_announce: Option<Instant>,
+    /// External addresses the router forwards to us through UPnP
+    upnp_addresses: Vec<Multiaddr>,
+    /// AutoNAT results, deciding which of our addresses are advertised
+    reachability: Reachability,
+    /// Peers found on the local network and their addresses, until mDNS expires them
+    mdns_peers: HashMap<PeerId, HashSet<Multiaddr>>,
+    /// Shutdown waiting for the relay connections to close
+    relay_release: Option<RelayRelease>,
+    /// Caller of a shutdown in progress, answered once the swarm stopped
+    shutdown_reply: Option<oneshot::Sender<()>>,
+    /// Step of a shutdown flushing the swarm before it stops
+    shutdown_flush: Option<ShutdownFlush>,
+    /// Set once shutting down finished, ending the run loop
+    stopped: bool,
+    /// Callers waiting for a peer's document list
+    peer_document_requests:
+        HashMap<PeerId, Vec<PendingReply<Result<Vec<String>, PeerRequestError>>>>,
+    /// Failed dials to the relay since the last successful connection
+    relay_dial_attempts: u32,
+    /// Dial attempts before giving up on the relay, 0 retries forever
+    max_relay_dial_attempts: u32,
+    /// When the next relay redial is due
+    relay_redial_at: Option<tokio::time::Instant>,
+    /// Callers waiting for a peer's heads of a document
+    convergence_requests:
+        HashMap<(PeerId, String), Vec<PendingReply<Result<Convergence, PeerRequestError>>>>,
+    /// Record puts waiting on their query
+    record_puts: HashMap<kad::QueryId, RecordPut>,
+    /// Callers waiting for a DHT record lookup
+    record_gets: HashMap<kad::QueryId, oneshot::Sender<Result<Option<Vec<u8>>, String>>>,
+    /// Failed record puts and when they're attempted again
+    record_put_retries: Vec<(tokio::time::Instant, RecordPut)>,
+    put_quorum: kad::Quorum,
+    max_put_attempts: u32,
+    change_throttle: ChangeThrottle,
+    /// Answers control requests from other peers
+    control_handler: RequestHandler,
+    /// Callers waiting for a peer's response to a control request
+    control_requests: HashMap<
+        request_response::OutboundRequestId,
+        oneshot::Sender<Result<Vec<u8>, PeerRequestError>>,
+    >,
+    /// Callers waiting for a peer's copy of a document
+    document_fetches: HashMap<
+        request_response::OutboundRequestId,
+        oneshot::Sender<Result<Option<Vec<u8>>, PeerRequestError>>,
+    >,
+    /// Requested dials in flight, by the connection they're attempting
+    pending_dials: HashMap<ConnectionId, DialRetry>,
+    /// Failed dials waiting to be attempted again, at most one per target
+    dial_retries: Vec<ScheduledRedial>,
+    /// Redials of a requested dial before giving up on it
+    max_dial_retries: u32,
+}
+
+/// A configured relay and how quickly it answered our probe
+struct RelayCandidate {
+    peer_id: PeerId,
+    address: Multiaddr,
+    probe_started_at: Option<Instant>,
+    /// Time from dialing until the relay's identify info arrived
+    response_time: Option<Duration>,
+    /// Listener on our circuit address through the relay, which holds the reservation
+    circuit_listener: Option<ListenerId>,
+    /// When a failed circuit listen or lost reservation is retried, sooner if the relay connects
+    /// again before
+    circuit_listen_retry_at: Option<tokio::time::Instant>,
+}
+
+/// A connectivity report in progress, waiting on the path being tried
+struct ConnectivityProbe {
+    peer: PeerId,
+    stage: ConnectivityStage,
+    stage_started_at: Instant,
+    deadline: tokio::time::Instant,
+    report: ConnectivityReport,
+    reply: oneshot::Sender<ConnectivityReport>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectivityStage {
+    Direct,
+    Relayed,
+    Upgraded,
+    Done,
+}
+
+/// A record put that is retried until it reaches the quorum
+struct RecordPut {
+    record: kad::Record,
+    /// Attempts made so far
+    attempts: u32,
+    reply: oneshot::Sender<Result<(), String>>,
+}
+
+/// A dial requested through a command, redialed with backoff until it connects or runs out of
+/// retries
+struct DialRetry {
+    target: DialTarget,
+    /// Failed attempts so far
+    failures: u32,
+}
+
+/// A failed dial waiting to be attempted again
+struct ScheduledRedial {
+    retry: DialRetry,
+    /// When the redial goes out, unless the lookup finishes first
+    at: tokio::time::Instant,
+    /// DHT lookup of a fresh path to the peer, the redial goes out as soon as it finished
+    lookup: Option<kad::QueryId>,
+}
+
+/// Circuit listeners being closed before disconnecting from the relay
+struct RelayRelease {
+    reply: oneshot::Sender<()>,
+    closing_listeners: HashSet<ListenerId>,
+    deadline: tokio::time::Instant,
+}
+
+/// Step of a shutdown after the relays were released
+enum ShutdownFlush {
+    /// Polling the swarm until the unsubscribes and provider removals went out
+    Unsubscribing(tokio::time::Instant),
+    /// Waiting for the remaining connections to close
+    Closing(tokio::time::Instant),
+}
+
+impl ShutdownFlush {
+    fn deadline(&self) -> tokio::time::Instant {
+        match self {
+            ShutdownFlush::Unsubscribing(deadline) | ShutdownFlush::Closing(deadline) => *deadline,
+        }
+    }
+}
+
+/// A oneshot reply waiting on an answer from the network
+struct PendingReply<T> {
+    reply: oneshot::Sender<T>,
+    started_at: Instant,
+}
+
+impl ConnectivityProbe {
+    /// Stores the outcome of the current path and advances to the next one
+    fn set_outcome(&mut self, outcome: PathOutcome) {
+        self.stage = match self.stage {
+            ConnectivityStage::Direct => {
+                self.report.direct = outcome;
+                ConnectivityStage::Relayed
+            }
+            ConnectivityStage::Relayed => {
+                self.report.relayed = outcome;
+                ConnectivityStage::Upgraded
+            }
+            ConnectivityStage::Upgraded => {
+                self.report.upgraded = outcome;
+                ConnectivityStage::Done
+            }
+            ConnectivityStage::Done => ConnectivityStage::Done,
+        };
+    }
 }
 
 impl SwarmManager {
     pub fn new(
         swarm: Swarm<Behaviour>,
-        event_tx: broadcast::Sender<Arc<SwarmEvent<BehaviourEvent>>>,
         command_rx: mpsc::Receiver<SwarmCommand>,
-        relay_peer_id: libp2p::PeerId,
-        relay_address: Multiaddr,
+        relays: Vec<(PeerId, Multiaddr)>,
+        identify_expiry: Duration,
+        max_relay_dial_attempts: u32,
     ) -> Self {
+        let (relay_peer_id, relay_address) = relays
+            .first()
+            .cloned()
+            .expect("at least one relay is configured");
         SwarmManager {
             swarm,
-            event_tx,
+            event_tx: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
             command_rx,
             relay_peer_id,
+            relay_candidates: relays
+                .into_iter()
+                .map(|(peer_id, address)| RelayCandidate {
+                    peer_id,
+                    address,
+                    probe_started_at: None,
+                    response_time: None,
+                    circuit_listener: None,
+                    circuit_listen_retry_at: None,
+                })
+                .collect(),
+            relay_selected: false,
+            last_relay_probe: Instant::now(),
+            self_check: None,
             sent_identify: false,
             received_identify: false,
             relay_address,
+            identify_cache: HashMap::new(),
+            identify_expiry,
+            document_lookups: Vec::new(),
+            provider_queries: HashMap::new(),
+            active_reservations: HashSet::new(),
+            reservation_limits: HashMap::new(),
+            connectivity_probes: Vec::new(),
+            reconnecting_peers: HashSet::new(),
+            provider_warmup: None,
+            node_event_tx: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            noise: None,
+            metrics: Metrics::default(),
+            isolation_watchdog: None,
+            idle_peer_timeout: None,
+            peer_activity: HashMap::new(),
+            gossip_allow_list: None,
+            topic_members: HashMap::new(),
+            synced_documents: Vec::new(),
+            bootstrap_peers: Vec::new(),
+            bootstrapped: false,
+            last_relay_announce: None,
+            upnp_addresses: Vec::new(),
+            reachability: Reachability::new(),
+            mdns_peers: HashMap::new(),
+            relay_release: None,
+            shutdown_reply: None,
+            shutdown_flush: None,
+            stopped: false,
+            peer_document_requests: HashMap::new(),
+            relay_dial_attempts: 0,
+            max_relay_dial_attempts,
+            relay_redial_at: None,
+            convergence_requests: HashMap::new(),
+            record_puts: HashMap::new(),
+            record_gets: HashMap::new(),
+            record_put_retries: Vec::new(),
+            put_quorum: kad::Quorum::One,
+            max_put_attempts: 1,
+            change_throttle: ChangeThrottle::new(change_throttle::DEFAULT_INTERVAL),
+            control_handler: control::echo_handler(),
+            control_requests: HashMap::new(),
+            document_fetches: HashMap::new(),
+            pending_dials: HashMap::new(),
+            dial_retries: Vec::new(),
+            max_dial_retries: 0,
         }
     }
 
+    /// Quorum record puts must reach, and how often they're attempted before giving up
+    pub fn with_record_put_policy(mut self, quorum: kad::Quorum, max_attempts: u32) -> Self {
+        self.put_quorum = quorum;
+        self.max_put_attempts = max_attempts;
+        self
+    }
+
+    /// Redials peers that couldn't be reached through a dial command up to `max_retries` times
+    pub fn with_dial_retries(mut self, max_retries: u32) -> Self {
+        self.max_dial_retries = max_retries;
+        self
+    }
+
+    /// Reads the bandwidth for the metrics from the registry the swarm was built with
+    pub fn with_metrics_registry(mut self, registry: Registry) -> Self {
+        self.metrics = Metrics::with_registry(registry);
+        self
+    }
+
+    /// Publishes each document's changes at most once per interval
+    pub fn with_change_publish_interval(mut self, interval: Duration) -> Self {
+        self.change_throttle = ChangeThrottle::new(interval);
+        self
+    }
+
+    /// Redials the relays and bootstraps kademlia again after `timeout` without any connection,
+    /// repeating every `timeout` until a connection is up
+    pub fn with_isolation_watchdog(mut self, timeout: Duration) -> Self {
+        let mut watchdog = IsolationWatchdog::new(timeout);
+        if self.swarm.network_info().num_peers() == 0 {
+            watchdog.isolated(tokio::time::Instant::now());
+        }
+        self.isolation_watchdog = Some(watchdog);
+        self
+    }
+
+    /// Disconnects peers we haven't synced documents or exchanged gossip with for `timeout`.
+    /// Relays are kept regardless, their connections hold our reservations.
+    pub fn with_idle_peer_reaper(mut self, timeout: Duration) -> Self {
+        self.idle_peer_timeout = Some(timeout);
+        self
+    }
+
+    /// Only accepts gossipsub messages published by the peers, rejecting the rest so they are
+    /// neither handled nor forwarded. Gossipsub must be configured with `validate_messages`, or
+    /// messages are forwarded before we get to decide.
+    pub fn with_gossip_allow_list(mut self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.gossip_allow_list = Some(peers.into_iter().collect());
+        self
+    }
+
+    /// Events buffered per subscriber of node and swarm events before the slowest one starts
+    /// missing events. Replaces the channels, so call it before subscribing.
+    pub fn with_event_channel_capacity(mut self, capacity: usize) -> Self {
+        self.event_tx = broadcast::channel(capacity).0;
+        self.node_event_tx = broadcast::channel(capacity).0;
+        self
+    }
+
+    /// Receives the node's events, only those sent after subscribing
+    pub fn subscribe_node_events(&self) -> broadcast::Receiver<NodeEvent> {
+        self.node_event_tx.subscribe()
+    }
+
+    /// Receives every raw swarm event, for consumers that need more than [`NodeEvent`] carries.
+    /// Only events sent after subscribing are received.
+    pub fn subscribe_swarm_events(&self) -> broadcast::Receiver<Arc<SwarmEvent<BehaviourEvent>>> {
+        self.event_tx.subscribe()
+    }
+
+    /// Documents sent to every peer we connect to, so both sides end up with the merged copy
+    pub fn with_synced_documents(mut self, document_ids: Vec<String>) -> Self {
+        self.synced_documents = document_ids;
+        self
+    }
+
+    /// Dials the peers on startup and bootstraps kademlia once the first of them is reachable
+    pub fn with_bootstrap_peers(mut self, peers: Vec<(PeerId, Multiaddr)>) -> Self {
+        self.bootstrap_peers = peers;
+        self
+    }
+
+    /// Lets [`SwarmCommand::RotatePreSharedKey`] change the pre-shared key of the transport
+    pub fn with_rekeyable_noise(mut self, noise: RekeyableNoise) -> Self {
+        self.noise = Some(noise);
+        self
+    }
+
+    /// Replaces the callback answering control protocol requests, which echoes by default
+    pub fn with_control_handler(mut self, handler: RequestHandler) -> Self {
+        self.control_handler = handler;
+        self
+    }
+
     pub async fn run(mut self) {
         info!("SwarmManager started");
-        loop {
+        let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+        // Connect to the relay servers. Not for the reservation or relayed connection, but to (a) learn
+        // our local public address and (b) enable a freshly started relay to learn its public address.
+        self.self_check = Some(SelfCheck::new(SELF_CHECK_TIMEOUT));
+        self.probe_relays();
+        self.dial_relay();
+        self.dial_bootstrap_peers();
+        let document_ids = self
+            .swarm
+            .behaviour()
+            .automerge
+            .document_ids()
+            .cloned()
+            .collect::<Vec<_>>();
+        for document_id in document_ids {
+            self.subscribe_document_topic(&document_id);
+        }
+
+        while !self.stopped {
             select! {
+                _ = wait_until(self.relay_redial_at) => {
+                    self.relay_redial_at = None;
+                    self.dial_relay();
+                }
+                _ = wait_until(self.relay_release.as_ref().map(|release| release.deadline)) => {
+                    warn!("Relay did not close the connection in time, shutting down anyway");
+                    self.finish_relay_release();
+                }
+                _ = wait_until(self.shutdown_flush.as_ref().map(ShutdownFlush::deadline)) => {
+                    self.advance_shutdown();
+                }
+                _ = wait_until(self.isolation_watchdog.as_ref().and_then(IsolationWatchdog::next_deadline)) => {
+                    self.recover_from_isolation();
+                }
+                _ = wait_until(self.relay_candidates.iter().filter_map(|candidate| candidate.circuit_listen_retry_at).min()) => {
+                    self.retry_circuit_listens();
+                }
+                _ = wait_until(self.change_throttle.next_deadline()) => {
+                    self.publish_due_changes();
+                }
+                _ = wait_until(self.connectivity_probes.iter().map(|(_, probe)| probe.deadline).min()) => {
+                    let now = tokio::time::Instant::now();
+                    let expired = self
+                        .connectivity_probes
+                        .extract_if(.., |(_, probe)| probe.deadline <= now)
+                        .map(|(_, probe)| probe)
+                        .collect::<Vec<_>>();
+                    for probe in expired {
+                        self.finish_connectivity_stage(probe, Err("timed out".to_string()));
+                    }
+                }
+                _ = wait_until(self.self_check.as_ref().map(SelfCheck::deadline)) => {
+                    if let Some(check) = self.self_check.take() {
+                        warn!("Startup self-check failed: {}", check.diagnosis(&self.relay_peer_id));
+                    }
+                }
+                _ = wait_until(self.record_put_retries.iter().map(|(at, _)| *at).min()) => {
+                    let now = tokio::time::Instant::now();
+                    let due = self
+                        .record_put_retries
+                        .extract_if(.., |(at, _)| *at <= now)
+                        .map(|(_, put)| put)
+                        .collect::<Vec<_>>();
+                    for put in due {
+                        self.start_record_put(put);
+                    }
+                }
+                _ = wait_until(self.dial_retries.iter().map(|redial| redial.at).min()) => {
+                    let now = tokio::time::Instant::now();
+                    let due = self
+                        .dial_retries
+                        .extract_if(.., |redial| redial.at <= now)
+                        .map(|redial| redial.retry)
+                        .collect::<Vec<_>>();
+                    for retry in due {
+                        self.start_dial(retry);
+                    }
+                }
+                _ = wait_until(self.document_lookups.iter().map(|lookup| lookup.deadline).min()) => {
+                    self.complete_document_lookups();
+                }
+                _ = wait_until(self.next_peer_request_timeout()) => {
+                    self.expire_peer_requests();
+                }
+                _ = maintenance.tick() => {
+                    self.expire_identify_cache();
+                    self.advance_provider_warmup();
+                    self.reap_idle_peers();
+                    if self.last_relay_probe.elapsed() >= RELAY_PROBE_INTERVAL {
+                        self.probe_relays();
+                    }
+                }
                 event = self.swarm.select_next_some() => {
-                    self.handle_swarm_event(&event);
-                    let _ = self.event_tx.send(Arc::new(event));
+                    // control requests carry their response channel, so they are consumed here
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Control(event)) = event {
+                        self.handle_control_event(event);
+                    } else if let SwarmEvent::Behaviour(BehaviourEvent::DocumentFetch(event)) = event {
+                        self.handle_document_fetch_event(event);
+                    } else if self.validate_gossip(&event) {
+                        self.handle_swarm_event(&event);
+                        if let Some(node_event) = node_event(&event) {
+                            let _ = self.node_event_tx.send(node_event);
+                        }
+                        let _ = self.event_tx.send(Arc::new(event));
+                    }
                 }
                 command = self.command_rx.recv() => {
                     if let Some(command) = command {
                         match command {
                             SwarmCommand::Dial(addr) => {
                                 debug!("Dialing {}", addr);
-                                match self.swarm.dial(addr.clone()) {
-                                    Ok(()) => {
-                                        debug!("Dialed {}", addr);
-                                    }
-                                    Err(err) => {
-                                        debug!("Failed to dial {}: {:?}", addr, err);
-                                    }
-                                }
+                                self.start_dial(DialRetry {
+                                    target: DialTarget::Address(addr),
+                                    failures: 0,
+                                });
                             }
                             SwarmCommand::BeginProviderRole(key) => {
                                 info!("Starting to provide for key {:?}", key);
@@ -90,37 +1000,43 @@ impl SwarmManager {
                                     }
                                 }
                             }
+                            SwarmCommand::ProvideWhenReady { key, document_id, readiness } => {
+                                info!("Announcing as provider for key {:?} once {} is ready", key, document_id);
+                                self.provider_warmup = Some(ProviderWarmup::new(key, document_id, readiness));
+                                self.advance_provider_warmup();
+                            }
                             SwarmCommand::StopProviderRole(key) => {
                                 debug!("Stopping to provide for key {:?}", key);
+                                if self.provider_warmup.as_ref().is_some_and(|warmup| warmup.key == key) {
+                                    self.provider_warmup = None;
+                                }
                                 self.swarm.behaviour_mut().kademlia.stop_providing(&key);
                                 debug!("Stopped providing for key");
                             }
-                            SwarmCommand::FindProviders(key) => {
+                            SwarmCommand::FindProviders(key, reply) => {
                                 debug!("Finding providers for key {:?}", key);
                                 let query_id = self.swarm.behaviour_mut().kademlia.get_providers(key);
                                 debug!("Started get_providers query with id {:?}", query_id);
+                                self.provider_queries.insert(query_id, ProviderQuery {
+                                    providers: HashSet::new(),
+                                    reply,
+                                });
                             }
-                            SwarmCommand::ListConnections => {
-                                let connections = self.swarm.connected_peers().collect::<Vec<_>>();
-                                if connections.is_empty() {
-                                    info!("No active connections");
-                                } else {
-                                    info!("Active connections:");
-                                    for conn in connections {
-                                        info!(" - {:?}", conn);
-                                    }
+                            SwarmCommand::ListConnections(reply) => {
+                                let _ = reply.send(self.swarm.connected_peers().copied().collect());
+                            }
+                            SwarmCommand::Disconnect(peer_id) => {
+                                match self.swarm.disconnect_peer_id(peer_id) {
+                                    Ok(()) => info!("Disconnecting {}", peer_id),
+                                    Err(()) => info!("Not connected to {}", peer_id),
                                 }
                             }
                             SwarmCommand::DialPeerId(peer_id) => {
                                 debug!("Dialing peer id {}", peer_id);
-                                match self.swarm.dial(peer_id) {
-                                    Ok(()) => {
-                                        debug!("Dialed peer {peer_id} successfully");
-                                    }
-                                    Err(err) => {
-                                        debug!("Failed to dial peer id {}: {:?}", peer_id, err);
-                                    }
-                                }
+                                self.start_dial(DialRetry {
+                                    target: DialTarget::Peer(peer_id),
+                                    failures: 0,
+                                });
                             },
                             SwarmCommand::PutTestValue(key, value) => {
                                 tracing::info!("Putting test value {} at {}", value, key);
@@ -128,15 +1044,250 @@ impl SwarmManager {
                                     doc.put(automerge::ROOT, key, value).unwrap();
                                 });
                             },
-                            SwarmCommand::GetTestValue(key) => {
-                                if let Some(doc) = self.swarm.behaviour_mut().automerge.get_document("test") {
-                                    if let Ok(Some(value)) = doc.get(automerge::ROOT, &key) {
-                                        tracing::info!("Got value for key {}: {:?}", key, value.0);
-                                    } else {
-                                        tracing::info!("Key {} not found in document", key);
+                            SwarmCommand::GetTestValue(key, reply) => {
+                                let value = self.swarm.behaviour().automerge.get_document("test")
+                                    .and_then(|doc| doc.get(automerge::ROOT, &key).ok().flatten())
+                                    .map(|(value, _)| value.to_str().map(str::to_string).unwrap_or_else(|| value.to_string()));
+                                let _ = reply.send(value);
+                            },
+                            SwarmCommand::PutBatch(document_id, values) => {
+                                let automerge = &mut self.swarm.behaviour_mut().automerge;
+                                if automerge.get_document(&document_id).is_none() {
+                                    tracing::info!("Document '{}' not found", document_id);
+                                } else if let Err(err) = automerge.put_json(&document_id, &values) {
+                                    warn!("Failed to apply batch to {}: {:?}", document_id, err);
+                                } else {
+                                    tracing::info!("Applied {} keys to {}", values.len(), document_id);
+                                }
+                            },
+                            SwarmCommand::DocumentExists(document_id) => {
+                                self.start_document_lookup(document_id);
+                            },
+                            SwarmCommand::ShareAddress(reply) => {
+                                let _ = reply.send(self.shared_addresses());
+                            },
+                            SwarmCommand::SyncDocuments(peer_id) => {
+                                let automerge = &mut self.swarm.behaviour_mut().automerge;
+                                let document_ids = automerge.document_ids().cloned().collect::<Vec<_>>();
+                                for document_id in document_ids {
+                                    automerge.request_sync(peer_id, &document_id);
+                                }
+                            },
+                            SwarmCommand::PeerDocuments(peer_id, reply) => {
+                                if self.swarm.behaviour_mut().automerge.request_available_documents(peer_id) {
+                                    self.peer_document_requests.entry(peer_id).or_default().push(PendingReply {
+                                        reply,
+                                        started_at: Instant::now(),
+                                    });
+                                } else {
+                                    let _ = reply.send(Err(PeerRequestError::NotConnected));
+                                }
+                            },
+                            SwarmCommand::Snapshot(path, reply) => {
+                                let automerge = &mut self.swarm.behaviour_mut().automerge;
+                                let path = path.unwrap_or_else(|| {
+                                    let timestamp = std::time::SystemTime::now()
+                                        .duration_since(std::time::UNIX_EPOCH)
+                                        .unwrap_or_default()
+                                        .as_secs();
+                                    automerge.data_dir().join(format!("snapshot-{timestamp}.archive"))
+                                });
+                                let result = automerge.export_archive(&path).map(|count| {
+                                    info!("Wrote {} documents to {}", count, path.display());
+                                    path
+                                });
+                                let _ = reply.send(result);
+                            },
+                            SwarmCommand::RestoreSnapshot(path, reply) => {
+                                let _ = reply.send(self.swarm.behaviour_mut().automerge.import_archive(&path));
+                            },
+                            SwarmCommand::Converged(peer_id, document_id, reply) => {
+                                if self.swarm.behaviour_mut().automerge.request_heads(peer_id, &document_id) {
+                                    self.convergence_requests.entry((peer_id, document_id)).or_default().push(PendingReply {
+                                        reply,
+                                        started_at: Instant::now(),
+                                    });
+                                } else {
+                                    let _ = reply.send(Err(PeerRequestError::NotConnected));
+                                }
+                            },
+                            SwarmCommand::ReleaseRelay(reply) => {
+                                self.release_relay(reply);
+                            },
+                            SwarmCommand::Shutdown(reply) => {
+                                self.shutdown(reply);
+                            },
+                            SwarmCommand::ChangeSizes(document_id, reply) => {
+                                let _ = reply.send(self.swarm.behaviour_mut().automerge.change_sizes(&document_id));
+                            },
+                            SwarmCommand::DocumentHeads(document_id, reply) => {
+                                let _ = reply.send(self.document_heads(&document_id));
+                            },
+                            SwarmCommand::DocumentPeers(document_id, reply) => {
+                                let _ = reply.send(self.swarm.behaviour_mut().automerge.document_peers(&document_id));
+                            },
+                            SwarmCommand::PutRecord { key, value, reply } => {
+                                self.start_record_put(RecordPut {
+                                    record: kad::Record::new(key, value),
+                                    attempts: 0,
+                                    reply,
+                                });
+                            },
+                            SwarmCommand::GetRecord(key, reply) => {
+                                let query_id = self.swarm.behaviour_mut().kademlia.get_record(key);
+                                self.record_gets.insert(query_id, reply);
+                            },
+                            SwarmCommand::SetLabels(document_id, labels, reply) => {
+                                let _ = reply.send(self.swarm.behaviour_mut().automerge.set_labels(&document_id, labels));
+                            },
+                            SwarmCommand::DeleteDocument(document_id, reply) => {
+                                let deleted = self.swarm.behaviour_mut().automerge.delete_document(&document_id);
+                                if deleted {
+                                    self.swarm.behaviour_mut().kademlia.stop_providing(&kad::RecordKey::new(&document_id));
+                                }
+                                let _ = reply.send(deleted);
+                            },
+                            SwarmCommand::CompactDocument { document_id, reply } => {
+                                let _ = reply.send(self.swarm.behaviour_mut().automerge.compact_document(&document_id));
+                            },
+                            SwarmCommand::SubscribeDocument(document_id) => {
+                                self.swarm.behaviour_mut().automerge.subscribe_document(&document_id);
+                            },
+                            SwarmCommand::UnsubscribeDocument(document_id) => {
+                                self.swarm.behaviour_mut().automerge.unsubscribe_document(&document_id);
+                            },
+                            SwarmCommand::ListDocuments(label, reply) => {
+                                let automerge = &self.swarm.behaviour().automerge;
+                                let document_ids = match label {
+                                    Some(label) => automerge.documents_with_label(&label),
+                                    None => {
+                                        let mut document_ids = automerge.document_ids().cloned().collect::<Vec<_>>();
+                                        document_ids.sort();
+                                        document_ids
+                                    }
+                                };
+                                let documents = document_ids
+                                    .into_iter()
+                                    .map(|document_id| {
+                                        let labels = automerge.labels(&document_id);
+                                        (document_id, labels)
+                                    })
+                                    .collect();
+                                let _ = reply.send(documents);
+                            },
+                            SwarmCommand::Metrics(reply) => {
+                                let network_info = self.swarm.network_info();
+                                let _ = reply.send(self.metrics.snapshot(
+                                    network_info.num_peers(),
+                                    network_info.connection_counters().num_connections(),
+                                ));
+                            },
+                            SwarmCommand::Liveness(reply) => {
+                                let _ = reply.send(Liveness {
+                                    connected_peers: self.swarm.connected_peers().copied().collect(),
+                                    active_syncs: self.swarm.behaviour().automerge.syncs_in_progress(),
+                                });
+                            }
+                            SwarmCommand::CollectGarbage { ttl, dry_run, reply } => {
+                                let collected = self.swarm.behaviour_mut().automerge.collect_garbage(ttl, dry_run);
+                                if !dry_run {
+                                    for document_id in &collected {
+                                        self.swarm.behaviour_mut().kademlia.stop_providing(&kad::RecordKey::new(document_id));
+                                    }
+                                }
+                                let _ = reply.send(collected);
+                            },
+                            SwarmCommand::RotatePreSharedKey { key, key_id, grace, reply } => {
+                                let result = match &self.noise {
+                                    Some(noise) => noise.rotate(common::string_to_32_bytes(&key).to_vec(), key_id.as_deref(), grace),
+                                    None => Err(libp2p::noise::Error::AuthenticationFailed),
+                                };
+                                if result.is_ok() {
+                                    info!("Pre-shared key rotated, accepting the previous key for {:?}", grace);
+                                }
+                                let _ = reply.send(result);
+                            },
+                            SwarmCommand::Reconnect(reply) => {
+                                let peers = self.swarm.connected_peers().copied().collect::<Vec<_>>();
+                                info!("Reconnecting to {} peers", peers.len());
+                                for peer_id in &peers {
+                                    if self.swarm.disconnect_peer_id(*peer_id).is_ok() {
+                                        self.reconnecting_peers.insert(*peer_id);
                                     }
+                                }
+                                let _ = reply.send(peers.len());
+                            },
+                            SwarmCommand::ConnectivityReport(peer, reply) => {
+                                self.start_connectivity_report(peer, reply);
+                            },
+                            SwarmCommand::MeshPeers(topic, reply) => {
+                                let _ = reply.send(self.topic_peers(&topic.hash()));
+                            },
+                            SwarmCommand::Subscribe(topic) => {
+                                match self.swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(&topic)) {
+                                    Ok(true) => info!("Subscribed to {topic}"),
+                                    Ok(false) => info!("Already subscribed to {topic}"),
+                                    Err(err) => warn!("Failed to subscribe to {topic}: {err:?}"),
+                                }
+                            },
+                            SwarmCommand::Unsubscribe(topic) => {
+                                if self.swarm.behaviour_mut().gossipsub.unsubscribe(&gossipsub::IdentTopic::new(&topic)) {
+                                    info!("Unsubscribed from {topic}");
+                                } else {
+                                    info!("Not subscribed to {topic}");
+                                }
+                            },
+                            SwarmCommand::Publish { topic, data } => {
+                                if let Err(err) = self.swarm.behaviour_mut().gossipsub.publish(gossipsub::IdentTopic::new(&topic), data) {
+                                    warn!("Failed to publish on {topic}: {err:?}");
+                                }
+                            },
+                            SwarmCommand::ReservationLimits(reply) => {
+                                let _ = reply.send(self.reservation_limits.clone());
+                            },
+                            SwarmCommand::ListReservations(reply) => {
+                                let reservations = self
+                                    .relay_candidates
+                                    .iter()
+                                    .map(|candidate| {
+                                        let remaining = self
+                                            .reservation_limits
+                                            .get(&candidate.peer_id)
+                                            .map(|limits| RESERVATION_TTL.saturating_sub(limits.accepted_at.elapsed()));
+                                        (candidate.peer_id, remaining)
+                                    })
+                                    .collect();
+                                let _ = reply.send(reservations);
+                            },
+                            SwarmCommand::RenewReservation(relay_peer_id, reply) => {
+                                let _ = reply.send(self.renew_reservation(relay_peer_id));
+                            },
+                            SwarmCommand::Status(reply) => {
+                                let network_info = self.swarm.network_info();
+                                let _ = reply.send(NodeStatus {
+                                    peer_id: *self.swarm.local_peer_id(),
+                                    listen_addrs: self.swarm.listeners().cloned().collect(),
+                                    addresses: self.shared_addresses(),
+                                    connected_peers: network_info.num_peers(),
+                                    connections: network_info.connection_counters().num_established(),
+                                });
+                            },
+                            SwarmCommand::GetPeerInfo(peer_id, reply) => {
+                                let _ = reply.send(self.identify_cache.get(&peer_id).map(PeerInfo::from));
+                            },
+                            SwarmCommand::SendRequest { peer, data, reply } => {
+                                let request_id = self.swarm.behaviour_mut().control.send_request(&peer, data);
+                                self.control_requests.insert(request_id, reply);
+                            },
+                            SwarmCommand::FetchDocument { peer, document_id, reply } => {
+                                let request_id = self.swarm.behaviour_mut().document_fetch.send_request(&peer, document_id);
+                                self.document_fetches.insert(request_id, reply);
+                            },
+                            SwarmCommand::DocumentJson(document_id) => {
+                                if let Some(json) = self.swarm.behaviour().automerge.document_to_json(&document_id) {
+                                    tracing::info!("Document {}: {:#}", document_id, json);
                                 } else {
-                                    tracing::info!("Document 'test' not found");
+                                    tracing::info!("Document '{}' not found", document_id);
                                 }
                             },
                         }
@@ -150,92 +1301,1815 @@ impl SwarmManager {
         }
     }
 
-    fn handle_swarm_event(&mut self, event: &SwarmEvent<BehaviourEvent>) {
+    fn handle_control_event(&mut self, event: request_response::Event<Vec<u8>, Vec<u8>>) {
         match event {
-            SwarmEvent::NewListenAddr {
-                address,
-                listener_id,
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+                ..
             } => {
-                info!("Listening on {} (listener_id={})", address, listener_id);
-            }
-            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                if let Some(peer_id) = peer_id {
-                    tracing::debug!("Failed to dial {peer_id}: {error:?}");
-                } else {
-                    tracing::debug!("Failed to dial unknown peer: {error:?}");
+                let response = (self.control_handler)(peer, request);
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .control
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    debug!("Could not respond to control request from {peer}, connection closed");
                 }
             }
-            SwarmEvent::ConnectionClosed {
-                peer_id,
-                endpoint,
-                cause,
+            request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
                 ..
             } => {
-                if endpoint.is_relayed() {
-                    tracing::debug!("Relay circuit closed from {peer_id} because {cause:?}");
-                } else {
-                    tracing::debug!("Connection closed from {peer_id} because {cause:?}");
+                if let Some(reply) = self.control_requests.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
                 }
             }
-            SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
             } => {
-                debug!("Connected to {peer_id}, endpoint: {endpoint:?}");
-
-                // bootstrap kademlia once connected to the relay
-                // happens automatically?
-                if &self.relay_peer_id == peer_id {
-                    debug!("Connected to relay, starting kademlia bootstrap");
-                    match self.swarm.behaviour_mut().kademlia.bootstrap() {
-                        Ok(result) => {
-                            debug!("Started kademlia bootstrap: {result:?}");
-                        }
-                        Err(err) => {
-                            warn!("Failed to start kademlia bootstrap: {err:?}");
-                        }
-                    }
+                debug!("Control request to {peer} failed: {error}");
+                if let Some(reply) = self.control_requests.remove(&request_id) {
+                    let _ = reply.send(Err(request_error(&error)));
                 }
             }
-            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent {
-                peer_id,
-                connection_id,
-            })) => {
-                if &self.relay_peer_id == peer_id {
-                    tracing::debug!(
-                        "Sent identify to relay {peer_id} via {connection_id}, should learn our public address soon"
-                    );
-                } else {
-                    tracing::debug!("Sent identify to {peer_id} via {connection_id}");
-                }
-                self.sent_identify = true;
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                debug!("Failed to answer control request from {peer}: {error}");
             }
-            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::v2::client::Event {
-                result,
-                tested_addr,
-                server,
-                ..
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    fn handle_document_fetch_event(
+        &mut self,
+        event: request_response::Event<String, Option<Vec<u8>>>,
+    ) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request: document_id,
+                        channel,
+                        ..
+                    },
+                ..
+            } => {
+                debug!("Peer {peer} fetches document {document_id}");
+                let document = self
+                    .swarm
+                    .behaviour_mut()
+                    .automerge
+                    .save_document(&document_id);
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .document_fetch
+                    .send_response(channel, document)
+                    .is_err()
+                {
+                    debug!("Could not send document {document_id} to {peer}, connection closed");
+                }
+            }
+            request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                if let Some(reply) = self.document_fetches.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            }
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                debug!("Document fetch from {peer} failed: {error}");
+                if let Some(reply) = self.document_fetches.remove(&request_id) {
+                    let _ = reply.send(Err(request_error(&error)));
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                debug!("Failed to answer document fetch from {peer}: {error}");
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    fn start_record_put(&mut self, mut put: RecordPut) {
+        put.attempts += 1;
+        match self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .put_record(put.record.clone(), self.put_quorum)
+        {
+            Ok(query_id) => {
+                self.record_puts.insert(query_id, put);
+            }
+            Err(err) => {
+                let _ = put
+                    .reply
+                    .send(Err(format!("failed to store record locally: {err}")));
+            }
+        }
+    }
+
+    /// Retries a failed put with backoff, or reports the failure once the attempts are used up
+    fn retry_record_put(&mut self, put: RecordPut, error: kad::PutRecordError) {
+        if put.attempts >= self.max_put_attempts {
+            let _ = put
+                .reply
+                .send(Err(format!("{error:?} after {} attempts", put.attempts)));
+            return;
+        }
+
+        let backoff = RECORD_PUT_INITIAL_BACKOFF.saturating_mul(1 << (put.attempts - 1).min(16));
+        debug!(
+            "Put of record {:?} failed ({error:?}), retrying in {:?}",
+            put.record.key, backoff
+        );
+        self.record_put_retries
+            .push((tokio::time::Instant::now() + backoff, put));
+    }
+
+    /// Dials a target of a dial command. Retries go by the peer id when there is one, so
+    /// addresses kademlia learned since the last attempt are tried as well.
+    fn start_dial(&mut self, retry: DialRetry) {
+        let opts = match (&retry.target, retry.target.peer_id()) {
+            (DialTarget::Address(address), Some(peer_id)) if retry.failures > 0 => {
+                DialOpts::peer_id(peer_id)
+                    .addresses(vec![address.clone()])
+                    .extend_addresses_through_behaviour()
+                    .build()
+            }
+            (DialTarget::Address(address), _) => DialOpts::from(address.clone()),
+            (DialTarget::Peer(peer_id), _) => DialOpts::from(*peer_id),
+        };
+        let connection_id = opts.connection_id();
+        match self.swarm.dial(opts) {
+            Ok(()) => {
+                debug!("Dialed {}", retry.target);
+                self.pending_dials.insert(connection_id, retry);
+            }
+            // already connected or dialing, nothing left to retry
+            Err(DialError::DialPeerConditionFalse(_)) => {
+                debug!("Not dialing {}, already connected or dialing", retry.target);
+            }
+            Err(err) => {
+                tracing::trace!("Dial error details: {err:?}");
+                self.retry_dial(retry, dial_error::classify(&err));
+            }
+        }
+    }
+
+    /// Schedules a failed dial again with backoff, or reports it once the retries are used up.
+    /// A failed relayed dial or one to a peer without known addresses first looks the peer up
+    /// in the DHT, which may turn up a relay it is reachable through now, and is redialed once
+    /// the lookup finished. A target already waiting for a redial isn't scheduled twice.
+    fn retry_dial(&mut self, mut retry: DialRetry, failure: DialFailure) {
+        if self
+            .dial_retries
+            .iter()
+            .any(|redial| redial.retry.target == retry.target)
+        {
+            debug!(
+                "Dial of {} failed ({failure}), a redial is already scheduled",
+                retry.target
+            );
+            return;
+        }
+        retry.failures += 1;
+        if retry.failures > self.max_dial_retries {
+            warn!(
+                "Giving up on dialing {} after {} attempts: {failure}",
+                retry.target, retry.failures
+            );
+            let _ = self.node_event_tx.send(NodeEvent::DialFailed {
+                target: retry.target,
+                attempts: retry.failures,
+                failure,
+            });
+            return;
+        }
+
+        let lookup = if (retry.target.is_relayed() || failure == DialFailure::NoAddresses)
+            && let Some(peer_id) = retry.target.peer_id()
+        {
+            debug!("Looking up {peer_id} in the DHT before redialing it");
+            Some(
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_closest_peers(peer_id),
+            )
+        } else {
+            None
+        };
+
+        let mut backoff = DIAL_RETRY_INITIAL_BACKOFF
+            .saturating_mul(1 << (retry.failures - 1).min(16))
+            .min(DIAL_RETRY_MAX_BACKOFF);
+        if lookup.is_some() {
+            backoff = backoff.max(DIAL_LOOKUP_TIMEOUT);
+        }
+        debug!(
+            "Dial of {} failed ({failure}), retrying in at most {:?}",
+            retry.target, backoff
+        );
+        self.dial_retries.push(ScheduledRedial {
+            retry,
+            at: tokio::time::Instant::now() + backoff,
+            lookup,
+        });
+    }
+
+    /// Redials the targets waiting for the finished DHT lookup
+    fn finish_dial_lookup(&mut self, query_id: kad::QueryId) {
+        let redials = self
+            .dial_retries
+            .extract_if(.., |redial| redial.lookup == Some(query_id))
+            .map(|redial| redial.retry)
+            .collect::<Vec<_>>();
+        for retry in redials {
+            debug!("Lookup finished, redialing {}", retry.target);
+            self.start_dial(retry);
+        }
+    }
+
+    /// Publishes the changes accumulated since the last publish of every document whose window
+    /// closed. Failed publishes keep the old heads, so the changes go out with the next one.
+    fn publish_due_changes(&mut self) {
+        for document_id in self.change_throttle.take_due(tokio::time::Instant::now()) {
+            let since = self.change_throttle.published_heads(&document_id).to_vec();
+            let Some((changes, heads)) = self
+                .swarm
+                .behaviour_mut()
+                .automerge
+                .changes_since(&document_id, &since)
+            else {
+                continue;
+            };
+            if changes.is_empty() {
+                continue;
+            }
+
+            match self
+                .swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(document_topic(&document_id), changes)
+            {
+                Ok(_) => self
+                    .change_throttle
+                    .set_published_heads(&document_id, heads),
+                Err(err) => debug!("Failed to publish changes of {document_id}: {err:?}"),
+            }
+        }
+    }
+
+    /// Subscribes to the topic the document's changes are published on, so changes of peers
+    /// reach us even without a sync running
+    fn subscribe_document_topic(&mut self, document_id: &str) {
+        if let Err(err) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&document_topic(document_id))
+        {
+            debug!("Failed to subscribe to the changes of {document_id}: {err:?}");
+        }
+    }
+
+    /// Applies changes a peer published on a document topic. Without unpublished changes of our
+    /// own, the received ones count as published, so they aren't published again.
+    fn apply_published_changes(&mut self, source: PeerId, document_id: &str, changes: &[u8]) {
+        let up_to_date = !self.change_throttle.is_pending(document_id);
+        match self
+            .swarm
+            .behaviour_mut()
+            .automerge
+            .apply_changes(source, document_id, changes)
+        {
+            Ok(Some(heads)) if up_to_date => {
+                self.change_throttle.set_published_heads(document_id, heads)
+            }
+            Ok(_) => {}
+            Err(err) => debug!("Failed to apply changes of {document_id} from {source}: {err:?}"),
+        }
+    }
+
+    /// Stops advertising every direct address once AutoNAT found none of them reachable, leaving
+    /// only the relayed ones, so the DHT isn't filled with addresses nobody can dial
+    fn fall_back_to_relayed_addresses(&mut self) {
+        let direct = self
+            .swarm
+            .external_addresses()
+            .filter(|address| !reachability::is_relayed(address))
+            .filter(|address| !self.reachability.is_confirmed(address))
+            .cloned()
+            .collect::<Vec<_>>();
+        for address in &direct {
+            self.swarm.remove_external_address(address);
+        }
+        if self.swarm.behaviour().relay_server.is_enabled() {
+            warn!("AutoNAT found no reachable address, peers won't be able to use us as a relay");
+        } else {
+            info!("AutoNAT found no reachable address, advertising only relayed addresses");
+        }
+    }
+
+    fn release_relay(&mut self, reply: oneshot::Sender<()>) {
+        if !self.is_relay_connected() {
+            let _ = reply.send(());
+            return;
+        }
+
+        let closing_listeners = self
+            .relay_candidates
+            .iter_mut()
+            .filter_map(|candidate| candidate.circuit_listener.take())
+            .collect::<HashSet<_>>();
+        for listener_id in &closing_listeners {
+            self.swarm.remove_listener(*listener_id);
+        }
+        info!(
+            "Releasing relays, closing {} circuit listeners",
+            closing_listeners.len()
+        );
+
+        // never re-dial the relays we're leaving
+        self.relay_redial_at = None;
+        self.relay_release = Some(RelayRelease {
+            reply,
+            closing_listeners,
+            deadline: tokio::time::Instant::now() + RELAY_RELEASE_TIMEOUT,
+        });
+        self.disconnect_released_relay();
+    }
+
+    /// Closes the relay connections once all circuit listeners are gone
+    fn disconnect_released_relay(&mut self) {
+        if self
+            .relay_release
+            .as_ref()
+            .is_some_and(|release| release.closing_listeners.is_empty())
+        {
+            for candidate in &self.relay_candidates {
+                let _ = self.swarm.disconnect_peer_id(candidate.peer_id);
+            }
+        }
+    }
+
+    fn is_relay_connected(&self) -> bool {
+        self.relay_candidates
+            .iter()
+            .any(|candidate| self.swarm.is_connected(&candidate.peer_id))
+    }
+
+    fn finish_relay_release(&mut self) {
+        if let Some(release) = self.relay_release.take() {
+            let _ = release.reply.send(());
+        }
+        self.finish_shutdown();
+    }
+
+    fn shutdown(&mut self, reply: oneshot::Sender<()>) {
+        info!("Shutting down the swarm");
+        let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+        // all our topics are ident topics, whose hash is the topic itself
+        for topic in gossipsub.topics().cloned().collect::<Vec<_>>() {
+            gossipsub.unsubscribe(&gossipsub::IdentTopic::new(topic.into_string()));
+        }
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        let provided_keys = kademlia
+            .store_mut()
+            .provided()
+            .map(|record| record.key.clone())
+            .collect::<HashSet<_>>();
+        for key in provided_keys {
+            kademlia.stop_providing(&key);
+        }
+
+        self.shutdown_reply = Some(reply);
+        // the release replies through the shutdown, not its own channel
+        let (release_tx, _) = oneshot::channel();
+        self.release_relay(release_tx);
+        if self.relay_release.is_none() {
+            self.finish_shutdown();
+        }
+    }
+
+    /// Keeps the swarm running for a moment once the relays are released, so the queued
+    /// unsubscribes and provider removals reach the peers before the connections close
+    fn finish_shutdown(&mut self) {
+        if self.shutdown_reply.is_none() || self.shutdown_flush.is_some() {
+            return;
+        }
+        if self.swarm.connected_peers().next().is_none() {
+            self.stop();
+            return;
+        }
+        self.shutdown_flush = Some(ShutdownFlush::Unsubscribing(
+            tokio::time::Instant::now() + SHUTDOWN_FLUSH_TIMEOUT,
+        ));
+    }
+
+    /// Closes the remaining connections once the unsubscribes went out, and stops the run loop
+    /// if they didn't close in time
+    fn advance_shutdown(&mut self) {
+        match self.shutdown_flush.take() {
+            Some(ShutdownFlush::Unsubscribing(_)) => {
+                for peer_id in self.swarm.connected_peers().copied().collect::<Vec<_>>() {
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                }
+                self.shutdown_flush = Some(ShutdownFlush::Closing(
+                    tokio::time::Instant::now() + SHUTDOWN_FLUSH_TIMEOUT,
+                ));
+            }
+            Some(ShutdownFlush::Closing(_)) => {
+                warn!("Connections did not close in time, shutting down anyway");
+                self.stop();
+            }
+            None => {}
+        }
+    }
+
+    /// Ends the run loop and answers the shutdown
+    fn stop(&mut self) {
+        self.stopped = true;
+        info!("Swarm shut down");
+        if let Some(reply) = self.shutdown_reply.take() {
+            let _ = reply.send(());
+        }
+    }
+
+    fn is_relay_candidate(&self, peer_id: &PeerId) -> bool {
+        self.relay_candidates
+            .iter()
+            .any(|candidate| &candidate.peer_id == peer_id)
+    }
+
+    /// Reports whether a gossipsub message passes the allow list, returning `false` for rejected
+    /// messages. Every other event passes.
+    fn validate_gossip(&mut self, event: &SwarmEvent<BehaviourEvent>) -> bool {
+        let SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            propagation_source,
+            message_id,
+            message,
+        })) = event
+        else {
+            return true;
+        };
+        let Some(allow_list) = &self.gossip_allow_list else {
+            return true;
+        };
+
+        let allowed = message
+            .source
+            .is_some_and(|source| allow_list.contains(&source));
+        let acceptance = if allowed {
+            gossipsub::MessageAcceptance::Accept
+        } else {
+            debug!(
+                "Rejecting gossipsub message on {} from {:?}, not in the allow list",
+                message.topic, message.source
+            );
+            gossipsub::MessageAcceptance::Reject
+        };
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(message_id, propagation_source, acceptance);
+        allowed
+    }
+
+    /// Disconnects peers idle for longer than the reaper's timeout, see
+    /// [`Self::with_idle_peer_reaper`]
+    fn reap_idle_peers(&mut self) {
+        let Some(timeout) = self.idle_peer_timeout else {
+            return;
+        };
+        let automerge = &self.swarm.behaviour().automerge;
+        let idle = self
+            .swarm
+            .connected_peers()
+            .filter(|peer_id| !self.is_relay_candidate(peer_id))
+            .filter(|peer_id| !automerge.is_syncing_with(**peer_id))
+            .filter(|peer_id| {
+                let last_active = self
+                    .peer_activity
+                    .get(*peer_id)
+                    .copied()
+                    .max(automerge.last_peer_activity(**peer_id));
+                last_active.is_none_or(|last_active| last_active.elapsed() >= timeout)
+            })
+            .copied()
+            .collect::<Vec<_>>();
+
+        for peer_id in idle {
+            info!("Disconnecting idle peer {peer_id}");
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+    }
+
+    /// Bootstraps kademlia through the relays we're connected to and announces our documents, so
+    /// other peers can find them through the DHT
+    fn announce_through_relay(&mut self) {
+        debug!("Connected to relay, starting kademlia bootstrap");
+        match self.swarm.behaviour_mut().kademlia.bootstrap() {
+            Ok(result) => {
+                debug!("Started kademlia bootstrap: {result:?}");
+            }
+            Err(err) => {
+                warn!("Failed to start kademlia bootstrap: {err:?}");
+            }
+        }
+
+        let document_ids = self
+            .swarm
+            .behaviour()
+            .automerge
+            .document_ids()
+            .cloned()
+            .collect::<Vec<_>>();
+        // provider roles of a previous run are kept by a persistent store, announce them again
+        // right away instead of waiting for the republish interval
+        let mut provided_keys = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .store_mut()
+            .provided()
+            .map(|record| record.key.clone())
+            .collect::<HashSet<_>>();
+        for document_id in document_ids {
+            let key = kad::RecordKey::new(&document_id);
+            provided_keys.remove(&key);
+            if let Err(err) = self.swarm.behaviour_mut().kademlia.start_providing(key) {
+                warn!("Failed to announce document {document_id}: {err:?}");
+            }
+        }
+        for key in provided_keys {
+            if let Err(err) = self
+                .swarm
+                .behaviour_mut()
+                .kademlia
+                .start_providing(key.clone())
+            {
+                warn!("Failed to announce provider record {key:?}: {err:?}");
+            }
+        }
+    }
+
+    /// Applies a step of the startup self-check, reporting success once every step completed
+    fn update_self_check(&mut self, update: impl FnOnce(&mut SelfCheck)) {
+        let Some(check) = self.self_check.as_mut() else {
+            return;
+        };
+        update(check);
+        if check.passed() {
+            info!(
+                "Startup self-check passed: relay connected, identified and reservation accepted"
+            );
+            self.self_check = None;
+        }
+    }
+
+    /// Dials every relay besides the primary that isn't connected, the primary is dialed with
+    /// its own backoff by [`Self::dial_relay`]
+    fn probe_relays(&mut self) {
+        let now = Instant::now();
+        self.last_relay_probe = now;
+        for candidate in self.relay_candidates.iter_mut() {
+            if candidate.peer_id == self.relay_peer_id {
+                candidate.probe_started_at.get_or_insert(now);
+                continue;
+            }
+            if self.swarm.is_connected(&candidate.peer_id) {
+                continue;
+            }
+
+            candidate.probe_started_at = Some(now);
+            candidate.response_time = None;
+            let address = candidate
+                .address
+                .clone()
+                .with_p2p(candidate.peer_id)
+                .unwrap_or_else(|address| address);
+            debug!("Probing relay {address}");
+            if let Err(err) = self.swarm.dial(address) {
+                debug!("Failed to probe relay {}: {err:?}", candidate.peer_id);
+            }
+        }
+    }
+
+    /// Makes the relay the primary one, which is redialed with backoff and used for relayed dials
+    fn select_relay(&mut self, peer_id: PeerId) {
+        let Some(candidate) = self
+            .relay_candidates
+            .iter()
+            .find(|candidate| candidate.peer_id == peer_id)
+        else {
+            return;
+        };
+
+        info!(
+            "Selected relay {} (responded in {:?})",
+            peer_id, candidate.response_time
+        );
+        self.relay_selected = true;
+        if peer_id != self.relay_peer_id {
+            self.relay_peer_id = peer_id;
+            self.relay_address = candidate.address.clone();
+            self.relay_redial_at = None;
+            self.relay_dial_attempts = 0;
+        }
+    }
+
+    /// Forgets the reservation on a relay that refused or dropped it, and makes another relay the
+    /// primary if it was ours, preferring one that holds a reservation for us. The circuit listen
+    /// is retried after [`CIRCUIT_LISTEN_RETRY_DELAY`], or once the relay connects again.
+    fn handle_reservation_failure(&mut self, relay_peer_id: PeerId) {
+        self.active_reservations.remove(&relay_peer_id);
+        self.reservation_limits.remove(&relay_peer_id);
+        if let Some(candidate) = self
+            .relay_candidates
+            .iter_mut()
+            .find(|candidate| candidate.peer_id == relay_peer_id)
+        {
+            candidate.circuit_listen_retry_at =
+                Some(tokio::time::Instant::now() + CIRCUIT_LISTEN_RETRY_DELAY);
+        }
+        if relay_peer_id != self.relay_peer_id {
+            return;
+        }
+
+        let others = self
+            .relay_candidates
+            .iter()
+            .map(|candidate| candidate.peer_id)
+            .filter(|peer_id| *peer_id != relay_peer_id)
+            .collect::<Vec<_>>();
+        let fallback = others
+            .iter()
+            .find(|peer_id| self.active_reservations.contains(*peer_id))
+            .or_else(|| {
+                others
+                    .iter()
+                    .find(|peer_id| self.swarm.is_connected(peer_id))
+            })
+            .or_else(|| others.first())
+            .copied();
+        let Some(fallback) = fallback else {
+            warn!("No other relay to fall back to, staying with {relay_peer_id}");
+            return;
+        };
+
+        info!("Falling back from relay {relay_peer_id} to {fallback}");
+        self.select_relay(fallback);
+        if self.swarm.is_connected(&fallback) {
+            if self.relay_candidates.iter().any(|candidate| {
+                candidate.peer_id == fallback && candidate.circuit_listener.is_none()
+            }) {
+                self.listen_on_relay_circuit(fallback);
+            }
+        } else {
+            self.dial_relay();
+        }
+    }
+
+    /// Keeps syncing the documents with a peer over the relayed connection after hole punching
+    /// failed. Syncs that didn't finish are requested again, the relayed connection is all we
+    /// have to the peer until it dials us directly.
+    fn fall_back_to_relayed_sync(&mut self, peer: PeerId) {
+        if !self.swarm.is_connected(&peer) {
+            info!("No relayed connection to {peer} left to sync over");
+            return;
+        }
+        let automerge = &mut self.swarm.behaviour_mut().automerge;
+        let in_progress = automerge.syncs_in_progress();
+        for document_id in &self.synced_documents {
+            if !in_progress.contains(&(peer, document_id.clone())) {
+                automerge.request_sync(peer, document_id);
+            }
+        }
+        info!("Syncing with {peer} over the relayed connection");
+        let _ = self
+            .node_event_tx
+            .send(NodeEvent::RelayedSyncFallback(peer));
+    }
+
+    /// Replaces the circuit listener on a connected relay, which requests a new reservation. The
+    /// old listener is only removed once the new one is listening, so a failed renewal keeps the
+    /// current reservation.
+    fn renew_reservation(&mut self, relay_peer_id: PeerId) -> Result<(), String> {
+        let Some(candidate) = self
+            .relay_candidates
+            .iter_mut()
+            .find(|candidate| candidate.peer_id == relay_peer_id)
+        else {
+            return Err(format!("{relay_peer_id} is not a configured relay"));
+        };
+        if !self.swarm.is_connected(&relay_peer_id) {
+            return Err(format!("relay {relay_peer_id} is not connected"));
+        }
+        let circuit_addr = candidate
+            .address
+            .clone()
+            .with(Protocol::P2p(relay_peer_id))
+            .with(Protocol::P2pCircuit);
+
+        info!("Renewing the reservation on relay {relay_peer_id}");
+        let listener_id = self
+            .swarm
+            .listen_on(circuit_addr.clone())
+            .map_err(|err| format!("failed to listen on relay circuit {circuit_addr}: {err}"))?;
+        if let Some(previous) = candidate.circuit_listener.replace(listener_id) {
+            self.swarm.remove_listener(previous);
+        }
+        candidate.circuit_listen_retry_at = None;
+        Ok(())
+    }
+
+    /// Listens on a circuit through the relay, which requests the reservation. A failure is logged
+    /// and the listen retried once the relay confirms a reservation.
+    fn listen_on_relay_circuit(&mut self, relay_peer_id: PeerId) {
+        let Some(candidate) = self
+            .relay_candidates
+            .iter_mut()
+            .find(|candidate| candidate.peer_id == relay_peer_id)
+        else {
+            return;
+        };
+        let circuit_addr = candidate
+            .address
+            .clone()
+            .with(Protocol::P2p(relay_peer_id))
+            .with(Protocol::P2pCircuit);
+
+        match self.swarm.listen_on(circuit_addr.clone()) {
+            Ok(listener_id) => {
+                candidate.circuit_listener = Some(listener_id);
+                candidate.circuit_listen_retry_at = None;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to listen on relay circuit {circuit_addr}: {err}, retrying in {:?}",
+                    CIRCUIT_LISTEN_RETRY_DELAY
+                );
+                candidate.circuit_listen_retry_at =
+                    Some(tokio::time::Instant::now() + CIRCUIT_LISTEN_RETRY_DELAY);
+            }
+        }
+    }
+
+    /// Listens again on the circuits of relays whose retry is due
+    fn retry_circuit_listens(&mut self) {
+        let now = tokio::time::Instant::now();
+        let due = self
+            .relay_candidates
+            .iter_mut()
+            .filter(|candidate| {
+                candidate
+                    .circuit_listen_retry_at
+                    .is_some_and(|at| at <= now)
+            })
+            .map(|candidate| {
+                candidate.circuit_listen_retry_at = None;
+                candidate.peer_id
+            })
+            .collect::<Vec<_>>();
+        if self.relay_release.is_some() {
+            return;
+        }
+        for relay_peer_id in due {
+            self.listen_on_relay_circuit(relay_peer_id);
+        }
+    }
+
+    fn dial_relay(&mut self) {
+        let address = self
+            .relay_address
+            .clone()
+            .with_p2p(self.relay_peer_id)
+            .unwrap_or_else(|address| address);
+        info!(
+            "Dialing relay {} (attempt {})",
+            address,
+            self.relay_dial_attempts + 1
+        );
+        if let Err(err) = self.swarm.dial(address) {
+            warn!("Failed to dial relay: {}", dial_error::classify(&err));
+            tracing::trace!("Dial error details: {err:?}");
+            self.schedule_relay_redial();
+        }
+    }
+
+    fn dial_bootstrap_peers(&mut self) {
+        for (peer_id, address) in &self.bootstrap_peers {
+            let address = address
+                .clone()
+                .with_p2p(*peer_id)
+                .unwrap_or_else(|address| address);
+            debug!("Dialing bootstrap peer {address}");
+            if let Err(err) = self.swarm.dial(address) {
+                warn!(
+                    "Failed to dial bootstrap peer {peer_id}: {}",
+                    dial_error::classify(&err)
+                );
+            }
+        }
+    }
+
+    /// Dials every relay and bootstrap peer again with fresh attempts, which also resolves DNS
+    /// addresses again, and restarts the kademlia bootstrap
+    fn recover_from_isolation(&mut self) {
+        let Some(watchdog) = &mut self.isolation_watchdog else {
+            return;
+        };
+        let now = tokio::time::Instant::now();
+        let recoveries = watchdog.start_recovery(now);
+        let duration = watchdog.isolated_for(now);
+        if self.relay_release.is_some() {
+            return;
+        }
+
+        info!(
+            "No connections for {:?}, redialing relays and bootstrap peers (recovery {})",
+            duration, recoveries
+        );
+        self.relay_dial_attempts = 0;
+        self.relay_redial_at = None;
+        self.dial_relay();
+        self.probe_relays();
+        self.dial_bootstrap_peers();
+        if let Err(err) = self.swarm.behaviour_mut().kademlia.bootstrap() {
+            debug!("Failed to restart kademlia bootstrap: {err:?}");
+        }
+
+        if recoveries >= ISOLATION_WARNING_CYCLES {
+            warn!(
+                "!!! Isolated from the network for {:?}, {} recoveries failed, check the relays and the network connection !!!",
+                duration, recoveries
+            );
+            let _ = self.node_event_tx.send(NodeEvent::Isolated {
+                duration,
+                recoveries,
+            });
+        }
+    }
+
+    /// Backs off exponentially between relay dials until the attempts are used up
+    fn schedule_relay_redial(&mut self) {
+        if self.relay_redial_at.is_some()
+            || self.relay_release.is_some()
+            || self.swarm.is_connected(&self.relay_peer_id)
+        {
+            return;
+        }
+
+        self.relay_dial_attempts += 1;
+        if self.max_relay_dial_attempts != 0
+            && self.relay_dial_attempts >= self.max_relay_dial_attempts
+        {
+            warn!(
+                "Giving up on relay {} after {} attempts",
+                self.relay_peer_id, self.relay_dial_attempts
+            );
+            return;
+        }
+
+        let backoff = relay_dial_backoff(self.relay_dial_attempts);
+        info!("Retrying relay dial in {:?}", backoff);
+        self.relay_redial_at = Some(tokio::time::Instant::now() + backoff);
+    }
+
+    fn shared_addresses(&self) -> SharedAddresses {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let circuit = circuit_addresses(
+            &self.relay_candidates,
+            &self.active_reservations,
+            local_peer_id,
+        );
+
+        // mapped addresses are known to be forwarded, so they go first
+        let mut direct = Vec::new();
+        for address in self
+            .upnp_addresses
+            .iter()
+            .chain(self.swarm.external_addresses())
+        {
+            if address
+                .iter()
+                .any(|protocol| protocol == Protocol::P2pCircuit)
+            {
+                continue;
+            }
+            let address = address
+                .clone()
+                .with_p2p(local_peer_id)
+                .unwrap_or_else(|a| a);
+            if !direct.contains(&address) {
+                direct.push(address);
+            }
+        }
+
+        SharedAddresses { circuit, direct }
+    }
+
+    fn start_document_lookup(&mut self, document_id: String) {
+        let provider_query = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .get_providers(kad::RecordKey::new(&document_id));
+
+        let peers = self
+            .swarm
+            .connected_peers()
+            .filter(|peer_id| **peer_id != self.relay_peer_id)
+            .take(DOCUMENT_LOOKUP_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut pending_peers = HashSet::new();
+        for peer_id in peers {
+            if self
+                .swarm
+                .behaviour_mut()
+                .automerge
+                .request_available_documents(peer_id)
+            {
+                pending_peers.insert(peer_id);
+            }
+        }
+
+        debug!(
+            "Looking up document {} via the DHT and {} peers",
+            document_id,
+            pending_peers.len()
+        );
+        self.document_lookups.push(DocumentLookup {
+            document_id,
+            provider_query,
+            dht_finished: false,
+            pending_peers,
+            found: HashSet::new(),
+            deadline: tokio::time::Instant::now() + DOCUMENT_LOOKUP_TIMEOUT,
+        });
+    }
+
+    /// Heads of a document as hex change hashes, `None` if we don't have the document
+    fn document_heads(&mut self, document_id: &str) -> Option<Vec<String>> {
+        let heads = self.swarm.behaviour_mut().automerge.heads(document_id)?;
+        Some(heads.iter().map(ChangeHash::to_string).collect())
+    }
+
+    fn topic_peers(&self, topic: &gossipsub::TopicHash) -> TopicPeers {
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        TopicPeers {
+            mesh: gossipsub.mesh_peers(topic).copied().collect(),
+            subscribed: gossipsub
+                .all_peers()
+                .filter(|(_, topics)| topics.contains(&topic))
+                .map(|(peer_id, _)| *peer_id)
+                .collect(),
+        }
+    }
+
+    fn start_connectivity_report(
+        &mut self,
+        peer: PeerId,
+        reply: oneshot::Sender<ConnectivityReport>,
+    ) {
+        let skipped = || PathOutcome::Skipped("not attempted".to_string());
+        self.run_connectivity_stage(ConnectivityProbe {
+            peer,
+            stage: ConnectivityStage::Direct,
+            stage_started_at: Instant::now(),
+            deadline: tokio::time::Instant::now(),
+            report: ConnectivityReport {
+                direct: skipped(),
+                relayed: skipped(),
+                upgraded: skipped(),
+            },
+            reply,
+        });
+    }
+
+    /// Tries the probe's current path, moving on to the next one when it can't be tried, and
+    /// replies once every path was tried
+    fn run_connectivity_stage(&mut self, mut probe: ConnectivityProbe) {
+        loop {
+            let address = match probe.stage {
+                ConnectivityStage::Direct => {
+                    let addresses = self
+                        .identify_cache
+                        .get(&probe.peer)
+                        .map(|cached| {
+                            cached
+                                .info
+                                .listen_addrs
+                                .iter()
+                                .filter(|address| {
+                                    !address
+                                        .iter()
+                                        .any(|protocol| protocol == Protocol::P2pCircuit)
+                                })
+                                .cloned()
+                                .collect::<Vec<_>>()
+                        })
+                        .unwrap_or_default();
+                    if addresses.is_empty() {
+                        probe.report.direct =
+                            PathOutcome::Skipped("no direct address known".to_string());
+                        probe.stage = ConnectivityStage::Relayed;
+                        continue;
+                    }
+                    addresses
+                }
+                ConnectivityStage::Relayed => vec![
+                    self.relay_address
+                        .clone()
+                        .with(Protocol::P2p(self.relay_peer_id))
+                        .with(Protocol::P2pCircuit)
+                        .with(Protocol::P2p(probe.peer)),
+                ],
+                ConnectivityStage::Upgraded => {
+                    let skip = if matches!(probe.report.direct, PathOutcome::Reachable(_)) {
+                        Some("already reachable directly")
+                    } else if !matches!(probe.report.relayed, PathOutcome::Reachable(_)) {
+                        Some("no relayed connection to upgrade")
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = skip {
+                        probe.report.upgraded = PathOutcome::Skipped(reason.to_string());
+                        probe.stage = ConnectivityStage::Done;
+                        continue;
+                    }
+
+                    // DCUtR starts on its own once the relayed connection is up
+                    probe.stage_started_at = Instant::now();
+                    probe.deadline = tokio::time::Instant::now() + CONNECTIVITY_STAGE_TIMEOUT;
+                    self.connectivity_probes.push((None, probe));
+                    return;
+                }
+                ConnectivityStage::Done => {
+                    let _ = probe.reply.send(probe.report);
+                    return;
+                }
+            };
+
+            // a connection we already have would satisfy a regular dial without testing the path
+            let opts = DialOpts::peer_id(probe.peer)
+                .condition(PeerCondition::Always)
+                .addresses(address)
+                .build();
+            let connection_id = opts.connection_id();
+            match self.swarm.dial(opts) {
+                Ok(()) => {
+                    probe.stage_started_at = Instant::now();
+                    probe.deadline = tokio::time::Instant::now() + CONNECTIVITY_STAGE_TIMEOUT;
+                    self.connectivity_probes.push((Some(connection_id), probe));
+                    return;
+                }
+                Err(err) => {
+                    let outcome = PathOutcome::Failed(dial_error::classify(&err).to_string());
+                    probe.set_outcome(outcome);
+                }
+            }
+        }
+    }
+
+    /// Records the outcome of the probe's current path and moves on to the next
+    fn finish_connectivity_stage(
+        &mut self,
+        mut probe: ConnectivityProbe,
+        result: Result<(), String>,
+    ) {
+        let outcome = match result {
+            Ok(()) => PathOutcome::Reachable(probe.stage_started_at.elapsed()),
+            Err(reason) => PathOutcome::Failed(reason),
+        };
+        probe.set_outcome(outcome);
+        self.run_connectivity_stage(probe);
+    }
+
+    /// Announces as provider once the warm-up's document is ready, otherwise takes the next step
+    /// towards it: looking up the existing providers or comparing heads with them
+    fn advance_provider_warmup(&mut self) {
+        let Some(warmup) = self.provider_warmup.as_mut() else {
+            return;
+        };
+
+        let behaviour = self.swarm.behaviour_mut();
+        let present = behaviour
+            .automerge
+            .get_document(&warmup.document_id)
+            .is_some();
+        if warmup.is_ready(present) {
+            let warmup = self.provider_warmup.take().expect("checked above");
+            match behaviour.kademlia.start_providing(warmup.key.clone()) {
+                Ok(_) => info!(
+                    "Document {} is ready, started providing for key {:?}",
+                    warmup.document_id, warmup.key
+                ),
+                Err(err) => warn!("Failed to start providing for key: {:?}", err),
+            }
+            return;
+        }
+
+        if !present {
+            debug!(
+                "Waiting for document {} before announcing as provider",
+                warmup.document_id
+            );
+            return;
+        }
+
+        if warmup.needs_lookup() {
+            let query_id = behaviour.kademlia.get_providers(warmup.key.clone());
+            warmup.start_lookup(query_id);
+            return;
+        }
+
+        let providers = warmup.providers().copied().collect::<Vec<_>>();
+        let mut asked = 0;
+        for provider in providers {
+            if behaviour
+                .automerge
+                .request_heads(provider, &warmup.document_id)
+            {
+                asked += 1;
+            }
+        }
+
+        // none of the providers are connected, look again in case the set changed
+        if asked == 0 && warmup.lookup_finished() {
+            debug!(
+                "No provider of {} reachable to converge with, looking up providers again",
+                warmup.document_id
+            );
+            warmup.restart_lookup();
+        }
+    }
+
+    /// Reports and removes lookups that got every answer or timed out
+    fn complete_document_lookups(&mut self) {
+        let now = tokio::time::Instant::now();
+        self.document_lookups.retain(|lookup| {
+            let answered = lookup.dht_finished && lookup.pending_peers.is_empty();
+            if !answered && now < lookup.deadline {
+                return true;
+            }
+
+            if lookup.found.is_empty() {
+                info!(
+                    "Document {} was not found on the network",
+                    lookup.document_id
+                );
+            } else {
+                info!(
+                    "Document {} exists on the network, reported by {:?}",
+                    lookup.document_id, lookup.found
+                );
+            }
+            false
+        });
+    }
+
+    /// Fails requests to peers that didn't answer in time
+    fn expire_peer_requests(&mut self) {
+        let now = Instant::now();
+        expire_pending_replies(&mut self.peer_document_requests, now);
+        expire_pending_replies(&mut self.convergence_requests, now);
+    }
+
+    /// When the first request to a peer times out
+    fn next_peer_request_timeout(&self) -> Option<tokio::time::Instant> {
+        next_reply_timeout(&self.peer_document_requests)
+            .into_iter()
+            .chain(next_reply_timeout(&self.convergence_requests))
+            .min()
+            .map(tokio::time::Instant::from_std)
+    }
+
+    /// Marks cached identify info as stale for peers that haven't refreshed it in time
+    fn expire_identify_cache(&mut self) {
+        let now = Instant::now();
+        for (peer_id, entry) in self.identify_cache.iter_mut() {
+            if !entry.stale && identify_expired(entry.received_at, now, self.identify_expiry) {
+                debug!("Identify info for {peer_id} is stale");
+                entry.stale = true;
+            }
+        }
+    }
+
+    fn handle_swarm_event(&mut self, event: &SwarmEvent<BehaviourEvent>) {
+        self.metrics.record(event);
+        match event {
+            SwarmEvent::NewListenAddr {
+                address,
+                listener_id,
+            } => {
+                info!("Listening on {} (listener_id={})", address, listener_id);
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id,
+                error,
+                connection_id,
+            } => {
+                let failure = dial_error::classify(error);
+                if let Some(index) = self
+                    .connectivity_probes
+                    .iter()
+                    .position(|(id, _)| id == &Some(*connection_id))
+                {
+                    let (_, probe) = self.connectivity_probes.remove(index);
+                    self.finish_connectivity_stage(probe, Err(failure.to_string()));
+                }
+
+                if let Some(peer_id) = peer_id {
+                    tracing::debug!("Failed to dial {peer_id}: {failure}");
+                } else {
+                    tracing::debug!("Failed to dial unknown peer: {failure}");
+                }
+                tracing::trace!("Dial error details: {error:?}");
+
+                if let Some(peer_id) = peer_id
+                    && self.is_relay_candidate(peer_id)
+                {
+                    self.update_self_check(|check| check.record_dial_error(error));
+                }
+
+                if let Some(peer_id) = peer_id
+                    && let Some(relay_peer_id) = circuit_relay(error)
+                {
+                    warn!("Relayed dial to {peer_id} through {relay_peer_id} failed: {failure}");
+                }
+
+                if *peer_id == Some(self.relay_peer_id) {
+                    self.schedule_relay_redial();
+                }
+
+                if let Some(retry) = self.pending_dials.remove(connection_id) {
+                    self.retry_dial(retry, failure);
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                cause,
+                ..
+            } => {
+                if endpoint.is_relayed() {
+                    tracing::debug!("Relay circuit closed from {peer_id} because {cause:?}");
+                } else {
+                    tracing::debug!("Connection closed from {peer_id} because {cause:?}");
+                }
+
+                if *num_established == 0 && self.active_reservations.remove(peer_id) {
+                    self.reservation_limits.remove(peer_id);
+                    if self.active_reservations.is_empty() {
+                        warn!(
+                            "Lost reservation with relay {peer_id}, no relay holds a reservation for us"
+                        );
+                    } else {
+                        info!(
+                            "Lost reservation with relay {peer_id}, still reachable through {} relays",
+                            self.active_reservations.len()
+                        );
+                    }
+                }
+
+                if *num_established == 0
+                    && self.is_relay_candidate(peer_id)
+                    && !self.is_relay_connected()
+                {
+                    self.finish_relay_release();
+                }
+                if matches!(self.shutdown_flush, Some(ShutdownFlush::Closing(_)))
+                    && self.swarm.connected_peers().next().is_none()
+                {
+                    self.shutdown_flush = None;
+                    self.stop();
+                }
+
+                if *num_established == 0 {
+                    self.peer_activity.remove(peer_id);
+                    self.topic_members.retain(|_, members| {
+                        members.remove(peer_id);
+                        !members.is_empty()
+                    });
+                }
+
+                if self.swarm.network_info().num_peers() == 0
+                    && let Some(watchdog) = &mut self.isolation_watchdog
+                {
+                    watchdog.isolated(tokio::time::Instant::now());
+                }
+
+                if *num_established == 0 && self.reconnecting_peers.remove(peer_id) {
+                    if peer_id == &self.relay_peer_id {
+                        self.dial_relay();
+                    } else if let Err(err) = self.swarm.dial(*peer_id) {
+                        debug!(
+                            "Failed to reconnect to {peer_id}: {}",
+                            dial_error::classify(&err)
+                        );
+                    }
+                } else if *num_established == 0 && peer_id == &self.relay_peer_id {
+                    // the relay restarted or dropped us and took the reservation with it, the
+                    // circuit is listened on again once identify completes on the new connection
+                    info!("Lost the connection to relay {peer_id}, redialing");
+                    self.schedule_relay_redial();
+                }
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                debug!("Listener {listener_id} closed: {reason:?}");
+                let mut denied_relay = None;
+                for candidate in self.relay_candidates.iter_mut() {
+                    if candidate.circuit_listener == Some(*listener_id) {
+                        candidate.circuit_listener = None;
+                        if reason.is_err() {
+                            denied_relay = Some(candidate.peer_id);
+                        }
+                    }
+                }
+                if let Some(relay_peer_id) = denied_relay
+                    && self.relay_release.is_none()
+                    && let Err(err) = reason
+                {
+                    warn!("Relay {relay_peer_id} refused or dropped our reservation: {err}");
+                    self.handle_reservation_failure(relay_peer_id);
+                }
+                if let Some(release) = &mut self.relay_release {
+                    release.closing_listeners.remove(listener_id);
+                }
+                self.disconnect_released_relay();
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                connection_id,
+                num_established,
+                ..
+            } => {
+                info!("{}", describe_connection(peer_id, endpoint));
+                self.peer_activity.insert(*peer_id, Instant::now());
+                if let Some(retry) = self.pending_dials.remove(connection_id)
+                    && retry.failures > 0
+                {
+                    info!(
+                        "Reached {} after {} failed attempts",
+                        retry.target, retry.failures
+                    );
+                }
+                if let Some(watchdog) = &mut self.isolation_watchdog {
+                    watchdog.connected();
+                }
+                // a relay we failed to listen through is reachable again, no need to wait
+                if self.relay_release.is_none()
+                    && self.relay_candidates.iter().any(|candidate| {
+                        &candidate.peer_id == peer_id
+                            && candidate.circuit_listener.is_none()
+                            && candidate.circuit_listen_retry_at.is_some()
+                    })
+                {
+                    self.listen_on_relay_circuit(*peer_id);
+                }
+
+                if num_established.get() == 1 && !self.is_relay_candidate(peer_id) {
+                    for document_id in &self.synced_documents {
+                        self.swarm
+                            .behaviour_mut()
+                            .automerge
+                            .request_sync(*peer_id, document_id);
+                    }
+                }
+
+                if let Some(index) = self
+                    .connectivity_probes
+                    .iter()
+                    .position(|(id, _)| id == &Some(*connection_id))
+                {
+                    let (_, probe) = self.connectivity_probes.remove(index);
+                    self.finish_connectivity_stage(probe, Ok(()));
+                }
+
+                // the attempts are only reset once the relay accepts our reservation, so a relay
+                // that keeps dropping us right after connecting still backs off
+                if &self.relay_peer_id == peer_id {
+                    self.relay_redial_at = None;
+                }
+
+                if !self.bootstrapped
+                    && self
+                        .bootstrap_peers
+                        .iter()
+                        .any(|(bootstrap_peer, _)| bootstrap_peer == peer_id)
+                {
+                    debug!("Reached bootstrap peer {peer_id}, starting kademlia bootstrap");
+                    match self.swarm.behaviour_mut().kademlia.bootstrap() {
+                        Ok(_) => self.bootstrapped = true,
+                        Err(err) => warn!("Failed to start kademlia bootstrap: {err:?}"),
+                    }
+                }
+
+                if self.is_relay_candidate(peer_id) {
+                    self.update_self_check(SelfCheck::record_connected);
+                    if self
+                        .last_relay_announce
+                        .is_none_or(|at| at.elapsed() >= RELAY_ANNOUNCE_MIN_INTERVAL)
+                    {
+                        self.last_relay_announce = Some(Instant::now());
+                        self.announce_through_relay();
+                    } else {
+                        debug!("Connected to relay {peer_id}, announced through a relay recently");
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent {
+                peer_id,
+                connection_id,
+            })) => {
+                if &self.relay_peer_id == peer_id {
+                    tracing::debug!(
+                        "Sent identify to relay {peer_id} via {connection_id}, should learn our public address soon"
+                    );
+                } else {
+                    tracing::debug!("Sent identify to {peer_id} via {connection_id}");
+                }
+                self.sent_identify = true;
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Error {
+                peer_id,
+                error,
+                ..
+            })) => {
+                debug!("Identify with {peer_id} failed: {error:?}");
+                if let Some(entry) = self.identify_cache.get_mut(peer_id) {
+                    entry.stale = true;
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::v2::client::Event {
+                result,
+                tested_addr,
+                server,
+                ..
             })) => {
                 let success = result.is_ok();
                 tracing::debug!(%tested_addr, %server, success, "AutoNAT test completed");
+                // confirmed addresses are made external by the AutoNAT behaviour itself
+                if !success {
+                    self.swarm.remove_external_address(tested_addr);
+                }
+                match self.reachability.record(tested_addr, success) {
+                    Some(NatStatus::Public) => {
+                        info!("AutoNAT confirmed {tested_addr} is reachable, advertising it");
+                    }
+                    Some(NatStatus::Private) => self.fall_back_to_relayed_addresses(),
+                    Some(NatStatus::Unknown) | None => {}
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                info,
+                peer_id,
+                ..
+            })) => {
+                self.received_identify = true;
+                // the address the peer observes us on is only a candidate, AutoNAT tests it and
+                // it's advertised once a server managed to dial us back on it
+                self.identify_cache.insert(
+                    *peer_id,
+                    CachedIdentify {
+                        info: info.clone(),
+                        received_at: Instant::now(),
+                        stale: false,
+                    },
+                );
+
+                if let Some(candidate) = self
+                    .relay_candidates
+                    .iter_mut()
+                    .find(|candidate| &candidate.peer_id == peer_id)
+                {
+                    if let Some(started_at) = candidate.probe_started_at.take() {
+                        candidate.response_time = Some(started_at.elapsed());
+                    }
+                    self.update_self_check(SelfCheck::record_identified);
+                    // the first relay to answer is the fastest responding one
+                    if !self.relay_selected {
+                        self.select_relay(*peer_id);
+                    }
+                }
+
+                // identify is received again on every push, only the first one starts listening
+                if self.sent_identify
+                    && self.relay_release.is_none()
+                    && self.relay_candidates.iter().any(|candidate| {
+                        &candidate.peer_id == peer_id && candidate.circuit_listener.is_none()
+                    })
+                {
+                    self.listen_on_relay_circuit(*peer_id);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetProviders(result),
+                    step,
+                    ..
+                },
+            )) if self
+                .provider_warmup
+                .as_ref()
+                .is_some_and(|warmup| warmup.is_lookup(id)) =>
+            {
+                let local_peer_id = *self.swarm.local_peer_id();
+                let warmup = self
+                    .provider_warmup
+                    .as_mut()
+                    .expect("checked by the match guard");
+                match result {
+                    Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                        warmup.record_providers(
+                            providers
+                                .iter()
+                                .filter(|provider| **provider != local_peer_id)
+                                .copied(),
+                        );
+                    }
+                    Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+                    Err(err) => {
+                        debug!("Provider lookup for {} failed: {err:?}", warmup.document_id);
+                    }
+                }
+                if step.last {
+                    warmup.finish_lookup();
+                    self.advance_provider_warmup();
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetProviders(result),
+                    step,
+                    ..
+                },
+            )) if self.provider_queries.contains_key(id) => {
+                let query = self
+                    .provider_queries
+                    .get_mut(id)
+                    .expect("checked by the match guard");
+                match result {
+                    Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                        query.providers.extend(providers.iter().copied());
+                    }
+                    Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+                    Err(err) => debug!("Provider query {id:?} failed: {err:?}"),
+                }
+                if step.last
+                    && let Some(query) = self.provider_queries.remove(id)
+                {
+                    let mut providers = query.providers.into_iter().collect::<Vec<_>>();
+                    providers.sort();
+                    let _ = query.reply.send(providers);
+                }
             }
-            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
-                info: identify::Info { .. },
-                peer_id,
-                ..
-            })) => {
-                self.received_identify = true;
-                // TODO only add observed addr if autonat says it's a public addr?
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetProviders(result),
+                    step,
+                    ..
+                },
+            )) if self
+                .document_lookups
+                .iter()
+                .any(|lookup| &lookup.provider_query == id) =>
+            {
+                let local_peer_id = *self.swarm.local_peer_id();
+                let lookup = self
+                    .document_lookups
+                    .iter_mut()
+                    .find(|lookup| &lookup.provider_query == id)
+                    .expect("checked by the match guard");
+                match result {
+                    Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                        lookup.found.extend(
+                            providers
+                                .iter()
+                                .filter(|provider| **provider != local_peer_id),
+                        );
+                    }
+                    Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+                    Err(err) => {
+                        debug!("Provider lookup for {} failed: {err:?}", lookup.document_id);
+                    }
+                }
+                lookup.dht_finished |= step.last;
+                self.complete_document_lookups();
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::AvailableDocuments { peer, document_ids },
+            )) => {
+                debug!("Peer {peer} has documents {document_ids:?}");
+                answer_pending_replies(
+                    &mut self.peer_document_requests,
+                    peer,
+                    Ok(document_ids.clone()),
+                );
+                for lookup in self.document_lookups.iter_mut() {
+                    if lookup.pending_peers.remove(peer)
+                        && document_ids.contains(&lookup.document_id)
+                    {
+                        lookup.found.insert(*peer);
+                    }
+                }
+                self.complete_document_lookups();
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::DocumentAvailable { peer, document_id },
+            )) => {
+                info!(
+                    "Peer {peer} has document {document_id}, which we don't have yet, requesting it"
+                );
+                self.swarm
+                    .behaviour_mut()
+                    .automerge
+                    .request_document(*peer, document_id);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::DocumentChanged { document_id },
+            )) => {
+                // documents created or fetched while running need their topic too
+                self.subscribe_document_topic(document_id);
+                self.change_throttle
+                    .mark_changed(document_id, tokio::time::Instant::now());
+                if self
+                    .provider_warmup
+                    .as_ref()
+                    .is_some_and(|warmup| &warmup.document_id == document_id)
+                {
+                    self.advance_provider_warmup();
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::RemoteHeads {
+                    peer,
+                    document_id,
+                    heads,
+                },
+            )) => {
+                let warming_up = self.provider_warmup.as_ref().is_some_and(|warmup| {
+                    &warmup.document_id == document_id && warmup.is_provider(peer)
+                });
+                if warming_up {
+                    let local = self.swarm.behaviour_mut().automerge.heads(document_id);
+                    if compare_heads(local, heads.clone()) == Convergence::Converged {
+                        if let Some(warmup) = self.provider_warmup.as_mut() {
+                            warmup.record_converged(*peer);
+                        }
+                        self.advance_provider_warmup();
+                    }
+                }
 
-                if peer_id == &self.relay_peer_id && self.sent_identify {
-                    let circuit_addr = self
-                        .relay_address
-                        .clone()
-                        .with(Protocol::P2p(self.relay_peer_id))
-                        .with(Protocol::P2pCircuit);
+                let Some(pending) = self
+                    .convergence_requests
+                    .remove(&(*peer, document_id.clone()))
+                else {
+                    return;
+                };
 
-                    self.swarm.listen_on(circuit_addr.clone()).unwrap();
+                let local = self.swarm.behaviour_mut().automerge.heads(document_id);
+                let convergence = compare_heads(local, heads.clone());
+                for pending in pending {
+                    let _ = pending.reply.send(Ok(convergence.clone()));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::DocumentDeleted { peer, document_id },
+            )) => {
+                info!("Peer {peer} deleted document {document_id}");
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .stop_providing(&kad::RecordKey::new(document_id));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::DocumentCompacted { document_id, size },
+            )) => {
+                info!("Compacted document {document_id} to {size} bytes");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::PeerThrottled { peer },
+            )) => {
+                info!("Dropping automerge messages of {peer}, it sends them too fast");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+                libp2p_automerge::Event::UnsupportedPeer { peer },
+            )) => {
+                debug!("Peer {peer} does not support the automerge protocol");
+                answer_pending_replies(
+                    &mut self.peer_document_requests,
+                    peer,
+                    Err(PeerRequestError::Unsupported),
+                );
+                for pending in self
+                    .convergence_requests
+                    .extract_if(|(requested, _), _| requested == peer)
+                    .flat_map(|(_, pending)| pending)
+                {
+                    let _ = pending.reply.send(Err(PeerRequestError::Unsupported));
+                }
+                for lookup in self.document_lookups.iter_mut() {
+                    lookup.pending_peers.remove(peer);
+                }
+                self.complete_document_lookups();
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::PutRecord(result),
+                    ..
+                },
+            )) if self.record_puts.contains_key(id) => {
+                let Some(put) = self.record_puts.remove(id) else {
+                    return;
+                };
+                match result {
+                    Ok(_) => {
+                        debug!(
+                            "Stored record {:?} after {} attempts",
+                            put.record.key, put.attempts
+                        );
+                        let _ = put.reply.send(Ok(()));
+                    }
+                    Err(err) => self.retry_record_put(put, err.clone()),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetRecord(result),
+                    step,
+                    ..
+                },
+            )) if self.record_gets.contains_key(id) => {
+                let reply = match result {
+                    Ok(kad::GetRecordOk::FoundRecord(found)) => {
+                        // the first record answers the caller, the rest of the query is moot
+                        if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(id) {
+                            query.finish();
+                        }
+                        Ok(Some(found.record.value.clone()))
+                    }
+                    Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => Ok(None),
+                    Err(kad::GetRecordError::NotFound { .. }) => Ok(None),
+                    Err(err) => Err(format!("{err:?}")),
+                };
+                if (reply.as_ref().is_ok_and(Option::is_some) || reply.is_err() || step.last)
+                    && let Some(sender) = self.record_gets.remove(id)
+                {
+                    let _ = sender.send(reply);
                 }
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed { id, step, .. },
+            )) if step.last
+                && self
+                    .dial_retries
+                    .iter()
+                    .any(|redial| redial.lookup == Some(*id)) =>
+            {
+                self.finish_dial_lookup(*id);
+            }
             SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
                 kad::Event::OutboundQueryProgressed { result, .. },
             )) => {
@@ -277,11 +3151,20 @@ impl SwarmManager {
                     limit,
                 },
             )) => {
-                let limit = limit.unwrap();
-                let ttl = limit.duration().unwrap().as_secs();
+                self.active_reservations.insert(*relay_peer_id);
+                self.update_self_check(SelfCheck::record_reserved);
+                if &self.relay_peer_id == relay_peer_id {
+                    self.relay_dial_attempts = 0;
+                }
+                let limits = ReservationLimits {
+                    duration: limit.as_ref().and_then(|limit| limit.duration()),
+                    data_in_bytes: limit.as_ref().and_then(|limit| limit.data_in_bytes()),
+                    accepted_at: Instant::now(),
+                };
                 tracing::debug!(
-                    "Relay reservation accepted from {relay_peer_id}, renewal: {renewal:?}, limit: {ttl}"
+                    "Relay reservation accepted from {relay_peer_id}, renewal: {renewal:?}, limits: {limits:?}"
                 );
+                self.reservation_limits.insert(*relay_peer_id, limits);
             }
             SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
                 relay::client::Event::OutboundCircuitEstablished {
@@ -289,29 +3172,1057 @@ impl SwarmManager {
                     limit,
                 },
             )) => {
-                let limit = limit.unwrap();
-                let ttl = limit.duration().unwrap().as_secs();
-                debug!("Relay circuit established via {relay_peer_id}, limit: {ttl}");
+                debug!(
+                    "Relay circuit established via {relay_peer_id}, limit: {}",
+                    describe_circuit_limit(
+                        limit.as_ref().and_then(|limit| limit.duration()),
+                        limit.as_ref().and_then(|limit| limit.data_in_bytes())
+                    )
+                );
             }
             SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
                 relay::client::Event::InboundCircuitEstablished { src_peer_id, limit },
             )) => {
-                let limit = limit.unwrap();
-                let ttl = limit.duration().unwrap().as_secs();
-                debug!("Inbound relay circuit established from {src_peer_id}, limit: {ttl}");
+                debug!(
+                    "Inbound relay circuit established from {src_peer_id}, limit: {}",
+                    describe_circuit_limit(
+                        limit.as_ref().and_then(|limit| limit.duration()),
+                        limit.as_ref().and_then(|limit| limit.data_in_bytes())
+                    )
+                );
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayServer(
+                relay::Event::ReservationReqAccepted { src_peer_id, .. },
+            )) => {
+                info!("Accepted relay reservation from {src_peer_id}");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayServer(
+                relay::Event::CircuitReqAccepted {
+                    src_peer_id,
+                    dst_peer_id,
+                    ..
+                },
+            )) => {
+                info!("Relaying circuit {src_peer_id} <-> {dst_peer_id}");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message,
+                ..
+            })) => {
+                debug!(
+                    "Gossipsub message on {} from {:?} via {}",
+                    message.topic, message.source, propagation_source
+                );
+                self.peer_activity
+                    .insert(*propagation_source, Instant::now());
+                let _ = self.node_event_tx.send(NodeEvent::GossipMessage {
+                    topic: message.topic.clone(),
+                    source: message.source,
+                    data: message.data.clone(),
+                });
+                if let Some(document_id) =
+                    message.topic.as_str().strip_prefix(DOCUMENT_TOPIC_PREFIX)
+                {
+                    let source = message.source.unwrap_or(*propagation_source);
+                    self.apply_published_changes(source, document_id, &message.data);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+                peer_id,
+                topic,
+            })) => {
+                debug!("{peer_id} subscribed to {topic}");
+                self.topic_members
+                    .entry(topic.clone())
+                    .or_default()
+                    .insert(*peer_id);
+                let _ = self.node_event_tx.send(NodeEvent::PeerSubscribed {
+                    peer: *peer_id,
+                    topic: topic.clone(),
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed {
+                peer_id,
+                topic,
+            })) => {
+                debug!("{peer_id} unsubscribed from {topic}");
+                if let Some(members) = self.topic_members.get_mut(topic) {
+                    members.remove(peer_id);
+                    if members.is_empty() {
+                        self.topic_members.remove(topic);
+                    }
+                }
+                let _ = self.node_event_tx.send(NodeEvent::PeerUnsubscribed {
+                    peer: *peer_id,
+                    topic: topic.clone(),
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(event)) => match event {
+                upnp::Event::NewExternalAddr(address) => {
+                    info!("Router maps {address} to us, peers can dial us directly");
+                    self.swarm.add_external_address(address.clone());
+                    if !self.upnp_addresses.contains(address) {
+                        self.upnp_addresses.push(address.clone());
+                    }
+                }
+                upnp::Event::ExpiredExternalAddr(address) => {
+                    warn!("Router stopped mapping {address}, falling back to the relay");
+                    self.swarm.remove_external_address(address);
+                    self.upnp_addresses.retain(|mapped| mapped != address);
+                }
+                upnp::Event::GatewayNotFound => {
+                    info!("No UPnP capable router found, relying on the relay");
+                }
+                upnp::Event::NonRoutableGateway => {
+                    info!("Router is not directly on the public internet, relying on the relay");
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(discovered))) => {
+                let mut new_peers = HashMap::<PeerId, Vec<Multiaddr>>::new();
+                for (peer_id, address) in discovered {
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(peer_id, address.clone());
+                    if self
+                        .mdns_peers
+                        .entry(*peer_id)
+                        .or_default()
+                        .insert(address.clone())
+                    {
+                        new_peers.entry(*peer_id).or_default().push(address.clone());
+                    }
+                }
+
+                // a direct connection to a local peer spares the relay hop
+                for (peer_id, addresses) in new_peers {
+                    debug!("Found {peer_id} on the local network at {addresses:?}");
+                    let opts = DialOpts::peer_id(peer_id)
+                        .addresses(addresses)
+                        .condition(PeerCondition::Disconnected)
+                        .build();
+                    if let Err(err) = self.swarm.dial(opts) {
+                        debug!(
+                            "Failed to dial local peer {peer_id}: {}",
+                            dial_error::classify(&err)
+                        );
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(expired))) => {
+                for (peer_id, address) in expired {
+                    debug!("Local address {address} of {peer_id} expired");
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .remove_address(peer_id, address);
+                    if let Some(addresses) = self.mdns_peers.get_mut(peer_id) {
+                        addresses.remove(address);
+                        if addresses.is_empty() {
+                            self.mdns_peers.remove(peer_id);
+                        }
+                    }
+                }
             }
             SwarmEvent::Behaviour(BehaviourEvent::Dcutr(libp2p::dcutr::Event {
                 remote_peer_id,
                 result,
-            })) => match result {
-                Ok(_) => {
-                    info!("DCUtR with {remote_peer_id} succeeded");
+            })) => {
+                match result {
+                    Ok(_) => {
+                        info!("DCUtR with {remote_peer_id} succeeded");
+                        let _ = self
+                            .node_event_tx
+                            .send(NodeEvent::DirectConnectionEstablished(*remote_peer_id));
+                    }
+                    Err(err) => {
+                        warn!("DCUtR with {remote_peer_id} failed: {err}");
+                        self.fall_back_to_relayed_sync(*remote_peer_id);
+                    }
                 }
-                Err(err) => {
-                    warn!("DCUtR with {remote_peer_id} failed: {err:?}");
+
+                if let Some(index) = self.connectivity_probes.iter().position(|(_, probe)| {
+                    probe.stage == ConnectivityStage::Upgraded && &probe.peer == remote_peer_id
+                }) {
+                    let (_, probe) = self.connectivity_probes.remove(index);
+                    let result = result.as_ref().map(|_| ()).map_err(ToString::to_string);
+                    self.finish_connectivity_stage(probe, result);
                 }
-            },
+            }
             _ => {}
         }
     }
 }
+
+/// Prefix of the gossipsub topics carrying document changes, followed by the document id
+const DOCUMENT_TOPIC_PREFIX: &str = "automerge/";
+
+/// Gossipsub topic carrying the changes of a document
+pub fn document_topic(document_id: &str) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(format!("{DOCUMENT_TOPIC_PREFIX}{document_id}"))
+}
+
+fn compare_heads(local: Option<Vec<ChangeHash>>, remote: Option<Vec<ChangeHash>>) -> Convergence {
+    let (Some(local), Some(remote)) = (&local, &remote) else {
+        return Convergence::Missing {
+            locally: local.is_none(),
+            remotely: remote.is_none(),
+        };
+    };
+
+    let local_only = local
+        .iter()
+        .filter(|head| !remote.contains(head))
+        .copied()
+        .collect::<Vec<_>>();
+    let remote_only = remote
+        .iter()
+        .filter(|head| !local.contains(head))
+        .copied()
+        .collect::<Vec<_>>();
+    if local_only.is_empty() && remote_only.is_empty() {
+        Convergence::Converged
+    } else {
+        Convergence::Divergent {
+            local_only,
+            remote_only,
+        }
+    }
+}
+
+/// Human readable limits of a relayed circuit, relays may leave either limit out
+fn describe_circuit_limit(duration: Option<Duration>, data_in_bytes: Option<u64>) -> String {
+    match (duration, data_in_bytes) {
+        (None, None) => "no limit".to_string(),
+        (Some(duration), None) => format!("{}s", duration.as_secs()),
+        (None, Some(bytes)) => format!("{bytes} bytes"),
+        (Some(duration), Some(bytes)) => format!("{}s or {bytes} bytes", duration.as_secs()),
+    }
+}
+
+/// Why a request to a peer failed, as reported to the command that sent it
+fn request_error(error: &request_response::OutboundFailure) -> PeerRequestError {
+    match error {
+        request_response::OutboundFailure::DialFailure
+        | request_response::OutboundFailure::ConnectionClosed => PeerRequestError::NotConnected,
+        request_response::OutboundFailure::Timeout => PeerRequestError::Timeout,
+        request_response::OutboundFailure::UnsupportedProtocols => PeerRequestError::Unsupported,
+        _ => PeerRequestError::Failed,
+    }
+}
+
+/// Relay of a failed dial through a relay circuit, `None` if no address went through one
+fn circuit_relay(error: &DialError) -> Option<PeerId> {
+    let DialError::Transport(attempts) = error else {
+        return None;
+    };
+    attempts.iter().find_map(|(address, _)| {
+        let mut relay = None;
+        for protocol in address.iter() {
+            match protocol {
+                Protocol::P2p(peer_id) => relay = Some(peer_id),
+                Protocol::P2pCircuit => return relay,
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+/// Human readable summary of how we're connected to a peer
+fn describe_connection(peer_id: &PeerId, endpoint: &ConnectedPoint) -> String {
+    let address = endpoint.get_remote_address();
+    if endpoint.is_relayed() {
+        // the relay is the last peer id before the circuit component
+        let relay = address
+            .iter()
+            .take_while(|protocol| *protocol != Protocol::P2pCircuit)
+            .filter_map(|protocol| match protocol {
+                Protocol::P2p(relay) => Some(relay.to_string()),
+                _ => None,
+            })
+            .last()
+            .unwrap_or_else(|| address.to_string());
+        format!("relayed connection to {peer_id} via {relay}")
+    } else {
+        format!(
+            "direct connection to {peer_id} via {}",
+            transport_name(address)
+        )
+    }
+}
+
+/// The application level event a swarm event amounts to, if any
+fn node_event(event: &SwarmEvent<BehaviourEvent>) -> Option<NodeEvent> {
+    let node_event = match event {
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            endpoint,
+            num_established,
+            ..
+        } if num_established.get() == 1 => NodeEvent::PeerConnected {
+            peer: *peer_id,
+            relayed: endpoint.is_relayed(),
+        },
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            ..
+        } => NodeEvent::PeerDisconnected { peer: *peer_id },
+        SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+            libp2p_automerge::Event::DocumentSynced { peer, document_id },
+        )) => NodeEvent::DocumentSynced {
+            peer: *peer,
+            document_id: document_id.clone(),
+        },
+        SwarmEvent::Behaviour(BehaviourEvent::Automerge(
+            libp2p_automerge::Event::DocumentDeleted { peer, document_id },
+        )) => NodeEvent::DocumentDeleted {
+            peer: *peer,
+            document_id: document_id.clone(),
+        },
+        SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+            result:
+                QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, providers })),
+            ..
+        })) => {
+            let mut providers = providers.iter().copied().collect::<Vec<_>>();
+            providers.sort();
+            NodeEvent::ProvidersFound {
+                key: key.clone(),
+                providers,
+            }
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+            relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                renewal,
+                ..
+            },
+        )) => NodeEvent::RelayReservationActive {
+            relay: *relay_peer_id,
+            renewal: *renewal,
+        },
+        _ => return None,
+    };
+    Some(node_event)
+}
+
+/// Name of the most specific transport protocol in an address
+fn transport_name(address: &Multiaddr) -> &'static str {
+    address
+        .iter()
+        .filter_map(|protocol| match protocol {
+            Protocol::Tcp(_) => Some("tcp"),
+            Protocol::Udp(_) => Some("udp"),
+            Protocol::Quic => Some("quic"),
+            Protocol::QuicV1 => Some("quic-v1"),
+            Protocol::Ws(_) => Some("websocket"),
+            Protocol::Wss(_) => Some("websocket-tls"),
+            Protocol::WebRTCDirect => Some("webrtc-direct"),
+            Protocol::Memory(_) => Some("memory"),
+            _ => None,
+        })
+        .last()
+        .unwrap_or("unknown")
+}
+
+/// Fails the replies pending for [`PEER_REQUEST_TIMEOUT`] by `now`
+fn expire_pending_replies<K, T>(
+    requests: &mut HashMap<K, Vec<PendingReply<Result<T, PeerRequestError>>>>,
+    now: Instant,
+) {
+    for pending in requests.values_mut() {
+        for expired in pending.extract_if(.., |pending| {
+            now.duration_since(pending.started_at) >= PEER_REQUEST_TIMEOUT
+        }) {
+            let _ = expired.reply.send(Err(PeerRequestError::Timeout));
+        }
+    }
+    requests.retain(|_, pending| !pending.is_empty());
+}
+
+/// Delay before the next relay dial after this many failed ones, doubling up to
+/// [`RELAY_DIAL_MAX_BACKOFF`]
+fn relay_dial_backoff(failed_attempts: u32) -> Duration {
+    RELAY_DIAL_INITIAL_BACKOFF
+        .saturating_mul(1 << failed_attempts.saturating_sub(1).min(16))
+        .min(RELAY_DIAL_MAX_BACKOFF)
+}
+
+/// Answers every caller waiting on the key
+fn answer_pending_replies<K: Eq + Hash, T: Clone>(
+    requests: &mut HashMap<K, Vec<PendingReply<T>>>,
+    key: &K,
+    answer: T,
+) {
+    for pending in requests.remove(key).unwrap_or_default() {
+        let _ = pending.reply.send(answer.clone());
+    }
+}
+
+/// When the first of the pending replies times out
+fn next_reply_timeout<K, T>(requests: &HashMap<K, Vec<PendingReply<T>>>) -> Option<Instant> {
+    requests
+        .values()
+        .flatten()
+        .map(|pending| pending.started_at + PEER_REQUEST_TIMEOUT)
+        .min()
+}
+
+/// Resolves at the deadline, or never if there is none
+async fn wait_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Our circuit address through every relay holding a reservation for us, in the order the
+/// relays are configured
+fn circuit_addresses(
+    relay_candidates: &[RelayCandidate],
+    active_reservations: &HashSet<PeerId>,
+    local_peer_id: PeerId,
+) -> Vec<Multiaddr> {
+    relay_candidates
+        .iter()
+        .filter(|candidate| active_reservations.contains(&candidate.peer_id))
+        .map(|candidate| {
+            candidate
+                .address
+                .clone()
+                .with(Protocol::P2p(candidate.peer_id))
+                .with(Protocol::P2pCircuit)
+                .with(Protocol::P2p(local_peer_id))
+        })
+        .collect()
+}
+
+/// Whether identify info received at `received_at` went without a refresh for longer than `expiry`
+fn identify_expired(received_at: Instant, now: Instant, expiry: Duration) -> bool {
+    now.saturating_duration_since(received_at) > expiry
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        core::{Endpoint, transport::PortUse},
+        identity,
+    };
+
+    use super::*;
+    use crate::local_config::{AppConfig, IdentifyConfig, RelayServerConfig, Transports};
+
+    #[test]
+    fn identify_entry_expires_after_configured_duration() {
+        let config = IdentifyConfig {
+            interval_secs: 10,
+            expiry_intervals: 3,
+        };
+        let received_at = Instant::now();
+
+        assert!(!identify_expired(
+            received_at,
+            received_at + Duration::from_secs(30),
+            config.expiry()
+        ));
+        assert!(identify_expired(
+            received_at,
+            received_at + Duration::from_secs(31),
+            config.expiry()
+        ));
+    }
+
+    #[test]
+    fn circuit_addresses_go_through_the_reserved_relays() {
+        let relay = |peer_id: PeerId, address: &str| RelayCandidate {
+            peer_id,
+            address: address.parse().unwrap(),
+            probe_started_at: None,
+            response_time: None,
+            circuit_listener: None,
+            circuit_listen_retry_at: None,
+        };
+        let (reserved, unreserved, local) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let relays = [
+            relay(unreserved, "/ip4/10.0.0.1/udp/4001/quic-v1"),
+            relay(reserved, "/ip4/10.0.0.2/udp/4001/quic-v1"),
+        ];
+
+        let addresses = circuit_addresses(&relays, &HashSet::from([reserved]), local);
+
+        let expected =
+            format!("/ip4/10.0.0.2/udp/4001/quic-v1/p2p/{reserved}/p2p-circuit/p2p/{local}");
+        assert_eq!(addresses, [expected.parse::<Multiaddr>().unwrap()]);
+    }
+
+    #[test]
+    fn connections_are_described_as_direct_or_relayed() {
+        let dialer = |address: String| ConnectedPoint::Dialer {
+            address: address.parse().unwrap(),
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        };
+        let (peer, relay) = (PeerId::random(), PeerId::random());
+
+        assert_eq!(
+            describe_connection(
+                &peer,
+                &dialer(format!(
+                    "/ip4/10.0.0.1/udp/4001/quic-v1/p2p/{relay}/p2p-circuit/p2p/{peer}"
+                ))
+            ),
+            format!("relayed connection to {peer} via {relay}")
+        );
+        assert_eq!(
+            describe_connection(&peer, &dialer("/ip4/10.0.0.2/udp/4001/quic-v1".to_string())),
+            format!("direct connection to {peer} via quic-v1")
+        );
+    }
+
+    fn node_config(relay_server: bool) -> AppConfig {
+        AppConfig {
+            relay_server: RelayServerConfig {
+                enabled: relay_server,
+            },
+            enable_mdns: false,
+            transports: Transports::Tcp,
+            ..Default::default()
+        }
+    }
+
+    /// Runs a relay on a loopback address in the background
+    async fn spawn_relay() -> (PeerId, Multiaddr) {
+        let keypair = identity::Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let (mut relay, _) = crate::build_swarm(
+            &node_config(true),
+            keypair,
+            "secret",
+            &mut Registry::default(),
+        )
+        .unwrap();
+        relay
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = relay.select_next_some().await {
+                break address;
+            }
+        };
+        tokio::spawn(async move { while relay.next().await.is_some() {} });
+        (peer_id, address)
+    }
+
+    /// A manager driven by the test instead of [`SwarmManager::run`]
+    fn swarm_manager(relays: Vec<(PeerId, Multiaddr)>) -> SwarmManager {
+        let (swarm, _) = crate::build_swarm(
+            &node_config(false),
+            identity::Keypair::generate_ed25519(),
+            "secret",
+            &mut Registry::default(),
+        )
+        .unwrap();
+        let (_commands, command_rx) = mpsc::channel(1);
+        SwarmManager::new(swarm, command_rx, relays, Duration::from_secs(60), 0)
+    }
+
+    #[tokio::test]
+    async fn record_put_missing_the_quorum_succeeds_on_a_retry_with_enough_peers() {
+        let (server_peer_id, server_address) = spawn_relay().await;
+        let mut manager = swarm_manager(vec![(server_peer_id, server_address.clone())])
+            .with_record_put_policy(kad::Quorum::One, 3);
+        let (reply, mut stored) = oneshot::channel();
+        manager.start_record_put(RecordPut {
+            record: kad::Record::new(b"key".to_vec(), b"value".to_vec()),
+            attempts: 0,
+            reply,
+        });
+
+        let result = tokio::time::timeout(Duration::from_secs(30), async {
+            // nobody to store the record with yet
+            while manager.record_put_retries.is_empty() {
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+            }
+            manager
+                .swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&server_peer_id, server_address);
+            let (_, put) = manager.record_put_retries.remove(0);
+            manager.start_record_put(put);
+            loop {
+                if let Ok(result) = stored.try_recv() {
+                    break result;
+                }
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+            }
+        })
+        .await
+        .expect("the put finished in time");
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn first_relay_to_respond_is_selected() {
+        let (relay_peer_id, relay_address) = spawn_relay().await;
+        // nothing listens on the first relay's address, so its dials are refused
+        let down = (PeerId::random(), "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        let mut manager = swarm_manager(vec![down, (relay_peer_id, relay_address)]);
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            manager.probe_relays();
+            manager.dial_relay();
+            while !manager.relay_selected {
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+            }
+        })
+        .await
+        .expect("a relay was selected in time");
+
+        assert_eq!(manager.relay_peer_id, relay_peer_id);
+    }
+
+    #[tokio::test]
+    async fn failed_circuit_listen_is_retried_instead_of_panicking() {
+        let relay_peer_id = PeerId::random();
+        // without a relay address the relay transport refuses the circuit
+        let mut manager = swarm_manager(vec![(relay_peer_id, Multiaddr::empty())]);
+        let candidate = |manager: &SwarmManager| {
+            manager
+                .relay_candidates
+                .iter()
+                .find(|candidate| candidate.peer_id == relay_peer_id)
+                .map(|candidate| {
+                    (
+                        candidate.circuit_listener,
+                        candidate.circuit_listen_retry_at,
+                    )
+                })
+                .unwrap()
+        };
+        assert_eq!(candidate(&manager), (None, None));
+
+        manager.listen_on_relay_circuit(relay_peer_id);
+
+        let (listener, retry_at) = candidate(&manager);
+        assert_eq!(listener, None);
+        assert!(retry_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn isolation_redials_the_relay_after_the_timeout() {
+        let relay_peer_id = PeerId::random();
+        let mut manager = swarm_manager(vec![(
+            relay_peer_id,
+            "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        )])
+        .with_isolation_watchdog(Duration::from_millis(50));
+        let mut events = manager.subscribe_node_events();
+
+        let deadline = manager
+            .isolation_watchdog
+            .as_ref()
+            .and_then(IsolationWatchdog::next_deadline)
+            .expect("a node without connections is isolated");
+        tokio::time::sleep_until(deadline).await;
+        manager.recover_from_isolation();
+        tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                if let SwarmEvent::Dialing {
+                    peer_id: Some(peer_id),
+                    ..
+                } = manager.swarm.select_next_some().await
+                    && peer_id == relay_peer_id
+                {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("the relay was redialed in time");
+
+        for _ in 1..ISOLATION_WARNING_CYCLES {
+            manager.recover_from_isolation();
+        }
+        assert!(
+            std::iter::from_fn(|| events.try_recv().ok()).any(|event| matches!(
+                event,
+                NodeEvent::Isolated { recoveries, .. } if recoveries == ISOLATION_WARNING_CYCLES
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn releasing_the_relay_closes_circuit_listeners_before_the_connection() {
+        let (relay_peer_id, relay_address) = spawn_relay().await;
+        let mut manager = swarm_manager(vec![(relay_peer_id, relay_address)]);
+
+        let released = tokio::time::timeout(Duration::from_secs(30), async {
+            manager.dial_relay();
+            while manager.active_reservations.is_empty() {
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+            }
+
+            let (reply, released) = oneshot::channel();
+            manager.release_relay(reply);
+            assert!(
+                manager
+                    .relay_candidates
+                    .iter()
+                    .all(|candidate| candidate.circuit_listener.is_none())
+            );
+            // the relay connection stays up until it saw the reservation go
+            assert!(manager.swarm.is_connected(&relay_peer_id));
+
+            let mut listener_closed = false;
+            loop {
+                let event = manager.swarm.select_next_some().await;
+                match &event {
+                    SwarmEvent::ListenerClosed { .. } => listener_closed = true,
+                    SwarmEvent::ConnectionClosed { peer_id, .. } if *peer_id == relay_peer_id => {
+                        assert!(listener_closed, "closed the relay connection first");
+                    }
+                    _ => {}
+                }
+                manager.handle_swarm_event(&event);
+                if !manager.swarm.is_connected(&relay_peer_id) {
+                    break released;
+                }
+            }
+        })
+        .await
+        .expect("the relay was released in time");
+
+        assert_eq!(released.await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn reservation_limits_are_the_ones_the_relay_accepted_with() {
+        let (relay_peer_id, relay_address) = spawn_relay().await;
+        let mut manager = swarm_manager(vec![(relay_peer_id, relay_address)]);
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            manager.dial_relay();
+            while manager.active_reservations.is_empty() {
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+            }
+        })
+        .await
+        .expect("the relay accepted the reservation in time");
+
+        let relay_config = relay::Config::default();
+        let limits = &manager.reservation_limits[&relay_peer_id];
+        assert_eq!(limits.duration, Some(relay_config.max_circuit_duration));
+        assert_eq!(limits.data_in_bytes, Some(relay_config.max_circuit_bytes));
+    }
+
+    #[tokio::test]
+    async fn connectivity_report_names_the_paths_that_reach_the_peer() {
+        let (relay_peer_id, relay_address) = spawn_relay().await;
+        let keypair = identity::Keypair::generate_ed25519();
+        let target = keypair.public().to_peer_id();
+        let (mut target_swarm, _) = crate::build_swarm(
+            &node_config(false),
+            keypair,
+            "secret",
+            &mut Registry::default(),
+        )
+        .unwrap();
+        target_swarm
+            .listen_on(
+                relay_address
+                    .clone()
+                    .with(Protocol::P2p(relay_peer_id))
+                    .with(Protocol::P2pCircuit),
+            )
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                if let SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                    relay::client::Event::ReservationReqAccepted { .. },
+                )) = target_swarm.select_next_some().await
+                {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("the target reserved a circuit in time");
+        tokio::spawn(async move { while target_swarm.next().await.is_some() {} });
+        let mut manager = swarm_manager(vec![(relay_peer_id, relay_address)]);
+
+        let (reply, _report) = oneshot::channel();
+        manager.start_connectivity_report(target, reply);
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while manager
+                .connectivity_probes
+                .iter()
+                .any(|(_, probe)| probe.stage == ConnectivityStage::Relayed)
+            {
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+            }
+        })
+        .await
+        .expect("the relayed path was tried in time");
+
+        let (_, probe) = manager
+            .connectivity_probes
+            .first()
+            .expect("the upgrade is tried after the relayed path");
+        assert!(matches!(probe.report.direct, PathOutcome::Skipped(_)));
+        assert!(matches!(probe.report.relayed, PathOutcome::Reachable(_)));
+    }
+
+    #[tokio::test]
+    async fn subscribed_peer_joins_the_topic_mesh() {
+        let topic = gossipsub::IdentTopic::new("chat");
+        let keypair = identity::Keypair::generate_ed25519();
+        let other = keypair.public().to_peer_id();
+        let (mut other_swarm, _) = crate::build_swarm(
+            &node_config(false),
+            keypair,
+            "secret",
+            &mut Registry::default(),
+        )
+        .unwrap();
+        other_swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&topic)
+            .unwrap();
+        other_swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = other_swarm.select_next_some().await
+            {
+                break address;
+            }
+        };
+        tokio::spawn(async move { while other_swarm.next().await.is_some() {} });
+        let mut manager = swarm_manager(vec![(
+            PeerId::random(),
+            "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        )]);
+        manager
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&topic)
+            .unwrap();
+        manager.swarm.dial(address).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while manager.topic_peers(&topic.hash()).mesh.is_empty() {
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+            }
+        })
+        .await
+        .expect("the peer joined the mesh in time");
+
+        let peers = manager.topic_peers(&topic.hash());
+        assert_eq!(peers.mesh, [other]);
+        assert_eq!(peers.subscribed, [other]);
+    }
+
+    #[tokio::test]
+    async fn published_message_is_surfaced_with_its_topic_and_source() {
+        let topic = gossipsub::IdentTopic::new("chat");
+        let keypair = identity::Keypair::generate_ed25519();
+        let publisher = keypair.public().to_peer_id();
+        let (mut publisher_swarm, _) = crate::build_swarm(
+            &node_config(false),
+            keypair,
+            "secret",
+            &mut Registry::default(),
+        )
+        .unwrap();
+        publisher_swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&topic)
+            .unwrap();
+        publisher_swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } =
+                publisher_swarm.select_next_some().await
+            {
+                break address;
+            }
+        };
+        let published = topic.clone();
+        tokio::spawn(async move {
+            loop {
+                // publish once we know someone listens on the topic
+                if let SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
+                    gossipsub::Event::Subscribed { .. },
+                )) = publisher_swarm.select_next_some().await
+                {
+                    let _ = publisher_swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .publish(published.clone(), b"hello".to_vec());
+                }
+            }
+        });
+        let mut manager = swarm_manager(vec![(
+            PeerId::random(),
+            "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        )]);
+        let mut events = manager.subscribe_node_events();
+        manager
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&topic)
+            .unwrap();
+        manager.swarm.dial(address).unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                let event = manager.swarm.select_next_some().await;
+                manager.handle_swarm_event(&event);
+                while let Ok(event) = events.try_recv() {
+                    if let NodeEvent::GossipMessage {
+                        topic,
+                        source,
+                        data,
+                    } = event
+                    {
+                        return (topic, source, data);
+                    }
+                }
+            }
+        })
+        .await
+        .expect("the message arrived in time");
+
+        assert_eq!(received, (topic.hash(), Some(publisher), b"hello".to_vec()));
+        assert!(manager.topic_members[&topic.hash()].contains(&publisher));
+    }
+
+    #[tokio::test]
+    async fn heads_are_reported_as_hex_change_hashes() {
+        let mut manager = swarm_manager(vec![(
+            PeerId::random(),
+            "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        )]);
+        let automerge = &mut manager.swarm.behaviour_mut().automerge;
+        automerge.modify_document("test", |doc| {
+            doc.put(automerge::ROOT, "key", "value").unwrap();
+        });
+        let expected = automerge
+            .heads("test")
+            .unwrap()
+            .iter()
+            .map(|head| {
+                head.0
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(manager.document_heads("test"), Some(expected));
+        assert_eq!(manager.document_heads("missing"), None);
+    }
+
+    #[tokio::test]
+    async fn mapped_address_becomes_an_external_address_until_it_expires() {
+        let mut manager = swarm_manager(vec![(
+            PeerId::random(),
+            "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        )]);
+        let address: Multiaddr = "/ip4/203.0.113.7/tcp/4001".parse().unwrap();
+
+        manager.handle_swarm_event(&SwarmEvent::Behaviour(BehaviourEvent::Upnp(
+            upnp::Event::NewExternalAddr(address.clone()),
+        )));
+        assert!(
+            manager
+                .swarm
+                .external_addresses()
+                .any(|external| *external == address)
+        );
+        assert_eq!(manager.upnp_addresses, [address.clone()]);
+
+        manager.handle_swarm_event(&SwarmEvent::Behaviour(BehaviourEvent::Upnp(
+            upnp::Event::ExpiredExternalAddr(address.clone()),
+        )));
+        assert!(
+            manager
+                .swarm
+                .external_addresses()
+                .all(|external| *external != address)
+        );
+        assert!(manager.upnp_addresses.is_empty());
+    }
+
+    #[test]
+    fn relay_dials_back_off_exponentially_up_to_the_maximum() {
+        let backoffs = [1, 2, 3, 6, 7, 40].map(relay_dial_backoff);
+
+        assert_eq!(
+            backoffs.map(|backoff| backoff.as_secs()),
+            [1, 2, 4, 32, 60, 60]
+        );
+    }
+
+    #[test]
+    fn peer_documents_are_answered_to_every_caller_asking_that_peer() {
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let mut requests = HashMap::new();
+        let mut pending = |peer: PeerId| {
+            let (reply, answer) = oneshot::channel::<Result<Vec<String>, PeerRequestError>>();
+            requests
+                .entry(peer)
+                .or_insert_with(Vec::new)
+                .push(PendingReply {
+                    reply,
+                    started_at: Instant::now(),
+                });
+            answer
+        };
+        let (mut first, mut second, mut unrelated) = (pending(peer), pending(peer), pending(other));
+
+        answer_pending_replies(&mut requests, &peer, Ok(vec!["doc".to_string()]));
+        answer_pending_replies(&mut requests, &other, Err(PeerRequestError::Unsupported));
+
+        assert_eq!(first.try_recv(), Ok(Ok(vec!["doc".to_string()])));
+        assert_eq!(second.try_recv(), Ok(Ok(vec!["doc".to_string()])));
+        assert_eq!(unrelated.try_recv(), Ok(Err(PeerRequestError::Unsupported)));
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn peer_requests_time_out_at_their_deadline() {
+        let started_at = Instant::now();
+        let (reply, mut answer) = oneshot::channel::<Result<(), PeerRequestError>>();
+        let mut requests =
+            HashMap::from([(PeerId::random(), vec![PendingReply { reply, started_at }])]);
+        let deadline = started_at + PEER_REQUEST_TIMEOUT;
+        assert_eq!(next_reply_timeout(&requests), Some(deadline));
+
+        expire_pending_replies(&mut requests, deadline - Duration::from_millis(1));
+        assert!(answer.try_recv().is_err());
+        assert_eq!(requests.len(), 1);
+
+        expire_pending_replies(&mut requests, deadline);
+        assert_eq!(answer.try_recv(), Ok(Err(PeerRequestError::Timeout)));
+        assert!(requests.is_empty());
+        assert_eq!(next_reply_timeout(&requests), None);
+    }
+}
@@ -0,0 +1,49 @@
+//! Two peers first reaching each other through a relay circuit upgrade to a direct connection
+//! through DCUtR.
+
+mod harness;
+
+use std::time::Duration;
+
+use peer::swarm_dispatch::{NodeEvent, SwarmCommand};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relayed_connection_is_upgraded_to_a_direct_one() {
+    let relay = harness::spawn_relay("dcutr-relay").await;
+    let mut listener = harness::spawn_node("dcutr-listener", &relay);
+    let mut dialer = harness::spawn_node("dcutr-dialer", &relay);
+
+    listener
+        .wait_for(TIMEOUT, |event| match event {
+            NodeEvent::RelayReservationActive {
+                relay: reserved, ..
+            } => (*reserved == relay.peer_id).then_some(()),
+            _ => None,
+        })
+        .await;
+    dialer
+        .commands
+        .send(SwarmCommand::Dial(relay.circuit_address(listener.peer_id)))
+        .await
+        .unwrap();
+
+    let listener_id = listener.peer_id;
+    let relayed = dialer
+        .wait_for(TIMEOUT, |event| match event {
+            NodeEvent::PeerConnected { peer, relayed } if *peer == listener_id => Some(*relayed),
+            _ => None,
+        })
+        .await;
+    assert!(relayed, "the first connection should go through the relay");
+    dialer
+        .wait_for(TIMEOUT, |event| match event {
+            NodeEvent::DirectConnectionEstablished(peer) if *peer == listener_id => Some(()),
+            NodeEvent::RelayedSyncFallback(peer) if *peer == listener_id => {
+                panic!("hole punching failed")
+            }
+            _ => None,
+        })
+        .await;
+}
@@ -0,0 +1,166 @@
+//! Runs nodes in the test process: a relay and peers reaching each other through it, each built
+//! the way the peer binary builds its own.
+
+use std::{path::PathBuf, time::Duration};
+
+use futures::StreamExt;
+use libp2p::{
+    Multiaddr, PeerId, identity, metrics::Registry, multiaddr::Protocol, swarm::SwarmEvent,
+};
+use peer::{
+    build_swarm,
+    local_config::{AppConfig, IdentityConfig, RelayConfig, RelayServerConfig, Transports},
+    swarm_dispatch::{NodeEvent, SwarmCommand},
+};
+use tokio::sync::{broadcast, mpsc};
+
+const PRE_SHARED_KEY: &str = "integration test key";
+
+/// A relay serving reservations and circuits on a loopback TCP address
+pub struct Relay {
+    pub peer_id: PeerId,
+    pub address: Multiaddr,
+    /// Removed once the relay is dropped
+    data_dir: PathBuf,
+}
+
+/// A peer run by a [`peer::swarm_dispatch::SwarmManager`]
+pub struct Node {
+    pub peer_id: PeerId,
+    pub commands: mpsc::Sender<SwarmCommand>,
+    pub events: broadcast::Receiver<NodeEvent>,
+    /// Removed once the node is dropped
+    data_dir: PathBuf,
+}
+
+/// A config for TCP on the loopback interface only, so nodes don't find each other through
+/// anything but the relay
+fn config(name: &str, relays: Vec<RelayConfig>) -> AppConfig {
+    let dir = data_dir(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    AppConfig {
+        identity: IdentityConfig {
+            key_file_path: dir.join("key.pem"),
+            pre_shared_key: PRE_SHARED_KEY.to_string(),
+            ..Default::default()
+        },
+        relays,
+        db_path: dir.join("data"),
+        enable_mdns: false,
+        enable_upnp: false,
+        documents: Vec::new(),
+        listen_addresses: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+        transports: Transports::Tcp,
+        ..Default::default()
+    }
+}
+
+/// A directory of its own for each node
+fn data_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("peer-harness-{}-{}", name, std::process::id()))
+}
+
+/// Starts a relay, returning once it listens
+pub async fn spawn_relay(name: &str) -> Relay {
+    let config = AppConfig {
+        relay_server: RelayServerConfig { enabled: true },
+        ..config(name, Vec::new())
+    };
+    let keypair = identity::Keypair::generate_ed25519();
+    let peer_id = keypair.public().to_peer_id();
+    let (mut swarm, _) =
+        build_swarm(&config, keypair, PRE_SHARED_KEY, &mut Registry::default()).unwrap();
+    swarm.listen_on(config.listen_addresses[0].clone()).unwrap();
+
+    let address = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            break address;
+        }
+    };
+    // the relay needs no manager, it only serves reservations and circuits
+    tokio::spawn(async move { while swarm.next().await.is_some() {} });
+
+    Relay {
+        peer_id,
+        address,
+        data_dir: data_dir(name),
+    }
+}
+
+/// Starts a peer using the relay
+pub fn spawn_node(name: &str, relay: &Relay) -> Node {
+    let config = config(
+        name,
+        vec![RelayConfig {
+            address: relay.address.clone(),
+            peer_id: relay.peer_id,
+        }],
+    );
+    let keypair = identity::Keypair::generate_ed25519();
+    let peer_id = keypair.public().to_peer_id();
+    let mut registry = Registry::default();
+    let (mut swarm, rekeyable_noise) =
+        build_swarm(&config, keypair, PRE_SHARED_KEY, &mut registry).unwrap();
+    swarm.listen_on(config.listen_addresses[0].clone()).unwrap();
+
+    let (commands, command_rx) = mpsc::channel(32);
+    let swarm_manager = peer::swarm_manager(&config, swarm, rekeyable_noise, registry, command_rx);
+    let events = swarm_manager.subscribe_node_events();
+    tokio::spawn(swarm_manager.run());
+
+    Node {
+        peer_id,
+        commands,
+        events,
+        data_dir: data_dir(name),
+    }
+}
+
+impl Relay {
+    /// Address reaching the peer through a circuit of this relay
+    pub fn circuit_address(&self, peer_id: PeerId) -> Multiaddr {
+        self.address
+            .clone()
+            .with(Protocol::P2p(self.peer_id))
+            .with(Protocol::P2pCircuit)
+            .with(Protocol::P2p(peer_id))
+    }
+}
+
+impl Node {
+    /// Waits for the first event the filter picks, failing the test after the timeout
+    pub async fn wait_for<T>(
+        &mut self,
+        timeout: Duration,
+        mut filter: impl FnMut(&NodeEvent) -> Option<T>,
+    ) -> T {
+        let wait = async {
+            loop {
+                match self.events.recv().await {
+                    Ok(event) => {
+                        if let Some(found) = filter(&event) {
+                            return found;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => panic!("node stopped"),
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .expect("timed out waiting for a node event")
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
@@ -0,0 +1,102 @@
+//! Single file snapshot of a set of documents.
+//!
+//! The archive starts with [`MAGIC`] and a big endian u32 document count, followed by one entry
+//! per document: the id as u32 length + UTF-8 bytes, then the saved document as u64 length +
+//! bytes.
+
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"AMARCH01";
+
+pub(crate) fn write_archive<W: Write>(
+    writer: &mut W,
+    documents: &[(String, Vec<u8>)],
+) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(documents.len() as u32).to_be_bytes())?;
+    for (document_id, bytes) in documents {
+        writer.write_all(&(document_id.len() as u32).to_be_bytes())?;
+        writer.write_all(document_id.as_bytes())?;
+        writer.write_all(&(bytes.len() as u64).to_be_bytes())?;
+        writer.write_all(bytes)?;
+    }
+    writer.flush()
+}
+
+pub(crate) fn read_archive<R: Read>(reader: &mut R) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a document archive"));
+    }
+
+    let count = read_u32(reader)?;
+    let mut documents = Vec::new();
+    for _ in 0..count {
+        let id_length = read_u32(reader)? as u64;
+        let document_id = String::from_utf8(read_bytes(reader, id_length)?)
+            .map_err(|_| invalid_data("document id is not valid UTF-8"))?;
+        let length = read_u64(reader)?;
+        documents.push((document_id, read_bytes(reader, length)?));
+    }
+
+    Ok(documents)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Reads exactly `length` bytes without trusting the length for the allocation up front
+fn read_bytes<R: Read>(reader: &mut R, length: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "archive is truncated",
+        ));
+    }
+    Ok(bytes)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_round_trips() {
+        let documents = vec![
+            ("a".to_string(), vec![1, 2, 3]),
+            ("ünïcode".to_string(), vec![]),
+            ("b".to_string(), vec![0; 1024]),
+        ];
+        let mut archive = Vec::new();
+        write_archive(&mut archive, &documents).unwrap();
+
+        assert_eq!(read_archive(&mut archive.as_slice()).unwrap(), documents);
+    }
+
+    #[test]
+    fn truncated_and_foreign_files_are_refused() {
+        let mut archive = Vec::new();
+        write_archive(&mut archive, &[("a".to_string(), vec![1, 2, 3])]).unwrap();
+
+        let truncated = read_archive(&mut &archive[..archive.len() - 1]).unwrap_err();
+        assert_eq!(truncated.kind(), io::ErrorKind::UnexpectedEof);
+        let foreign = read_archive(&mut &b"not an archive"[..]).unwrap_err();
+        assert_eq!(foreign.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -2,20 +2,30 @@ use std::{
     collections::{HashMap, HashSet, VecDeque},
     convert::Infallible,
     hash::Hash,
+    io,
     path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
 use automerge::{
-    AutoCommit,
-    sync::{self, Message, SyncDoc},
+    AutoCommit, ChangeHash,
+    sync::{self, SyncDoc},
 };
 use either::Either::{self, Left};
+use futures::FutureExt;
+use futures_timer::Delay;
 use libp2p::{
     PeerId,
     swarm::{ConnectionId, NetworkBehaviour, NotifyHandler, ToSwarm, dummy},
 };
 
-use crate::handler::{Command, Handler, InEvent};
+use crate::{
+    capability::{Capability, CapabilityVerifier},
+    handler::{Command, Handler, InEvent, OutEvent, Subscriptions},
+    protocol::{Message, SyncErrorReason},
+    rate_limiter::{Admission, RateLimiter, SyncRateLimit},
+    sync_scheduler::{SyncScheduler, SyncScheduling},
+};
 
 /// Event generated by the Automerge behaviour
 #[derive(Debug)]
@@ -44,6 +54,48 @@ pub enum Event {
         document_id: String,
         error: String,
     },
+    /// A peer told us which documents it has
+    AvailableDocuments {
+        peer: PeerId,
+        document_ids: Vec<String>,
+    },
+    /// A peer has a document we don't, that we would accept. Emitted for every such document of
+    /// an [`Event::AvailableDocuments`].
+    DocumentAvailable {
+        peer: PeerId,
+        document_id: String,
+    },
+    /// A peer doesn't support the automerge protocol
+    UnsupportedPeer {
+        peer: PeerId,
+    },
+    /// A peer told us the heads of its copy of a document, `None` if it doesn't have it
+    RemoteHeads {
+        peer: PeerId,
+        document_id: String,
+        heads: Option<Vec<ChangeHash>>,
+    },
+    /// A peer deleted a document, so we removed our copy as well
+    DocumentDeleted {
+        peer: PeerId,
+        document_id: String,
+    },
+    /// The outbound queue reached [`Config::max_queued_messages`], emitted again only after it
+    /// drained to half of that
+    OutboundQueueFull {
+        queued_messages: usize,
+    },
+    /// The peer sent messages faster than [`Config::inbound_sync_limit`] allows, they're
+    /// dropped until it slows down. Emitted again only after one of its messages got through.
+    PeerThrottled {
+        peer: PeerId,
+    },
+    /// A document was reloaded from its saved form and persisted as a snapshot, `size` is the
+    /// byte size of that snapshot
+    DocumentCompacted {
+        document_id: String,
+        size: usize,
+    },
 }
 
 #[derive(Debug)]
@@ -51,216 +103,2649 @@ pub struct Config {
     pub max_simultaneous_syncs: usize,
     pub documents_whitelist: Option<Vec<String>>,
     pub data_dir: PathBuf,
+    /// How long a peer counts as working on a document after its last message about it
+    pub peer_activity_window: Duration,
+    /// Connections per peer that get an automerge handler, further ones get a dummy handler
+    pub max_handlers_per_peer: usize,
+    pub persistence: Persistence,
+    /// Order in which queued syncs get one of the `max_simultaneous_syncs` slots
+    pub sync_scheduling: SyncScheduling,
+    /// How long a started sync may hold its slot without converging before it's started over,
+    /// behind the syncs already waiting for a slot
+    pub sync_timeout: Duration,
+    /// Messages waiting to be handed to the connection handlers before `queue_overflow` applies
+    /// to the changes of documents
+    pub max_queued_messages: usize,
+    pub queue_overflow: QueueOverflow,
+    /// Messages of any kind each peer may send us, so a peer can't keep us busy merging.
+    /// Unlimited when `None`.
+    pub inbound_sync_limit: Option<SyncRateLimit>,
+    /// Changes a document may gain after its last compaction before it's compacted again.
+    /// Documents are only compacted on request when `None`.
+    pub compact_after_changes: Option<usize>,
+}
+
+/// What happens to the changes of a document while the outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueOverflow {
+    /// No sync messages are generated until the queue drained to half its size, every change
+    /// made in the meantime is sent in one go after
+    #[default]
+    Backpressure,
+    /// The oldest queued sync message is dropped to make room, its sync is started over once
+    /// the queue drained
+    DropOldest,
+}
+
+/// How documents are written to the data directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Persistence {
+    /// Every change rewrites the whole document
+    Snapshot,
+    /// Every change is appended to a log next to the document, which is rewritten as a snapshot
+    /// once the log holds `compact_after` entries
+    ChangeLog { compact_after: usize },
+}
+
+/// Progress of a document's change log
+struct ChangeLogState {
+    /// Heads covered by the snapshot and the log
+    logged_heads: Vec<ChangeHash>,
+    entries: usize,
 }
 
 pub struct Behaviour {
     /// Events to be sent to the handler
     queued_events: VecDeque<ToSwarm<Event, InEvent>>,
     active_syncs: HashMap<PeerId, HashSet<ConnectionId>>,
-    /// Pending commands to send to connection handlers
-    pending_commands: HashMap<(PeerId, String), VecDeque<Command>>,
     config: Config,
     documents: HashMap<String, automerge::AutoCommit>,
+    /// When each peer last sent us a message about a document
+    document_activity: HashMap<String, HashMap<PeerId, Instant>>,
+    /// When documents were last changed while running
+    last_modified: HashMap<String, SystemTime>,
+    /// Change logs of the documents, when persisting through change logs
+    change_logs: HashMap<String, ChangeLogState>,
+    /// Local labels of the documents, never synced
+    labels: crate::labels::Labels,
+    sync_scheduler: SyncScheduler,
+    /// Wakes the behaviour when the first running sync times out
+    sync_timer: Option<Delay>,
+    /// Automerge sync state with each peer, per document
+    sync_states: HashMap<(PeerId, String), sync::State>,
+    /// Syncs still exchanging messages, until both sides have the same heads
+    unsynced: HashSet<(PeerId, String)>,
+    /// Documents deleted here or by a peer, which are never synced again
+    tombstones: crate::tombstones::Tombstones,
+    /// Documents with changes not sent to peers because the outbound queue was full
+    held_back: HashSet<String>,
+    /// Whether [`Event::OutboundQueueFull`] was emitted since the queue last drained
+    queue_full: bool,
+    /// Messages handed to each connection's handler that it didn't write yet
+    in_flight: HashMap<ConnectionId, usize>,
+    /// Documents synced with peers
+    subscriptions: Subscriptions,
+    /// Documents connected peers refused because they aren't subscribed to them or we may not
+    /// write them
+    refused: HashSet<(PeerId, String)>,
+    /// Decides who may change documents, everyone may without one
+    capability_verifier: Option<Box<dyn CapabilityVerifier>>,
+    /// Our own capabilities, sent along with our sync messages of each document
+    capabilities: HashMap<String, Capability>,
+    /// Drops messages of peers over [`Config::inbound_sync_limit`]
+    rate_limiter: Option<RateLimiter>,
+    /// Changes each document gained since it was last compacted or loaded, counted as they
+    /// arrive so checking whether it's due doesn't go through the document's history
+    changes_since_compaction: HashMap<String, usize>,
+    /// Documents to compact once none of their syncs is running anymore
+    pending_compactions: HashSet<String>,
 }
 
 impl Behaviour {
     pub fn new(config: Config) -> Self {
+        let sync_scheduler = SyncScheduler::new(
+            config.sync_scheduling,
+            config.max_simultaneous_syncs,
+            config.sync_timeout,
+        );
+        let rate_limiter = config.inbound_sync_limit.map(RateLimiter::new);
         let mut behaviour = Behaviour {
             queued_events: VecDeque::new(),
             active_syncs: HashMap::new(),
-            pending_commands: HashMap::new(),
             config,
             documents: HashMap::new(),
+            document_activity: HashMap::new(),
+            last_modified: HashMap::new(),
+            change_logs: HashMap::new(),
+            labels: crate::labels::Labels::new(),
+            sync_scheduler,
+            sync_timer: None,
+            sync_states: HashMap::new(),
+            unsynced: HashSet::new(),
+            tombstones: crate::tombstones::Tombstones::new(),
+            held_back: HashSet::new(),
+            queue_full: false,
+            in_flight: HashMap::new(),
+            subscriptions: Subscriptions::default(),
+            refused: HashSet::new(),
+            capability_verifier: None,
+            capabilities: HashMap::new(),
+            rate_limiter,
+            changes_since_compaction: HashMap::new(),
+            pending_compactions: HashSet::new(),
         };
+        behaviour.labels =
+            crate::labels::load(&crate::labels::labels_path(&behaviour.config.data_dir))
+                .unwrap_or_else(|err| {
+                    tracing::warn!("Failed to load document labels: {}", err);
+                    crate::labels::Labels::new()
+                });
+        behaviour.tombstones = crate::tombstones::load(&crate::tombstones::tombstones_path(
+            &behaviour.config.data_dir,
+        ))
+        .unwrap_or_else(|err| {
+            tracing::warn!("Failed to load document tombstones: {}", err);
+            crate::tombstones::Tombstones::new()
+        });
 
         behaviour.initialize_config_documents();
+        behaviour.load_stored_documents();
         behaviour.write_all_documents();
         behaviour
     }
 
+    /// Like [`Self::new`], keeping the documents in `dir` instead of the configured data
+    /// directory
+    pub fn with_storage(mut config: Config, dir: impl Into<PathBuf>) -> Self {
+        config.data_dir = dir.into();
+        Self::new(config)
+    }
+
+    /// Only applies changes of peers the verifier lets write the document. Sync messages with
+    /// changes from anyone else are refused with [`SyncErrorReason::UNAUTHORIZED`], full
+    /// documents and deletions are ignored.
+    pub fn with_capability_verifier(mut self, verifier: impl CapabilityVerifier) -> Self {
+        self.capability_verifier = Some(Box::new(verifier));
+        self
+    }
+
+    /// Sends the capability along with our sync messages of the document, for peers that
+    /// check who may write it
+    pub fn set_capability(&mut self, document_id: &str, capability: Capability) {
+        self.capabilities
+            .insert(document_id.to_string(), capability);
+    }
+
     pub fn modify_document<F>(&mut self, document_id: &str, f: F)
     where
         F: FnOnce(&mut AutoCommit),
     {
-        if let Some(doc) = self.documents.get_mut(document_id) {
+        let _ = self.try_modify_document(document_id, |doc| {
             f(doc);
-            let mut state = sync::State::new();
-            let x = doc.sync().generate_sync_message(&mut state).unwrap();
-            let bytes = x.encode();
-            doc.sync()
-                .receive_sync_message(&mut state, Message::decode(&bytes).unwrap())
-                .unwrap();
+            Ok::<_, Infallible>(())
+        });
+    }
 
-            let commit = doc.commit();
-            tracing::debug!("Document {} modified, new heads: {:?}", document_id, commit);
+    /// Like [`Self::modify_document`], aborting the change when `f` fails. Nothing of an aborted
+    /// change is committed, persisted or sent to peers.
+    pub fn try_modify_document<F, E>(&mut self, document_id: &str, f: F) -> Result<(), E>
+    where
+        F: FnOnce(&mut AutoCommit) -> Result<(), E>,
+    {
+        let Some(doc) = self.documents.get_mut(document_id) else {
+            return Ok(());
+        };
+        if let Err(err) = f(doc) {
+            doc.rollback();
+            return Err(err);
+        }
+        let commit = doc.commit();
+        tracing::debug!("Document {} modified, new heads: {:?}", document_id, commit);
 
-            self.write_to_disk(document_id);
-            self.notify_document_changed(document_id.to_string());
+        self.document_changed(document_id, usize::from(commit.is_some()));
+        Ok(())
+    }
+
+    /// Applies changes a peer published outside of a sync, e.g. over gossipsub. Returns the new
+    /// heads if the changes added anything. Changes to documents we don't have, deleted ones or
+    /// ones the peer may not write are ignored, as are changes over the peer's
+    /// [`Config::inbound_sync_limit`].
+    pub fn apply_changes(
+        &mut self,
+        peer: PeerId,
+        document_id: &str,
+        changes: &[u8],
+    ) -> Result<Option<Vec<ChangeHash>>, automerge::AutomergeError> {
+        if !self.admit(peer) {
+            return Ok(None);
+        }
+        if self.is_deleted(document_id) || !self.may_write(peer, document_id, None) {
+            return Ok(None);
+        }
+        let Some(doc) = self.documents.get_mut(document_id) else {
+            return Ok(None);
+        };
+        let heads = doc.get_heads();
+        doc.load_incremental(changes)?;
+        let new_heads = doc.get_heads();
+        if new_heads == heads {
+            return Ok(None);
         }
+
+        // counting the changes would mean parsing them, a published batch counts as one
+        self.document_changed(document_id, 1);
+        Ok(Some(new_heads))
     }
 
     pub fn get_document(&self, document_id: &str) -> Option<&AutoCommit> {
         self.documents.get(document_id)
     }
 
-    /// Notify all other connected peers via that a document has changed
-    fn notify_document_changed(&mut self, document_id: String) {
-        for (peer_id, connection_ids) in &self.active_syncs {
-            let Some(connection_id) = connection_ids.iter().next() else {
-                continue;
-            };
+    pub fn document_ids(&self) -> impl Iterator<Item = &String> {
+        self.documents.keys()
+    }
 
-            tracing::debug!(
-                "Notifying peer {} of document change {} on connection {}",
-                peer_id,
-                document_id.clone(),
-                connection_id
-            );
-            self.queued_events.push_back(ToSwarm::NotifyHandler {
-                peer_id: *peer_id,
-                handler: NotifyHandler::One(*connection_id),
-                event: InEvent::DocumentChanged {
-                    document_id: document_id.clone(),
-                },
-            });
-        }
+    /// Asks a connected peer for its full copy of a document, which is merged into ours once it
+    /// arrives. Returns `false` if the peer isn't connected.
+    pub fn request_document(&mut self, peer: PeerId, document_id: &str) -> bool {
+        self.send_message(
+            peer,
+            Message::RequestDocument {
+                document_id: document_id.to_string(),
+            },
+        )
     }
-}
 
-impl Behaviour {
-    fn initialize_config_documents(&mut self) {
-        let Some(whitelist) = &self.config.documents_whitelist else {
-            return;
+    /// Asks a connected peer which documents it has, answered with [`Event::AvailableDocuments`].
+    /// Returns `false` if the peer isn't connected.
+    pub fn request_available_documents(&mut self, peer: PeerId) -> bool {
+        self.send_message(peer, Message::RequestAvailableDocuments)
+    }
+
+    /// Current heads of a local document
+    pub fn heads(&mut self, document_id: &str) -> Option<Vec<ChangeHash>> {
+        self.documents
+            .get_mut(document_id)
+            .map(AutoCommit::get_heads)
+    }
+
+    /// Changes made after the given heads in the incremental save format, together with the
+    /// document's current heads
+    pub fn changes_since(
+        &mut self,
+        document_id: &str,
+        heads: &[ChangeHash],
+    ) -> Option<(Vec<u8>, Vec<ChangeHash>)> {
+        self.documents
+            .get_mut(document_id)
+            .map(|doc| (doc.save_after(heads), doc.get_heads()))
+    }
+
+    /// When the peer last sent us a message about any document
+    pub fn last_peer_activity(&self, peer: PeerId) -> Option<Instant> {
+        self.document_activity
+            .values()
+            .filter_map(|activity| activity.get(&peer))
+            .max()
+            .copied()
+    }
+
+    /// Whether a sync with the peer is still exchanging messages
+    pub fn is_syncing_with(&self, peer: PeerId) -> bool {
+        self.unsynced.iter().any(|(syncing, _)| *syncing == peer)
+    }
+
+    /// Syncs still exchanging messages, by peer and document
+    pub fn syncs_in_progress(&self) -> Vec<(PeerId, String)> {
+        self.unsynced.iter().cloned().collect()
+    }
+
+    /// Connected peers that sent us messages about the document within the activity window
+    pub fn document_peers(&mut self, document_id: &str) -> Vec<PeerId> {
+        let window = self.config.peer_activity_window;
+        let Some(activity) = self.document_activity.get_mut(document_id) else {
+            return Vec::new();
         };
+        activity.retain(|_, last_seen| last_seen.elapsed() <= window);
 
-        for doc_id in whitelist {
-            if let Some(doc) = self.read_from_disk(doc_id) {
-                self.documents.insert(doc_id.clone(), doc);
-                continue;
-            };
+        let mut peers = activity
+            .keys()
+            .filter(|peer| self.active_syncs.contains_key(peer))
+            .copied()
+            .collect::<Vec<_>>();
+        peers.sort();
+        peers
+    }
 
-            tracing::debug!("Creating new document {}", doc_id);
-            self.documents
-                .entry(doc_id.clone())
-                .or_insert_with(|| automerge::AutoCommit::new());
+    /// Finds unpinned documents that weren't modified or asked about by any peer within `ttl`
+    /// and, unless `dry_run` is set, removes them from memory and disk
+    pub fn collect_garbage(&mut self, ttl: Duration, dry_run: bool) -> Vec<String> {
+        let now = SystemTime::now();
+        let mut collected = self
+            .documents
+            .keys()
+            .filter(|document_id| !self.is_pinned(document_id))
+            .filter(|document_id| {
+                self.last_modified_at(document_id)
+                    .is_some_and(|modified| now.duration_since(modified).unwrap_or_default() > ttl)
+            })
+            .filter(|document_id| {
+                self.document_activity
+                    .get(*document_id)
+                    .is_none_or(|activity| activity.values().all(|seen| seen.elapsed() > ttl))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        collected.sort();
+
+        if !dry_run {
+            let labeled = collected
+                .iter()
+                .any(|document_id| self.labels.contains_key(document_id));
+            for document_id in &collected {
+                self.remove_document(document_id);
+            }
+            if labeled {
+                self.write_labels();
+            }
         }
+
+        collected
     }
 
-    fn read_from_disk(&self, _document_id: &str) -> Option<AutoCommit> {
-        if self.documents.contains_key(_document_id) {
-            return None;
+    /// Deletes a document here and on every connected peer. A tombstone is kept, so the document
+    /// isn't synced back from a peer that still has a copy, and peers that later send changes
+    /// to it are told it was deleted instead. Deletion wins over such concurrent changes.
+    /// Returns `false` if we don't have the document.
+    pub fn delete_document(&mut self, document_id: &str) -> bool {
+        if !self.documents.contains_key(document_id) {
+            return false;
+        }
+
+        tracing::info!("Deleting document {}", document_id);
+        self.bury(document_id);
+        let peers = self.active_syncs.keys().copied().collect::<Vec<_>>();
+        for peer in peers {
+            self.send_delete(peer, document_id);
         }
+        true
+    }
+
+    /// Whether the document was deleted here or by a peer
+    pub fn is_deleted(&self, document_id: &str) -> bool {
+        self.tombstones.contains(document_id)
+    }
+
+    /// Removes the document and everything kept about it from memory and disk
+    fn remove_document(&mut self, document_id: &str) {
+        self.labels.remove(document_id);
+        self.documents.remove(document_id);
+        self.document_activity.remove(document_id);
+        self.last_modified.remove(document_id);
+        self.changes_since_compaction.remove(document_id);
+        self.pending_compactions.remove(document_id);
+        if let Err(err) = std::fs::remove_file(self.document_path(document_id))
+            && err.kind() != io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove {} from disk: {}", document_id, err);
+        }
+        if self.change_logs.remove(document_id).is_some()
+            && let Err(err) = std::fs::remove_file(crate::change_log::log_path(
+                &self.document_path(document_id),
+            ))
+            && err.kind() != io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove change log of {}: {}", document_id, err);
+        }
+    }
+
+    /// Removes the document, ends its syncs and writes its tombstone
+    fn bury(&mut self, document_id: &str) {
+        let labeled = self.labels.contains_key(document_id);
+        self.remove_document(document_id);
+        if labeled {
+            self.write_labels();
+        }
+        self.end_syncs(document_id);
+
+        if self.tombstones.insert(document_id.to_string()) {
+            self.write_tombstones();
+        }
+    }
 
-        if let Ok(bytes) = std::fs::read(format!("./data/{}.automerge", _document_id)) {
-            if let Ok(doc) = automerge::AutoCommit::load(&bytes) {
-                tracing::debug!("Loaded document {} from disk", _document_id);
-                return Some(doc);
+    /// Drops the sync states of the document and frees the slots of its running syncs
+    fn end_syncs(&mut self, document_id: &str) {
+        self.sync_states
+            .retain(|(_, synced), _| synced != document_id);
+        let syncing = self
+            .unsynced
+            .extract_if(|(_, synced)| synced == document_id)
+            .collect::<Vec<_>>();
+        for (peer, document_id) in syncing {
+            self.sync_scheduler.finish(peer, &document_id);
+        }
+        self.start_queued_syncs();
+    }
+
+    /// Syncs the document with peers from now on, fetching it from them if we don't have it.
+    /// The first subscription to a document we didn't unsubscribe from switches from syncing
+    /// every document to syncing only the subscribed ones, so a peer can keep a subset of the
+    /// documents.
+    pub fn subscribe_document(&mut self, document_id: &str) {
+        let switching = self.subscriptions.switches_on(document_id);
+        if !self.subscriptions.subscribe(document_id) {
+            return;
+        }
+        if switching {
+            let unsubscribed = self
+                .documents
+                .keys()
+                .filter(|id| *id != document_id)
+                .cloned()
+                .collect::<Vec<_>>();
+            for unsubscribed in unsubscribed {
+                self.end_syncs(&unsubscribed);
             }
         }
 
-        None
+        tracing::info!("Subscribed to document {}", document_id);
+        self.command_handlers(Command::SubscribeDocument {
+            document_id: document_id.to_string(),
+        });
+        if self.documents.contains_key(document_id) {
+            self.notify_document_changed(document_id.to_string());
+        } else if self.is_whitelisted(document_id) && !self.is_deleted(document_id) {
+            let peers = self.active_syncs.keys().copied().collect::<Vec<_>>();
+            for peer in peers {
+                self.request_document(peer, document_id);
+            }
+        }
     }
 
-    fn write_all_documents(&mut self) {
-        for document_id in self.documents.keys().cloned().collect::<Vec<_>>() {
-            self.write_to_disk(&document_id);
+    /// Stops syncing the document with peers, keeping our copy of it. Without subscriptions
+    /// every other document stays subscribed, including ones we learn about later.
+    pub fn unsubscribe_document(&mut self, document_id: &str) {
+        if !self.subscriptions.unsubscribe(document_id) {
+            return;
         }
+
+        tracing::info!("Unsubscribed from document {}", document_id);
+        self.command_handlers(Command::UnsubscribeDocument {
+            document_id: document_id.to_string(),
+        });
+        self.held_back.remove(document_id);
+        self.end_syncs(document_id);
     }
 
-    fn write_to_disk(&mut self, _document_id: &str) {
-        self.documents.get_mut(_document_id).map(|doc| {
-            let bytes = doc.save();
-            std::fs::create_dir_all(self.config.data_dir.clone()).ok();
-            std::fs::write(
-                self.config
-                    .data_dir
-                    .join(format!("{}.automerge", _document_id)),
-                bytes,
-            )
-            .ok();
+    /// Whether the document is synced with peers
+    pub fn is_subscribed(&self, document_id: &str) -> bool {
+        self.subscriptions.contains(document_id)
+    }
+
+    /// Passes the command to the handler of every connection
+    fn command_handlers(&mut self, command: Command) {
+        for (peer, connections) in &self.active_syncs {
+            for connection_id in connections {
+                self.queued_events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: *peer,
+                    handler: NotifyHandler::One(*connection_id),
+                    event: InEvent::Command(command.clone()),
+                });
+            }
+        }
+    }
+
+    /// Tells a connected peer the document was deleted
+    fn send_delete(&mut self, peer: PeerId, document_id: &str) {
+        let Some(connection_id) = self
+            .active_syncs
+            .get(&peer)
+            .and_then(|connections| connections.iter().next())
+            .copied()
+        else {
+            return;
+        };
+        self.queued_events.push_back(ToSwarm::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::One(connection_id),
+            event: InEvent::Command(Command::DeleteDocument {
+                document_id: document_id.to_string(),
+                auth: self.capabilities.get(document_id).cloned(),
+            }),
         });
     }
-}
 
-impl NetworkBehaviour for Behaviour {
-    type ConnectionHandler = Handler;
+    fn write_tombstones(&self) {
+        std::fs::create_dir_all(&self.config.data_dir).ok();
+        if let Err(err) = crate::tombstones::save(
+            &crate::tombstones::tombstones_path(&self.config.data_dir),
+            &self.tombstones,
+        ) {
+            tracing::warn!("Failed to write document tombstones: {}", err);
+        }
+    }
 
-    type ToSwarm = Event;
+    /// Last change made while running, or the time the document was last written to disk
+    fn last_modified_at(&self, document_id: &str) -> Option<SystemTime> {
+        self.last_modified.get(document_id).copied().or_else(|| {
+            std::fs::metadata(self.document_path(document_id))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+    }
 
-    fn handle_established_inbound_connection(
-        &mut self,
-        connection_id: libp2p::swarm::ConnectionId,
-        peer: libp2p::PeerId,
-        _local_addr: &libp2p::Multiaddr,
-        _remote_addr: &libp2p::Multiaddr,
-    ) -> Result<libp2p::swarm::THandler<Self>, libp2p::swarm::ConnectionDenied> {
-        tracing::warn!("Established inbound connection: {:?}", peer);
-        self.active_syncs
-            .entry(peer)
-            .or_insert_with(HashSet::new)
-            .insert(connection_id);
-        Ok(crate::handler::Handler::new())
+    /// Encoded size in bytes of every change in the document's history, oldest first
+    pub fn change_sizes(&mut self, document_id: &str) -> Option<Vec<usize>> {
+        self.documents.get_mut(document_id).map(|doc| {
+            doc.get_changes(&[])
+                .iter()
+                .map(|change| change.raw_bytes().len())
+                .collect()
+        })
     }
 
-    fn handle_established_outbound_connection(
-        &mut self,
-        connection_id: libp2p::swarm::ConnectionId,
-        peer: libp2p::PeerId,
-        _addr: &libp2p::Multiaddr,
-        _role_override: libp2p::core::Endpoint,
-        _port_use: libp2p::core::transport::PortUse,
-    ) -> Result<libp2p::swarm::THandler<Self>, libp2p::swarm::ConnectionDenied> {
-        tracing::warn!(
-            "Established outbound connection: {:?} {:?}",
+    /// Asks a connected peer for the heads of its copy of a document, answered with
+    /// [`Event::RemoteHeads`]. Returns `false` if the peer isn't connected.
+    pub fn request_heads(&mut self, peer: PeerId, document_id: &str) -> bool {
+        self.send_message(
             peer,
-            connection_id
-        );
-        self.active_syncs
-            .entry(peer)
-            .or_insert_with(HashSet::new)
-            .insert(connection_id);
-        Ok(crate::handler::Handler::new())
+            Message::RequestHeads {
+                document_id: document_id.to_string(),
+            },
+        )
     }
 
-    fn on_swarm_event(&mut self, event: libp2p::swarm::FromSwarm) {
-        match event {
-            libp2p::swarm::FromSwarm::ConnectionClosed(e) => {
-                tracing::debug!("Connection closed: {:?} {:?}", e.peer_id, e.connection_id);
-                if let Some(conns) = self.active_syncs.get_mut(&e.peer_id) {
-                    conns.retain(|&id| id != e.connection_id);
-                    if conns.is_empty() {
-                        self.active_syncs.remove(&e.peer_id);
-                    }
-                }
+    /// Compacts a document: reloads it from [`AutoCommit::save`], which drops what automerge
+    /// keeps in memory besides the changes themselves, and persists it as a snapshot. While
+    /// syncs of the document are running it's compacted once they all finished, so no peer
+    /// sees the document change under its sync state. Emits [`Event::DocumentCompacted`] when
+    /// done. Returns `false` if we don't have the document.
+    pub fn compact_document(&mut self, document_id: &str) -> bool {
+        if !self.documents.contains_key(document_id) {
+            return false;
+        }
+
+        if self.is_syncing_document(document_id) {
+            tracing::debug!("Compacting {} once its syncs finished", document_id);
+            self.pending_compactions.insert(document_id.to_string());
+        } else {
+            self.compact(document_id);
+        }
+        true
+    }
+
+    /// Whether a sync of the document with any peer is still exchanging messages
+    fn is_syncing_document(&self, document_id: &str) -> bool {
+        self.unsynced
+            .iter()
+            .any(|(_, syncing)| syncing == document_id)
+    }
+
+    /// Counts the changes the document gained and compacts it once it gained more than
+    /// [`Config::compact_after_changes`] since it was last compacted or loaded. Returns whether
+    /// it was compacted right away, which persisted it as well.
+    fn compact_if_due(&mut self, document_id: &str, changes: usize) -> bool {
+        let Some(compact_after) = self.config.compact_after_changes else {
+            return false;
+        };
+        let count = self
+            .changes_since_compaction
+            .entry(document_id.to_string())
+            .or_default();
+        *count += changes;
+        if *count <= compact_after || self.pending_compactions.contains(document_id) {
+            return false;
+        }
+        if self.is_syncing_document(document_id) {
+            self.compact_document(document_id);
+            return false;
+        }
+        self.compact(document_id)
+    }
+
+    /// Returns whether the compacted document was persisted
+    fn compact(&mut self, document_id: &str) -> bool {
+        let Some(doc) = self.documents.get_mut(document_id) else {
+            return false;
+        };
+        let saved = doc.save();
+        let compacted = match AutoCommit::load(&saved) {
+            // keep the actor, so our later changes don't show up as another author's
+            Ok(compacted) => compacted.with_actor(doc.get_actor().clone()),
+            Err(err) => {
+                tracing::warn!("Failed to compact {}: {}", document_id, err);
+                return false;
             }
-            _ => {}
+        };
+        *doc = compacted;
+        self.changes_since_compaction
+            .insert(document_id.to_string(), 0);
+
+        let persisted = if let Persistence::ChangeLog { .. } = self.config.persistence {
+            self.compact_change_log(document_id)
+        } else {
+            self.write_to_disk(document_id);
+            true
+        };
+        tracing::debug!("Compacted {} to {} bytes", document_id, saved.len());
+        self.queued_events
+            .push_back(ToSwarm::GenerateEvent(Event::DocumentCompacted {
+                document_id: document_id.to_string(),
+                size: saved.len(),
+            }));
+        persisted
+    }
+
+    /// Our full copy of a document, as sent to peers asking for it. `None` for documents we
+    /// wouldn't sync: ones outside the whitelist or that we unsubscribed from. Like a sync,
+    /// reading needs no capability, the [`CapabilityVerifier`] only decides who may write.
+    pub fn save_document(&mut self, document_id: &str) -> Option<Vec<u8>> {
+        if !self.is_whitelisted(document_id) || !self.is_subscribed(document_id) {
+            return None;
         }
+        self.documents.get_mut(document_id).map(AutoCommit::save)
     }
 
-    fn on_connection_handler_event(
+    /// Sends our full copy of a document to a connected peer, which merges it into its own.
+    /// Returns `false` if we don't have the document or the peer isn't connected.
+    pub fn send_document(&mut self, peer: PeerId, document_id: &str) -> bool {
+        let Some(doc) = self.documents.get_mut(document_id) else {
+            return false;
+        };
+        let document = doc.save();
+        self.send_message(
+            peer,
+            Message::Document {
+                document_id: document_id.to_string(),
+                document,
+                auth: self.capabilities.get(document_id).cloned(),
+            },
+        )
+    }
+
+    /// Starts the automerge sync protocol for a document with a connected peer, from a fresh sync
+    /// state. Returns `false` if we don't have the document or the peer isn't connected.
+    pub fn start_sync(&mut self, peer: PeerId, document_id: &str) -> bool {
+        if !self.is_subscribed(document_id) {
+            return false;
+        }
+        let Some(connection_id) = self
+            .active_syncs
+            .get(&peer)
+            .and_then(|connections| connections.iter().next())
+            .copied()
+        else {
+            return false;
+        };
+        let Some(doc) = self.documents.get_mut(document_id) else {
+            return false;
+        };
+
+        let mut state = sync::State::new();
+        let Some(message) = doc.sync().generate_sync_message(&mut state) else {
+            return false;
+        };
+        self.sync_states
+            .insert((peer, document_id.to_string()), state);
+        self.unsynced.insert((peer, document_id.to_string()));
+        self.queued_events.push_back(ToSwarm::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::One(connection_id),
+            event: InEvent::Command(Command::StartSync {
+                document_id: document_id.to_string(),
+                message: message.encode(),
+                auth: self.capabilities.get(document_id).cloned(),
+            }),
+        });
+        self.queued_events
+            .push_back(ToSwarm::GenerateEvent(Event::SyncStarted {
+                peer,
+                document_id: document_id.to_string(),
+            }));
+        true
+    }
+
+    /// Applies a sync message from a peer to the document and answers with our next message, if
+    /// we have anything left to send. Documents we don't have yet are created empty first.
+    ///
+    /// The exchange is done once we have nothing left to send or both sides have the same
+    /// heads, which emits [`Event::DocumentSynced`] and frees the sync slot if we started it.
+    fn handle_sync_message(
         &mut self,
-        _peer_id: libp2p::PeerId,
-        _connection_id: libp2p::swarm::ConnectionId,
-        event: libp2p::swarm::THandlerOutEvent<Self>,
+        peer: PeerId,
+        connection_id: ConnectionId,
+        document_id: String,
+        message: Vec<u8>,
+        auth: Option<Capability>,
     ) {
-        tracing::warn!("Connection event: {:?}", event);
-        match event {
-            _ => {
-                tracing::warn!("Unhandled handler event: {:?}", event);
+        if self.is_deleted(&document_id) {
+            // the peer changed the document before it learned about the deletion
+            tracing::debug!(
+                "Ignoring sync of deleted document {} from {}",
+                document_id,
+                peer
+            );
+            self.send_delete(peer, &document_id);
+            return;
+        }
+        self.document_activity
+            .entry(document_id.clone())
+            .or_default()
+            .insert(peer, Instant::now());
+        if !self.is_whitelisted(&document_id) {
+            tracing::warn!(
+                "Ignoring sync of {} from {}, not in the whitelist",
+                document_id,
+                peer
+            );
+            return;
+        }
+        if !self.is_subscribed(&document_id) {
+            // sent before the handler learned we unsubscribed
+            self.send_message(
+                peer,
+                Message::SyncError {
+                    details: format!("not subscribed to {document_id}"),
+                    document_id,
+                    reason: SyncErrorReason::NOT_SUBSCRIBED,
+                },
+            );
+            return;
+        }
+        // the peer subscribed to it since it refused our sync
+        self.refused.remove(&(peer, document_id.clone()));
+
+        let message = match sync::Message::decode(&message) {
+            Ok(message) => message,
+            Err(err) => {
+                self.queued_events
+                    .push_back(ToSwarm::GenerateEvent(Event::SyncError {
+                        peer,
+                        document_id,
+                        error: err.to_string(),
+                    }));
+                return;
+            }
+        };
+        // a sync message without changes only asks for ours, which anyone may
+        if !message.changes.is_empty() && !self.may_write(peer, &document_id, auth.as_ref()) {
+            tracing::warn!(
+                "Refusing changes of {} from unauthorized {}",
+                document_id,
+                peer
+            );
+            self.send_message(
+                peer,
+                Message::SyncError {
+                    details: format!("not authorized to write {document_id}"),
+                    document_id: document_id.clone(),
+                    reason: SyncErrorReason::UNAUTHORIZED,
+                },
+            );
+            self.queued_events
+                .push_back(ToSwarm::GenerateEvent(Event::SyncError {
+                    peer,
+                    document_id,
+                    error: "unauthorized changes".to_string(),
+                }));
+            return;
+        }
+
+        let first_message = !self.sync_states.contains_key(&(peer, document_id.clone()));
+        let doc = self.documents.entry(document_id.clone()).or_default();
+        let state = self
+            .sync_states
+            .entry((peer, document_id.clone()))
+            .or_insert_with(sync::State::new);
+        let heads_before = doc.get_heads();
+        let received = message.changes.len();
+        if let Err(err) = doc.sync().receive_sync_message(state, message) {
+            self.queued_events
+                .push_back(ToSwarm::GenerateEvent(Event::SyncError {
+                    peer,
+                    document_id,
+                    error: err.to_string(),
+                }));
+            return;
+        }
+        let changed = doc.get_heads() != heads_before;
+        let reply = doc.sync().generate_sync_message(state);
+        let converged = reply.is_none()
+            || state.their_heads.as_ref().is_some_and(|their_heads| {
+                let mut their_heads = their_heads.clone();
+                their_heads.sort();
+                let mut heads = doc.get_heads();
+                heads.sort();
+                their_heads == heads
+            });
+
+        if let Some(reply) = reply {
+            self.queued_events.push_back(ToSwarm::NotifyHandler {
+                peer_id: peer,
+                handler: NotifyHandler::One(connection_id),
+                event: InEvent::Send(Message::SyncMessage {
+                    document_id: document_id.clone(),
+                    message: reply.encode(),
+                    auth: self.capabilities.get(&document_id).cloned(),
+                }),
+            });
+        }
+        let key = (peer, document_id.clone());
+        if !converged {
+            self.unsynced.insert(key);
+        } else if self.unsynced.remove(&key) || first_message {
+            self.queued_events
+                .push_back(ToSwarm::GenerateEvent(Event::DocumentSynced {
+                    peer,
+                    document_id: document_id.clone(),
+                }));
+            if self.sync_scheduler.finish(peer, &document_id) {
+                self.queued_events
+                    .push_back(ToSwarm::GenerateEvent(Event::SyncFinished {
+                        peer,
+                        document_id: document_id.clone(),
+                    }));
+                self.start_queued_syncs();
             }
         }
+        if changed {
+            self.last_modified
+                .insert(document_id.clone(), SystemTime::now());
+            if !self.compact_if_due(&document_id, received) {
+                self.write_to_disk(&document_id);
+            }
+            self.notify_document_changed(document_id.clone());
+            self.queued_events
+                .push_back(ToSwarm::GenerateEvent(Event::DocumentChanged {
+                    document_id,
+                }));
+        }
     }
 
-    fn poll(
-        &mut self,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<ToSwarm<Self::ToSwarm, libp2p::swarm::THandlerInEvent<Self>>> {
-        if let Some(event) = self.queued_events.pop_front() {
-            return std::task::Poll::Ready(event);
-        } else if self.queued_events.capacity() > 100 {
-            self.queued_events.shrink_to_fit();
+    /// Queues a sync of the document with a connected peer, started once a sync slot is free
+    pub fn request_sync(&mut self, peer: PeerId, document_id: &str) {
+        self.queued_events
+            .push_back(ToSwarm::GenerateEvent(Event::SyncRequested {
+                peer,
+                document_id: document_id.to_string(),
+            }));
+        self.sync_scheduler.enqueue(peer, document_id);
+        self.start_queued_syncs();
+    }
+
+    /// Abandons the syncs that held their slot past [`Config::sync_timeout`] and queues them
+    /// again
+    fn expire_syncs(&mut self, now: Instant) {
+        for (peer, document_id) in self.sync_scheduler.expire(now) {
+            tracing::debug!("Sync of {} with {} timed out", document_id, peer);
+            let key = (peer, document_id.clone());
+            self.sync_states.remove(&key);
+            self.unsynced.remove(&key);
+            self.queued_events
+                .push_back(ToSwarm::GenerateEvent(Event::SyncError {
+                    peer,
+                    document_id: document_id.clone(),
+                    error: "sync timed out".to_string(),
+                }));
+            self.sync_scheduler.enqueue(peer, &document_id);
+        }
+        self.start_queued_syncs();
+    }
+
+    /// Starts queued syncs while slots are free. A sync holds its slot until the sync messages
+    /// converge, see [`Self::handle_sync_message`], the peer disconnects or it times out.
+    fn start_queued_syncs(&mut self) {
+        while let Some((peer, document_id)) = self.sync_scheduler.start_next() {
+            if self.start_sync(peer, &document_id) {
+                continue;
+            }
+            let error = if !self.is_subscribed(&document_id) {
+                "document is not subscribed"
+            } else if self.documents.contains_key(&document_id) {
+                "peer is not connected"
+            } else {
+                "document not found"
+            };
+            self.queued_events
+                .push_back(ToSwarm::GenerateEvent(Event::SyncError {
+                    peer,
+                    document_id: document_id.clone(),
+                    error: error.to_string(),
+                }));
+            self.sync_scheduler.finish(peer, &document_id);
         }
+    }
 
-        std::task::Poll::Pending
+    /// Applies every key of a JSON object to the document root as a single change
+    pub fn put_json(
+        &mut self,
+        document_id: &str,
+        values: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), automerge::AutomergeError> {
+        self.try_modify_document(document_id, |doc| {
+            crate::json::put_json_object(doc, &automerge::ROOT, values)
+        })
+    }
+
+    /// Materializes the full state of a document as JSON
+    pub fn document_to_json(&self, document_id: &str) -> Option<serde_json::Value> {
+        self.documents
+            .get(document_id)
+            .map(|doc| crate::json::object_to_json(doc, &automerge::ROOT))
+    }
+
+    /// Replaces the local labels of a document. Returns `false` if we don't have the document.
+    pub fn set_labels(&mut self, document_id: &str, labels: Vec<String>) -> bool {
+        if !self.documents.contains_key(document_id) {
+            return false;
+        }
+
+        if labels.is_empty() {
+            self.labels.remove(document_id);
+        } else {
+            self.labels
+                .insert(document_id.to_string(), labels.into_iter().collect());
+        }
+        self.write_labels();
+        true
+    }
+
+    /// Local labels of a document, sorted
+    pub fn labels(&self, document_id: &str) -> Vec<String> {
+        self.labels
+            .get(document_id)
+            .map(|labels| labels.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Documents carrying the label, sorted
+    pub fn documents_with_label(&self, label: &str) -> Vec<String> {
+        let mut document_ids = self
+            .labels
+            .iter()
+            .filter(|(document_id, labels)| {
+                labels.contains(label) && self.documents.contains_key(*document_id)
+            })
+            .map(|(document_id, _)| document_id.clone())
+            .collect::<Vec<_>>();
+        document_ids.sort();
+        document_ids
+    }
+
+    fn write_labels(&self) {
+        std::fs::create_dir_all(&self.config.data_dir).ok();
+        if let Err(err) = crate::labels::save(
+            &crate::labels::labels_path(&self.config.data_dir),
+            &self.labels,
+        ) {
+            tracing::warn!("Failed to write document labels: {}", err);
+        }
+    }
+
+    /// Directory the documents are persisted in
+    pub fn data_dir(&self) -> &Path {
+        &self.config.data_dir
+    }
+
+    /// Saves every document into a single archive file, returning the number of documents written
+    pub fn export_archive(&mut self, path: &Path) -> io::Result<usize> {
+        let mut documents = self
+            .documents
+            .iter_mut()
+            .map(|(document_id, doc)| (document_id.clone(), doc.save()))
+            .collect::<Vec<_>>();
+        documents.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+        crate::archive::write_archive(&mut file, &documents)?;
+        Ok(documents.len())
+    }
+
+    /// Restores the documents of an archive. Documents we already have are merged with the
+    /// archived version, so nothing written since the snapshot is lost. Either every document
+    /// is imported or, on an error, none of them.
+    pub fn import_archive(&mut self, path: &Path) -> io::Result<Vec<String>> {
+        let mut file = io::BufReader::new(std::fs::File::open(path)?);
+        let archived = crate::archive::read_archive(&mut file)?;
+
+        // load everything first so a corrupt entry doesn't leave a partial import behind
+        let mut loaded = Vec::with_capacity(archived.len());
+        for (document_id, bytes) in archived {
+            let doc = AutoCommit::load(&bytes).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("document {document_id}: {err}"),
+                )
+            })?;
+            loaded.push((document_id, doc));
+        }
+
+        // merge into copies of ours, so a failing merge doesn't leave a partial import either
+        let mut merged = Vec::with_capacity(loaded.len());
+        for (document_id, mut doc) in loaded {
+            let changes = match self.documents.get(&document_id) {
+                Some(existing) => {
+                    let mut existing = existing.clone();
+                    let added = existing.merge(&mut doc).map_err(|err| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("document {document_id}: {err}"),
+                        )
+                    })?;
+                    doc = existing;
+                    Some(added.len()).filter(|added| *added > 0)
+                }
+                // as good as loaded, there's nothing to compact yet
+                None => Some(0),
+            };
+            merged.push((document_id, doc, changes));
+        }
+
+        // restoring an archive brings deleted documents back
+        let undeleted = merged
+            .iter()
+            .filter(|(document_id, _, _)| self.tombstones.remove(document_id))
+            .count();
+        if undeleted > 0 {
+            self.write_tombstones();
+        }
+
+        let mut document_ids = Vec::with_capacity(merged.len());
+        for (document_id, doc, changes) in merged {
+            if let Some(changes) = changes {
+                self.documents.insert(document_id.clone(), doc);
+                self.document_changed(&document_id, changes);
+            }
+            document_ids.push(document_id);
+        }
+
+        Ok(document_ids)
+    }
+
+    /// Merges a full copy of a document into ours, or stores it if we don't have the document.
+    /// Copies created independently under the same id converge instead of replacing each other.
+    fn merge_document(
+        &mut self,
+        document_id: &str,
+        mut doc: AutoCommit,
+    ) -> Result<(), automerge::AutomergeError> {
+        let changes = match self.documents.get_mut(document_id) {
+            Some(existing) => {
+                let added = existing.merge(&mut doc)?;
+                if added.is_empty() {
+                    return Ok(());
+                }
+                added.len()
+            }
+            // as good as loaded, there's nothing to compact yet
+            None => {
+                self.documents.insert(document_id.to_string(), doc);
+                0
+            }
+        };
+
+        self.document_changed(document_id, changes);
+        Ok(())
+    }
+
+    /// Persists a document that gained changes and passes them on to peers and the swarm
+    fn document_changed(&mut self, document_id: &str, changes: usize) {
+        self.last_modified
+            .insert(document_id.to_string(), SystemTime::now());
+        if !self.compact_if_due(document_id, changes) {
+            self.write_to_disk(document_id);
+        }
+        self.notify_document_changed(document_id.to_string());
+        self.queued_events
+            .push_back(ToSwarm::GenerateEvent(Event::DocumentChanged {
+                document_id: document_id.to_string(),
+            }));
+    }
+
+    /// Whether a message of the peer gets through [`Config::inbound_sync_limit`]. Applies to
+    /// every message, so a peer can't dodge the limit by sending full documents instead of
+    /// sync messages, or by publishing its changes instead of syncing them.
+    fn admit(&mut self, peer: PeerId) -> bool {
+        let Some(rate_limiter) = &mut self.rate_limiter else {
+            return true;
+        };
+        match rate_limiter.admit(peer) {
+            Admission::Allowed => true,
+            Admission::Dropped { first } => {
+                if first {
+                    tracing::warn!("{} sends messages too fast, dropping them", peer);
+                    self.queued_events
+                        .push_back(ToSwarm::GenerateEvent(Event::PeerThrottled { peer }));
+                }
+                false
+            }
+        }
+    }
+
+    /// Documents named in the whitelist are kept no matter how old they are
+    fn is_pinned(&self, document_id: &str) -> bool {
+        self.config
+            .documents_whitelist
+            .as_ref()
+            .is_some_and(|whitelist| whitelist.iter().any(|id| id == document_id))
+    }
+
+    /// Whether the peer may change the document. Capabilities whose signature doesn't cover the
+    /// document and the peer are dropped before the verifier sees them.
+    fn may_write(&self, peer: PeerId, document_id: &str, capability: Option<&Capability>) -> bool {
+        let Some(verifier) = &self.capability_verifier else {
+            return true;
+        };
+        let capability = capability.filter(|capability| capability.verify(document_id, peer));
+        verifier.may_write(peer, document_id, capability)
+    }
+
+    fn is_whitelisted(&self, document_id: &str) -> bool {
+        self.config
+            .documents_whitelist
+            .as_ref()
+            .is_none_or(|whitelist| whitelist.iter().any(|id| id == document_id))
+    }
+
+    /// Sends the document's changes to every connected peer, continuing the sync where there is
+    /// one with the peer and queueing one on the sync scheduler otherwise
+    fn notify_document_changed(&mut self, document_id: String) {
+        if !self.is_subscribed(&document_id) {
+            return;
+        }
+        let peers = self.active_syncs.keys().copied().collect::<Vec<_>>();
+        for peer in peers {
+            if self.refused.contains(&(peer, document_id.clone())) {
+                continue;
+            }
+            if !self.make_room(&document_id) {
+                break;
+            }
+            let Some(state) = self.sync_states.get_mut(&(peer, document_id.clone())) else {
+                self.sync_scheduler.enqueue(peer, &document_id);
+                continue;
+            };
+            let Some(doc) = self.documents.get_mut(&document_id) else {
+                break;
+            };
+            let Some(message) = doc.sync().generate_sync_message(state) else {
+                continue;
+            };
+
+            tracing::debug!("Sending changes of {} to {}", document_id, peer);
+            self.unsynced.insert((peer, document_id.clone()));
+            self.send_message(
+                peer,
+                Message::SyncMessage {
+                    document_id: document_id.clone(),
+                    message: message.encode(),
+                    auth: self.capabilities.get(&document_id).cloned(),
+                },
+            );
+        }
+        self.start_queued_syncs();
+    }
+}
+
+impl Behaviour {
+    /// Messages and commands waiting to be handed to the connection handlers, plus the messages
+    /// the handlers still have to write
+    fn queued_messages(&self) -> usize {
+        let waiting = self
+            .queued_events
+            .iter()
+            .filter(|event| matches!(event, ToSwarm::NotifyHandler { .. }))
+            .count();
+        waiting + self.in_flight.values().sum::<usize>()
+    }
+
+    /// Whether a sync message of the document fits in the outbound queue, applying the
+    /// overflow policy when it doesn't. Documents whose changes aren't sent now are sent once
+    /// the queue drained.
+    fn make_room(&mut self, document_id: &str) -> bool {
+        let queued_messages = self.queued_messages();
+        if queued_messages < self.config.max_queued_messages {
+            return true;
+        }
+        if !self.queue_full {
+            self.queue_full = true;
+            tracing::warn!(
+                "Outbound queue is full with {} messages, applying {:?}",
+                queued_messages,
+                self.config.queue_overflow
+            );
+            self.queued_events
+                .push_back(ToSwarm::GenerateEvent(Event::OutboundQueueFull {
+                    queued_messages,
+                }));
+        }
+        if self.config.queue_overflow == QueueOverflow::DropOldest
+            && self.drop_oldest_sync_message()
+        {
+            return true;
+        }
+        self.held_back.insert(document_id.to_string());
+        false
+    }
+
+    /// Drops the oldest queued sync message. Its sync state counts the changes in it as sent,
+    /// so the state is forgotten to start the sync over once the queue drained.
+    fn drop_oldest_sync_message(&mut self) -> bool {
+        let Some(position) = self.queued_events.iter().position(|event| {
+            matches!(
+                event,
+                ToSwarm::NotifyHandler {
+                    event: InEvent::Send(Message::SyncMessage { .. }),
+                    ..
+                }
+            )
+        }) else {
+            return false;
+        };
+        let Some(ToSwarm::NotifyHandler {
+            peer_id,
+            event: InEvent::Send(Message::SyncMessage { document_id, .. }),
+            ..
+        }) = self.queued_events.remove(position)
+        else {
+            unreachable!("found a queued sync message at the position");
+        };
+
+        tracing::debug!("Dropped a sync message of {} to {}", document_id, peer_id);
+        self.sync_states.remove(&(peer_id, document_id.clone()));
+        self.held_back.insert(document_id);
+        true
+    }
+
+    /// Queues a message on one of the connections to the peer
+    fn send_message(&mut self, peer: PeerId, message: Message) -> bool {
+        let Some(connection_id) = self
+            .active_syncs
+            .get(&peer)
+            .and_then(|connections| connections.iter().next())
+        else {
+            return false;
+        };
+
+        self.queued_events.push_back(ToSwarm::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::One(*connection_id),
+            event: InEvent::Send(message),
+        });
+        true
+    }
+
+    fn handle_message(&mut self, peer: PeerId, connection_id: ConnectionId, message: Message) {
+        if let Some(document_id) = message.document_id() {
+            self.document_activity
+                .entry(document_id.to_string())
+                .or_default()
+                .insert(peer, Instant::now());
+        }
+
+        match message {
+            Message::RequestAvailableDocuments => {
+                // sorted so the answer doesn't depend on HashMap iteration order
+                let mut document_ids = self
+                    .documents
+                    .keys()
+                    .filter(|id| self.is_subscribed(id))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                document_ids.sort();
+                document_ids.dedup();
+                self.queued_events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    handler: NotifyHandler::One(connection_id),
+                    event: InEvent::Send(Message::AvailableDocuments { document_ids }),
+                });
+            }
+            Message::AvailableDocuments { document_ids } => {
+                for document_id in &document_ids {
+                    if self.is_deleted(document_id) {
+                        // the peer missed the deletion while it was away
+                        self.send_delete(peer, document_id);
+                    } else if !self.documents.contains_key(document_id)
+                        && self.is_whitelisted(document_id)
+                        && self.is_subscribed(document_id)
+                    {
+                        self.queued_events.push_back(ToSwarm::GenerateEvent(
+                            Event::DocumentAvailable {
+                                peer,
+                                document_id: document_id.clone(),
+                            },
+                        ));
+                    }
+                }
+                self.queued_events
+                    .push_back(ToSwarm::GenerateEvent(Event::AvailableDocuments {
+                        peer,
+                        document_ids,
+                    }));
+            }
+            Message::RequestDocument { document_id } => {
+                let reply = match self.documents.get_mut(&document_id) {
+                    Some(doc) => Message::Document {
+                        document: doc.save(),
+                        auth: self.capabilities.get(&document_id).cloned(),
+                        document_id,
+                    },
+                    None if self.tombstones.contains(&document_id) => Message::DeleteDocument {
+                        auth: self.capabilities.get(&document_id).cloned(),
+                        document_id,
+                    },
+                    None => Message::SyncError {
+                        details: format!("no document {document_id}"),
+                        document_id,
+                        reason: SyncErrorReason::DOCUMENT_NOT_FOUND,
+                    },
+                };
+                self.queued_events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    handler: NotifyHandler::One(connection_id),
+                    event: InEvent::Send(reply),
+                });
+            }
+            Message::SyncError {
+                document_id,
+                reason,
+                details,
+            } => {
+                if reason == SyncErrorReason::NOT_SUBSCRIBED
+                    || reason == SyncErrorReason::UNAUTHORIZED
+                {
+                    // stop sending it changes until it syncs the document itself
+                    let key = (peer, document_id.clone());
+                    self.sync_states.remove(&key);
+                    if self.unsynced.remove(&key) && self.sync_scheduler.finish(peer, &document_id)
+                    {
+                        self.start_queued_syncs();
+                    }
+                    self.refused.insert(key);
+                }
+                self.queued_events
+                    .push_back(ToSwarm::GenerateEvent(Event::SyncError {
+                        peer,
+                        document_id,
+                        error: format!("{reason:?}: {details}"),
+                    }));
+            }
+            Message::RequestHeads { document_id } => {
+                let heads = self
+                    .heads(&document_id)
+                    .map(|heads| heads.iter().map(|head| head.0.to_vec()).collect());
+                self.queued_events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    handler: NotifyHandler::One(connection_id),
+                    event: InEvent::Send(Message::Heads { document_id, heads }),
+                });
+            }
+            Message::Heads { document_id, heads } => {
+                let heads = heads.map(|heads| {
+                    heads
+                        .iter()
+                        .filter_map(|head| ChangeHash::try_from(head.as_slice()).ok())
+                        .collect()
+                });
+                self.queued_events
+                    .push_back(ToSwarm::GenerateEvent(Event::RemoteHeads {
+                        peer,
+                        document_id,
+                        heads,
+                    }));
+            }
+            Message::Document {
+                document_id,
+                document,
+                auth,
+            } => {
+                if self.is_deleted(&document_id) {
+                    tracing::debug!("Ignoring deleted document {} from {}", document_id, peer);
+                    self.send_delete(peer, &document_id);
+                    return;
+                }
+                if !self.is_whitelisted(&document_id) {
+                    tracing::warn!(
+                        "Ignoring document {} from {}, not in the whitelist",
+                        document_id,
+                        peer
+                    );
+                    return;
+                }
+                if !self.is_subscribed(&document_id) {
+                    tracing::debug!(
+                        "Ignoring unsubscribed document {} from {}",
+                        document_id,
+                        peer
+                    );
+                    return;
+                }
+                if !self.may_write(peer, &document_id, auth.as_ref()) {
+                    tracing::warn!(
+                        "Ignoring document {} from unauthorized {}",
+                        document_id,
+                        peer
+                    );
+                    return;
+                }
+
+                let result = AutoCommit::load(&document)
+                    .and_then(|doc| self.merge_document(&document_id, doc));
+                match result {
+                    Ok(()) => {
+                        self.queued_events.push_back(ToSwarm::GenerateEvent(
+                            Event::DocumentSynced { peer, document_id },
+                        ));
+                    }
+                    Err(err) => tracing::warn!(
+                        "Failed to merge document {} from {}: {}",
+                        document_id,
+                        peer,
+                        err
+                    ),
+                }
+            }
+            Message::DeleteDocument { document_id, auth } => {
+                if self.is_deleted(&document_id) {
+                    self.document_activity.remove(&document_id);
+                    return;
+                }
+                if self.is_pinned(&document_id) {
+                    tracing::warn!(
+                        "Ignoring deletion of whitelisted document {} by {}",
+                        document_id,
+                        peer
+                    );
+                    return;
+                }
+                if !self.may_write(peer, &document_id, auth.as_ref()) {
+                    tracing::warn!(
+                        "Ignoring deletion of {} by unauthorized {}",
+                        document_id,
+                        peer
+                    );
+                    return;
+                }
+                // without a verifier vouching for the peer, it could fill our tombstones with
+                // documents we never had
+                if !self.documents.contains_key(&document_id) && self.capability_verifier.is_none()
+                {
+                    tracing::debug!(
+                        "Ignoring deletion of unknown document {} by {}",
+                        document_id,
+                        peer
+                    );
+                    return;
+                }
+
+                tracing::info!("Peer {} deleted document {}", peer, document_id);
+                self.bury(&document_id);
+                // pass it on, so peers that aren't connected to the sender learn about it too
+                let peers = self
+                    .active_syncs
+                    .keys()
+                    .filter(|connected| **connected != peer)
+                    .copied()
+                    .collect::<Vec<_>>();
+                for connected in peers {
+                    self.send_delete(connected, &document_id);
+                }
+                self.queued_events
+                    .push_back(ToSwarm::GenerateEvent(Event::DocumentDeleted {
+                        peer,
+                        document_id,
+                    }));
+            }
+            message => {
+                tracing::warn!("Unhandled message from {}: {:?}", peer, message);
+            }
+        }
+    }
+
+    /// Hands out an automerge handler unless the peer already has the maximum number of them,
+    /// so a peer opening many connections can't make us run many handlers
+    fn new_handler(
+        &mut self,
+        peer: PeerId,
+        connection_id: ConnectionId,
+    ) -> Either<Handler, dummy::ConnectionHandler> {
+        let connections = self.active_syncs.entry(peer).or_default();
+        if connections.len() >= self.config.max_handlers_per_peer {
+            tracing::debug!(
+                "Peer {} already has {} automerge handlers, using a dummy handler for {}",
+                peer,
+                connections.len(),
+                connection_id
+            );
+            return Either::Right(dummy::ConnectionHandler);
+        }
+
+        connections.insert(connection_id);
+        Left(Handler::new(self.subscriptions.clone()))
+    }
+
+    fn initialize_config_documents(&mut self) {
+        let Some(whitelist) = self.config.documents_whitelist.clone() else {
+            return;
+        };
+
+        for doc_id in &whitelist {
+            if self.is_deleted(doc_id) {
+                continue;
+            }
+            if let Some(doc) = self.read_from_disk(doc_id) {
+                self.documents.insert(doc_id.clone(), doc);
+                continue;
+            };
+
+            tracing::debug!("Creating new document {}", doc_id);
+            self.documents
+                .entry(doc_id.clone())
+                .or_insert_with(|| automerge::AutoCommit::new());
+        }
+    }
+
+    /// Loads every other document a previous run left in the data directory
+    fn load_stored_documents(&mut self) {
+        let entries = match std::fs::read_dir(&self.config.data_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                tracing::warn!("Failed to read the data directory: {}", err);
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_document = path
+                .extension()
+                .is_some_and(|extension| extension == "automerge" || extension == "changes");
+            let Some(document_id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if !is_document || !self.is_whitelisted(document_id) || self.is_deleted(document_id) {
+                continue;
+            }
+
+            let document_id = document_id.to_string();
+            if let Some(doc) = self.read_from_disk(&document_id) {
+                self.changes_since_compaction.insert(document_id.clone(), 0);
+                self.documents.insert(document_id, doc);
+            }
+        }
+    }
+
+    fn read_from_disk(&mut self, document_id: &str) -> Option<AutoCommit> {
+        if self.documents.contains_key(document_id) {
+            return None;
+        }
+
+        if let Persistence::ChangeLog { .. } = self.config.persistence {
+            return self.read_change_log(document_id);
+        }
+
+        let bytes = std::fs::read(self.document_path(document_id)).ok()?;
+        match AutoCommit::load(&bytes) {
+            Ok(doc) => {
+                tracing::debug!("Loaded document {} from disk", document_id);
+                Some(doc)
+            }
+            Err(err) => {
+                tracing::warn!("Failed to load {}: {}", document_id, err);
+                None
+            }
+        }
+    }
+
+    fn write_all_documents(&mut self) {
+        for document_id in self.documents.keys().cloned().collect::<Vec<_>>() {
+            // start every run from a snapshot, so logs don't grow across restarts
+            if let Persistence::ChangeLog { .. } = self.config.persistence {
+                self.compact_change_log(&document_id);
+            } else {
+                self.write_to_disk(&document_id);
+            }
+        }
+    }
+
+    /// Loads the snapshot, if any, and replays the change log on top of it
+    fn read_change_log(&mut self, document_id: &str) -> Option<AutoCommit> {
+        let path = self.document_path(document_id);
+        let log_path = crate::change_log::log_path(&path);
+        let mut doc = match std::fs::read(&path) {
+            Ok(bytes) => AutoCommit::load(&bytes)
+                .inspect_err(|err| tracing::warn!("Failed to load {}: {}", document_id, err))
+                .ok()?,
+            Err(_) if log_path.exists() => AutoCommit::new(),
+            Err(_) => return None,
+        };
+
+        let entries = crate::change_log::replay(&mut doc, &log_path)
+            .inspect_err(|err| {
+                tracing::warn!("Failed to replay change log of {}: {}", document_id, err)
+            })
+            .ok()?;
+        tracing::debug!(
+            "Loaded document {} from disk, replayed {} logged changes",
+            document_id,
+            entries
+        );
+        self.change_logs.insert(
+            document_id.to_string(),
+            ChangeLogState {
+                logged_heads: doc.get_heads(),
+                entries,
+            },
+        );
+        Some(doc)
+    }
+
+    /// Appends the changes made since the last append to the document's log, compacting the log
+    /// once it is long enough. Documents without a log yet start with a snapshot.
+    fn append_to_change_log(&mut self, document_id: &str, compact_after: usize) {
+        let path = self.document_path(document_id);
+        let Some(doc) = self.documents.get_mut(document_id) else {
+            return;
+        };
+        let Some(state) = self.change_logs.get_mut(document_id) else {
+            self.compact_change_log(document_id);
+            return;
+        };
+
+        let changes = doc.save_after(&state.logged_heads);
+        if changes.is_empty() {
+            return;
+        }
+        std::fs::create_dir_all(&self.config.data_dir).ok();
+        if let Err(err) = crate::change_log::append(&crate::change_log::log_path(&path), &changes) {
+            tracing::warn!("Failed to append to change log of {}: {}", document_id, err);
+            return;
+        }
+        state.logged_heads = doc.get_heads();
+        state.entries += 1;
+
+        if state.entries >= compact_after {
+            self.compact_change_log(document_id);
+        }
+    }
+
+    /// Rewrites the document as a snapshot and starts an empty change log
+    ///
+    /// Returns whether the log was replaced by a snapshot of the document
+    fn compact_change_log(&mut self, document_id: &str) -> bool {
+        let path = self.document_path(document_id);
+        let Some(doc) = self.documents.get_mut(document_id) else {
+            return false;
+        };
+
+        std::fs::create_dir_all(&self.config.data_dir).ok();
+        if let Err(err) = crate::change_log::compact(&path, &doc.save()) {
+            tracing::warn!("Failed to compact change log of {}: {}", document_id, err);
+            return false;
+        }
+        self.change_logs.insert(
+            document_id.to_string(),
+            ChangeLogState {
+                logged_heads: doc.get_heads(),
+                entries: 0,
+            },
+        );
+        true
+    }
+
+    fn write_to_disk(&mut self, _document_id: &str) {
+        if let Persistence::ChangeLog { compact_after } = self.config.persistence {
+            self.append_to_change_log(_document_id, compact_after);
+            return;
+        }
+
+        let path = self.document_path(_document_id);
+        let Some(doc) = self.documents.get_mut(_document_id) else {
+            return;
+        };
+        let bytes = doc.save();
+        std::fs::create_dir_all(&self.config.data_dir).ok();
+        // through a temporary file, so a crash mid-write never leaves a corrupt document behind
+        let temporary = path.with_extension("tmp");
+        if let Err(err) =
+            std::fs::write(&temporary, bytes).and_then(|()| std::fs::rename(&temporary, &path))
+        {
+            tracing::warn!("Failed to write {}: {}", _document_id, err);
+        }
+    }
+
+    fn document_path(&self, document_id: &str) -> PathBuf {
+        self.config
+            .data_dir
+            .join(format!("{}.automerge", document_id))
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = Either<Handler, dummy::ConnectionHandler>;
+
+    type ToSwarm = Event;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: libp2p::swarm::ConnectionId,
+        peer: libp2p::PeerId,
+        _local_addr: &libp2p::Multiaddr,
+        _remote_addr: &libp2p::Multiaddr,
+    ) -> Result<libp2p::swarm::THandler<Self>, libp2p::swarm::ConnectionDenied> {
+        tracing::warn!("Established inbound connection: {:?}", peer);
+        Ok(self.new_handler(peer, connection_id))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: libp2p::swarm::ConnectionId,
+        peer: libp2p::PeerId,
+        _addr: &libp2p::Multiaddr,
+        _role_override: libp2p::core::Endpoint,
+        _port_use: libp2p::core::transport::PortUse,
+    ) -> Result<libp2p::swarm::THandler<Self>, libp2p::swarm::ConnectionDenied> {
+        tracing::warn!(
+            "Established outbound connection: {:?} {:?}",
+            peer,
+            connection_id
+        );
+        let handler = self.new_handler(peer, connection_id);
+        // learn which documents the peer has, so a freshly joined node finds out what exists
+        if handler.is_left() {
+            self.queued_events.push_back(ToSwarm::NotifyHandler {
+                peer_id: peer,
+                handler: NotifyHandler::One(connection_id),
+                event: InEvent::Send(Message::RequestAvailableDocuments),
+            });
+        }
+        Ok(handler)
+    }
+
+    fn on_swarm_event(&mut self, event: libp2p::swarm::FromSwarm) {
+        match event {
+            libp2p::swarm::FromSwarm::ConnectionClosed(e) => {
+                tracing::debug!("Connection closed: {:?} {:?}", e.peer_id, e.connection_id);
+                self.in_flight.remove(&e.connection_id);
+                if let Some(conns) = self.active_syncs.get_mut(&e.peer_id) {
+                    conns.retain(|&id| id != e.connection_id);
+                    if conns.is_empty() {
+                        self.active_syncs.remove(&e.peer_id);
+                        self.sync_scheduler.remove_peer(e.peer_id);
+                        self.sync_states.retain(|(peer, _), _| *peer != e.peer_id);
+                        self.unsynced.retain(|(peer, _)| *peer != e.peer_id);
+                        self.refused.retain(|(peer, _)| *peer != e.peer_id);
+                        if let Some(rate_limiter) = &mut self.rate_limiter {
+                            rate_limiter.remove_peer(e.peer_id);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: libp2p::PeerId,
+        connection_id: libp2p::swarm::ConnectionId,
+        event: libp2p::swarm::THandlerOutEvent<Self>,
+    ) {
+        tracing::debug!("Connection event: {:?}", event);
+        let event = match event {
+            Left(event) => event,
+            Either::Right(never) => match never {},
+        };
+        match event {
+            OutEvent::Message(message) => {
+                if self.admit(peer_id) {
+                    self.handle_message(peer_id, connection_id, message);
+                }
+            }
+            OutEvent::SyncMessage {
+                document_id,
+                message,
+                auth,
+            } => {
+                if self.admit(peer_id) {
+                    self.handle_sync_message(peer_id, connection_id, document_id, message, auth);
+                    return;
+                }
+                // the dropped message broke the exchange, it starts over with the next message
+                let key = (peer_id, document_id.clone());
+                self.sync_states.remove(&key);
+                if self.unsynced.remove(&key) && self.sync_scheduler.finish(peer_id, &document_id) {
+                    self.start_queued_syncs();
+                }
+            }
+            OutEvent::Unsupported => {
+                self.queued_events
+                    .push_back(ToSwarm::GenerateEvent(Event::UnsupportedPeer {
+                        peer: peer_id,
+                    }));
+            }
+            OutEvent::OutboundFailure(error) => {
+                tracing::debug!("Failed to send to {}: {}", peer_id, error);
+            }
+            OutEvent::Dequeued(count) => {
+                if let Some(in_flight) = self.in_flight.get_mut(&connection_id) {
+                    *in_flight = in_flight.saturating_sub(count);
+                    if *in_flight == 0 {
+                        self.in_flight.remove(&connection_id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<ToSwarm<Self::ToSwarm, libp2p::swarm::THandlerInEvent<Self>>> {
+        let now = Instant::now();
+        if self
+            .sync_scheduler
+            .next_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.expire_syncs(now);
+        }
+        if let Some(deadline) = self.sync_scheduler.next_deadline() {
+            let timer = self
+                .sync_timer
+                .get_or_insert_with(|| Delay::new(Duration::ZERO));
+            timer.reset(deadline.saturating_duration_since(now));
+            // only registers the wake up, the syncs are expired on the poll it triggers
+            let _ = timer.poll_unpin(cx);
+        }
+
+        if (self.queue_full || !self.held_back.is_empty())
+            && self.queued_messages() <= self.config.max_queued_messages / 2
+        {
+            self.queue_full = false;
+            for document_id in std::mem::take(&mut self.held_back) {
+                self.notify_document_changed(document_id);
+            }
+        }
+
+        if !self.pending_compactions.is_empty() {
+            let due = self
+                .pending_compactions
+                .iter()
+                .filter(|document_id| !self.is_syncing_document(document_id))
+                .cloned()
+                .collect::<Vec<_>>();
+            for document_id in due {
+                self.pending_compactions.remove(&document_id);
+                self.compact(&document_id);
+            }
+        }
+
+        if let Some(event) = self.queued_events.pop_front() {
+            // counted until the handler reports it wrote them, see [`OutEvent::Dequeued`]
+            if let ToSwarm::NotifyHandler {
+                handler: NotifyHandler::One(connection_id),
+                event:
+                    InEvent::Send(_)
+                    | InEvent::Command(Command::StartSync { .. } | Command::DeleteDocument { .. }),
+                ..
+            } = &event
+            {
+                *self.in_flight.entry(*connection_id).or_default() += 1;
+            }
+            return std::task::Poll::Ready(event.map_in(Left));
+        } else if self.queued_events.capacity() > 100 {
+            self.queued_events.shrink_to_fit();
+        }
+
+        std::task::Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{ReadDoc, transaction::Transactable};
+
+    use super::*;
+
+    /// An empty data directory of its own for each test
+    fn data_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libp2p-automerge-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn config(data_dir: PathBuf, documents: &[&str]) -> Config {
+        Config {
+            max_simultaneous_syncs: 4,
+            documents_whitelist: Some(documents.iter().map(|id| id.to_string()).collect()),
+            data_dir,
+            peer_activity_window: Duration::from_secs(60),
+            max_handlers_per_peer: 2,
+            persistence: Persistence::Snapshot,
+            sync_scheduling: SyncScheduling::Fifo,
+            sync_timeout: Duration::from_secs(30),
+            max_queued_messages: 64,
+            queue_overflow: QueueOverflow::Backpressure,
+            inbound_sync_limit: None,
+            compact_after_changes: None,
+        }
+    }
+
+    #[test]
+    fn available_documents_are_sorted() {
+        let mut behaviour = Behaviour::new(config(
+            data_dir("available-documents"),
+            &["c", "a", "d", "b", "a"],
+        ));
+        behaviour.queued_events.clear();
+
+        behaviour.handle_message(
+            PeerId::random(),
+            ConnectionId::new_unchecked(0),
+            Message::RequestAvailableDocuments,
+        );
+
+        let Some(ToSwarm::NotifyHandler {
+            event: InEvent::Send(Message::AvailableDocuments { document_ids }),
+            ..
+        }) = behaviour.queued_events.pop_front()
+        else {
+            panic!("expected an AvailableDocuments answer");
+        };
+        assert_eq!(document_ids, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn documents_of_a_peer_are_reported_to_the_peer_asking() {
+        let (provider_id, asking_id) = (PeerId::random(), PeerId::random());
+        let connection_id = ConnectionId::new_unchecked(0);
+        let mut provider = Behaviour::new(config(data_dir("lookup-provider"), &["doc"]));
+        let mut asking = Behaviour::new(config(data_dir("lookup-asking"), &[]));
+        asking
+            .active_syncs
+            .insert(provider_id, HashSet::from([connection_id]));
+        provider.queued_events.clear();
+        asking.queued_events.clear();
+
+        assert!(asking.request_available_documents(provider_id));
+        let Some(ToSwarm::NotifyHandler {
+            event: InEvent::Send(request),
+            ..
+        }) = asking.queued_events.pop_front()
+        else {
+            panic!("expected a request for the available documents");
+        };
+        provider.handle_message(asking_id, connection_id, request);
+        let Some(ToSwarm::NotifyHandler {
+            event: InEvent::Send(answer),
+            ..
+        }) = provider.queued_events.pop_front()
+        else {
+            panic!("expected an AvailableDocuments answer");
+        };
+        asking.handle_message(provider_id, connection_id, answer);
+
+        assert!(asking.queued_events.iter().any(|event| matches!(
+            event,
+            ToSwarm::GenerateEvent(Event::AvailableDocuments { peer, document_ids })
+                if *peer == provider_id && document_ids == &["doc"]
+        )));
+    }
+
+    #[test]
+    fn configured_documents_exist_and_are_sent_to_a_connected_peer() {
+        let (peer, stranger) = (PeerId::random(), PeerId::random());
+        let mut behaviour = Behaviour::new(config(data_dir("configured"), &["a", "b"]));
+        behaviour
+            .active_syncs
+            .insert(peer, HashSet::from([ConnectionId::new_unchecked(0)]));
+        behaviour.queued_events.clear();
+
+        assert!(behaviour.get_document("a").is_some());
+        assert!(behaviour.get_document("b").is_some());
+        assert!(behaviour.send_document(peer, "a"));
+        assert!(!behaviour.send_document(stranger, "a"));
+        assert!(matches!(
+            behaviour.queued_events.pop_front(),
+            Some(ToSwarm::NotifyHandler {
+                peer_id,
+                event: InEvent::Send(Message::Document { document_id, .. }),
+                ..
+            }) if peer_id == peer && document_id == "a"
+        ));
+        assert!(behaviour.queued_events.is_empty());
+    }
+
+    #[test]
+    fn documents_are_filtered_by_their_labels() {
+        let dir = data_dir("labels");
+        let mut behaviour = Behaviour::new(config(dir.clone(), &["a", "b", "c"]));
+
+        assert!(behaviour.set_labels("a", vec!["project:x".to_string(), "archived".to_string()]));
+        assert!(behaviour.set_labels("b", vec!["project:x".to_string()]));
+        assert!(!behaviour.set_labels("missing", vec!["project:x".to_string()]));
+        assert_eq!(behaviour.documents_with_label("project:x"), ["a", "b"]);
+        assert_eq!(behaviour.documents_with_label("archived"), ["a"]);
+        assert_eq!(behaviour.labels("a"), ["archived", "project:x"]);
+
+        // labels are kept across restarts
+        let restarted = Behaviour::new(config(dir, &["a", "b", "c"]));
+        assert_eq!(restarted.documents_with_label("project:x"), ["a", "b"]);
+        assert!(restarted.labels("c").is_empty());
+    }
+
+    #[test]
+    fn changes_start_syncs_through_the_scheduler() {
+        let mut behaviour = Behaviour::new(Config {
+            max_simultaneous_syncs: 1,
+            ..config(data_dir("changes-scheduled"), &["doc"])
+        });
+        let peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for (i, peer) in peers.iter().enumerate() {
+            behaviour
+                .active_syncs
+                .insert(*peer, HashSet::from([ConnectionId::new_unchecked(i)]));
+        }
+        behaviour.queued_events.clear();
+
+        put(&mut behaviour, "doc", "key", 1);
+
+        let started = behaviour
+            .queued_events
+            .iter()
+            .filter(|event| matches!(event, ToSwarm::GenerateEvent(Event::SyncStarted { .. })))
+            .count();
+        assert_eq!(started, 1);
+    }
+
+    #[test]
+    fn timed_out_sync_is_started_again() {
+        let mut behaviour = Behaviour::new(Config {
+            max_simultaneous_syncs: 1,
+            sync_timeout: Duration::ZERO,
+            ..config(data_dir("sync-timeout"), &["doc"])
+        });
+        let peer = PeerId::random();
+        behaviour
+            .active_syncs
+            .insert(peer, HashSet::from([ConnectionId::new_unchecked(0)]));
+        behaviour.request_sync(peer, "doc");
+        behaviour.queued_events.clear();
+
+        behaviour.expire_syncs(Instant::now());
+
+        let events = behaviour
+            .queued_events
+            .iter()
+            .filter_map(|event| match event {
+                ToSwarm::GenerateEvent(Event::SyncError { .. }) => Some("error"),
+                ToSwarm::GenerateEvent(Event::SyncStarted { .. }) => Some("started"),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events, ["error", "started"]);
+    }
+
+    #[test]
+    fn peers_cannot_delete_whitelisted_or_unknown_documents() {
+        let mut behaviour = Behaviour::new(config(data_dir("peer-deletes"), &["doc"]));
+        let delete = |behaviour: &mut Behaviour, document_id: &str| {
+            behaviour.handle_message(
+                PeerId::random(),
+                ConnectionId::new_unchecked(0),
+                Message::DeleteDocument {
+                    document_id: document_id.to_string(),
+                    auth: None,
+                },
+            )
+        };
+
+        delete(&mut behaviour, "doc");
+        assert!(!behaviour.is_deleted("doc"));
+        assert!(behaviour.documents.contains_key("doc"));
+
+        behaviour.config.documents_whitelist = None;
+        behaviour
+            .documents
+            .insert("notes".to_string(), AutoCommit::new());
+        delete(&mut behaviour, "notes");
+        delete(&mut behaviour, "unknown");
+        assert!(behaviour.is_deleted("notes"));
+        assert!(!behaviour.is_deleted("unknown"));
+    }
+
+    #[test]
+    fn documents_and_deletions_need_a_capability_from_a_trusted_issuer() {
+        let issuer = libp2p::identity::Keypair::generate_ed25519();
+        let mut behaviour = Behaviour::new(Config {
+            documents_whitelist: None,
+            ..config(data_dir("capabilities"), &[])
+        })
+        .with_capability_verifier(crate::capability::TrustedIssuers(HashSet::from([issuer
+            .public()
+            .to_peer_id()])));
+        let (authorized, unauthorized) = (PeerId::random(), PeerId::random());
+        let auth = Some(Capability::sign(&issuer, "notes", authorized).unwrap());
+        let mut remote = AutoCommit::new();
+        remote.put(automerge::ROOT, "key", 1).unwrap();
+        let document = |auth: &Option<Capability>| Message::Document {
+            document_id: "notes".to_string(),
+            document: remote.clone().save(),
+            auth: auth.clone(),
+        };
+        let delete = |auth: &Option<Capability>| Message::DeleteDocument {
+            document_id: "notes".to_string(),
+            auth: auth.clone(),
+        };
+        let send = |behaviour: &mut Behaviour, peer: PeerId, message: Message| {
+            behaviour.handle_message(peer, ConnectionId::new_unchecked(0), message)
+        };
+
+        send(&mut behaviour, unauthorized, document(&None));
+        // the capability was issued to another peer
+        send(&mut behaviour, unauthorized, document(&auth));
+        assert!(!behaviour.documents.contains_key("notes"));
+        send(&mut behaviour, authorized, document(&auth));
+        assert!(behaviour.documents.contains_key("notes"));
+
+        send(&mut behaviour, authorized, delete(&None));
+        send(&mut behaviour, unauthorized, delete(&auth));
+        assert!(!behaviour.is_deleted("notes"));
+        send(&mut behaviour, authorized, delete(&auth));
+        assert!(behaviour.is_deleted("notes"));
+    }
+
+    #[test]
+    fn diverged_copies_converge_after_exchanging_full_documents() {
+        let mut ours = Behaviour::new(config(data_dir("merge-ours"), &["doc"]));
+        let mut theirs = Behaviour::new(config(data_dir("merge-theirs"), &["doc"]));
+        put(&mut ours, "doc", "shared", 1);
+        put(&mut ours, "doc", "ours", 1);
+        put(&mut theirs, "doc", "shared", 2);
+        put(&mut theirs, "doc", "theirs", 2);
+        let full_document = |behaviour: &mut Behaviour| Message::Document {
+            document_id: "doc".to_string(),
+            document: behaviour.save_document("doc").unwrap(),
+            auth: None,
+        };
+
+        let (to_theirs, to_ours) = (full_document(&mut ours), full_document(&mut theirs));
+        ours.handle_message(PeerId::random(), ConnectionId::new_unchecked(0), to_ours);
+        theirs.handle_message(PeerId::random(), ConnectionId::new_unchecked(0), to_theirs);
+
+        let merged = ours.document_to_json("doc").unwrap();
+        assert_eq!(merged["ours"], 1);
+        assert_eq!(merged["theirs"], 2);
+        assert_eq!(theirs.document_to_json("doc").unwrap(), merged);
+        assert_eq!(ours.heads("doc"), theirs.heads("doc"));
+    }
+
+    #[test]
+    fn garbage_collection_keeps_pinned_and_recently_used_documents() {
+        let mut behaviour = Behaviour::new(config(data_dir("gc"), &["pinned"]));
+        let ttl = Duration::from_secs(60 * 60);
+        let long_ago = SystemTime::now() - 2 * ttl;
+        for document_id in ["pinned", "stale", "edited", "watched"] {
+            behaviour
+                .documents
+                .entry(document_id.to_string())
+                .or_insert_with(AutoCommit::new);
+            behaviour
+                .last_modified
+                .insert(document_id.to_string(), long_ago);
+        }
+        behaviour
+            .last_modified
+            .insert("edited".to_string(), SystemTime::now());
+        behaviour.document_activity.insert(
+            "watched".to_string(),
+            HashMap::from([(PeerId::random(), Instant::now())]),
+        );
+
+        assert_eq!(behaviour.collect_garbage(ttl, true), ["stale"]);
+        assert!(behaviour.documents.contains_key("stale"));
+        assert_eq!(behaviour.collect_garbage(ttl, false), ["stale"]);
+
+        let mut kept = behaviour.documents.keys().cloned().collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, ["edited", "pinned", "watched"]);
+    }
+
+    #[test]
+    fn every_message_of_a_peer_counts_towards_its_rate_limit() {
+        let mut behaviour = Behaviour::new(Config {
+            inbound_sync_limit: Some(SyncRateLimit {
+                messages_per_sec: 1,
+                burst: 3,
+            }),
+            ..config(data_dir("rate-limit"), &["doc"])
+        });
+        let (flooding, normal) = (PeerId::random(), PeerId::random());
+        behaviour.queued_events.clear();
+        let request = |behaviour: &mut Behaviour, peer: PeerId| {
+            behaviour.on_connection_handler_event(
+                peer,
+                ConnectionId::new_unchecked(0),
+                Left(OutEvent::Message(Message::RequestAvailableDocuments)),
+            )
+        };
+
+        for _ in 0..10 {
+            request(&mut behaviour, flooding);
+        }
+        request(&mut behaviour, normal);
+
+        let answered = |peer: PeerId| {
+            behaviour
+                .queued_events
+                .iter()
+                .filter(|event| matches!(event, ToSwarm::NotifyHandler { peer_id, .. } if *peer_id == peer))
+                .count()
+        };
+        assert_eq!(answered(flooding), 3);
+        assert_eq!(answered(normal), 1);
+        assert!(behaviour.queued_events.iter().any(|event| matches!(
+            event,
+            ToSwarm::GenerateEvent(Event::PeerThrottled { peer }) if *peer == flooding
+        )));
+    }
+
+    #[test]
+    fn published_changes_count_towards_the_rate_limit() {
+        let mut behaviour = Behaviour::new(Config {
+            inbound_sync_limit: Some(SyncRateLimit {
+                messages_per_sec: 1,
+                burst: 3,
+            }),
+            ..config(data_dir("rate-limit-published"), &["doc"])
+        });
+        let (flooding, normal) = (PeerId::random(), PeerId::random());
+        let changes = |value: i64| {
+            let mut doc = AutoCommit::new();
+            doc.put(automerge::ROOT, "key", value).unwrap();
+            doc.save()
+        };
+
+        let applied = (0..10)
+            .filter(|i| {
+                behaviour
+                    .apply_changes(flooding, "doc", &changes(*i))
+                    .unwrap()
+                    .is_some()
+            })
+            .count();
+
+        assert_eq!(applied, 3);
+        assert!(
+            behaviour
+                .apply_changes(normal, "doc", &changes(10))
+                .unwrap()
+                .is_some()
+        );
+        assert!(behaviour.queued_events.iter().any(|event| matches!(
+            event,
+            ToSwarm::GenerateEvent(Event::PeerThrottled { peer }) if *peer == flooding
+        )));
+    }
+
+    #[test]
+    fn messages_the_handler_did_not_write_count_towards_the_queue() {
+        let mut behaviour = Behaviour::new(Config {
+            max_queued_messages: 4,
+            ..config(data_dir("in-flight"), &["doc"])
+        });
+        let peer = PeerId::random();
+        let connection_id = ConnectionId::new_unchecked(0);
+        behaviour
+            .active_syncs
+            .insert(peer, HashSet::from([connection_id]));
+        behaviour.queued_events.clear();
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+
+        // a peer too slow to read, every message stays in its handler
+        for _ in 0..4 {
+            behaviour.send_document(peer, "doc");
+        }
+        while behaviour.poll(&mut cx).is_ready() {}
+        put(&mut behaviour, "doc", "key", 1);
+
+        assert!(behaviour.held_back.contains("doc"));
+        assert!(behaviour.queue_full);
+
+        behaviour.on_connection_handler_event(peer, connection_id, Left(OutEvent::Dequeued(4)));
+        let _ = behaviour.poll(&mut cx);
+
+        assert!(behaviour.held_back.is_empty());
+        assert!(!behaviour.queue_full);
+    }
+
+    #[test]
+    fn oldest_sync_message_is_dropped_once_the_queue_is_full() {
+        let mut behaviour = Behaviour::new(Config {
+            max_queued_messages: 2,
+            queue_overflow: QueueOverflow::DropOldest,
+            ..config(data_dir("drop-oldest"), &["a", "b", "c"])
+        });
+        let peer = PeerId::random();
+        behaviour
+            .active_syncs
+            .insert(peer, HashSet::from([ConnectionId::new_unchecked(0)]));
+        for document_id in ["a", "b", "c"] {
+            behaviour
+                .sync_states
+                .insert((peer, document_id.to_string()), sync::State::new());
+        }
+        behaviour.queued_events.clear();
+
+        put(&mut behaviour, "a", "key", 1);
+        put(&mut behaviour, "b", "key", 1);
+        put(&mut behaviour, "c", "key", 1);
+
+        let queued = behaviour
+            .queued_events
+            .iter()
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler {
+                    event: InEvent::Send(Message::SyncMessage { document_id, .. }),
+                    ..
+                } => Some(document_id.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(queued, ["b", "c"]);
+        let reported = behaviour
+            .queued_events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    ToSwarm::GenerateEvent(Event::OutboundQueueFull { queued_messages: 2 })
+                )
+            })
+            .count();
+        assert_eq!(reported, 1);
+        // the dropped changes are sent again with a fresh sync once the queue drained
+        assert!(behaviour.held_back.contains("a"));
+        assert!(!behaviour.sync_states.contains_key(&(peer, "a".to_string())));
+    }
+
+    #[test]
+    fn connections_beyond_the_cap_get_a_dummy_handler() {
+        let mut behaviour = Behaviour::new(config(data_dir("handler-cap"), &["doc"]));
+        let peer = PeerId::random();
+        let addr = libp2p::Multiaddr::empty();
+
+        let handlers = (0..3)
+            .map(|i| {
+                behaviour
+                    .handle_established_inbound_connection(
+                        ConnectionId::new_unchecked(i),
+                        peer,
+                        &addr,
+                        &addr,
+                    )
+                    .unwrap()
+                    .is_left()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(handlers, [true, true, false]);
+
+        // the cap is per peer
+        let other = behaviour
+            .handle_established_inbound_connection(
+                ConnectionId::new_unchecked(3),
+                PeerId::random(),
+                &addr,
+                &addr,
+            )
+            .unwrap();
+        assert!(other.is_left());
+    }
+
+    fn put(behaviour: &mut Behaviour, document_id: &str, key: &str, value: i64) {
+        behaviour.modify_document(document_id, |doc| {
+            doc.put(automerge::ROOT, key, value).unwrap();
+        });
+    }
+
+    #[test]
+    fn snapshot_survives_a_restart() {
+        let dir = data_dir("snapshot-restart");
+        let mut behaviour = Behaviour::new(config(dir.clone(), &["doc"]));
+        put(&mut behaviour, "doc", "counter", 1);
+        put(&mut behaviour, "doc", "counter", 2);
+        assert!(
+            !behaviour
+                .document_path("doc")
+                .with_extension("tmp")
+                .exists()
+        );
+        drop(behaviour);
+
+        let mut restarted = Behaviour::new(config(dir, &["doc"]));
+
+        assert_eq!(
+            restarted.document_to_json("doc"),
+            Some(serde_json::json!({ "counter": 2 }))
+        );
+        assert_eq!(
+            restarted
+                .documents
+                .get_mut("doc")
+                .unwrap()
+                .get_changes(&[])
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn change_log_is_replayed_on_load() {
+        let dir = data_dir("change-log");
+        let change_log_config = || Config {
+            persistence: Persistence::ChangeLog { compact_after: 100 },
+            ..config(dir.clone(), &["doc"])
+        };
+        let mut behaviour = Behaviour::new(change_log_config());
+        for value in 0..5 {
+            put(&mut behaviour, "doc", &format!("key{value}"), value);
+        }
+        assert!(crate::change_log::log_path(&behaviour.document_path("doc")).exists());
+        let expected = behaviour.document_to_json("doc");
+
+        let mut reloaded = Behaviour::new(change_log_config());
+
+        assert_eq!(reloaded.document_to_json("doc"), expected);
+        assert_eq!(
+            reloaded.documents.get_mut("doc").unwrap().get_heads(),
+            behaviour.documents.get_mut("doc").unwrap().get_heads()
+        );
+    }
+
+    #[test]
+    fn change_log_compaction_keeps_the_document() {
+        let dir = data_dir("change-log-compaction");
+        let change_log_config = || Config {
+            persistence: Persistence::ChangeLog { compact_after: 2 },
+            ..config(dir.clone(), &["doc"])
+        };
+        let mut behaviour = Behaviour::new(change_log_config());
+        for value in 0..5 {
+            put(&mut behaviour, "doc", "counter", value);
+        }
+        assert_eq!(behaviour.change_logs["doc"].entries, 1);
+
+        let reloaded = Behaviour::new(change_log_config());
+
+        assert_eq!(
+            reloaded.document_to_json("doc"),
+            Some(serde_json::json!({ "counter": 4 }))
+        );
+    }
+
+    #[test]
+    fn unsubscribing_keeps_syncing_every_other_document() {
+        let mut behaviour = Behaviour::new(config(data_dir("unsubscribe"), &["a", "b"]));
+        behaviour.active_syncs.insert(
+            PeerId::random(),
+            HashSet::from([ConnectionId::new_unchecked(0)]),
+        );
+        behaviour.queued_events.clear();
+
+        behaviour.unsubscribe_document("a");
+
+        assert!(!behaviour.is_subscribed("a"));
+        assert!(behaviour.is_subscribed("b"));
+        assert!(behaviour.is_subscribed("created-later"));
+        let commands = behaviour
+            .queued_events
+            .iter()
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler {
+                    event: InEvent::Command(command),
+                    ..
+                } => Some(command),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            commands[..],
+            [Command::UnsubscribeDocument { ref document_id }] if document_id == "a"
+        ));
+
+        behaviour.subscribe_document("a");
+
+        assert!(behaviour.is_subscribed("a"));
+        assert!(behaviour.is_subscribed("created-later"));
+
+        behaviour.subscribe_document("b");
+
+        assert!(behaviour.is_subscribed("b"));
+        assert!(!behaviour.is_subscribed("a"));
+    }
+
+    #[test]
+    fn archive_import_merges_into_our_copies() {
+        let dir = data_dir("archive-export");
+        let mut exporting = Behaviour::new(config(dir.clone(), &["a", "b"]));
+        put(&mut exporting, "a", "archived", 1);
+        put(&mut exporting, "b", "archived", 2);
+        let path = dir.join("documents.archive");
+        assert_eq!(exporting.export_archive(&path).unwrap(), 2);
+
+        let mut importing = Behaviour::new(config(data_dir("archive-import"), &["a", "b"]));
+        put(&mut importing, "a", "ours", 3);
+
+        assert_eq!(importing.import_archive(&path).unwrap(), ["a", "b"]);
+        assert_eq!(
+            importing.document_to_json("a"),
+            Some(serde_json::json!({ "archived": 1, "ours": 3 }))
+        );
+        assert_eq!(
+            importing.document_to_json("b"),
+            Some(serde_json::json!({ "archived": 2 }))
+        );
+    }
+
+    #[test]
+    fn unsubscribed_documents_are_not_saved_for_peers() {
+        let mut behaviour = Behaviour::new(config(data_dir("save-unsubscribed"), &["a", "b"]));
+
+        behaviour.unsubscribe_document("a");
+
+        assert!(behaviour.save_document("a").is_none());
+        assert!(behaviour.save_document("b").is_some());
+    }
+
+    #[test]
+    fn change_sizes_cover_the_whole_history() {
+        let mut behaviour = Behaviour::new(config(data_dir("change-sizes"), &["doc"]));
+        for i in 0..3 {
+            put(&mut behaviour, "doc", "key", i);
+        }
+
+        let sizes = behaviour.change_sizes("doc").unwrap();
+
+        let history = behaviour
+            .documents
+            .get_mut("doc")
+            .unwrap()
+            .get_changes(&[])
+            .iter()
+            .map(|change| change.raw_bytes().len())
+            .sum::<usize>();
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes.iter().sum::<usize>(), history);
+        assert_eq!(behaviour.change_sizes("unknown"), None);
+    }
+
+    #[test]
+    fn batch_put_produces_a_single_change() {
+        let mut behaviour = Behaviour::new(config(data_dir("batch-put"), &["doc"]));
+        let values = (0..10)
+            .map(|i| (format!("key{i}"), serde_json::Value::from(i)))
+            .collect();
+
+        behaviour.put_json("doc", &values).unwrap();
+
+        let doc = behaviour.documents.get_mut("doc").unwrap();
+        assert_eq!(doc.keys(automerge::ROOT).count(), 10);
+        assert_eq!(doc.get_changes(&[]).len(), 1);
+    }
+
+    #[test]
+    fn failed_modification_is_rolled_back() {
+        let mut behaviour = Behaviour::new(config(data_dir("rollback"), &["doc"]));
+        behaviour.queued_events.clear();
+
+        let result = behaviour.try_modify_document("doc", |doc| {
+            doc.put(automerge::ROOT, "partial", 1).unwrap();
+            Err("failed halfway")
+        });
+
+        assert_eq!(result, Err("failed halfway"));
+        let doc = behaviour.documents.get_mut("doc").unwrap();
+        assert_eq!(doc.keys(automerge::ROOT).count(), 0);
+        assert!(doc.get_changes(&[]).is_empty());
+        assert!(behaviour.queued_events.is_empty());
+    }
+
+    #[test]
+    fn documents_are_compacted_once_they_gained_enough_changes() {
+        let dir = data_dir("compact-after-changes");
+        let mut behaviour = Behaviour::new(Config {
+            compact_after_changes: Some(2),
+            ..config(dir.clone(), &["doc"])
+        });
+        let compactions = |behaviour: &Behaviour| {
+            behaviour
+                .queued_events
+                .iter()
+                .filter(|event| {
+                    matches!(
+                        event,
+                        ToSwarm::GenerateEvent(Event::DocumentCompacted { .. })
+                    )
+                })
+                .count()
+        };
+
+        put(&mut behaviour, "doc", "key", 1);
+        put(&mut behaviour, "doc", "key", 2);
+        assert_eq!(compactions(&behaviour), 0);
+
+        put(&mut behaviour, "doc", "key", 3);
+        assert_eq!(compactions(&behaviour), 1);
+        assert_eq!(behaviour.changes_since_compaction["doc"], 0);
+        drop(behaviour);
+
+        let mut restarted = Behaviour::new(config(dir, &["doc"]));
+        assert_eq!(
+            restarted.document_to_json("doc"),
+            Some(serde_json::json!({ "key": 3 }))
+        );
     }
 }
@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+
+use libp2p::{
+    PeerId,
+    identity::{Keypair, PublicKey, SigningError},
+};
+
+/// Prefix of the signed bytes, so a capability can't be passed off as a signature over
+/// something else made with the same key
+const SIGNING_DOMAIN: &[u8] = b"libp2p-automerge-capability:";
+
+/// Grant by an issuer that a peer may write a document, sent along with the peer's sync
+/// messages, full copies and deletions of that document. The signature covers the document and the peer it was issued
+/// to, so it's worthless for any other document or sender.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capability {
+    pub issuer: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+impl Capability {
+    /// Signs a grant for `holder` to write the document
+    pub fn sign(issuer: &Keypair, document_id: &str, holder: PeerId) -> Result<Self, SigningError> {
+        let signature = issuer.sign(&signed_bytes(document_id, holder))?;
+        Ok(Capability {
+            issuer: issuer.public(),
+            signature,
+        })
+    }
+
+    /// Whether the issuer granted `holder` to write the document
+    pub fn verify(&self, document_id: &str, holder: PeerId) -> bool {
+        self.issuer
+            .verify(&signed_bytes(document_id, holder), &self.signature)
+    }
+}
+
+fn signed_bytes(document_id: &str, holder: PeerId) -> Vec<u8> {
+    let mut bytes = SIGNING_DOMAIN.to_vec();
+    bytes.extend_from_slice(&(document_id.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(document_id.as_bytes());
+    bytes.extend_from_slice(&holder.to_bytes());
+    bytes
+}
+
+/// Decides which peers may change which documents. Asked for every sync message carrying
+/// changes and for every full document or deletion a peer sends, with the capability that came
+/// along if its signature checks out.
+pub trait CapabilityVerifier: Send + 'static {
+    fn may_write(&self, peer: PeerId, document_id: &str, capability: Option<&Capability>) -> bool;
+}
+
+/// Lets a peer write a document with a capability issued by one of these peers
+pub struct TrustedIssuers(pub HashSet<PeerId>);
+
+impl CapabilityVerifier for TrustedIssuers {
+    fn may_write(
+        &self,
+        _peer: PeerId,
+        _document_id: &str,
+        capability: Option<&Capability>,
+    ) -> bool {
+        capability.is_some_and(|capability| self.0.contains(&capability.issuer.to_peer_id()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_covers_the_document_and_the_holder() {
+        let issuer = Keypair::generate_ed25519();
+        let holder = PeerId::random();
+        let capability = Capability::sign(&issuer, "doc", holder).unwrap();
+
+        assert!(capability.verify("doc", holder));
+        assert!(!capability.verify("other", holder));
+        assert!(!capability.verify("doc", PeerId::random()));
+        let forged = Capability {
+            issuer: Keypair::generate_ed25519().public(),
+            ..capability
+        };
+        assert!(!forged.verify("doc", holder));
+    }
+}
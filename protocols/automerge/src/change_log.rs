@@ -0,0 +1,70 @@
+//! Append-only log of document changes, kept next to the document's snapshot.
+//!
+//! Each entry is a big endian u32 length followed by changes in automerge's incremental save
+//! format. A document is restored by loading its snapshot and replaying the log on top of it.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use automerge::AutoCommit;
+
+/// Log belonging to the snapshot at `snapshot_path`
+pub(crate) fn log_path(snapshot_path: &Path) -> PathBuf {
+    snapshot_path.with_extension("changes")
+}
+
+pub(crate) fn append(path: &Path, changes: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut entry = Vec::with_capacity(changes.len() + 4);
+    entry.extend_from_slice(&(changes.len() as u32).to_be_bytes());
+    entry.extend_from_slice(changes);
+    file.write_all(&entry)?;
+    file.sync_data()
+}
+
+/// Applies every entry of the log to the document, returning the number of entries. A missing
+/// log has no entries, an entry cut short by a crash mid-append is dropped.
+pub(crate) fn replay(doc: &mut AutoCommit, path: &Path) -> io::Result<usize> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut rest = bytes.as_slice();
+    let mut entries = 0;
+    while !rest.is_empty() {
+        let Some((length, tail)) = rest.split_first_chunk::<4>() else {
+            tracing::warn!("Dropping truncated entry at the end of {}", path.display());
+            break;
+        };
+        let length = u32::from_be_bytes(*length) as usize;
+        if tail.len() < length {
+            tracing::warn!("Dropping truncated entry at the end of {}", path.display());
+            break;
+        }
+
+        let (changes, tail) = tail.split_at(length);
+        doc.load_incremental(changes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        entries += 1;
+        rest = tail;
+    }
+
+    Ok(entries)
+}
+
+/// Writes the snapshot through a temporary file and only then drops the log, so a crash in
+/// between leaves a log whose changes are already in the snapshot, which replays harmlessly
+pub(crate) fn compact(snapshot_path: &Path, snapshot: &[u8]) -> io::Result<()> {
+    let temporary = snapshot_path.with_extension("tmp");
+    fs::write(&temporary, snapshot)?;
+    fs::rename(&temporary, snapshot_path)?;
+    match fs::remove_file(log_path(snapshot_path)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
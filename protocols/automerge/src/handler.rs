@@ -1,66 +1,192 @@
-use std::task::Poll;
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    task::{Poll, Waker},
+};
 
+use futures::{FutureExt, future::BoxFuture};
 use libp2p::{
-    PeerId, Stream, StreamProtocol,
-    autonat::v2::client::Event,
-    core::upgrade::{DeniedUpgrade, ReadyUpgrade},
-    swarm::{ConnectionHandler, ConnectionHandlerEvent, SubstreamProtocol},
+    Stream,
+    swarm::{
+        ConnectionHandler, ConnectionHandlerEvent, StreamUpgradeError, SubstreamProtocol,
+        handler::{
+            ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        },
+    },
 };
-use tracing::warn;
 
-use crate::protocol::PROTOCOL_NAME;
+use crate::{
+    capability::Capability,
+    protocol::{Message, SyncErrorReason, Upgrade, Version},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Command {
+    /// Opens the sync of a document with the first message generated from our sync state
     StartSync {
         document_id: String,
-        peer: PeerId,
-    },
-    SendChanges {
-        document_id: String,
-        changes: Vec<u8>,
-        peer: PeerId,
-    },
-    BroadcastChanges {
-        document_id: String,
-        changes: Vec<u8>,
+        message: Vec<u8>,
+        auth: Option<Capability>,
     },
-    RequestSync {
+    /// Tells the remote we deleted the document
+    DeleteDocument {
         document_id: String,
-        peer: PeerId,
+        auth: Option<Capability>,
     },
+    /// Accepts sync messages of the document from now on, see [`Subscriptions::subscribe`]
+    SubscribeDocument { document_id: String },
+    /// Refuses sync messages of the document from now on
+    UnsubscribeDocument { document_id: String },
+}
+
+/// Documents synced with peers, shared by the behaviour and its handlers so both agree on them
+#[derive(Debug, Clone)]
+pub enum Subscriptions {
+    /// Every document, including ones we learn about later, except the unsubscribed ones
+    All { excluded: HashSet<String> },
+    /// Only the subscribed documents
+    Only(HashSet<String>),
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions::All {
+            excluded: HashSet::new(),
+        }
+    }
+}
+
+impl Subscriptions {
+    pub fn contains(&self, document_id: &str) -> bool {
+        match self {
+            Subscriptions::All { excluded } => !excluded.contains(document_id),
+            Subscriptions::Only(subscribed) => subscribed.contains(document_id),
+        }
+    }
+
+    /// Whether subscribing to the document switches from every document to only the subscribed
+    /// ones
+    pub fn switches_on(&self, document_id: &str) -> bool {
+        matches!(self, Subscriptions::All { excluded } if !excluded.contains(document_id))
+    }
+
+    /// Subscribes to the document, returning whether anything changed. While syncing every
+    /// document this takes back an earlier unsubscribe of it, and otherwise switches to syncing
+    /// only the subscribed documents, so a peer can keep a subset of them.
+    pub fn subscribe(&mut self, document_id: &str) -> bool {
+        match self {
+            Subscriptions::All { excluded } if excluded.remove(document_id) => true,
+            Subscriptions::All { .. } => {
+                *self = Subscriptions::Only(HashSet::from([document_id.to_string()]));
+                true
+            }
+            Subscriptions::Only(subscribed) => subscribed.insert(document_id.to_string()),
+        }
+    }
+
+    /// Unsubscribes from the document, returning whether it was subscribed
+    pub fn unsubscribe(&mut self, document_id: &str) -> bool {
+        match self {
+            Subscriptions::All { excluded } => excluded.insert(document_id.to_string()),
+            Subscriptions::Only(subscribed) => subscribed.remove(document_id),
+        }
+    }
 }
 
 /// Event from behaviour to the connection handler
 #[derive(Debug)]
 pub enum InEvent {
-    DocumentChanged { document_id: String },
+    /// Send a message to the remote over the outbound substream
+    Send(Message),
+    Command(Command),
+}
+
+/// Event from the connection handler to the behaviour
+#[derive(Debug)]
+pub enum OutEvent {
+    /// A message was received from the remote
+    Message(Message),
+    /// The remote sent an encoded automerge sync message for a document
+    SyncMessage {
+        document_id: String,
+        message: Vec<u8>,
+        auth: Option<Capability>,
+    },
+    /// The remote does not speak the automerge protocol
+    Unsupported,
+    /// Queued messages were dropped because the outbound substream failed
+    OutboundFailure(String),
+    /// This many of the messages the behaviour sent us left the outbound queue, written to the
+    /// substream or dropped because it failed
+    Dequeued(usize),
 }
 
 pub struct Handler {
-    pending_events: Vec<Event>,
+    /// Events for the behaviour, delivered in the order they were queued
+    pending_events: VecDeque<OutEvent>,
+    /// Messages waiting to be written to the outbound substream, flagged when the behaviour sent
+    /// them and counts them until they're [`OutEvent::Dequeued`]
+    outbound_queue: VecDeque<(Message, bool)>,
+    outbound: Option<OutboundState>,
+    /// Reads the next message from the remote's substream
+    inbound: Option<BoxFuture<'static, io::Result<(Stream, Version, Message)>>>,
+    /// Task of the last `poll` that returned pending, woken when there is new work
+    waker: Option<Waker>,
+    /// Documents whose sync messages are passed to the behaviour
+    subscriptions: Subscriptions,
+    /// Version negotiated on the last outbound substream, which our messages are encoded for
+    version: Version,
 }
 
 impl Handler {
-    pub fn new() -> Self {
+    pub fn new(subscriptions: Subscriptions) -> Self {
         Handler {
-            pending_events: Vec::new(),
+            pending_events: VecDeque::new(),
+            outbound_queue: VecDeque::new(),
+            outbound: None,
+            inbound: None,
+            waker: None,
+            subscriptions,
+            version: Version::V1,
+        }
+    }
+
+    fn is_subscribed(&self, document_id: &str) -> bool {
+        self.subscriptions.contains(document_id)
+    }
+
+    fn push_event(&mut self, event: OutEvent) {
+        self.pending_events.push_back(event);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
         }
     }
 }
 
 impl ConnectionHandler for Handler {
     type FromBehaviour = InEvent;
-    type ToBehaviour = Event;
-    type InboundProtocol = ReadyUpgrade<StreamProtocol>;
-    type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type ToBehaviour = OutEvent;
+    type InboundProtocol = Upgrade;
+    type OutboundProtocol = Upgrade;
     type InboundOpenInfo = ();
     type OutboundOpenInfo = ();
 
     fn listen_protocol(
         &self,
     ) -> libp2p::swarm::SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
-        SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), ())
+        SubstreamProtocol::new(Upgrade::default(), ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        !self.outbound_queue.is_empty()
+            || matches!(
+                self.outbound,
+                Some(OutboundState::PendingStream) | Some(OutboundState::Sending(..))
+            )
     }
 
     fn poll(
@@ -73,15 +199,140 @@ impl ConnectionHandler for Handler {
             Self::ToBehaviour,
         >,
     > {
-        if let Some(event) = self.pending_events.pop() {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+        }
+
+        while let Some(inbound) = self.inbound.as_mut() {
+            match inbound.poll_unpin(cx) {
+                Poll::Ready(Ok((stream, version, message))) => {
+                    self.inbound = Some(receive_message(stream, version).boxed());
+                    let event = match message {
+                        Message::SyncMessage { document_id, .. }
+                            if !self.is_subscribed(&document_id) =>
+                        {
+                            tracing::debug!("Refusing sync of unsubscribed {}", document_id);
+                            self.outbound_queue.push_back((
+                                Message::SyncError {
+                                    details: format!("not subscribed to {document_id}"),
+                                    document_id,
+                                    reason: SyncErrorReason::NOT_SUBSCRIBED,
+                                },
+                                false,
+                            ));
+                            continue;
+                        }
+                        Message::SyncMessage {
+                            document_id,
+                            message,
+                            auth,
+                        } => OutEvent::SyncMessage {
+                            document_id,
+                            message,
+                            auth,
+                        },
+                        message => OutEvent::Message(message),
+                    };
+                    return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+                }
+                Poll::Ready(Err(err)) => {
+                    tracing::debug!("Inbound automerge substream closed: {:?}", err);
+                    self.inbound = None;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match self.outbound.take() {
+                Some(OutboundState::Sending(mut sending, counted)) => {
+                    match sending.poll_unpin(cx) {
+                        Poll::Ready(Ok(stream)) => {
+                            self.outbound = Some(OutboundState::Idle(stream));
+                        }
+                        Poll::Ready(Err(err)) => {
+                            tracing::debug!("Failed to write to automerge substream: {:?}", err);
+                        }
+                        Poll::Pending => {
+                            self.outbound = Some(OutboundState::Sending(sending, counted));
+                            break;
+                        }
+                    }
+                    if counted {
+                        self.pending_events.push_back(OutEvent::Dequeued(1));
+                    }
+                }
+                Some(OutboundState::Idle(stream)) => {
+                    if let Some((message, counted)) = self.outbound_queue.pop_front() {
+                        self.outbound = Some(OutboundState::Sending(
+                            send_message(stream, self.version, message).boxed(),
+                            counted,
+                        ));
+                    } else {
+                        self.outbound = Some(OutboundState::Idle(stream));
+                        break;
+                    }
+                }
+                Some(OutboundState::PendingStream) => {
+                    self.outbound = Some(OutboundState::PendingStream);
+                    break;
+                }
+                None => {
+                    if self.outbound_queue.is_empty() {
+                        break;
+                    }
+
+                    self.outbound = Some(OutboundState::PendingStream);
+                    return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                        protocol: SubstreamProtocol::new(Upgrade::default(), ()),
+                    });
+                }
+            }
+        }
+
+        // written messages are reported once the outbound substream is busy or idle again
+        if let Some(event) = self.pending_events.pop_front() {
             return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
         }
 
+        self.waker = Some(cx.waker().clone());
         Poll::Pending
     }
 
     fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
-        warn!("Received behaviour event: {:?}", event);
+        match event {
+            InEvent::Send(message) => {
+                self.outbound_queue.push_back((message, true));
+                self.wake();
+            }
+            // the outbound substream is requested in `poll` as soon as a message is queued
+            InEvent::Command(Command::StartSync {
+                document_id,
+                message,
+                auth,
+            }) => {
+                self.outbound_queue.push_back((
+                    Message::SyncMessage {
+                        document_id,
+                        message,
+                        auth,
+                    },
+                    true,
+                ));
+                self.wake();
+            }
+            InEvent::Command(Command::DeleteDocument { document_id, auth }) => {
+                self.outbound_queue
+                    .push_back((Message::DeleteDocument { document_id, auth }, true));
+                self.wake();
+            }
+            InEvent::Command(Command::SubscribeDocument { document_id }) => {
+                self.subscriptions.subscribe(&document_id);
+            }
+            InEvent::Command(Command::UnsubscribeDocument { document_id }) => {
+                self.subscriptions.unsubscribe(&document_id);
+            }
+        }
     }
 
     fn on_connection_event(
@@ -94,10 +345,119 @@ impl ConnectionHandler for Handler {
         >,
     ) {
         tracing::debug!("Connection handler event: {:?}", event);
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: (stream, version),
+                ..
+            }) => {
+                // the remote reuses a single substream, a new one replaces the previous
+                self.inbound = Some(receive_message(stream, version).boxed());
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: (stream, version),
+                ..
+            }) => {
+                tracing::debug!("Negotiated automerge {:?}", version);
+                self.version = version;
+                self.outbound = Some(OutboundState::Idle(stream));
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { error, .. }) => {
+                self.outbound = None;
+                let dropped = self
+                    .outbound_queue
+                    .drain(..)
+                    .filter(|(_, counted)| *counted)
+                    .count();
+                let event = match error {
+                    StreamUpgradeError::NegotiationFailed => OutEvent::Unsupported,
+                    error => OutEvent::OutboundFailure(error.to_string()),
+                };
+                self.push_event(event);
+                if dropped > 0 {
+                    self.push_event(OutEvent::Dequeued(dropped));
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 enum OutboundState {
-    PedingStream,
-    Ready(Stream),
+    /// An outbound substream has been requested but isn't negotiated yet
+    PendingStream,
+    Idle(Stream),
+    /// Writing a message, flagged when the behaviour counts it
+    Sending(BoxFuture<'static, io::Result<Stream>>, bool),
+}
+
+async fn send_message(
+    mut stream: Stream,
+    version: Version,
+    message: Message,
+) -> io::Result<Stream> {
+    version.write_message(&mut stream, &message).await?;
+    Ok(stream)
+}
+
+async fn receive_message(
+    mut stream: Stream,
+    version: Version,
+) -> io::Result<(Stream, Version, Message)> {
+    let message = version.read_message(&mut stream).await?;
+    Ok((stream, version, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(handler: &mut Handler) -> Poll<ConnectionHandlerEvent<Upgrade, (), OutEvent>> {
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+        handler.poll(&mut cx)
+    }
+
+    #[test]
+    fn messages_and_events_keep_the_order_they_were_queued_in() {
+        let mut handler = Handler::new(Subscriptions::default());
+        handler.on_behaviour_event(InEvent::Send(Message::RequestDocument {
+            document_id: "a".to_string(),
+        }));
+        handler.on_behaviour_event(InEvent::Command(Command::StartSync {
+            document_id: "b".to_string(),
+            message: Vec::new(),
+            auth: None,
+        }));
+        handler.on_behaviour_event(InEvent::Send(Message::RequestHeads {
+            document_id: "c".to_string(),
+        }));
+        handler.on_behaviour_event(InEvent::Command(Command::DeleteDocument {
+            document_id: "d".to_string(),
+            auth: None,
+        }));
+
+        assert!(matches!(
+            poll(&mut handler),
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+        ));
+        // the outbound substream writes the queue front to back
+        let queued = handler
+            .outbound_queue
+            .iter()
+            .filter_map(|(message, _)| message.document_id())
+            .collect::<Vec<_>>();
+        assert_eq!(queued, ["a", "b", "c", "d"]);
+
+        handler.on_connection_event(ConnectionEvent::DialUpgradeError(DialUpgradeError {
+            info: (),
+            error: StreamUpgradeError::Timeout,
+        }));
+        let mut events = Vec::new();
+        while let Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event)) = poll(&mut handler) {
+            events.push(event);
+        }
+        assert!(matches!(
+            events.as_slice(),
+            [OutEvent::OutboundFailure(_), OutEvent::Dequeued(4)]
+        ));
+    }
 }
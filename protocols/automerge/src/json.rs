@@ -0,0 +1,178 @@
+use automerge::{
+    AutomergeError, ObjId, ObjType, ReadDoc, ScalarValue, Value, transaction::Transactable,
+};
+use serde_json::{Map, Number, Value as Json};
+
+/// Recursively materializes an automerge object into its JSON representation
+pub(crate) fn object_to_json<D: ReadDoc>(doc: &D, obj: &ObjId) -> Json {
+    match doc.object_type(obj) {
+        Ok(ObjType::Map) | Ok(ObjType::Table) => {
+            let mut map = Map::new();
+            for key in doc.keys(obj) {
+                if let Ok(Some((value, id))) = doc.get(obj, key.as_str()) {
+                    let value = value_to_json(doc, value, &id);
+                    map.insert(key, value);
+                }
+            }
+            Json::Object(map)
+        }
+        Ok(ObjType::List) => Json::Array(
+            (0..doc.length(obj))
+                .filter_map(|index| doc.get(obj, index).ok().flatten())
+                .map(|(value, id)| value_to_json(doc, value, &id))
+                .collect(),
+        ),
+        Ok(ObjType::Text) => Json::String(doc.text(obj).unwrap_or_default()),
+        Err(_) => Json::Null,
+    }
+}
+
+fn value_to_json<D: ReadDoc>(doc: &D, value: Value<'_>, id: &ObjId) -> Json {
+    match value {
+        Value::Object(_) => object_to_json(doc, id),
+        Value::Scalar(scalar) => scalar_to_json(&scalar),
+    }
+}
+
+fn scalar_to_json(scalar: &ScalarValue) -> Json {
+    match scalar {
+        ScalarValue::Str(s) => Json::String(s.to_string()),
+        // timestamps are milliseconds since the unix epoch
+        ScalarValue::Int(i) | ScalarValue::Timestamp(i) => Json::from(*i),
+        ScalarValue::Uint(u) => Json::from(*u),
+        ScalarValue::F64(f) => Number::from_f64(*f).map_or(Json::Null, Json::Number),
+        ScalarValue::Counter(counter) => Json::from(i64::from(counter)),
+        ScalarValue::Boolean(b) => Json::Bool(*b),
+        ScalarValue::Bytes(bytes) | ScalarValue::Unknown { bytes, .. } => {
+            Json::Array(bytes.iter().map(|b| Json::from(*b)).collect())
+        }
+        ScalarValue::Null => Json::Null,
+    }
+}
+
+/// Writes every key of a JSON object into an automerge map, creating nested maps and lists
+pub(crate) fn put_json_object<T: Transactable>(
+    doc: &mut T,
+    obj: &ObjId,
+    values: &Map<String, Json>,
+) -> Result<(), AutomergeError> {
+    for (key, value) in values {
+        match value {
+            Json::Object(map) => {
+                let child = doc.put_object(obj, key.as_str(), ObjType::Map)?;
+                put_json_object(doc, &child, map)?;
+            }
+            Json::Array(items) => {
+                let child = doc.put_object(obj, key.as_str(), ObjType::List)?;
+                insert_json_list(doc, &child, items)?;
+            }
+            scalar => doc.put(obj, key.as_str(), json_to_scalar(scalar))?,
+        }
+    }
+
+    Ok(())
+}
+
+fn insert_json_list<T: Transactable>(
+    doc: &mut T,
+    obj: &ObjId,
+    items: &[Json],
+) -> Result<(), AutomergeError> {
+    for (index, item) in items.iter().enumerate() {
+        match item {
+            Json::Object(map) => {
+                let child = doc.insert_object(obj, index, ObjType::Map)?;
+                put_json_object(doc, &child, map)?;
+            }
+            Json::Array(items) => {
+                let child = doc.insert_object(obj, index, ObjType::List)?;
+                insert_json_list(doc, &child, items)?;
+            }
+            scalar => doc.insert(obj, index, json_to_scalar(scalar))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps a JSON scalar onto the closest automerge scalar. Containers are handled by the callers.
+fn json_to_scalar(value: &Json) -> ScalarValue {
+    match value {
+        Json::Bool(b) => ScalarValue::Boolean(*b),
+        Json::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                ScalarValue::Int(i)
+            } else if let Some(u) = n.as_u64() {
+                ScalarValue::Uint(u)
+            } else {
+                ScalarValue::F64(n.as_f64().unwrap_or_default())
+            }
+        }
+        Json::String(s) => ScalarValue::from(s.as_str()),
+        Json::Null | Json::Array(_) | Json::Object(_) => ScalarValue::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{AutoCommit, ROOT};
+    use serde_json::json;
+
+    use super::*;
+
+    fn round_trip(value: Json) -> Json {
+        let Json::Object(values) = value else {
+            panic!("not an object");
+        };
+        let mut doc = AutoCommit::new();
+        put_json_object(&mut doc, &ROOT, &values).unwrap();
+        object_to_json(&doc, &ROOT)
+    }
+
+    #[test]
+    fn nested_objects_round_trip() {
+        let value = json!({
+            "name": "doc",
+            "count": -3,
+            "big": u64::MAX,
+            "ratio": 0.5,
+            "done": false,
+            "missing": null,
+            "tags": ["a", ["b", 1], { "c": true }],
+            "nested": { "deeper": { "empty": {} }, "list": [] },
+        });
+
+        assert_eq!(round_trip(value.clone()), value);
+    }
+
+    #[test]
+    fn text_counters_and_timestamps_become_json_scalars() {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(&ROOT, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "hello").unwrap();
+        doc.put(&ROOT, "counter", ScalarValue::counter(5)).unwrap();
+        doc.increment(&ROOT, "counter", 2).unwrap();
+        doc.put(&ROOT, "at", ScalarValue::Timestamp(1_700_000_000_000))
+            .unwrap();
+        doc.put(&ROOT, "bytes", ScalarValue::Bytes(vec![1, 2]))
+            .unwrap();
+
+        assert_eq!(
+            object_to_json(&doc, &ROOT),
+            json!({
+                "text": "hello",
+                "counter": 7,
+                "at": 1_700_000_000_000i64,
+                "bytes": [1, 2],
+            })
+        );
+    }
+
+    #[test]
+    fn non_object_roots_are_refused() {
+        // the root of a document is a map, so only objects can be written into it
+        for root in [json!([1, 2]), json!("text"), json!(1), json!(null)] {
+            assert!(serde_json::from_value::<Map<String, Json>>(root).is_err());
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Local labels of documents, kept in a JSON file in the data directory. Labels aren't part of
+//! the documents, so they are never synced to other peers.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const LABELS_FILE: &str = "labels.json";
+
+pub(crate) type Labels = HashMap<String, BTreeSet<String>>;
+
+pub(crate) fn labels_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(LABELS_FILE)
+}
+
+/// Labels of every document, a missing file meaning no document has labels
+pub(crate) fn load(path: &Path) -> io::Result<Labels> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Labels::new()),
+        Err(err) => Err(err),
+    }
+}
+
+pub(crate) fn save(path: &Path, labels: &Labels) -> io::Result<()> {
+    let bytes = serde_json::to_vec_pretty(labels)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, path)
+}
@@ -1,5 +1,18 @@
+mod archive;
 mod behaviour;
+mod capability;
+mod change_log;
 mod handler;
+mod json;
+mod labels;
+mod messages;
 mod protocol;
+mod rate_limiter;
+mod sync_scheduler;
+mod tombstones;
 
-pub use behaviour::{Behaviour, Config};
+pub use behaviour::{Behaviour, Config, Event, Persistence, QueueOverflow};
+pub use capability::{Capability, CapabilityVerifier, TrustedIssuers};
+pub use protocol::{Message, SyncErrorReason, read_message, write_message};
+pub use rate_limiter::SyncRateLimit;
+pub use sync_scheduler::SyncScheduling;
@@ -19,6 +19,7 @@ use super::*;
 pub struct DocumentSyncMessage<'a> {
     pub id: Cow<'a, str>,
     pub message: Cow<'a, [u8]>,
+    pub auth: Option<messages::Capability<'a>>,
 }
 
 impl<'a> MessageRead<'a> for DocumentSyncMessage<'a> {
@@ -28,6 +29,7 @@ impl<'a> MessageRead<'a> for DocumentSyncMessage<'a> {
             match r.next_tag(bytes) {
                 Ok(10) => msg.id = r.read_string(bytes).map(Cow::Borrowed)?,
                 Ok(18) => msg.message = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(26) => msg.auth = Some(r.read_message::<messages::Capability>(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -41,11 +43,49 @@ impl<'a> MessageWrite for DocumentSyncMessage<'a> {
         0
         + if self.id == "" { 0 } else { 1 + sizeof_len((&self.id).len()) }
         + if self.message == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.message).len()) }
+        + self.auth.as_ref().map_or(0, |m| 1 + sizeof_len((m).get_size()))
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
         if self.id != "" { w.write_with_tag(10, |w| w.write_string(&**&self.id))?; }
         if self.message != Cow::Borrowed(b"") { w.write_with_tag(18, |w| w.write_bytes(&**&self.message))?; }
+        if let Some(ref s) = self.auth { w.write_with_tag(26, |w| w.write_message(s))?; }
+        Ok(())
+    }
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Capability<'a> {
+    pub issuer: Cow<'a, [u8]>,
+    pub signature: Cow<'a, [u8]>,
+}
+
+impl<'a> MessageRead<'a> for Capability<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.issuer = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(18) => msg.signature = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for Capability<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + if self.issuer == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.issuer).len()) }
+        + if self.signature == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.signature).len()) }
+    }
+
+    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
+        if self.issuer != Cow::Borrowed(b"") { w.write_with_tag(10, |w| w.write_bytes(&**&self.issuer))?; }
+        if self.signature != Cow::Borrowed(b"") { w.write_with_tag(18, |w| w.write_bytes(&**&self.signature))?; }
         Ok(())
     }
 }
@@ -131,6 +171,8 @@ pub enum Reason {
     INVALID_MESSAGE = 1,
     DOCUMENT_NOT_FOUND = 2,
     INTERNAL_ERROR = 3,
+    NOT_SUBSCRIBED = 4,
+    UNAUTHORIZED = 5,
 }
 
 impl Default for Reason {
@@ -146,6 +188,8 @@ impl From<i32> for Reason {
             1 => Reason::INVALID_MESSAGE,
             2 => Reason::DOCUMENT_NOT_FOUND,
             3 => Reason::INTERNAL_ERROR,
+            4 => Reason::NOT_SUBSCRIBED,
+            5 => Reason::UNAUTHORIZED,
             _ => Self::default(),
         }
     }
@@ -158,6 +202,8 @@ impl<'a> From<&'a str> for Reason {
             "INVALID_MESSAGE" => Reason::INVALID_MESSAGE,
             "DOCUMENT_NOT_FOUND" => Reason::DOCUMENT_NOT_FOUND,
             "INTERNAL_ERROR" => Reason::INTERNAL_ERROR,
+            "NOT_SUBSCRIBED" => Reason::NOT_SUBSCRIBED,
+            "UNAUTHORIZED" => Reason::UNAUTHORIZED,
             _ => Self::default(),
         }
     }
@@ -247,6 +293,7 @@ impl<'a> MessageWrite for RequestDocument<'a> {
 pub struct Document<'a> {
     pub id: Cow<'a, str>,
     pub document: Cow<'a, [u8]>,
+    pub auth: Option<messages::Capability<'a>>,
 }
 
 impl<'a> MessageRead<'a> for Document<'a> {
@@ -256,6 +303,7 @@ impl<'a> MessageRead<'a> for Document<'a> {
             match r.next_tag(bytes) {
                 Ok(10) => msg.id = r.read_string(bytes).map(Cow::Borrowed)?,
                 Ok(18) => msg.document = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(26) => msg.auth = Some(r.read_message::<messages::Capability>(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -269,11 +317,121 @@ impl<'a> MessageWrite for Document<'a> {
         0
         + if self.id == "" { 0 } else { 1 + sizeof_len((&self.id).len()) }
         + if self.document == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.document).len()) }
+        + self.auth.as_ref().map_or(0, |m| 1 + sizeof_len((m).get_size()))
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
         if self.id != "" { w.write_with_tag(10, |w| w.write_string(&**&self.id))?; }
         if self.document != Cow::Borrowed(b"") { w.write_with_tag(18, |w| w.write_bytes(&**&self.document))?; }
+        if let Some(ref s) = self.auth { w.write_with_tag(26, |w| w.write_message(s))?; }
+        Ok(())
+    }
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RequestHeads<'a> {
+    pub id: Cow<'a, str>,
+}
+
+impl<'a> MessageRead<'a> for RequestHeads<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.id = r.read_string(bytes).map(Cow::Borrowed)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for RequestHeads<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + if self.id == "" { 0 } else { 1 + sizeof_len((&self.id).len()) }
+    }
+
+    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
+        if self.id != "" { w.write_with_tag(10, |w| w.write_string(&**&self.id))?; }
+        Ok(())
+    }
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Heads<'a> {
+    pub id: Cow<'a, str>,
+    pub heads: Vec<Cow<'a, [u8]>>,
+    pub found: bool,
+}
+
+impl<'a> MessageRead<'a> for Heads<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.id = r.read_string(bytes).map(Cow::Borrowed)?,
+                Ok(18) => msg.heads.push(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(24) => msg.found = r.read_bool(bytes)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for Heads<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + if self.id == "" { 0 } else { 1 + sizeof_len((&self.id).len()) }
+        + self.heads.iter().map(|s| 1 + sizeof_len((s).len())).sum::<usize>()
+        + if self.found == false { 0 } else { 1 + sizeof_varint(*(&self.found) as u64) }
+    }
+
+    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
+        if self.id != "" { w.write_with_tag(10, |w| w.write_string(&**&self.id))?; }
+        for s in &self.heads { w.write_with_tag(18, |w| w.write_bytes(&**s))?; }
+        if self.found != false { w.write_with_tag(24, |w| w.write_bool(*&self.found))?; }
+        Ok(())
+    }
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DeleteDocument<'a> {
+    pub id: Cow<'a, str>,
+    pub auth: Option<messages::Capability<'a>>,
+}
+
+impl<'a> MessageRead<'a> for DeleteDocument<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.id = r.read_string(bytes).map(Cow::Borrowed)?,
+                Ok(18) => msg.auth = Some(r.read_message::<messages::Capability>(bytes)?),
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for DeleteDocument<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + if self.id == "" { 0 } else { 1 + sizeof_len((&self.id).len()) }
+        + self.auth.as_ref().map_or(0, |m| 1 + sizeof_len((m).get_size()))
+    }
+
+    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
+        if self.id != "" { w.write_with_tag(10, |w| w.write_string(&**&self.id))?; }
+        if let Some(ref s) = self.auth { w.write_with_tag(18, |w| w.write_message(s))?; }
         Ok(())
     }
 }
@@ -295,6 +453,9 @@ impl<'a> MessageRead<'a> for Message<'a> {
                 Ok(34) => msg.msg = messages::mod_Message::OneOfmsg::request_available_documents(r.read_message::<messages::RequestAvailableDocuments>(bytes)?),
                 Ok(42) => msg.msg = messages::mod_Message::OneOfmsg::request_document(r.read_message::<messages::RequestDocument>(bytes)?),
                 Ok(50) => msg.msg = messages::mod_Message::OneOfmsg::document(r.read_message::<messages::Document>(bytes)?),
+                Ok(58) => msg.msg = messages::mod_Message::OneOfmsg::request_heads(r.read_message::<messages::RequestHeads>(bytes)?),
+                Ok(66) => msg.msg = messages::mod_Message::OneOfmsg::heads(r.read_message::<messages::Heads>(bytes)?),
+                Ok(74) => msg.msg = messages::mod_Message::OneOfmsg::delete_document(r.read_message::<messages::DeleteDocument>(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -313,6 +474,9 @@ impl<'a> MessageWrite for Message<'a> {
             messages::mod_Message::OneOfmsg::request_available_documents(ref m) => 1 + sizeof_len((m).get_size()),
             messages::mod_Message::OneOfmsg::request_document(ref m) => 1 + sizeof_len((m).get_size()),
             messages::mod_Message::OneOfmsg::document(ref m) => 1 + sizeof_len((m).get_size()),
+            messages::mod_Message::OneOfmsg::request_heads(ref m) => 1 + sizeof_len((m).get_size()),
+            messages::mod_Message::OneOfmsg::heads(ref m) => 1 + sizeof_len((m).get_size()),
+            messages::mod_Message::OneOfmsg::delete_document(ref m) => 1 + sizeof_len((m).get_size()),
             messages::mod_Message::OneOfmsg::None => 0,
     }    }
 
@@ -323,6 +487,9 @@ impl<'a> MessageWrite for Message<'a> {
             messages::mod_Message::OneOfmsg::request_available_documents(ref m) => { w.write_with_tag(34, |w| w.write_message(m))? },
             messages::mod_Message::OneOfmsg::request_document(ref m) => { w.write_with_tag(42, |w| w.write_message(m))? },
             messages::mod_Message::OneOfmsg::document(ref m) => { w.write_with_tag(50, |w| w.write_message(m))? },
+            messages::mod_Message::OneOfmsg::request_heads(ref m) => { w.write_with_tag(58, |w| w.write_message(m))? },
+            messages::mod_Message::OneOfmsg::heads(ref m) => { w.write_with_tag(66, |w| w.write_message(m))? },
+            messages::mod_Message::OneOfmsg::delete_document(ref m) => { w.write_with_tag(74, |w| w.write_message(m))? },
             messages::mod_Message::OneOfmsg::None => {},
     }        Ok(())
     }
@@ -340,6 +507,9 @@ pub enum OneOfmsg<'a> {
     request_available_documents(messages::RequestAvailableDocuments),
     request_document(messages::RequestDocument<'a>),
     document(messages::Document<'a>),
+    request_heads(messages::RequestHeads<'a>),
+    heads(messages::Heads<'a>),
+    delete_document(messages::DeleteDocument<'a>),
     None,
 }
 
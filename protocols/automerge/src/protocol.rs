@@ -1,3 +1,520 @@
-use libp2p::StreamProtocol;
+use std::{borrow::Cow, convert::Infallible, io};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, future};
+use libp2p::{
+    StreamProtocol,
+    core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo},
+    identity::PublicKey,
+};
+use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
+
+use crate::{
+    capability::Capability,
+    messages::messages::{self as proto, mod_Message::OneOfmsg},
+};
+
+pub use crate::messages::messages::mod_SyncErrorReason::Reason as SyncErrorReason;
 
 pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/automerge/0.0.1");
+
+/// Versions of the protocol we speak, newest first. A dialer proposes them in this order, so a
+/// substream runs on the newest version both ends support.
+pub const SUPPORTED_VERSIONS: [Version; 1] = [Version::V1];
+
+/// A version of the automerge wire format, each negotiated under its own protocol name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    /// Protobuf messages prefixed with their length as a big endian u32
+    V1,
+    /// Framed like V1 under a protocol name of its own, stands in for a future version when
+    /// testing negotiation
+    #[cfg(test)]
+    V2,
+}
+
+impl Version {
+    pub fn protocol_name(self) -> StreamProtocol {
+        match self {
+            Version::V1 => PROTOCOL_NAME,
+            #[cfg(test)]
+            Version::V2 => StreamProtocol::new("/automerge/0.0.2"),
+        }
+    }
+
+    /// Writes a single message framed the way this version frames them
+    pub async fn write_message<S>(self, stream: &mut S, message: &Message) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        match self {
+            Version::V1 => write_message(stream, message).await,
+            #[cfg(test)]
+            Version::V2 => write_message(stream, message).await,
+        }
+    }
+
+    /// Reads a single message framed the way this version frames them
+    pub async fn read_message<S>(self, stream: &mut S) -> io::Result<Message>
+    where
+        S: AsyncRead + Unpin,
+    {
+        match self {
+            Version::V1 => read_message(stream).await,
+            #[cfg(test)]
+            Version::V2 => read_message(stream).await,
+        }
+    }
+}
+
+/// Negotiates one of the [`SUPPORTED_VERSIONS`] and yields the substream with the version
+/// both ends agreed on
+#[derive(Debug, Clone, Copy)]
+pub struct Upgrade {
+    versions: &'static [Version],
+}
+
+impl Default for Upgrade {
+    fn default() -> Self {
+        Upgrade {
+            versions: &SUPPORTED_VERSIONS,
+        }
+    }
+}
+
+impl Upgrade {
+    fn version(&self, protocol: &StreamProtocol) -> Version {
+        self.versions
+            .iter()
+            .copied()
+            .find(|version| &version.protocol_name() == protocol)
+            .expect("negotiated one of our protocols")
+    }
+}
+
+impl UpgradeInfo for Upgrade {
+    type Info = StreamProtocol;
+    type InfoIter = Vec<StreamProtocol>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.versions
+            .iter()
+            .copied()
+            .map(Version::protocol_name)
+            .collect()
+    }
+}
+
+impl<C> InboundUpgrade<C> for Upgrade {
+    type Output = (C, Version);
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, stream: C, protocol: Self::Info) -> Self::Future {
+        future::ready(Ok((stream, self.version(&protocol))))
+    }
+}
+
+impl<C> OutboundUpgrade<C> for Upgrade {
+    type Output = (C, Version);
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, stream: C, protocol: Self::Info) -> Self::Future {
+        future::ready(Ok((stream, self.version(&protocol))))
+    }
+}
+
+/// Largest frame accepted from a remote, so a peer can't make us allocate arbitrary amounts of
+/// memory by announcing a huge length prefix
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// A message exchanged over the automerge protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    SyncMessage {
+        document_id: String,
+        message: Vec<u8>,
+        /// Proof that the sender may write the document
+        auth: Option<Capability>,
+    },
+    SyncError {
+        document_id: String,
+        reason: SyncErrorReason,
+        details: String,
+    },
+    AvailableDocuments {
+        document_ids: Vec<String>,
+    },
+    RequestAvailableDocuments,
+    RequestDocument {
+        document_id: String,
+    },
+    Document {
+        document_id: String,
+        document: Vec<u8>,
+        auth: Option<Capability>,
+    },
+    RequestHeads {
+        document_id: String,
+    },
+    /// Current heads of a document, `None` if the sender doesn't have it
+    Heads {
+        document_id: String,
+        heads: Option<Vec<Vec<u8>>>,
+    },
+    /// The sender deleted the document and won't accept it again
+    DeleteDocument {
+        document_id: String,
+        auth: Option<Capability>,
+    },
+}
+
+impl Message {
+    /// The document the message is about, if it's about a single one
+    pub fn document_id(&self) -> Option<&str> {
+        match self {
+            Message::SyncMessage { document_id, .. }
+            | Message::SyncError { document_id, .. }
+            | Message::RequestDocument { document_id }
+            | Message::Document { document_id, .. }
+            | Message::RequestHeads { document_id }
+            | Message::Heads { document_id, .. }
+            | Message::DeleteDocument { document_id, .. } => Some(document_id),
+            Message::AvailableDocuments { .. } | Message::RequestAvailableDocuments => None,
+        }
+    }
+
+    fn to_proto(&self) -> proto::Message<'_> {
+        let msg = match self {
+            Message::SyncMessage {
+                document_id,
+                message,
+                auth,
+            } => OneOfmsg::sync_message(proto::DocumentSyncMessage {
+                id: Cow::Borrowed(document_id),
+                message: Cow::Borrowed(message),
+                auth: auth.as_ref().map(capability_to_proto),
+            }),
+            Message::SyncError {
+                document_id,
+                reason,
+                details,
+            } => OneOfmsg::sync_error(proto::DocumentSyncError {
+                id: Cow::Borrowed(document_id),
+                reason: Some(proto::SyncErrorReason {
+                    reason: *reason,
+                    details: Cow::Borrowed(details),
+                }),
+            }),
+            Message::AvailableDocuments { document_ids } => {
+                OneOfmsg::available_documents(proto::AvailableDocuments {
+                    ids: document_ids
+                        .iter()
+                        .map(|id| Cow::Borrowed(id.as_str()))
+                        .collect(),
+                })
+            }
+            Message::RequestAvailableDocuments => {
+                OneOfmsg::request_available_documents(proto::RequestAvailableDocuments {})
+            }
+            Message::RequestDocument { document_id } => {
+                OneOfmsg::request_document(proto::RequestDocument {
+                    id: Cow::Borrowed(document_id),
+                })
+            }
+            Message::Document {
+                document_id,
+                document,
+                auth,
+            } => OneOfmsg::document(proto::Document {
+                id: Cow::Borrowed(document_id),
+                document: Cow::Borrowed(document),
+                auth: auth.as_ref().map(capability_to_proto),
+            }),
+            Message::RequestHeads { document_id } => OneOfmsg::request_heads(proto::RequestHeads {
+                id: Cow::Borrowed(document_id),
+            }),
+            Message::Heads { document_id, heads } => OneOfmsg::heads(proto::Heads {
+                id: Cow::Borrowed(document_id),
+                heads: heads
+                    .iter()
+                    .flatten()
+                    .map(|head| Cow::Borrowed(head.as_slice()))
+                    .collect(),
+                found: heads.is_some(),
+            }),
+            Message::DeleteDocument { document_id, auth } => {
+                OneOfmsg::delete_document(proto::DeleteDocument {
+                    id: Cow::Borrowed(document_id),
+                    auth: auth.as_ref().map(capability_to_proto),
+                })
+            }
+        };
+
+        proto::Message { msg }
+    }
+
+    fn from_proto(message: proto::Message<'_>) -> Option<Self> {
+        let message = match message.msg {
+            OneOfmsg::sync_message(m) => Message::SyncMessage {
+                document_id: m.id.into_owned(),
+                message: m.message.into_owned(),
+                auth: m.auth.and_then(capability_from_proto),
+            },
+            OneOfmsg::sync_error(m) => {
+                let reason = m.reason.unwrap_or_default();
+                Message::SyncError {
+                    document_id: m.id.into_owned(),
+                    reason: reason.reason,
+                    details: reason.details.into_owned(),
+                }
+            }
+            OneOfmsg::available_documents(m) => Message::AvailableDocuments {
+                document_ids: m.ids.into_iter().map(Cow::into_owned).collect(),
+            },
+            OneOfmsg::request_available_documents(_) => Message::RequestAvailableDocuments,
+            OneOfmsg::request_document(m) => Message::RequestDocument {
+                document_id: m.id.into_owned(),
+            },
+            OneOfmsg::document(m) => Message::Document {
+                document_id: m.id.into_owned(),
+                document: m.document.into_owned(),
+                auth: m.auth.and_then(capability_from_proto),
+            },
+            OneOfmsg::request_heads(m) => Message::RequestHeads {
+                document_id: m.id.into_owned(),
+            },
+            OneOfmsg::heads(m) => Message::Heads {
+                document_id: m.id.into_owned(),
+                heads: m
+                    .found
+                    .then(|| m.heads.into_iter().map(Cow::into_owned).collect()),
+            },
+            OneOfmsg::delete_document(m) => Message::DeleteDocument {
+                document_id: m.id.into_owned(),
+                auth: m.auth.and_then(capability_from_proto),
+            },
+            OneOfmsg::None => return None,
+        };
+
+        Some(message)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let proto = self.to_proto();
+        let mut bytes = Vec::with_capacity(proto.get_size());
+        proto
+            .write_message(&mut Writer::new(&mut bytes))
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = BytesReader::from_bytes(bytes);
+        let proto = proto::Message::from_reader(&mut reader, bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Message::from_proto(proto)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty message"))
+    }
+}
+
+fn capability_to_proto(capability: &Capability) -> proto::Capability<'_> {
+    proto::Capability {
+        issuer: Cow::Owned(capability.issuer.encode_protobuf()),
+        signature: Cow::Borrowed(&capability.signature),
+    }
+}
+
+/// A capability with an unreadable key proves nothing, same as none at all
+fn capability_from_proto(capability: proto::Capability<'_>) -> Option<Capability> {
+    Some(Capability {
+        issuer: PublicKey::try_decode_protobuf(&capability.issuer).ok()?,
+        signature: capability.signature.into_owned(),
+    })
+}
+
+/// Writes a single message prefixed with its length as a big endian u32
+pub async fn write_message<S>(stream: &mut S, message: &Message) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bytes = message.encode();
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message of {} bytes exceeds the maximum size", bytes.len()),
+        ));
+    }
+
+    stream
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(&bytes).await?;
+    stream.flush().await
+}
+
+/// Reads a single length prefixed message, refusing frames above [`MAX_MESSAGE_SIZE`]
+pub async fn read_message<S>(stream: &mut S) -> io::Result<Message>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {length} bytes exceeds the maximum size"),
+        ));
+    }
+
+    // read_exact keeps reading until the whole frame arrived, so partial reads are handled
+    let mut bytes = vec![0u8; length];
+    stream.read_exact(&mut bytes).await?;
+    Message::decode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::StreamExt;
+    use libp2p::{
+        Multiaddr, PeerId, Transport,
+        core::{
+            Endpoint,
+            transport::{DialOpts, ListenerId, MemoryTransport, PortUse, TransportEvent},
+            upgrade::{InboundConnectionUpgrade, OutboundConnectionUpgrade},
+        },
+        multiaddr::Protocol,
+        yamux,
+    };
+
+    use super::*;
+
+    /// Runs [`Upgrade`] in place of a connection's security upgrade, recording the version it
+    /// agreed on
+    #[derive(Clone)]
+    struct Negotiate {
+        upgrade: Upgrade,
+        agreed: Arc<Mutex<Option<Version>>>,
+    }
+
+    impl Negotiate {
+        fn new(versions: &'static [Version]) -> Self {
+            Negotiate {
+                upgrade: Upgrade { versions },
+                agreed: Arc::default(),
+            }
+        }
+
+        fn agreed(&self) -> Option<Version> {
+            *self.agreed.lock().unwrap()
+        }
+
+        fn finish<C>(&self, (stream, version): (C, Version)) -> (PeerId, C) {
+            *self.agreed.lock().unwrap() = Some(version);
+            (PeerId::random(), stream)
+        }
+    }
+
+    impl UpgradeInfo for Negotiate {
+        type Info = StreamProtocol;
+        type InfoIter = Vec<StreamProtocol>;
+
+        fn protocol_info(&self) -> Self::InfoIter {
+            self.upgrade.protocol_info()
+        }
+    }
+
+    impl<C> InboundConnectionUpgrade<C> for Negotiate {
+        type Output = (PeerId, C);
+        type Error = Infallible;
+        type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+        fn upgrade_inbound(self, stream: C, protocol: Self::Info) -> Self::Future {
+            let negotiated = InboundUpgrade::upgrade_inbound(self.upgrade, stream, protocol);
+            future::ready(Ok(self.finish(negotiated.into_inner().unwrap())))
+        }
+    }
+
+    impl<C> OutboundConnectionUpgrade<C> for Negotiate {
+        type Output = (PeerId, C);
+        type Error = Infallible;
+        type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+        fn upgrade_outbound(self, stream: C, protocol: Self::Info) -> Self::Future {
+            let negotiated = OutboundUpgrade::upgrade_outbound(self.upgrade, stream, protocol);
+            future::ready(Ok(self.finish(negotiated.into_inner().unwrap())))
+        }
+    }
+
+    /// Versions the listener and the dialer agree on over an in-memory transport
+    fn negotiate(
+        listener: &'static [Version],
+        dialer: &'static [Version],
+    ) -> (Option<Version>, Option<Version>) {
+        let (listener, dialer) = (Negotiate::new(listener), Negotiate::new(dialer));
+        let transport = |negotiate: &Negotiate| {
+            MemoryTransport::default()
+                .upgrade(libp2p::core::upgrade::Version::V1)
+                .authenticate(negotiate.clone())
+                .multiplex(yamux::Config::default())
+                .boxed()
+        };
+        let address: Multiaddr = Protocol::Memory(memory_port()).into();
+        let mut listening = transport(&listener);
+        listening
+            .listen_on(ListenerId::next(), address.clone())
+            .unwrap();
+        let dial = transport(&dialer)
+            .dial(
+                address,
+                DialOpts {
+                    role: Endpoint::Dialer,
+                    port_use: PortUse::New,
+                },
+            )
+            .unwrap();
+        let accept = async {
+            loop {
+                if let TransportEvent::Incoming { upgrade, .. } = listening.select_next_some().await
+                {
+                    return upgrade.await;
+                }
+            }
+        };
+
+        let (accepted, dialed) = futures::executor::block_on(futures::future::join(accept, dial));
+        accepted.unwrap();
+        dialed.unwrap();
+        (listener.agreed(), dialer.agreed())
+    }
+
+    /// Memory port of its own for each connection, tests run in parallel
+    fn memory_port() -> u64 {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    }
+
+    #[test]
+    fn older_peer_and_newer_peer_agree_on_v1() {
+        const V1: &[Version] = &[Version::V1];
+        const V1_AND_V2: &[Version] = &[Version::V2, Version::V1];
+
+        assert_eq!(
+            negotiate(V1, V1_AND_V2),
+            (Some(Version::V1), Some(Version::V1))
+        );
+        assert_eq!(
+            negotiate(V1_AND_V2, V1),
+            (Some(Version::V1), Some(Version::V1))
+        );
+        assert_eq!(
+            negotiate(V1_AND_V2, V1_AND_V2),
+            (Some(Version::V2), Some(Version::V2))
+        );
+    }
+}
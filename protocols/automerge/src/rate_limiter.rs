@@ -0,0 +1,102 @@
+use std::{collections::HashMap, time::Instant};
+
+use libp2p::PeerId;
+
+/// How many messages a peer may send us before the rest are dropped. Every message counts, sync
+/// messages as much as full documents or requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncRateLimit {
+    /// Messages per second a peer may keep sending
+    pub messages_per_sec: u32,
+    /// Messages a peer may send at once after being quiet for a while
+    pub burst: u32,
+}
+
+/// Whether a message gets through the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admission {
+    Allowed,
+    /// Over the limit, `first` for the first message dropped since the peer last got through
+    Dropped {
+        first: bool,
+    },
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    throttled: bool,
+}
+
+/// Token bucket per peer, refilled at the limit's rate up to its burst
+pub(crate) struct RateLimiter {
+    limit: SyncRateLimit,
+    buckets: HashMap<PeerId, Bucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: SyncRateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the peer's bucket for one message
+    pub(crate) fn admit(&mut self, peer: PeerId) -> Admission {
+        let now = Instant::now();
+        let burst = f64::from(self.limit.burst);
+        let bucket = self.buckets.entry(peer).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+            throttled: false,
+        });
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64()
+            * f64::from(self.limit.messages_per_sec);
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.throttled = false;
+            Admission::Allowed
+        } else {
+            let first = !bucket.throttled;
+            bucket.throttled = true;
+            Admission::Dropped { first }
+        }
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer: PeerId) {
+        self.buckets.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flooding_peer_does_not_hold_up_others() {
+        let mut limiter = RateLimiter::new(SyncRateLimit {
+            messages_per_sec: 1,
+            burst: 5,
+        });
+        let (flooding, normal) = (PeerId::random(), PeerId::random());
+
+        let admissions = (0..100)
+            .map(|_| limiter.admit(flooding))
+            .collect::<Vec<_>>();
+
+        assert!(admissions[..5].iter().all(|a| *a == Admission::Allowed));
+        assert_eq!(admissions[5], Admission::Dropped { first: true });
+        assert!(
+            admissions[6..]
+                .iter()
+                .all(|a| *a == Admission::Dropped { first: false })
+        );
+        for _ in 0..5 {
+            assert_eq!(limiter.admit(normal), Admission::Allowed);
+        }
+    }
+}
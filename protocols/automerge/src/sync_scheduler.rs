@@ -0,0 +1,205 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// Order in which queued syncs are started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncScheduling {
+    /// In the order they were requested
+    Fifo,
+    /// Taking turns between documents, so a document with many peers to sync with doesn't hold
+    /// up the others
+    RoundRobin,
+}
+
+/// Queue of syncs waiting for one of the limited sync slots
+pub(crate) struct SyncScheduler {
+    policy: SyncScheduling,
+    max_active: usize,
+    /// How long a running sync may hold its slot
+    timeout: Duration,
+    /// Running syncs and when they time out
+    active: HashMap<(PeerId, String), Instant>,
+    /// Queued syncs in request order, used by [`SyncScheduling::Fifo`]
+    queue: VecDeque<(PeerId, String)>,
+    /// Documents with queued syncs in turn order, used by [`SyncScheduling::RoundRobin`]
+    turns: VecDeque<String>,
+    /// Peers waiting for each document, used by [`SyncScheduling::RoundRobin`]
+    waiting: HashMap<String, VecDeque<PeerId>>,
+}
+
+impl SyncScheduler {
+    pub(crate) fn new(policy: SyncScheduling, max_active: usize, timeout: Duration) -> Self {
+        SyncScheduler {
+            policy,
+            max_active,
+            timeout,
+            active: HashMap::new(),
+            queue: VecDeque::new(),
+            turns: VecDeque::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// Queues a sync unless the same sync is already queued or running
+    pub(crate) fn enqueue(&mut self, peer: PeerId, document_id: &str) {
+        if self.active.contains_key(&(peer, document_id.to_string()))
+            || self.is_queued(peer, document_id)
+        {
+            return;
+        }
+
+        match self.policy {
+            SyncScheduling::Fifo => self.queue.push_back((peer, document_id.to_string())),
+            SyncScheduling::RoundRobin => {
+                let waiting = self.waiting.entry(document_id.to_string()).or_default();
+                if waiting.is_empty() {
+                    self.turns.push_back(document_id.to_string());
+                }
+                waiting.push_back(peer);
+            }
+        }
+    }
+
+    /// Takes the next queued sync if a slot is free, counting it as running until
+    /// [`Self::finish`] is called for it or it times out
+    pub(crate) fn start_next(&mut self) -> Option<(PeerId, String)> {
+        if self.active.len() >= self.max_active {
+            return None;
+        }
+
+        let next = match self.policy {
+            SyncScheduling::Fifo => self.queue.pop_front()?,
+            SyncScheduling::RoundRobin => {
+                let document_id = self.turns.pop_front()?;
+                let waiting = self.waiting.get_mut(&document_id)?;
+                let peer = waiting.pop_front()?;
+                if waiting.is_empty() {
+                    self.waiting.remove(&document_id);
+                } else {
+                    self.turns.push_back(document_id.clone());
+                }
+                (peer, document_id)
+            }
+        };
+        self.active
+            .insert(next.clone(), Instant::now() + self.timeout);
+        Some(next)
+    }
+
+    /// Frees the sync's slot, returns `false` if it wasn't running
+    pub(crate) fn finish(&mut self, peer: PeerId, document_id: &str) -> bool {
+        self.active
+            .remove(&(peer, document_id.to_string()))
+            .is_some()
+    }
+
+    /// When the first running sync times out
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.active.values().min().copied()
+    }
+
+    /// Frees the slots of the syncs that timed out by `now` and returns them
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<(PeerId, String)> {
+        self.active
+            .extract_if(|_, deadline| *deadline <= now)
+            .map(|(sync, _)| sync)
+            .collect()
+    }
+
+    /// Drops the peer's queued and running syncs, e.g. once it disconnected
+    pub(crate) fn remove_peer(&mut self, peer: PeerId) {
+        self.active.retain(|(active, _), _| *active != peer);
+        self.queue.retain(|(queued, _)| *queued != peer);
+        for waiting in self.waiting.values_mut() {
+            waiting.retain(|waiting| *waiting != peer);
+        }
+        self.waiting.retain(|_, waiting| !waiting.is_empty());
+        self.turns
+            .retain(|document_id| self.waiting.contains_key(document_id));
+    }
+
+    fn is_queued(&self, peer: PeerId, document_id: &str) -> bool {
+        match self.policy {
+            SyncScheduling::Fifo => self
+                .queue
+                .iter()
+                .any(|(queued, queued_document)| *queued == peer && queued_document == document_id),
+            SyncScheduling::RoundRobin => self
+                .waiting
+                .get(document_id)
+                .is_some_and(|waiting| waiting.contains(&peer)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn round_robin_makes_progress_on_every_document_under_saturation() {
+        let mut scheduler = SyncScheduler::new(SyncScheduling::RoundRobin, 1, TIMEOUT);
+        for _ in 0..5 {
+            scheduler.enqueue(PeerId::random(), "a");
+        }
+        scheduler.enqueue(PeerId::random(), "b");
+        scheduler.enqueue(PeerId::random(), "c");
+
+        let mut started = Vec::new();
+        while let Some((peer, document_id)) = scheduler.start_next() {
+            assert!(scheduler.start_next().is_none());
+            assert!(scheduler.finish(peer, &document_id));
+            started.push(document_id);
+        }
+
+        assert_eq!(started.len(), 7);
+        assert_eq!(&started[..3], ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn held_slots_block_further_syncs() {
+        let mut scheduler = SyncScheduler::new(SyncScheduling::Fifo, 2, TIMEOUT);
+        for document_id in ["a", "b", "c"] {
+            scheduler.enqueue(PeerId::random(), document_id);
+        }
+
+        let (peer, document_id) = scheduler.start_next().unwrap();
+        assert!(scheduler.start_next().is_some());
+        assert!(scheduler.start_next().is_none());
+
+        assert!(scheduler.finish(peer, &document_id));
+        assert!(!scheduler.finish(peer, &document_id));
+        assert_eq!(
+            scheduler.start_next().map(|(_, document_id)| document_id),
+            Some("c".to_string())
+        );
+    }
+
+    #[test]
+    fn timed_out_syncs_free_their_slot() {
+        let mut scheduler = SyncScheduler::new(SyncScheduling::Fifo, 1, TIMEOUT);
+        let peer = PeerId::random();
+        scheduler.enqueue(peer, "a");
+        scheduler.enqueue(peer, "b");
+        scheduler.start_next().unwrap();
+        let deadline = scheduler.next_deadline().unwrap();
+
+        assert!(
+            scheduler
+                .expire(deadline - Duration::from_secs(1))
+                .is_empty()
+        );
+        assert_eq!(scheduler.expire(deadline), [(peer, "a".to_string())]);
+        assert!(!scheduler.finish(peer, "a"));
+        assert_eq!(
+            scheduler.start_next().map(|(_, document_id)| document_id),
+            Some("b".to_string())
+        );
+    }
+}
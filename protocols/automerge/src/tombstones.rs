@@ -0,0 +1,34 @@
+//! Documents deleted on this node, kept in a JSON file in the data directory so a peer that
+//! still has a copy can't sync it back. Tombstones are never removed, a deleted id stays deleted.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const TOMBSTONES_FILE: &str = "tombstones.json";
+
+pub(crate) type Tombstones = BTreeSet<String>;
+
+pub(crate) fn tombstones_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(TOMBSTONES_FILE)
+}
+
+/// Ids of every deleted document, a missing file meaning none were deleted
+pub(crate) fn load(path: &Path) -> io::Result<Tombstones> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Tombstones::new()),
+        Err(err) => Err(err),
+    }
+}
+
+pub(crate) fn save(path: &Path, tombstones: &Tombstones) -> io::Result<()> {
+    let bytes = serde_json::to_vec_pretty(tombstones)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, path)
+}
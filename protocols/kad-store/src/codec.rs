@@ -0,0 +1,155 @@
+//! On-disk format of records and provider records.
+//!
+//! Integers are big endian, byte strings are a u32 length followed by the bytes. Expiry is stored
+//! as unix milliseconds, 0 meaning the record never expires, since the monotonic [`Instant`]
+//! kademlia works with doesn't survive a restart.
+//!
+//! A record file holds the key, the publisher (empty if unknown), the expiry and the value. A
+//! provider file holds the key and a u32 count of providers, each being the peer id, the expiry
+//! and a u32 count of addresses.
+
+use std::{
+    io,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{
+    Multiaddr, PeerId,
+    kad::{ProviderRecord, Record, RecordKey},
+};
+
+pub(crate) fn encode_record(record: &Record) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(record.value.len() + 64);
+    write_bytes(&mut bytes, record.key.as_ref());
+    write_bytes(
+        &mut bytes,
+        &record
+            .publisher
+            .map(|peer| peer.to_bytes())
+            .unwrap_or_default(),
+    );
+    bytes.extend_from_slice(&to_unix_millis(record.expires).to_be_bytes());
+    write_bytes(&mut bytes, &record.value);
+    bytes
+}
+
+pub(crate) fn decode_record(bytes: &[u8]) -> io::Result<Record> {
+    let mut reader = Reader(bytes);
+    let key = RecordKey::new(&reader.bytes()?);
+    let publisher = reader.bytes()?;
+    let publisher = if publisher.is_empty() {
+        None
+    } else {
+        Some(PeerId::from_bytes(&publisher).map_err(|_| invalid_data("invalid publisher"))?)
+    };
+    let expires = from_unix_millis(reader.u64()?);
+    let value = reader.bytes()?;
+    Ok(Record {
+        key,
+        value,
+        publisher,
+        expires,
+    })
+}
+
+pub(crate) fn encode_providers(key: &RecordKey, providers: &[ProviderRecord]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_bytes(&mut bytes, key.as_ref());
+    bytes.extend_from_slice(&(providers.len() as u32).to_be_bytes());
+    for provider in providers {
+        write_bytes(&mut bytes, &provider.provider.to_bytes());
+        bytes.extend_from_slice(&to_unix_millis(provider.expires).to_be_bytes());
+        bytes.extend_from_slice(&(provider.addresses.len() as u32).to_be_bytes());
+        for address in &provider.addresses {
+            write_bytes(&mut bytes, &address.to_vec());
+        }
+    }
+    bytes
+}
+
+pub(crate) fn decode_providers(bytes: &[u8]) -> io::Result<(RecordKey, Vec<ProviderRecord>)> {
+    let mut reader = Reader(bytes);
+    let key = RecordKey::new(&reader.bytes()?);
+    let count = reader.u32()?;
+    let mut providers = Vec::new();
+    for _ in 0..count {
+        let provider =
+            PeerId::from_bytes(&reader.bytes()?).map_err(|_| invalid_data("invalid provider"))?;
+        let expires = from_unix_millis(reader.u64()?);
+        let address_count = reader.u32()?;
+        let mut addresses = Vec::new();
+        for _ in 0..address_count {
+            addresses.push(
+                Multiaddr::try_from(reader.bytes()?)
+                    .map_err(|_| invalid_data("invalid provider address"))?,
+            );
+        }
+        providers.push(ProviderRecord {
+            key: key.clone(),
+            provider,
+            expires,
+            addresses,
+        });
+    }
+    Ok((key, providers))
+}
+
+fn to_unix_millis(expires: Option<Instant>) -> u64 {
+    let Some(expires) = expires else {
+        return 0;
+    };
+
+    let remaining = expires.saturating_duration_since(Instant::now());
+    (SystemTime::now() + remaining)
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+        .max(1)
+}
+
+fn from_unix_millis(millis: u64) -> Option<Instant> {
+    if millis == 0 {
+        return None;
+    }
+
+    let expires = UNIX_EPOCH + Duration::from_millis(millis);
+    let remaining = expires
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Some(Instant::now() + remaining)
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, length: usize) -> io::Result<&[u8]> {
+        if self.0.len() < length {
+            return Err(invalid_data("unexpected end of file"));
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,490 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use libp2p::{
+    PeerId,
+    kad::{
+        K_VALUE, ProviderRecord, Record, RecordKey,
+        store::{Error, RecordStore, Result},
+    },
+};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::codec;
+
+const RECORDS_DIR: &str = "records";
+const PROVIDERS_DIR: &str = "providers";
+/// Longest key whose hex is used as file name as is, file names are limited to 255 bytes
+const MAX_PLAIN_KEY_BYTES: usize = 127;
+/// Prefix of the file names of longer keys, which are named after the key's hash instead. It
+/// isn't hex, so it can't collide with the name of a short key.
+const HASHED_KEY_PREFIX: &str = "sha256-";
+
+/// Why a [`DiskStore`] couldn't store a record
+#[derive(Debug)]
+pub enum PutError {
+    /// One of the store's limits was reached
+    Limit(Error),
+    /// Writing the record failed
+    Io(io::Error),
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PutError::Limit(err) => write!(f, "{err}"),
+            PutError::Io(err) => write!(f, "failed writing the record: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for PutError {}
+
+/// Limits of a [`DiskStore`], matching those of kademlia's `MemoryStoreConfig`
+#[derive(Debug, Clone)]
+pub struct DiskStoreConfig {
+    pub max_records: usize,
+    /// Largest record value accepted, in bytes
+    pub max_value_bytes: usize,
+    pub max_providers_per_key: usize,
+    /// Keys this node itself provides
+    pub max_provided_keys: usize,
+}
+
+impl Default for DiskStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 1024,
+            max_value_bytes: 65 * 1024,
+            max_providers_per_key: K_VALUE.get(),
+            max_provided_keys: 1024,
+        }
+    }
+}
+
+/// Record store keeping every record in its own file, so records survive restarts and only their
+/// keys are held in memory. Provider records are small and read on every republish, so they are
+/// kept in memory and written through to disk.
+///
+/// Expired records and providers are never returned. They are pruned when the store is opened,
+/// and expired records also once the store is full.
+pub struct DiskStore {
+    local_id: PeerId,
+    config: DiskStoreConfig,
+    records_dir: PathBuf,
+    providers_dir: PathBuf,
+    records: HashSet<RecordKey>,
+    providers: HashMap<RecordKey, Vec<ProviderRecord>>,
+    /// Provider records of the local node
+    provided: HashSet<ProviderRecord>,
+}
+
+impl DiskStore {
+    pub fn open(local_id: PeerId, path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_config(local_id, path, DiskStoreConfig::default())
+    }
+
+    /// Opens the store at `path`, loading the records and providers a previous run left there
+    pub fn with_config(
+        local_id: PeerId,
+        path: impl AsRef<Path>,
+        config: DiskStoreConfig,
+    ) -> io::Result<Self> {
+        let records_dir = path.as_ref().join(RECORDS_DIR);
+        let providers_dir = path.as_ref().join(PROVIDERS_DIR);
+        fs::create_dir_all(&records_dir)?;
+        fs::create_dir_all(&providers_dir)?;
+
+        let mut store = DiskStore {
+            local_id,
+            config,
+            records_dir,
+            providers_dir,
+            records: HashSet::new(),
+            providers: HashMap::new(),
+            provided: HashSet::new(),
+        };
+        store.load()?;
+        Ok(store)
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.records_dir)? {
+            let path = entry?.path();
+            // leftovers of writes interrupted by a crash
+            if path.extension().is_some() {
+                continue;
+            }
+            match fs::read(&path).and_then(|bytes| codec::decode_record(&bytes)) {
+                Ok(record) if record.is_expired(Instant::now()) => {
+                    if let Err(err) = remove_file(&path) {
+                        warn!("Failed removing expired record {}: {}", path.display(), err);
+                    }
+                }
+                Ok(record) => {
+                    self.records.insert(record.key);
+                }
+                Err(err) => warn!("Skipping unreadable record {}: {}", path.display(), err),
+            }
+        }
+
+        for entry in fs::read_dir(&self.providers_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some() {
+                continue;
+            }
+            match fs::read(&path).and_then(|bytes| codec::decode_providers(&bytes)) {
+                Ok((key, mut providers)) => {
+                    let now = Instant::now();
+                    providers.retain(|record| !record.is_expired(now));
+                    if providers.is_empty() {
+                        if let Err(err) = remove_file(&path) {
+                            warn!(
+                                "Failed removing expired providers {}: {}",
+                                path.display(),
+                                err
+                            );
+                        }
+                        continue;
+                    }
+                    self.provided.extend(
+                        providers
+                            .iter()
+                            .filter(|record| record.provider == self.local_id)
+                            .cloned(),
+                    );
+                    self.providers.insert(key, providers);
+                }
+                Err(err) => warn!("Skipping unreadable providers {}: {}", path.display(), err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`RecordStore::put`], telling a failed write apart from the store being full
+    pub fn try_put(&mut self, record: Record) -> std::result::Result<(), PutError> {
+        if record.value.len() >= self.config.max_value_bytes {
+            return Err(PutError::Limit(Error::ValueTooLarge));
+        }
+
+        if !self.records.contains(&record.key) && self.records.len() >= self.config.max_records {
+            self.prune_expired_records();
+            if self.records.len() >= self.config.max_records {
+                return Err(PutError::Limit(Error::MaxRecords));
+            }
+        }
+
+        write_atomic(
+            &self.record_path(&record.key),
+            &codec::encode_record(&record),
+        )
+        .map_err(PutError::Io)?;
+        self.records.insert(record.key);
+        Ok(())
+    }
+
+    fn record_path(&self, key: &RecordKey) -> PathBuf {
+        self.records_dir.join(file_name(key))
+    }
+
+    fn providers_path(&self, key: &RecordKey) -> PathBuf {
+        self.providers_dir.join(file_name(key))
+    }
+
+    fn read_record(&self, key: &RecordKey) -> Option<Record> {
+        match fs::read(self.record_path(key)).and_then(|bytes| codec::decode_record(&bytes)) {
+            Ok(record) => Some(record),
+            Err(err) => {
+                warn!("Failed reading record {:?}: {}", key, err);
+                None
+            }
+        }
+    }
+
+    /// Removes every expired record, which reads all of them
+    fn prune_expired_records(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .records
+            .iter()
+            .filter(|key| {
+                self.read_record(key)
+                    .is_none_or(|record| record.is_expired(now))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    /// Writes the providers of a key, removing the file once none are left
+    fn write_providers(&self, key: &RecordKey) {
+        let path = self.providers_path(key);
+        let result = match self.providers.get(key) {
+            Some(providers) => write_atomic(&path, &codec::encode_providers(key, providers)),
+            None => remove_file(&path),
+        };
+        if let Err(err) = result {
+            warn!("Failed writing providers of {:?}: {}", key, err);
+        }
+    }
+}
+
+impl RecordStore for DiskStore {
+    type RecordsIter<'a> = Box<dyn Iterator<Item = Cow<'a, Record>> + 'a>;
+    type ProvidedIter<'a> = Box<dyn Iterator<Item = Cow<'a, ProviderRecord>> + 'a>;
+
+    fn get(&self, key: &RecordKey) -> Option<Cow<'_, Record>> {
+        if !self.records.contains(key) {
+            return None;
+        }
+        self.read_record(key)
+            .filter(|record| !record.is_expired(Instant::now()))
+            .map(Cow::Owned)
+    }
+
+    fn put(&mut self, record: Record) -> Result<()> {
+        let key = record.key.clone();
+        self.try_put(record).map_err(|err| match err {
+            PutError::Limit(err) => err,
+            // kademlia's store errors can't carry an io error, use `try_put` to get it
+            PutError::Io(err) => {
+                warn!("Failed writing record {:?}: {}", key, err);
+                Error::MaxRecords
+            }
+        })
+    }
+
+    fn remove(&mut self, key: &RecordKey) {
+        if self.records.remove(key)
+            && let Err(err) = remove_file(&self.record_path(key))
+        {
+            warn!("Failed removing record {:?}: {}", key, err);
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        let now = Instant::now();
+        Box::new(
+            self.records
+                .iter()
+                .filter_map(|key| self.read_record(key))
+                .filter(move |record| !record.is_expired(now))
+                .map(Cow::Owned),
+        )
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        if !self.providers.contains_key(&record.key)
+            && self.providers.len() >= self.config.max_provided_keys
+        {
+            return Err(Error::MaxProvidedKeys);
+        }
+
+        let key = record.key.clone();
+        let providers = self.providers.entry(key.clone()).or_default();
+        if let Some(existing) = providers
+            .iter_mut()
+            .find(|existing| existing.provider == record.provider)
+        {
+            if record.provider == self.local_id {
+                self.provided.remove(existing);
+                self.provided.insert(record.clone());
+            }
+            *existing = record;
+        } else if providers.len() < self.config.max_providers_per_key {
+            if record.provider == self.local_id {
+                self.provided.insert(record.clone());
+            }
+            providers.push(record);
+        } else {
+            return Ok(());
+        }
+
+        self.write_providers(&key);
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        let now = Instant::now();
+        self.providers
+            .get(key)
+            .map(|providers| {
+                providers
+                    .iter()
+                    .filter(|record| !record.is_expired(now))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        Box::new(self.provided.iter().map(Cow::Borrowed))
+    }
+
+    fn remove_provider(&mut self, key: &RecordKey, provider: &PeerId) {
+        let Some(providers) = self.providers.get_mut(key) else {
+            return;
+        };
+        let Some(index) = providers
+            .iter()
+            .position(|record| &record.provider == provider)
+        else {
+            return;
+        };
+
+        let record = providers.remove(index);
+        if record.provider == self.local_id {
+            self.provided.remove(&record);
+        }
+        if providers.is_empty() {
+            self.providers.remove(key);
+        }
+        self.write_providers(key);
+    }
+}
+
+/// Hex of the key, keys are arbitrary bytes and not safe to use as file names. Keys too long for
+/// a file name are hashed first, the files hold the full key.
+fn file_name(key: &RecordKey) -> String {
+    if key.as_ref().len() > MAX_PLAIN_KEY_BYTES {
+        return format!("{HASHED_KEY_PREFIX}{}", hex(&Sha256::digest(key.as_ref())));
+    }
+    hex(key.as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Writes through a temporary file so a crash never leaves a half written record behind
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, path)
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::Multiaddr;
+
+    use super::*;
+
+    /// An empty store directory of its own for each test
+    fn store_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libp2p-kad-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn records_are_put_read_and_removed() {
+        let dir = store_dir("records");
+        let local_id = PeerId::random();
+        let key = RecordKey::new(&"document");
+        let mut store = DiskStore::open(local_id, &dir).unwrap();
+
+        store
+            .put(Record::new(key.clone(), b"value".to_vec()))
+            .unwrap();
+        assert_eq!(store.get(&key).unwrap().value, b"value");
+        assert_eq!(store.records().count(), 1);
+
+        let mut reopened = DiskStore::open(local_id, &dir).unwrap();
+        assert_eq!(reopened.get(&key).unwrap().value, b"value");
+
+        reopened.remove(&key);
+        assert!(reopened.get(&key).is_none());
+        assert!(DiskStore::open(local_id, &dir).unwrap().get(&key).is_none());
+    }
+
+    #[test]
+    fn long_keys_are_stored_under_their_hash() {
+        let dir = store_dir("long-keys");
+        let local_id = PeerId::random();
+        let key = RecordKey::new(&vec![7u8; 300]);
+        let mut store = DiskStore::open(local_id, &dir).unwrap();
+
+        store
+            .put(Record::new(key.clone(), b"value".to_vec()))
+            .unwrap();
+
+        let name = file_name(&key);
+        assert!(name.starts_with(HASHED_KEY_PREFIX));
+        assert!(name.len() < 255);
+        let reopened = DiskStore::open(local_id, &dir).unwrap();
+        assert_eq!(reopened.get(&key).unwrap().key, key);
+    }
+
+    #[test]
+    fn failed_writes_are_io_errors() {
+        let dir = store_dir("failed-writes");
+        let mut store = DiskStore::open(PeerId::random(), &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let result = store.try_put(Record::new(RecordKey::new(&"document"), b"value".to_vec()));
+
+        assert!(matches!(result, Err(PutError::Io(_))));
+    }
+
+    #[test]
+    fn providers_are_added_and_removed() {
+        let dir = store_dir("providers");
+        let local_id = PeerId::random();
+        let remote_id = PeerId::random();
+        let key = RecordKey::new(&"document");
+        let address = "/ip4/127.0.0.1/tcp/4001".parse::<Multiaddr>().unwrap();
+        let mut store = DiskStore::open(local_id, &dir).unwrap();
+
+        store
+            .add_provider(ProviderRecord::new(key.clone(), local_id, vec![]))
+            .unwrap();
+        store
+            .add_provider(ProviderRecord::new(
+                key.clone(),
+                remote_id,
+                vec![address.clone()],
+            ))
+            .unwrap();
+        assert_eq!(store.providers(&key).len(), 2);
+        assert_eq!(store.provided().count(), 1);
+
+        let mut reopened = DiskStore::open(local_id, &dir).unwrap();
+        let providers = reopened.providers(&key);
+        assert_eq!(providers.len(), 2);
+        assert!(
+            providers
+                .iter()
+                .any(|record| record.provider == remote_id && record.addresses == [address.clone()])
+        );
+        assert_eq!(reopened.provided().count(), 1);
+
+        reopened.remove_provider(&key, &local_id);
+        assert_eq!(reopened.provided().count(), 0);
+        reopened.remove_provider(&key, &remote_id);
+        assert!(reopened.providers(&key).is_empty());
+        assert!(
+            DiskStore::open(local_id, &dir)
+                .unwrap()
+                .providers(&key)
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Kademlia record stores beyond the in-memory one libp2p ships with.
+
+mod codec;
+mod disk;
+
+use std::borrow::Cow;
+
+use either::Either;
+use libp2p::{
+    PeerId,
+    kad::{
+        ProviderRecord, Record, RecordKey,
+        store::{MemoryStore, RecordStore, Result},
+    },
+};
+
+pub use disk::{DiskStore, DiskStoreConfig, PutError};
+
+/// Either record store, so the kind of store can be picked at runtime
+pub enum Store {
+    Memory(MemoryStore),
+    Disk(DiskStore),
+}
+
+impl RecordStore for Store {
+    type RecordsIter<'a> = Either<
+        <MemoryStore as RecordStore>::RecordsIter<'a>,
+        <DiskStore as RecordStore>::RecordsIter<'a>,
+    >;
+    type ProvidedIter<'a> = Either<
+        <MemoryStore as RecordStore>::ProvidedIter<'a>,
+        <DiskStore as RecordStore>::ProvidedIter<'a>,
+    >;
+
+    fn get(&self, key: &RecordKey) -> Option<Cow<'_, Record>> {
+        match self {
+            Store::Memory(store) => store.get(key),
+            Store::Disk(store) => store.get(key),
+        }
+    }
+
+    fn put(&mut self, record: Record) -> Result<()> {
+        match self {
+            Store::Memory(store) => store.put(record),
+            Store::Disk(store) => store.put(record),
+        }
+    }
+
+    fn remove(&mut self, key: &RecordKey) {
+        match self {
+            Store::Memory(store) => store.remove(key),
+            Store::Disk(store) => store.remove(key),
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        match self {
+            Store::Memory(store) => Either::Left(store.records()),
+            Store::Disk(store) => Either::Right(store.records()),
+        }
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        match self {
+            Store::Memory(store) => store.add_provider(record),
+            Store::Disk(store) => store.add_provider(record),
+        }
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        match self {
+            Store::Memory(store) => store.providers(key),
+            Store::Disk(store) => store.providers(key),
+        }
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        match self {
+            Store::Memory(store) => Either::Left(store.provided()),
+            Store::Disk(store) => Either::Right(store.provided()),
+        }
+    }
+
+    fn remove_provider(&mut self, key: &RecordKey, provider: &PeerId) {
+        match self {
+            Store::Memory(store) => store.remove_provider(key, provider),
+            Store::Disk(store) => store.remove_provider(key, provider),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// Error message the relay server closes a circuit with once it carried more than
+/// `max_circuit_bytes`. The relay doesn't report running byte counts, this is the only sign of
+/// a circuit running into the limit. libp2p doesn't expose it as an error kind of its own, so
+/// this copies the string from libp2p-relay's `copy_future.rs`, last checked against
+/// libp2p-relay 0.18.0. Check it again when upgrading libp2p, a changed message only shows up as
+/// byte limit closes counted as errors.
+const BYTE_LIMIT_ERROR: &str = "Max circuit bytes reached.";
+
+/// Why a relayed circuit closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Both ends were done with it
+    Done,
+    /// It carried more than the byte limit
+    ByteLimit,
+    /// It failed with any other error
+    Error,
+}
+
+impl CloseReason {
+    /// Reason for the error a circuit closed with. The only place matching on
+    /// [`BYTE_LIMIT_ERROR`].
+    pub fn of(error: Option<&std::io::Error>) -> Self {
+        match error {
+            None => CloseReason::Done,
+            Some(error) if error.to_string() == BYTE_LIMIT_ERROR => CloseReason::ByteLimit,
+            Some(_) => CloseReason::Error,
+        }
+    }
+}
+
+/// When the open circuits were accepted, by source and destination, so a closed circuit can be
+/// logged with how long it lasted
+#[derive(Default)]
+pub struct OpenCircuits {
+    accepted: HashMap<(PeerId, PeerId), Vec<Instant>>,
+}
+
+impl OpenCircuits {
+    pub fn accepted(&mut self, src_peer_id: PeerId, dst_peer_id: PeerId) {
+        self.accepted
+            .entry((src_peer_id, dst_peer_id))
+            .or_default()
+            .push(Instant::now());
+    }
+
+    /// How long the oldest open circuit between the peers lasted, taking it as the one that
+    /// closed
+    pub fn closed(&mut self, src_peer_id: PeerId, dst_peer_id: PeerId) -> Option<Duration> {
+        let key = (src_peer_id, dst_peer_id);
+        let accepted = self.accepted.get_mut(&key)?;
+        let accepted_at = accepted.remove(0);
+        if accepted.is_empty() {
+            self.accepted.remove(&key);
+        }
+        Some(accepted_at.elapsed())
+    }
+
+    pub fn len(&self) -> usize {
+        self.accepted.values().map(Vec::len).sum()
+    }
+}
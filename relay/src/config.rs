@@ -0,0 +1,268 @@
+use std::{num::NonZeroU32, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use libp2p::{identity, relay};
+use serde::{Deserialize, Serialize};
+
+pub use common::Transports;
+
+const CONFIG_DIR_NAME: &str = "chippy";
+const CONFIG_FILE_NAME: &str = "Relay.toml";
+const KEY_FILE_NAME: &str = "relay_key";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayAppConfig {
+    /// Port listened on for TCP and QUIC on all interfaces
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Transports listened on, peers behind networks blocking the other one can't reach us
+    #[serde(default)]
+    pub transports: Transports,
+    /// Listen on the IPv6 instead of the IPv4 interfaces
+    #[serde(default)]
+    pub use_ipv6: bool,
+    /// Pre-shared key for Noise, peers with a different key fail the handshake
+    #[serde(default)]
+    pub pre_shared_key: String,
+    /// Protobuf encoded keypair of the relay, generated on the first start. A new identity is
+    /// generated on every start when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file_path: Option<PathBuf>,
+    /// Directory to keep DHT records in so they survive restarts, held in memory when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_store_dir: Option<PathBuf>,
+    /// Port to serve Prometheus metrics on over HTTP, not served when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_port: Option<u16>,
+    /// Circuits a client may abandon right after opening them before its reservations and
+    /// circuits are refused
+    #[serde(default = "default_abuse_threshold")]
+    pub abuse_threshold: u32,
+    #[serde(default)]
+    pub limits: RelayLimits,
+}
+
+/// Rate limits of the relay server, every rate counted over `rate_interval_secs`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayLimits {
+    #[serde(default = "default_rate_interval_secs")]
+    pub rate_interval_secs: u64,
+    #[serde(default = "default_reservations_per_peer")]
+    pub reservations_per_peer: u32,
+    #[serde(default = "default_reservations_per_ip")]
+    pub reservations_per_ip: u32,
+    #[serde(default = "default_circuits_per_peer")]
+    pub circuits_per_peer: u32,
+    #[serde(default = "default_circuits_per_ip")]
+    pub circuits_per_ip: u32,
+    /// Bytes relayed per circuit before it's closed. There is no early warning as a circuit
+    /// nears the limit, the relay doesn't report byte counts until it closes the circuit.
+    #[serde(default = "default_max_circuit_bytes")]
+    pub max_circuit_bytes: u64,
+}
+
+fn default_port() -> u16 {
+    4001
+}
+
+fn default_abuse_threshold() -> u32 {
+    10
+}
+
+fn default_rate_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_reservations_per_peer() -> u32 {
+    60
+}
+
+fn default_reservations_per_ip() -> u32 {
+    1000
+}
+
+fn default_circuits_per_peer() -> u32 {
+    500
+}
+
+fn default_circuits_per_ip() -> u32 {
+    1000
+}
+
+fn default_max_circuit_bytes() -> u64 {
+    5 * 1024 * 1024 * 1024 // 5 gibibyte
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self {
+            rate_interval_secs: default_rate_interval_secs(),
+            reservations_per_peer: default_reservations_per_peer(),
+            reservations_per_ip: default_reservations_per_ip(),
+            circuits_per_peer: default_circuits_per_peer(),
+            circuits_per_ip: default_circuits_per_ip(),
+            max_circuit_bytes: default_max_circuit_bytes(),
+        }
+    }
+}
+
+impl RelayLimits {
+    /// Relay server config with these limits, the rates already checked by
+    /// [`RelayAppConfig::validate`]
+    pub fn relay_config(&self) -> relay::Config {
+        let interval = Duration::from_secs(self.rate_interval_secs);
+        let rate = |limit: u32| NonZeroU32::new(limit).expect("validated to be non-zero");
+        let mut config = relay::Config::default()
+            .reservation_rate_per_peer(rate(self.reservations_per_peer), interval)
+            .reservation_rate_per_ip(rate(self.reservations_per_ip), interval)
+            .circuit_src_per_ip(rate(self.circuits_per_ip), interval)
+            .circuit_src_per_peer(rate(self.circuits_per_peer), interval);
+        config.max_circuit_bytes = self.max_circuit_bytes;
+        config
+    }
+}
+
+impl Default for RelayAppConfig {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            transports: Transports::default(),
+            use_ipv6: false,
+            pre_shared_key: "".to_string(),
+            key_file_path: Some(
+                dirs::config_dir()
+                    .unwrap()
+                    .join(CONFIG_DIR_NAME)
+                    .join(KEY_FILE_NAME),
+            ),
+            record_store_dir: None,
+            metrics_port: None,
+            abuse_threshold: default_abuse_threshold(),
+            limits: RelayLimits::default(),
+        }
+    }
+}
+
+impl RelayAppConfig {
+    pub fn default_config_location() -> String {
+        let home_dir = dirs::config_dir().expect("Could not find config directory");
+        let config_dir = home_dir.join(CONFIG_DIR_NAME);
+        std::fs::create_dir_all(&config_dir).expect("Could not create config directory");
+        config_dir
+            .join(CONFIG_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    pub fn load(path: Option<String>) -> Result<Self> {
+        let path = path.unwrap_or_else(Self::default_config_location);
+        let config_data = std::fs::read_to_string(path)?;
+        let config: RelayAppConfig = toml::from_str(&config_data)?;
+        Ok(config)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let config_data = toml::to_string(self)?;
+        std::fs::write(Self::default_config_location(), config_data)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.pre_shared_key.is_empty() {
+            anyhow::bail!(
+                "Failed loading config at {}: Pre-shared key cannot be empty",
+                Self::default_config_location()
+            );
+        }
+
+        let limits = &self.limits;
+        if limits.rate_interval_secs == 0
+            || limits.reservations_per_peer == 0
+            || limits.reservations_per_ip == 0
+            || limits.circuits_per_peer == 0
+            || limits.circuits_per_ip == 0
+        {
+            anyhow::bail!(
+                "Failed loading config at {}: Relay rate limits must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        if self.abuse_threshold == 0 {
+            anyhow::bail!(
+                "Failed loading config at {}: Abuse threshold must be greater than zero",
+                Self::default_config_location()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Keypair from the key file, generating the file first if it doesn't exist yet
+    pub fn load_keypair(&self) -> Result<identity::Keypair> {
+        let Some(path) = &self.key_file_path else {
+            return Ok(identity::Keypair::generate_ed25519());
+        };
+
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let keypair = identity::Keypair::generate_ed25519();
+            write_key_file(path, &keypair.to_protobuf_encoding()?)?;
+            tracing::info!("Generated a new relay identity in {}", path.display());
+            return Ok(keypair);
+        }
+
+        restrict_key_permissions(path)?;
+        let bytes = std::fs::read(path)?;
+        Ok(identity::Keypair::from_protobuf_encoding(&bytes)?)
+    }
+}
+
+/// Restricts the key file to the owner when other users can read it
+#[cfg(unix)]
+fn restrict_key_permissions(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        tracing::warn!(
+            "Key file {} has permissions {:o} and is readable by other users, changing them to 600",
+            path.display(),
+            mode & 0o777
+        );
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_key_permissions(_path: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Writes the key file readable and writable by the owner only, restricting an existing file
+/// before the key is written to it
+#[cfg(unix)]
+fn write_key_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::{
+        io::Write,
+        os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    };
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_key_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
@@ -1,31 +1,77 @@
 use std::{
     error::Error,
     net::{Ipv4Addr, Ipv6Addr},
-    num::NonZeroU32,
+    path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
 
 use clap::Parser;
+use common::{generate_ed25519_from_seed, string_to_32_bytes};
 use futures::StreamExt;
 use libp2p::{
-    autonat,
-    core::{Multiaddr, multiaddr::Protocol},
+    Transport, autonat,
+    core::{Multiaddr, multiaddr::Protocol, transport::OptionalTransport, upgrade},
     identify, identity,
     kad::{self, store::MemoryStore},
-    noise, ping, relay,
+    metrics::{Metrics, Recorder, Registry},
+    noise, ping, quic, relay,
     swarm::{NetworkBehaviour, SwarmEvent},
     tcp, yamux,
 };
+use libp2p_kad_store::{DiskStore, Store};
 use rand::rngs::OsRng;
-use sha2::{Digest, Sha256};
 use tracing_subscriber::EnvFilter;
 
-/// Hashes a string to a [u8; 32] key using SHA-256.
-fn string_to_32_bytes(s: &str) -> [u8; 32] {
-    let hash = Sha256::digest(s.as_bytes());
-    let mut arr = [0u8; 32];
-    arr.copy_from_slice(&hash[..]);
-    arr
+use crate::{
+    circuits::{CloseReason, OpenCircuits},
+    config::RelayAppConfig,
+    metrics::RelayMetrics,
+    scoring::{PeerScores, SCORE_DECAY_INTERVAL, ScoreLimiter},
+};
+
+mod circuits;
+mod config;
+mod metrics;
+mod scoring;
+
+/// Loads the config with the command line flags taking precedence over the file. Without a
+/// config file a default one is created to edit, and the flags alone have to make a valid
+/// config.
+fn get_config_or_default(opts: &Opt) -> Result<RelayAppConfig, Box<dyn Error>> {
+    let mut config = match RelayAppConfig::load(opts.config.clone()) {
+        Ok(config) => config,
+        Err(err) if opts.config.is_some() => return Err(err.into()),
+        Err(_) => {
+            RelayAppConfig::default().save()?;
+            tracing::info!(
+                "No valid config found. A default config has been created at {}",
+                RelayAppConfig::default_config_location()
+            );
+            RelayAppConfig::default()
+        }
+    };
+
+    if let Some(port) = opts.port {
+        config.port = port;
+    }
+    if let Some(use_ipv6) = opts.use_ipv6 {
+        config.use_ipv6 = use_ipv6;
+    }
+    if let Some(key) = &opts.key {
+        config.pre_shared_key = key.clone();
+    }
+    if let Some(record_store) = &opts.record_store {
+        config.record_store_dir = Some(record_store.clone());
+    }
+    if let Some(metrics_port) = opts.metrics_port {
+        config.metrics_port = Some(metrics_port);
+    }
+    if let Some(abuse_threshold) = opts.abuse_threshold {
+        config.abuse_threshold = abuse_threshold;
+    }
+    config.validate()?;
+    Ok(config)
 }
 
 #[tokio::main]
@@ -40,42 +86,65 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .try_init();
 
     let opts = Opt::parse();
+    let config = get_config_or_default(&opts).unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(1);
+    });
 
     let local_key = if let Some(seed) = opts.secret_key_seed {
         generate_ed25519_from_seed(seed)
     } else {
-        generate_ed25519()
+        config.load_keypair()?
     };
 
-    let mut kademlia = libp2p::kad::Behaviour::new(
-        local_key.public().to_peer_id(),
-        MemoryStore::new(local_key.public().to_peer_id()),
-    );
+    let record_store = match &config.record_store_dir {
+        Some(path) => Store::Disk(DiskStore::open(local_key.public().to_peer_id(), path)?),
+        None => Store::Memory(MemoryStore::new(local_key.public().to_peer_id())),
+    };
+    let mut kademlia = libp2p::kad::Behaviour::new(local_key.public().to_peer_id(), record_store);
     kademlia.set_mode(Some(kad::Mode::Server));
 
+    let pre_shared_key = config.pre_shared_key.clone();
     let noise_config_with_prologue =
-        |keypair: &identity::Keypair| -> Result<noise::Config, std::io::Error> {
+        move |keypair: &identity::Keypair| -> Result<noise::Config, std::io::Error> {
             let mut config = noise::Config::new(keypair).expect("Noise key generation failed");
-            config = config.with_prologue(string_to_32_bytes(&opts.key).to_vec());
+            config = config.with_prologue(string_to_32_bytes(&pre_shared_key).to_vec());
             Ok(config)
         };
 
-    let mut relay_config = relay::Config::default()
-        .reservation_rate_per_peer(NonZeroU32::new(60).unwrap(), Duration::from_secs(60 * 60))
-        .reservation_rate_per_ip(NonZeroU32::new(1000).unwrap(), Duration::from_secs(60 * 60))
-        .circuit_src_per_ip(NonZeroU32::new(1000).unwrap(), Duration::from_secs(60 * 60))
-        .circuit_src_per_peer(NonZeroU32::new(500).unwrap(), Duration::from_secs(60 * 60));
-
-    relay_config.max_circuit_bytes = 5 * 1024 * 1024 * 1024; // 5 gibibyte
+    let mut relay_config = config.limits.relay_config();
+    let peer_scores = PeerScores::new(config.abuse_threshold);
+    relay_config
+        .reservation_rate_limiters
+        .push(Box::new(ScoreLimiter(peer_scores.clone())));
+    relay_config
+        .circuit_src_rate_limiters
+        .push(Box::new(ScoreLimiter(peer_scores.clone())));
 
+    // recorded either way, only served with --metrics-port
+    let mut registry = Registry::default();
+    // a disabled transport refuses every address, so nothing is listened on or dialed over it
+    let transports = config.transports;
     let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key.clone())
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise_config_with_prologue,
-            yamux::Config::default,
-        )?
-        .with_quic()
+        .with_other_transport(|key| -> Result<_, Box<dyn Error + Send + Sync>> {
+            if !transports.tcp() {
+                return Ok(OptionalTransport::none());
+            }
+            Ok(OptionalTransport::some(
+                tcp::tokio::Transport::new(tcp::Config::default())
+                    .upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(noise_config_with_prologue(key)?)
+                    .multiplex(yamux::Config::default()),
+            ))
+        })?
+        .with_other_transport(|key| {
+            if !transports.quic() {
+                return OptionalTransport::none();
+            }
+            OptionalTransport::some(quic::tokio::Transport::new(quic::Config::new(key)))
+        })?
+        .with_bandwidth_metrics(&mut registry)
         .with_behaviour(|key| Behaviour {
             relay: relay::Behaviour::new(key.public().to_peer_id(), relay_config),
             ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(20))),
@@ -90,23 +159,38 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
 
+    let metrics = Metrics::new(&mut registry);
+    let relay_metrics = RelayMetrics::new(&mut registry);
+    if let Some(port) = config.metrics_port {
+        let registry = Arc::new(registry);
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(port, registry).await {
+                tracing::error!("Metrics server failed: {err}");
+            }
+        });
+    }
+
     // Listen on all interfaces
-    let listen_addr_tcp = Multiaddr::empty()
-        .with(match opts.use_ipv6 {
-            Some(true) => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
-        })
-        .with(Protocol::Tcp(opts.port));
-    swarm.listen_on(listen_addr_tcp.clone())?;
-
-    let listen_addr_quic = Multiaddr::empty()
-        .with(match opts.use_ipv6 {
-            Some(true) => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
-        })
-        .with(Protocol::Udp(opts.port))
-        .with(Protocol::QuicV1);
-    swarm.listen_on(listen_addr_quic)?;
+    if transports.tcp() {
+        let listen_addr_tcp = Multiaddr::empty()
+            .with(match config.use_ipv6 {
+                true => Protocol::from(Ipv6Addr::UNSPECIFIED),
+                false => Protocol::from(Ipv4Addr::UNSPECIFIED),
+            })
+            .with(Protocol::Tcp(config.port));
+        swarm.listen_on(listen_addr_tcp)?;
+    }
+
+    if transports.quic() {
+        let listen_addr_quic = Multiaddr::empty()
+            .with(match config.use_ipv6 {
+                true => Protocol::from(Ipv6Addr::UNSPECIFIED),
+                false => Protocol::from(Ipv4Addr::UNSPECIFIED),
+            })
+            .with(Protocol::Udp(config.port))
+            .with(Protocol::QuicV1);
+        swarm.listen_on(listen_addr_quic)?;
+    }
 
     swarm
         .behaviour_mut()
@@ -114,8 +198,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .start_providing(local_key.clone().public().to_peer_id().to_bytes().into())
         .expect("failed to start providing as kademlia relay");
 
+    let max_circuit_bytes = config.limits.max_circuit_bytes;
+    let mut open_circuits = OpenCircuits::default();
+    let mut score_decay = tokio::time::interval(SCORE_DECAY_INTERVAL);
     loop {
-        match swarm.next().await.expect("Infinite Stream.") {
+        let event = tokio::select! {
+            event = swarm.next() => event.expect("Infinite Stream."),
+            _ = score_decay.tick() => {
+                let decayed = peer_scores.lock().expect("peer scores lock poisoned").decay();
+                for (peer_id, score) in decayed {
+                    relay_metrics.peer_score(peer_id, score);
+                }
+                continue;
+            }
+        };
+        metrics.record(&event);
+        match &event {
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => metrics.record(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => metrics.record(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(event)) => metrics.record(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(event)) => metrics.record(event),
+            _ => {}
+        }
+
+        match event {
             SwarmEvent::NewListenAddr { address, .. } => {
                 println!("Listening on {address:?}");
             }
@@ -126,6 +232,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 ..
             })) => {
                 let success = result.is_ok();
+                relay_metrics.autonat_test(success);
                 tracing::info!(%tested_addr, %client, success, "AutoNAT test completed");
             }
             SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
@@ -150,8 +257,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
             SwarmEvent::Behaviour(BehaviourEvent::Relay(
                 relay::Event::ReservationReqAccepted { src_peer_id, .. },
             )) => {
+                relay_metrics.reservation_accepted();
                 tracing::info!("Reservation request accepted from {src_peer_id}");
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(relay::Event::ReservationReqDenied {
+                src_peer_id,
+                ..
+            })) => {
+                relay_metrics.reservation_denied();
+                tracing::info!("Reservation request denied from {src_peer_id}");
+            }
             SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::RoutingUpdated {
                 peer,
                 ..
@@ -169,8 +284,50 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 dst_peer_id,
                 ..
             })) => {
+                relay_metrics.circuit_accepted();
+                open_circuits.accepted(src_peer_id, dst_peer_id);
+                peer_scores
+                    .lock()
+                    .expect("peer scores lock poisoned")
+                    .circuit_accepted(src_peer_id);
                 tracing::info!("Circuit request accepted from {src_peer_id} <-> {dst_peer_id}");
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(relay::Event::CircuitReqDenied {
+                src_peer_id,
+                dst_peer_id,
+                ..
+            })) => {
+                relay_metrics.circuit_denied();
+                tracing::info!("Circuit request denied from {src_peer_id} <-> {dst_peer_id}");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            })) => {
+                let reason = CloseReason::of(error.as_ref());
+                relay_metrics.circuit_closed(reason);
+                let duration = open_circuits.closed(src_peer_id, dst_peer_id);
+                let open = open_circuits.len();
+                match reason {
+                    CloseReason::ByteLimit => tracing::warn!(
+                        %src_peer_id,
+                        %dst_peer_id,
+                        ?duration,
+                        max_circuit_bytes,
+                        open,
+                        "Circuit closed after relaying more than the byte limit"
+                    ),
+                    CloseReason::Done | CloseReason::Error => tracing::info!(
+                        %src_peer_id,
+                        %dst_peer_id,
+                        ?duration,
+                        ?error,
+                        open,
+                        "Circuit closed"
+                    ),
+                }
+            }
             SwarmEvent::ConnectionClosed {
                 peer_id,
                 connection_id,
@@ -183,6 +340,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 } else {
                     tracing::info!("Connection closed from {peer_id} because {cause:?}");
                 }
+
+                if cause.is_some()
+                    && let Some(score) = peer_scores
+                        .lock()
+                        .expect("peer scores lock poisoned")
+                        .connection_failed(peer_id)
+                {
+                    tracing::info!("{peer_id} abandoned a circuit, abuse score {score}");
+                    relay_metrics.peer_score(peer_id, score);
+                }
             }
             event => {
                 // tracing::info!("{event:?}")
@@ -195,40 +362,49 @@ async fn main() -> Result<(), Box<dyn Error>> {
 struct Behaviour {
     relay: relay::Behaviour,
     identify: identify::Behaviour,
-    kademlia: libp2p::kad::Behaviour<MemoryStore>,
+    kademlia: libp2p::kad::Behaviour<Store>,
     ping: ping::Behaviour,
     autonat: autonat::v2::server::Behaviour,
 }
 
-fn generate_ed25519() -> identity::Keypair {
-    identity::Keypair::generate_ed25519()
-}
-
-fn generate_ed25519_from_seed(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
-
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
-}
-
+/// Every flag but `--config` and `--secret-key-seed` overrides the matching setting of the
+/// config file
 #[derive(Debug, Parser)]
 #[command(name = "libp2p relay")]
 struct Opt {
+    /// Config file path
+    #[arg(long)]
+    config: Option<String>,
+
     /// Determine if the relay listen on ipv6 or ipv4 loopback address. the default is ipv4
     #[arg(long)]
     use_ipv6: Option<bool>,
 
-    /// Fixed value to generate deterministic peer id
+    /// Fixed value to generate deterministic peer id, instead of the key file
     #[arg(long)]
     secret_key_seed: Option<u8>,
 
     /// The port used to listen on all interfaces
     #[arg(long)]
-    port: u16,
+    port: Option<u16>,
 
     /// Pre-shared key for Noise protocol
     ///
     /// Example: "mysecretkey"
     #[arg(long)]
-    key: String,
+    key: Option<String>,
+
+    /// Directory to keep DHT records in so they survive restarts, held in memory when unset
+    #[arg(long)]
+    record_store: Option<PathBuf>,
+
+    /// Port to serve Prometheus metrics on over HTTP, covering connections, bandwidth (relayed
+    /// circuits included), reservations, circuits and AutoNAT tests. Not served when unset
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// Circuits a client may abandon right after opening them before its reservations and
+    /// circuits are refused. Every 10 minutes one abandoned circuit is forgiven.
+    #[arg(long)]
+    abuse_threshold: Option<u32>,
 }
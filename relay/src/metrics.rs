@@ -0,0 +1,177 @@
+use std::{net::Ipv4Addr, sync::Arc};
+
+use libp2p::{PeerId, metrics::Registry};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::circuits::CloseReason;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct AutonatLabels {
+    success: bool,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CircuitCloseLabels {
+    reason: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PeerLabels {
+    peer: String,
+}
+
+/// Relay specific counters, next to the connection, bandwidth and protocol metrics recorded by
+/// `libp2p-metrics`
+#[derive(Clone)]
+pub struct RelayMetrics {
+    reservations_accepted: Counter,
+    reservations_denied: Counter,
+    circuits_accepted: Counter,
+    circuits_denied: Counter,
+    circuits_closed: Family<CircuitCloseLabels, Counter>,
+    autonat_tests: Family<AutonatLabels, Counter>,
+    peer_scores: Family<PeerLabels, Gauge>,
+}
+
+impl RelayMetrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let registry = registry.sub_registry_with_prefix("relay_server");
+        let metrics = RelayMetrics {
+            reservations_accepted: Counter::default(),
+            reservations_denied: Counter::default(),
+            circuits_accepted: Counter::default(),
+            circuits_denied: Counter::default(),
+            circuits_closed: Family::default(),
+            autonat_tests: Family::default(),
+            peer_scores: Family::default(),
+        };
+        registry.register(
+            "reservations_accepted",
+            "Reservation requests accepted",
+            metrics.reservations_accepted.clone(),
+        );
+        registry.register(
+            "reservations_denied",
+            "Reservation requests denied",
+            metrics.reservations_denied.clone(),
+        );
+        registry.register(
+            "circuits_accepted",
+            "Circuit requests accepted",
+            metrics.circuits_accepted.clone(),
+        );
+        registry.register(
+            "circuits_denied",
+            "Circuit requests denied",
+            metrics.circuits_denied.clone(),
+        );
+        registry.register(
+            "circuits_closed",
+            "Relayed circuits closed, by whether they were done, hit the byte limit or failed",
+            metrics.circuits_closed.clone(),
+        );
+        registry.register(
+            "autonat_tests",
+            "AutoNAT dial back tests run for clients, by outcome",
+            metrics.autonat_tests.clone(),
+        );
+        registry.register(
+            "peer_scores",
+            "Abuse scores of clients that abandoned circuits, only peers scoring above zero",
+            metrics.peer_scores.clone(),
+        );
+        metrics
+    }
+
+    pub fn reservation_accepted(&self) {
+        self.reservations_accepted.inc();
+    }
+
+    pub fn reservation_denied(&self) {
+        self.reservations_denied.inc();
+    }
+
+    pub fn circuit_accepted(&self) {
+        self.circuits_accepted.inc();
+    }
+
+    pub fn circuit_denied(&self) {
+        self.circuits_denied.inc();
+    }
+
+    pub fn circuit_closed(&self, reason: CloseReason) {
+        let reason = match reason {
+            CloseReason::Done => "done",
+            CloseReason::ByteLimit => "byte_limit",
+            CloseReason::Error => "error",
+        };
+        self.circuits_closed
+            .get_or_create(&CircuitCloseLabels {
+                reason: reason.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn peer_score(&self, peer: PeerId, score: u32) {
+        let labels = PeerLabels {
+            peer: peer.to_string(),
+        };
+        if score == 0 {
+            self.peer_scores.remove(&labels);
+        } else {
+            self.peer_scores.get_or_create(&labels).set(score.into());
+        }
+    }
+
+    pub fn autonat_test(&self, success: bool) {
+        self.autonat_tests
+            .get_or_create(&AutonatLabels { success })
+            .inc();
+    }
+}
+
+/// Serves the registry in the Prometheus text format on every path of the port, until the
+/// process exits
+pub async fn serve(port: u16, registry: Arc<Registry>) -> std::io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    tracing::info!("Serving metrics on port {port}");
+
+    loop {
+        let (mut stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!("Failed to accept metrics connection: {err}");
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            // the request itself doesn't matter, every path answers with the metrics
+            let mut request = [0u8; 1024];
+            if let Err(err) = stream.read(&mut request).await {
+                tracing::debug!("Failed to read metrics request from {remote}: {err}");
+                return;
+            }
+
+            let mut body = String::new();
+            if let Err(err) = encode(&mut body, &registry) {
+                tracing::warn!("Failed to encode metrics: {err}");
+                return;
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(err) = stream.write_all(response.as_bytes()).await {
+                tracing::debug!("Failed to send metrics to {remote}: {err}");
+            }
+        });
+    }
+}
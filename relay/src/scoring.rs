@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use libp2p::{Multiaddr, PeerId, relay};
+
+/// A circuit whose source disconnects with an error within this long after it was accepted
+/// counts as abandoned
+const ABANDON_WINDOW: Duration = Duration::from_secs(10);
+
+/// How often every score drops by one, so a peer that behaves again is let back in
+pub const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Abuse scores of relay clients. A client scores a point for every circuit it abandons right
+/// after opening it, and is refused reservations and circuits once it reaches the threshold.
+pub struct PeerScores {
+    threshold: u32,
+    scores: HashMap<PeerId, u32>,
+    /// When each client's most recent circuit was accepted
+    circuits_accepted: HashMap<PeerId, Instant>,
+}
+
+impl PeerScores {
+    pub fn new(threshold: u32) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(PeerScores {
+            threshold,
+            scores: HashMap::new(),
+            circuits_accepted: HashMap::new(),
+        }))
+    }
+
+    pub fn circuit_accepted(&mut self, src_peer_id: PeerId) {
+        self.circuits_accepted.insert(src_peer_id, Instant::now());
+    }
+
+    /// Scores the peer if the connection failed shortly after one of its circuits was accepted,
+    /// returning the new score if it changed
+    pub fn connection_failed(&mut self, peer_id: PeerId) -> Option<u32> {
+        let accepted_at = self.circuits_accepted.remove(&peer_id)?;
+        if accepted_at.elapsed() > ABANDON_WINDOW {
+            return None;
+        }
+
+        let score = self.scores.entry(peer_id).or_default();
+        *score += 1;
+        if *score == self.threshold {
+            tracing::warn!(
+                "{peer_id} keeps abandoning circuits, refusing its reservations and circuits"
+            );
+        }
+        Some(*score)
+    }
+
+    /// Lowers every score by one, returning the new scores of the peers that still have one
+    /// and zero for those that no longer do
+    pub fn decay(&mut self) -> Vec<(PeerId, u32)> {
+        self.circuits_accepted
+            .retain(|_, accepted_at| accepted_at.elapsed() <= ABANDON_WINDOW);
+
+        let decayed = self
+            .scores
+            .iter_mut()
+            .map(|(peer_id, score)| {
+                *score -= 1;
+                (*peer_id, *score)
+            })
+            .collect::<Vec<_>>();
+        self.scores.retain(|_, score| *score > 0);
+        decayed
+    }
+
+    fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.scores
+            .get(peer_id)
+            .is_some_and(|score| *score >= self.threshold)
+    }
+}
+
+/// Refuses relay reservations and circuits of clients whose score reached the threshold
+pub struct ScoreLimiter(pub Arc<Mutex<PeerScores>>);
+
+impl relay::RateLimiter for ScoreLimiter {
+    fn try_next(&mut self, peer: PeerId, _addr: &Multiaddr, _now: Instant) -> bool {
+        !self
+            .0
+            .lock()
+            .expect("peer scores lock poisoned")
+            .is_banned(&peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::relay::RateLimiter;
+
+    use super::*;
+
+    #[test]
+    fn client_abandoning_circuits_is_refused_past_the_threshold() {
+        let (noisy, quiet) = (PeerId::random(), PeerId::random());
+        let scores = PeerScores::new(2);
+        let mut limiter = ScoreLimiter(scores.clone());
+        let address = Multiaddr::empty();
+
+        let abandon = |peer_id| {
+            let mut scores = scores.lock().unwrap();
+            scores.circuit_accepted(peer_id);
+            scores.connection_failed(peer_id)
+        };
+        assert_eq!(abandon(noisy), Some(1));
+        assert!(limiter.try_next(noisy, &address, Instant::now()));
+        assert_eq!(abandon(noisy), Some(2));
+        assert!(!limiter.try_next(noisy, &address, Instant::now()));
+        assert!(limiter.try_next(quiet, &address, Instant::now()));
+        // a failure without an accepted circuit isn't scored
+        assert_eq!(scores.lock().unwrap().connection_failed(quiet), None);
+
+        assert_eq!(scores.lock().unwrap().decay(), [(noisy, 1)]);
+        assert!(limiter.try_next(noisy, &address, Instant::now()));
+    }
+}